@@ -0,0 +1,11 @@
+#![no_main]
+
+use bittorrent_starter_rust::bencode;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(value) = bencode::decode(data) {
+        let encoded = bencode::encode(&value).expect("decoded values encode");
+        assert_eq!(bencode::decode(&encoded).unwrap(), value);
+    }
+});
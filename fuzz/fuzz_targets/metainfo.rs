@@ -0,0 +1,23 @@
+#![no_main]
+
+use bittorrent_starter_rust::TorrentFile;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(torrent) = TorrentFile::from_bytes(data) else {
+        return;
+    };
+    // Whatever loads must be safe to work with.
+    let _ = torrent.info_hash();
+    let _ = torrent.info_hash_v2();
+    let _ = torrent.info.file_name();
+    // A v2-only torrent has merkle nodes where v1 has SHA-1 hashes, and no
+    // v1 length.
+    let hash_len = if torrent.info.pieces.is_empty() { 32 } else { 20 };
+    let mut length = 0;
+    for piece in 0..torrent.info.num_pieces() {
+        length += torrent.info.piece_len(piece);
+        assert_eq!(torrent.info.piece_hash(piece).len(), hash_len);
+    }
+    assert_eq!(length, torrent.info.size());
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use bittorrent_starter_rust::peer::{Handshake, Message, HANDSHAKE_LEN};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(handshake) = <&[u8; HANDSHAKE_LEN]>::try_from(data) {
+        if let Ok(decoded) = Handshake::from_bytes(handshake) {
+            assert_eq!(decoded.to_bytes(), data);
+        }
+    }
+    // `data` is a message body, as read after its length prefix.
+    if let Ok(message) = Message::from_bytes(data) {
+        let bytes = message.to_bytes();
+        assert_eq!(Message::from_bytes(&bytes[4..]).unwrap(), message);
+    }
+});
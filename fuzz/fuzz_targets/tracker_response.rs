@@ -0,0 +1,8 @@
+#![no_main]
+
+use bittorrent_starter_rust::tracker;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = tracker::parse_response(data);
+});
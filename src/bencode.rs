@@ -0,0 +1,271 @@
+use std::{collections::HashMap, iter::Peekable};
+
+use anyhow::{anyhow, bail, Result};
+use serde::de::DeserializeOwned;
+
+type Bytes<'a> = Peekable<std::slice::Iter<'a, u8>>;
+
+/// How deeply lists and dictionaries may nest, so that hostile input can't
+/// exhaust the stack.
+const MAX_DEPTH: usize = 256;
+
+fn next(iter: &mut Bytes) -> Result<u8> {
+    iter.next()
+        .copied()
+        .ok_or_else(|| anyhow!("unexpected end of input"))
+}
+
+// The digits up to `end`, which is consumed.
+fn digits_until(iter: &mut Bytes, end: u8) -> Result<String> {
+    let mut digits = String::new();
+    loop {
+        match next(iter)? {
+            char if char == end => break,
+            char @ (b'0'..=b'9' | b'-') => digits.push(char as char),
+            char => bail!("unexpected {:?} in a number", char as char),
+        }
+    }
+    // Only the canonical form: no leading zeros, no "-0".
+    let magnitude = digits.strip_prefix('-').unwrap_or(&digits);
+    let negative = magnitude.len() < digits.len();
+    if magnitude.is_empty()
+        || magnitude.contains('-')
+        || (magnitude.starts_with('0') && (magnitude.len() > 1 || negative))
+    {
+        bail!("invalid number {digits:?}");
+    }
+    Ok(digits)
+}
+
+fn parse_ben_string(iter: &mut Bytes) -> Result<String> {
+    let length = digits_until(iter, b':')?;
+    let length = length
+        .parse::<usize>()
+        .map_err(|_| anyhow!("invalid string length {length:?}"))?;
+    if length > iter.len() {
+        bail!("string of {length} bytes runs past the end of the input");
+    }
+    Ok(iter.take(length).map(|&byte| byte as char).collect())
+}
+
+fn parse_ben_int(iter: &mut Bytes) -> Result<serde_json::Value> {
+    iter.next(); // Skip the 'i'
+    let num = digits_until(iter, b'e')?;
+    let num = num
+        .parse::<i64>()
+        .map_err(|_| anyhow!("integer {num} out of range"))?;
+    Ok(serde_json::Value::Number(serde_json::Number::from(num)))
+}
+
+fn parse_ben_list(iter: &mut Bytes, depth: usize) -> Result<serde_json::Value> {
+    iter.next(); // Skip the 'l'
+    let mut items = Vec::new();
+    loop {
+        match iter.peek() {
+            Some(&b'e') => {
+                iter.next(); // Consume the 'e'
+                break;
+            }
+            _ => items.push(decode_value(iter, depth)?),
+        }
+    }
+    Ok(serde_json::Value::Array(items))
+}
+
+fn parse_ben_dict(iter: &mut Bytes, depth: usize) -> Result<serde_json::Value> {
+    iter.next(); // Skip the 'd'
+    let mut map = HashMap::new();
+    loop {
+        match iter.peek() {
+            Some(&b'e') => {
+                iter.next(); // Consume the 'e'
+                break;
+            }
+            Some(_) => {
+                let key = parse_ben_string(iter)?;
+                let value = decode_value(iter, depth)?;
+                map.insert(key, value);
+            }
+            None => bail!("unterminated dictionary"),
+        }
+    }
+    Ok(serde_json::json!(map))
+}
+
+fn decode_value(iter: &mut Bytes, depth: usize) -> Result<serde_json::Value> {
+    if depth == MAX_DEPTH {
+        bail!("values nested more than {MAX_DEPTH} deep");
+    }
+    match iter.peek() {
+        Some(&byte) if byte.is_ascii_digit() => {
+            let string = parse_ben_string(iter)?;
+            Ok(serde_json::Value::String(string))
+        }
+        Some(&b'i') => parse_ben_int(iter),
+        Some(&b'l') => parse_ben_list(iter, depth + 1),
+        Some(&b'd') => parse_ben_dict(iter, depth + 1),
+        Some(&&byte) => bail!("unexpected {:?} at the start of a value", byte as char),
+        None => bail!("unexpected end of input"),
+    }
+}
+
+/// Decodes the value at the start of `iter`, leaving it after the value.
+pub fn decode_bencoded_value(iter: &mut Bytes) -> Result<serde_json::Value> {
+    decode_value(iter, 0)
+}
+
+/// Decodes a bencoded value into its JSON equivalent, with byte strings
+/// shown as text.
+pub fn decode(encoded: &[u8]) -> Result<serde_json::Value> {
+    decode_bencoded_value(&mut encoded.iter().peekable())
+}
+
+/// Deserializes `bytes` like `serde_bencode::from_bytes`, after checking
+/// that they are well formed: serde_bencode allocates whatever length a
+/// string claims and recurses as deeply as its input nests, so a few hostile
+/// bytes could otherwise abort the process.
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    decode(bytes)?;
+    Ok(serde_bencode::from_bytes(bytes)?)
+}
+
+/// The inverse of [`decode`]: each character of a string is one byte, and
+/// dictionaries are written with their keys sorted. Values bencode has no
+/// equivalent for, like floats or `null`, are an error.
+pub fn encode(value: &serde_json::Value) -> Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    encode_into(value, &mut encoded)?;
+    Ok(encoded)
+}
+
+fn encode_string(string: &str, encoded: &mut Vec<u8>) -> Result<()> {
+    let bytes = string
+        .chars()
+        .map(|char| u8::try_from(char).map_err(|_| anyhow!("{char:?} is not a byte")))
+        .collect::<Result<Vec<u8>>>()?;
+    encoded.extend(format!("{}:", bytes.len()).as_bytes());
+    encoded.extend(bytes);
+    Ok(())
+}
+
+fn encode_into(value: &serde_json::Value, encoded: &mut Vec<u8>) -> Result<()> {
+    match value {
+        serde_json::Value::String(string) => encode_string(string, encoded)?,
+        serde_json::Value::Number(number) => {
+            let number = number
+                .as_i64()
+                .ok_or_else(|| anyhow!("{number} is not a bencode integer"))?;
+            encoded.extend(format!("i{number}e").as_bytes());
+        }
+        serde_json::Value::Array(items) => {
+            encoded.push(b'l');
+            for item in items {
+                encode_into(item, encoded)?;
+            }
+            encoded.push(b'e');
+        }
+        serde_json::Value::Object(map) => {
+            // Keys of single-byte characters sort the same as their bytes.
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|&(key, _)| key);
+            encoded.push(b'd');
+            for (key, value) in entries {
+                encode_string(key, encoded)?;
+                encode_into(value, encoded)?;
+            }
+            encoded.push(b'e');
+        }
+        serde_json::Value::Null | serde_json::Value::Bool(_) => {
+            bail!("{value} has no bencode equivalent")
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::testing::Rng;
+
+    fn arbitrary(rng: &mut Rng, depth: usize) -> Value {
+        let string = |rng: &mut Rng| {
+            let len = rng.below(12);
+            rng.bytes(len)
+                .into_iter()
+                .map(char::from)
+                .collect::<String>()
+        };
+        // Containers get rarer the deeper they are.
+        let kinds = if depth < 4 { 4 } else { 2 };
+        match rng.below(kinds) {
+            0 => Value::String(string(rng)),
+            1 => json!(rng.next_u64() as i64 >> rng.below(64)),
+            2 => (0..rng.below(5))
+                .map(|_| arbitrary(rng, depth + 1))
+                .collect(),
+            _ => (0..rng.below(5))
+                .map(|_| (string(rng), arbitrary(rng, depth + 1)))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let mut rng = Rng::new(0xbe4c0de);
+        for _ in 0..2000 {
+            let value = arbitrary(&mut rng, 0);
+            let encoded = encode(&value).unwrap();
+            assert_eq!(decode(&encoded).unwrap(), value, "{encoded:?}");
+            // Values are self-delimiting, so no prefix is one.
+            for end in 0..encoded.len() {
+                assert!(decode(&encoded[..end]).is_err(), "{:?}", &encoded[..end]);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for bad in [
+            &b""[..],
+            b"i12",
+            b"ie",
+            b"i-e",
+            b"i-0e",
+            b"i03e",
+            b"i1-2e",
+            b"i99999999999999999999e",
+            b"5:abc",
+            b"99999999999999999999:a",
+            b"-1:a",
+            b"l",
+            b"li1e",
+            b"d3:key",
+            b"di1ei2ee",
+            b"x",
+        ] {
+            assert!(decode(bad).is_err(), "{}", String::from_utf8_lossy(bad));
+        }
+        let deep = [vec![b'l'; MAX_DEPTH + 1], vec![b'e'; MAX_DEPTH + 1]].concat();
+        assert!(decode(&deep).is_err());
+        let shallow = [vec![b'l'; MAX_DEPTH], vec![b'e'; MAX_DEPTH]].concat();
+        assert!(decode(&shallow).is_ok());
+    }
+
+    #[test]
+    fn survives_garbage() {
+        let mut rng = Rng::new(7);
+        for _ in 0..5000 {
+            let len = rng.below(24);
+            // Mostly bencode's own characters, to get past the first byte.
+            let garbage: Vec<u8> = (0..len)
+                .map(|_| b"0123456789:-idle"[rng.below(16)])
+                .collect();
+            if let Ok(value) = decode(&garbage) {
+                assert_eq!(decode(&encode(&value).unwrap()).unwrap(), value);
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Window over which transfer rates are averaged for choking.
+const RATE_WINDOW: Duration = Duration::from_secs(20);
+
+#[derive(Clone, Debug)]
+pub struct ChokerConfig {
+    /// Peers unchoked for giving us the best rates.
+    pub regular_slots: usize,
+    /// Peers unchoked regardless of rate, rotated periodically.
+    pub optimistic_slots: usize,
+    pub rechoke_interval: Duration,
+    pub optimistic_interval: Duration,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        ChokerConfig {
+            regular_slots: 4,
+            optimistic_slots: 1,
+            rechoke_interval: Duration::from_secs(10),
+            optimistic_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Bytes transferred over a sliding window, for rate estimates.
+pub struct RateMeter {
+    samples: VecDeque<(Instant, u64)>,
+    window: Duration,
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        RateMeter::with_window(RATE_WINDOW)
+    }
+}
+
+impl RateMeter {
+    /// A shorter window follows changes sooner, a longer one smooths more.
+    pub fn with_window(window: Duration) -> Self {
+        RateMeter {
+            samples: VecDeque::new(),
+            window,
+        }
+    }
+
+    pub fn record(&mut self, bytes: u64, now: Instant) {
+        self.samples.push_back((now, bytes));
+        self.expire(now);
+    }
+
+    /// Bytes per second over the window ending at `now`.
+    pub fn rate(&mut self, now: Instant) -> f64 {
+        self.expire(now);
+        let total: u64 = self.samples.iter().map(|&(_, bytes)| bytes).sum();
+        total as f64 / self.window.as_secs_f64()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(at, _)) = self.samples.front() {
+            if now.duration_since(at) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+}
+
+/// What the choker needs to know about a peer at rechoke time.
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    pub interested: bool,
+    /// Rate at which the peer sends us data.
+    pub download_rate: f64,
+    /// Rate at which we send the peer data.
+    pub upload_rate: f64,
+}
+
+/// The standard tit-for-tat choker: reciprocate with the peers that give us
+/// the best download rates (or, when seeding, take our uploads fastest) and
+/// keep rotating an optimistic unchoke so new peers get a chance.
+pub struct Choker {
+    config: ChokerConfig,
+    optimistic: Vec<SocketAddr>,
+    last_rotation: Option<Instant>,
+    // When each peer last had an optimistic slot; rotation favours the
+    // peers that waited longest.
+    last_optimistic: HashMap<SocketAddr, Instant>,
+}
+
+impl Choker {
+    pub fn new(config: ChokerConfig) -> Self {
+        Choker {
+            config,
+            optimistic: Vec::new(),
+            last_rotation: None,
+            last_optimistic: HashMap::new(),
+        }
+    }
+
+    /// Returns the set of peers to unchoke; everyone else gets choked.
+    pub fn rechoke(
+        &mut self,
+        peers: &[PeerStats],
+        seeding: bool,
+        now: Instant,
+    ) -> HashSet<SocketAddr> {
+        let mut candidates: Vec<&PeerStats> = peers.iter().filter(|peer| peer.interested).collect();
+        let rate = |peer: &PeerStats| {
+            if seeding {
+                peer.upload_rate
+            } else {
+                peer.download_rate
+            }
+        };
+        // Stable sort keeps the caller's order among equal rates.
+        candidates.sort_by(|a, b| rate(b).total_cmp(&rate(a)));
+
+        let regular: HashSet<SocketAddr> = candidates
+            .iter()
+            .take(self.config.regular_slots)
+            .map(|peer| peer.addr)
+            .collect();
+
+        // Keep optimistic peers that are still eligible until it is time to
+        // rotate; a peer promoted to a regular slot frees its optimistic one.
+        let eligible: Vec<SocketAddr> = candidates
+            .iter()
+            .map(|peer| peer.addr)
+            .filter(|addr| !regular.contains(addr))
+            .collect();
+        let rotate = self
+            .last_rotation
+            .is_none_or(|last| now.duration_since(last) >= self.config.optimistic_interval);
+        if rotate {
+            self.optimistic.clear();
+            self.last_rotation = Some(now);
+        } else {
+            self.optimistic.retain(|addr| eligible.contains(addr));
+        }
+
+        let mut waiting: Vec<SocketAddr> = eligible
+            .into_iter()
+            .filter(|addr| !self.optimistic.contains(addr))
+            .collect();
+        waiting.sort_by_key(|addr| self.last_optimistic.get(addr).copied());
+        for addr in waiting {
+            if self.optimistic.len() >= self.config.optimistic_slots {
+                break;
+            }
+            self.optimistic.push(addr);
+            self.last_optimistic.insert(addr, now);
+        }
+
+        self.last_optimistic
+            .retain(|addr, _| peers.iter().any(|peer| peer.addr == *addr));
+        regular
+            .into_iter()
+            .chain(self.optimistic.iter().copied())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    fn config(regular_slots: usize) -> ChokerConfig {
+        ChokerConfig {
+            regular_slots,
+            ..ChokerConfig::default()
+        }
+    }
+
+    // Replays per-second byte counts through rate meters, like the rates a
+    // real session would measure over the last rechoke interval.
+    fn simulate(history: &[(u8, bool, &[u64], &[u64])], start: Instant) -> Vec<PeerStats> {
+        history
+            .iter()
+            .map(|&(n, interested, downloaded, uploaded)| {
+                let mut download = RateMeter::default();
+                let mut upload = RateMeter::default();
+                for (second, &bytes) in downloaded.iter().enumerate() {
+                    download.record(bytes, start + Duration::from_secs(second as u64));
+                }
+                for (second, &bytes) in uploaded.iter().enumerate() {
+                    upload.record(bytes, start + Duration::from_secs(second as u64));
+                }
+                let now = start + Duration::from_secs(10);
+                PeerStats {
+                    addr: addr(n),
+                    interested,
+                    download_rate: download.rate(now),
+                    upload_rate: upload.rate(now),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unchokes_fastest_uploaders_to_us_plus_one_optimistic() {
+        let start = Instant::now();
+        let peers = simulate(
+            &[
+                (1, true, &[100; 10], &[]),
+                (2, true, &[900; 10], &[]),
+                (3, true, &[500; 10], &[]),
+                (4, true, &[10; 10], &[]),
+                (5, false, &[5000; 10], &[]),
+            ],
+            start,
+        );
+        let mut choker = Choker::new(config(2));
+        let unchoked = choker.rechoke(&peers, false, start + Duration::from_secs(10));
+
+        assert!(unchoked.contains(&addr(2)));
+        assert!(unchoked.contains(&addr(3)));
+        // Uninterested peers never take a slot, however fast they are.
+        assert!(!unchoked.contains(&addr(5)));
+        assert_eq!(unchoked.len(), 3);
+    }
+
+    #[test]
+    fn seeding_ranks_by_upload_rate() {
+        let start = Instant::now();
+        let peers = simulate(
+            &[
+                (1, true, &[], &[2000; 10]),
+                (2, true, &[9000; 10], &[10; 10]),
+                (3, true, &[], &[700; 10]),
+            ],
+            start,
+        );
+        let mut choker = Choker::new(ChokerConfig {
+            regular_slots: 1,
+            optimistic_slots: 0,
+            ..ChokerConfig::default()
+        });
+        let unchoked = choker.rechoke(&peers, true, start + Duration::from_secs(10));
+        assert_eq!(unchoked, HashSet::from([addr(1)]));
+    }
+
+    #[test]
+    fn old_samples_fall_out_of_the_rate() {
+        let start = Instant::now();
+        let mut meter = RateMeter::default();
+        meter.record(40_000, start);
+        assert_eq!(meter.rate(start + Duration::from_secs(5)), 2000.0);
+        assert_eq!(meter.rate(start + Duration::from_secs(30)), 0.0);
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_interval() {
+        let start = Instant::now();
+        let peers = simulate(
+            &[
+                (1, true, &[1000; 10], &[]),
+                (2, true, &[0; 10], &[]),
+                (3, true, &[0; 10], &[]),
+                (4, true, &[0; 10], &[]),
+            ],
+            start,
+        );
+        let mut choker = Choker::new(config(1));
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let optimistic = |unchoked: HashSet<SocketAddr>| {
+            let mut others: Vec<_> = unchoked.into_iter().filter(|&a| a != addr(1)).collect();
+            assert_eq!(others.len(), 1);
+            others.pop().unwrap()
+        };
+
+        let first = optimistic(choker.rechoke(&peers, false, at(0)));
+        // Regular rechokes keep the same optimistic peer...
+        assert_eq!(optimistic(choker.rechoke(&peers, false, at(10))), first);
+        assert_eq!(optimistic(choker.rechoke(&peers, false, at(20))), first);
+        // ...until the optimistic interval has passed.
+        let second = optimistic(choker.rechoke(&peers, false, at(30)));
+        assert_ne!(second, first);
+        let third = optimistic(choker.rechoke(&peers, false, at(60)));
+        assert!(third != first && third != second);
+        // Everyone has had a turn, so the longest-waiting peer is next.
+        assert_eq!(optimistic(choker.rechoke(&peers, false, at(90))), first);
+    }
+
+    #[test]
+    fn optimistic_slot_is_refilled_when_its_peer_leaves() {
+        let start = Instant::now();
+        let mut peers = simulate(
+            &[
+                (1, true, &[1000; 10], &[]),
+                (2, true, &[], &[]),
+                (3, true, &[], &[]),
+            ],
+            start,
+        );
+        let mut choker = Choker::new(config(1));
+        let unchoked = choker.rechoke(&peers, false, start);
+        let optimistic = *unchoked.iter().find(|&&a| a != addr(1)).unwrap();
+
+        peers.retain(|peer| peer.addr != optimistic);
+        let unchoked = choker.rechoke(&peers, false, start + Duration::from_secs(10));
+        assert_eq!(unchoked.len(), 2);
+        assert!(!unchoked.contains(&optimistic));
+    }
+}
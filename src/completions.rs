@@ -0,0 +1,222 @@
+//! Shell completion scripts, generated from the clap definition of the CLI
+//! so that they never fall behind it.
+
+use std::io::{self, Write};
+
+use clap::{Arg, Command, ValueEnum, ValueHint};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn generate(self, cli: &Command, out: &mut impl Write) -> io::Result<()> {
+        let mut cli = cli.clone();
+        // Propagates global flags and adds --help and --version everywhere.
+        cli.build();
+        match self {
+            Shell::Bash => bash(&cli, out),
+            Shell::Zsh => {
+                writeln!(out, "#compdef {}", cli.get_name())?;
+                writeln!(out, "autoload -U +X bashcompinit && bashcompinit")?;
+                bash(&cli, out)
+            }
+            Shell::Fish => fish(&cli, out),
+        }
+    }
+}
+
+fn subcommands(cli: &Command) -> impl Iterator<Item = &Command> {
+    cli.get_subcommands()
+        .filter(|command| !command.is_hide_set())
+}
+
+fn options(command: &Command) -> impl Iterator<Item = &Arg> {
+    command
+        .get_arguments()
+        .filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+}
+
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_action().takes_values()
+}
+
+fn takes_path(arg: &Arg) -> bool {
+    matches!(
+        arg.get_value_hint(),
+        ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath
+    )
+}
+
+fn possible_values(arg: &Arg) -> Vec<String> {
+    arg.get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_string())
+        .collect()
+}
+
+fn flags(arg: &Arg) -> Vec<String> {
+    let short = arg.get_short().map(|short| format!("-{short}"));
+    let long = arg.get_long().map(|long| format!("--{long}"));
+    short.into_iter().chain(long).collect()
+}
+
+// The first line of an argument's help, quoted for fish.
+fn description(help: Option<String>) -> String {
+    let help = help.unwrap_or_default();
+    let line = help.lines().next().unwrap_or_default();
+    format!("'{}'", line.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn bash(cli: &Command, out: &mut impl Write) -> io::Result<()> {
+    let name = cli.get_name();
+    let function = format!("_{}", name.replace('-', "_"));
+    let commands: Vec<&str> = subcommands(cli).map(Command::get_name).collect();
+
+    writeln!(out, "{function}() {{")?;
+    writeln!(out, "    local cur prev cmd opts values")?;
+    writeln!(out, "    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"")?;
+    writeln!(out, "    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"")?;
+    writeln!(out, "    cmd=\"\"")?;
+    writeln!(
+        out,
+        "    for word in \"${{COMP_WORDS[@]:1:COMP_CWORD-1}}\"; do"
+    )?;
+    writeln!(out, "        case \"$word\" in")?;
+    writeln!(
+        out,
+        "            {}) cmd=\"$word\"; break ;;",
+        commands.join("|")
+    )?;
+    writeln!(out, "        esac")?;
+    writeln!(out, "    done")?;
+
+    // Values of the option just typed. Flag names mean the same thing in
+    // every subcommand, so one table covers them all.
+    writeln!(out, "    case \"$prev\" in")?;
+    let all = std::iter::once(cli).chain(subcommands(cli));
+    let mut seen = Vec::new();
+    for arg in all.flat_map(options).filter(|arg| takes_value(arg)) {
+        let flags = flags(arg);
+        if flags.iter().all(|flag| seen.contains(flag)) {
+            continue;
+        }
+        seen.extend(flags.iter().cloned());
+        let values = possible_values(arg);
+        let reply = if !values.is_empty() {
+            format!(
+                "COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+                values.join(" ")
+            )
+        } else if takes_path(arg) {
+            "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string()
+        } else {
+            // Nothing sensible to offer for numbers and the like.
+            "COMPREPLY=()".to_string()
+        };
+        writeln!(out, "        {}) {reply}; return ;;", flags.join("|"))?;
+    }
+    writeln!(out, "    esac")?;
+
+    writeln!(out, "    case \"$cmd\" in")?;
+    let top: Vec<String> = options(cli).flat_map(flags).collect();
+    writeln!(
+        out,
+        "        \"\") opts=\"{} {}\"; values=\"{}\" ;;",
+        commands.join(" "),
+        top.join(" "),
+        commands.join(" ")
+    )?;
+    for command in subcommands(cli) {
+        let opts: Vec<String> = options(command).flat_map(flags).collect();
+        let values: Vec<String> = command
+            .get_positionals()
+            .flat_map(possible_values)
+            .collect();
+        writeln!(
+            out,
+            "        {}) opts=\"{}\"; values=\"{}\" ;;",
+            command.get_name(),
+            opts.join(" "),
+            values.join(" ")
+        )?;
+    }
+    writeln!(out, "    esac")?;
+
+    writeln!(out, "    if [[ \"$cur\" == -* ]]; then")?;
+    writeln!(
+        out,
+        "        COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))"
+    )?;
+    writeln!(out, "    elif [[ -n \"$values\" ]]; then")?;
+    writeln!(
+        out,
+        "        COMPREPLY=($(compgen -W \"$values\" -- \"$cur\"))"
+    )?;
+    writeln!(out, "    else")?;
+    writeln!(out, "        COMPREPLY=($(compgen -f -- \"$cur\"))")?;
+    writeln!(out, "    fi")?;
+    writeln!(out, "}}")?;
+    writeln!(out, "complete -F {function} {name}")
+}
+
+fn fish(cli: &Command, out: &mut impl Write) -> io::Result<()> {
+    let name = cli.get_name();
+    let top = "__fish_use_subcommand".to_string();
+    for arg in options(cli) {
+        fish_option(out, name, &top, arg)?;
+    }
+    for command in subcommands(cli) {
+        writeln!(
+            out,
+            "complete -c {name} -n {top} -f -a {} -d {}",
+            command.get_name(),
+            description(command.get_about().map(|about| about.to_string()))
+        )?;
+        let condition = format!("'__fish_seen_subcommand_from {}'", command.get_name());
+        for arg in options(command) {
+            fish_option(out, name, &condition, arg)?;
+        }
+        let values: Vec<String> = command
+            .get_positionals()
+            .flat_map(possible_values)
+            .collect();
+        if !values.is_empty() {
+            writeln!(
+                out,
+                "complete -c {name} -n {condition} -f -a '{}'",
+                values.join(" ")
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn fish_option(out: &mut impl Write, name: &str, condition: &str, arg: &Arg) -> io::Result<()> {
+    let mut line = format!("complete -c {name} -n {condition}");
+    if let Some(short) = arg.get_short() {
+        line += &format!(" -s {short}");
+    }
+    if let Some(long) = arg.get_long() {
+        line += &format!(" -l {long}");
+    }
+    line += &format!(
+        " -d {}",
+        description(arg.get_help().map(|help| help.to_string()))
+    );
+    if takes_value(arg) {
+        let values = possible_values(arg);
+        if !values.is_empty() {
+            line += &format!(" -r -f -a '{}'", values.join(" "));
+        } else if takes_path(arg) {
+            line += " -r -F";
+        } else {
+            line += " -r -f";
+        }
+    }
+    writeln!(out, "{line}")
+}
@@ -0,0 +1,583 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet},
+    fs,
+    hash::{BuildHasher, Hasher},
+    net::{SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+
+use crate::bencode;
+use krpc::{Args, Krpc, Response};
+use routing::{distance, RoutingTable, K};
+
+mod krpc;
+mod routing;
+
+pub type NodeId = [u8; 20];
+
+const CLIENT_VERSION: &[u8] = b"RB\x00\x01";
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Queries in flight per lookup round.
+const ALPHA: usize = 3;
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_VALUES: usize = 50;
+
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+pub struct DhtConfig {
+    pub bind: SocketAddr,
+    /// `host:port` of nodes used to join the network.
+    pub bootstrap: Vec<String>,
+    /// Where the node id and routing table are kept between runs.
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            bind: SocketAddr::from(([0, 0, 0, 0], crate::extension::LISTEN_PORT)),
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|s| s.to_string()).collect(),
+            state_file: Some(std::env::temp_dir().join("rust-bittorrent-dht.dat")),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct State {
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+struct Secrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+struct Inner {
+    socket: UdpSocket,
+    id: NodeId,
+    config: DhtConfig,
+    table: Mutex<RoutingTable>,
+    storage: Mutex<HashMap<NodeId, HashMap<SocketAddrV4, Instant>>>,
+    pending: Mutex<HashMap<Vec<u8>, mpsc::Sender<Krpc>>>,
+    secrets: Mutex<Secrets>,
+    next_transaction: AtomicU16,
+    shutdown: AtomicBool,
+}
+
+/// A mainline DHT node (BEP 5). Incoming packets are handled on a
+/// background thread; lookups block the calling thread.
+pub struct Dht {
+    inner: Arc<Inner>,
+}
+
+struct Lookup {
+    // Nodes that answered, keyed by distance to the target, with the token
+    // they handed out for `announce_peer`.
+    responded: BTreeMap<NodeId, (NodeId, SocketAddrV4, Option<ByteBuf>)>,
+    peers: HashSet<SocketAddr>,
+}
+
+impl Dht {
+    pub fn start(config: DhtConfig) -> Result<Self> {
+        let socket = UdpSocket::bind(config.bind)?;
+        socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+
+        let state = config
+            .state_file
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|bytes| bencode::from_bytes::<State>(&bytes).ok());
+        let id = match &state {
+            Some(state) => state.id.as_slice().try_into()?,
+            None => random_id(),
+        };
+        let mut table = RoutingTable::new(id);
+        if let Some(state) = &state {
+            for (node_id, addr) in krpc::decode_nodes(&state.nodes) {
+                table.insert(node_id, addr);
+            }
+        }
+
+        let inner = Arc::new(Inner {
+            socket,
+            id,
+            config,
+            table: Mutex::new(table),
+            storage: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(Secrets {
+                current: random_id(),
+                previous: random_id(),
+                rotated: Instant::now(),
+            }),
+            next_transaction: AtomicU16::new(0),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let receiver = inner.clone();
+        thread::spawn(move || receiver.receive_loop());
+        let maintainer = inner.clone();
+        thread::spawn(move || maintainer.maintenance_loop());
+
+        Ok(Dht { inner })
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.inner.table.lock().unwrap().len()
+    }
+
+    /// Joins the network through the configured bootstrap nodes and any
+    /// nodes remembered from the last run.
+    pub fn bootstrap(&self) -> Result<()> {
+        let routers: Vec<SocketAddrV4> = self
+            .inner
+            .config
+            .bootstrap
+            .iter()
+            .filter_map(|host| host.to_socket_addrs().ok())
+            .flatten()
+            .filter_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })
+            .collect();
+
+        let own_id = self.inner.id;
+        thread::scope(|s| {
+            for router in &routers {
+                s.spawn(|| self.inner.find_node(*router, &own_id));
+            }
+        });
+
+        self.inner.lookup(&own_id, false);
+        if self.node_count() == 0 {
+            bail!("could not reach any DHT bootstrap node");
+        }
+        Ok(())
+    }
+
+    /// Looks up peers for `info_hash`. With `announce_port` set, we also
+    /// announce ourselves to the closest nodes that answered.
+    pub fn get_peers(
+        &self,
+        info_hash: &[u8; 20],
+        announce_port: Option<u16>,
+    ) -> Result<Vec<SocketAddr>> {
+        let lookup = self.inner.lookup(info_hash, true);
+
+        if let Some(port) = announce_port {
+            thread::scope(|s| {
+                for (_, addr, token) in lookup.responded.values().take(K) {
+                    let Some(token) = token else { continue };
+                    let args = Args {
+                        id: ByteBuf::from(self.inner.id.to_vec()),
+                        info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                        port: Some(port),
+                        token: Some(token.clone()),
+                        ..Default::default()
+                    };
+                    s.spawn(move || self.inner.query(*addr, "announce_peer", args));
+                }
+            });
+        }
+
+        Ok(lookup.peers.into_iter().collect())
+    }
+
+    /// Writes the node id and routing table to the configured state file.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.inner.config.state_file else {
+            return Ok(());
+        };
+        let nodes: Vec<(NodeId, SocketAddrV4)> = self
+            .inner
+            .table
+            .lock()
+            .unwrap()
+            .nodes()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        let state = State {
+            id: ByteBuf::from(self.inner.id.to_vec()),
+            nodes: ByteBuf::from(krpc::encode_nodes(&nodes)),
+        };
+        fs::write(path, serde_bencode::to_bytes(&state)?)?;
+        Ok(())
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        let _ = self.save();
+        self.inner.shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Inner {
+    fn receive_loop(&self) {
+        let mut buffer = [0u8; 2048];
+        while !self.shutdown.load(Ordering::Relaxed) {
+            let Ok((len, from)) = self.socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            let Ok(message) = bencode::from_bytes::<Krpc>(&buffer[..len]) else {
+                continue;
+            };
+            if let Some(id) = message.sender_id() {
+                self.table.lock().unwrap().insert(id, from);
+            }
+
+            match message.y.as_str() {
+                "q" => {
+                    let reply = self.answer(message, from);
+                    if let Ok(bytes) = serde_bencode::to_bytes(&reply) {
+                        let _ = self.socket.send_to(&bytes, from);
+                    }
+                }
+                "r" | "e" => {
+                    if let Some(tx) = self.pending.lock().unwrap().remove(message.t.as_slice()) {
+                        let _ = tx.send(message);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn maintenance_loop(&self) {
+        let mut last_run = Instant::now();
+        while !self.shutdown.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(500));
+            if last_run.elapsed() < MAINTENANCE_INTERVAL {
+                continue;
+            }
+            last_run = Instant::now();
+
+            let questionable = self.table.lock().unwrap().questionable();
+            for node in questionable {
+                let args = Args {
+                    id: ByteBuf::from(self.id.to_vec()),
+                    ..Default::default()
+                };
+                if self.query(node.addr, "ping", args).is_err() {
+                    self.table.lock().unwrap().mark_failed(&node.id);
+                }
+            }
+
+            let mut storage = self.storage.lock().unwrap();
+            for peers in storage.values_mut() {
+                peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+            }
+            storage.retain(|_, peers| !peers.is_empty());
+        }
+    }
+
+    fn answer(&self, query: Krpc, from: SocketAddrV4) -> Krpc {
+        match self.try_answer(&query, from) {
+            Ok(response) => Krpc::response(query.t, response),
+            Err(e) => {
+                let code = match query.q.as_deref() {
+                    Some("ping" | "find_node" | "get_peers" | "announce_peer") => {
+                        krpc::ERROR_PROTOCOL
+                    }
+                    _ => krpc::ERROR_METHOD_UNKNOWN,
+                };
+                Krpc::error(query.t, code, &e.to_string())
+            }
+        }
+    }
+
+    fn try_answer(&self, query: &Krpc, from: SocketAddrV4) -> Result<Response> {
+        let args = query
+            .a
+            .as_ref()
+            .ok_or_else(|| anyhow!("missing arguments"))?;
+        let mut response = Response {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+
+        match query.q.as_deref() {
+            Some("ping") => {}
+            Some("find_node") => {
+                let target = krpc::node_id(&args.target)?;
+                response.nodes = Some(self.closest_nodes(&target));
+            }
+            Some("get_peers") => {
+                let info_hash = krpc::node_id(&args.info_hash)?;
+                response.token = Some(ByteBuf::from(self.token(&from, false).to_vec()));
+                let values: Vec<ByteBuf> = self
+                    .storage
+                    .lock()
+                    .unwrap()
+                    .get(&info_hash)
+                    .into_iter()
+                    .flat_map(|peers| peers.keys())
+                    .take(MAX_VALUES)
+                    .map(|peer| ByteBuf::from(krpc::encode_peer(peer)))
+                    .collect();
+                if values.is_empty() {
+                    response.nodes = Some(self.closest_nodes(&info_hash));
+                } else {
+                    response.values = Some(values);
+                }
+            }
+            Some("announce_peer") => {
+                let info_hash = krpc::node_id(&args.info_hash)?;
+                let token = args
+                    .token
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("missing token"))?;
+                if token.as_slice() != self.token(&from, false)
+                    && token.as_slice() != self.token(&from, true)
+                {
+                    bail!("bad token");
+                }
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => bail!("missing port"),
+                };
+                self.storage
+                    .lock()
+                    .unwrap()
+                    .entry(info_hash)
+                    .or_default()
+                    .insert(SocketAddrV4::new(*from.ip(), port), Instant::now());
+            }
+            Some(method) => bail!("unknown method {method}"),
+            None => bail!("missing method"),
+        }
+        Ok(response)
+    }
+
+    fn closest_nodes(&self, target: &NodeId) -> ByteBuf {
+        let nodes: Vec<(NodeId, SocketAddrV4)> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        ByteBuf::from(krpc::encode_nodes(&nodes))
+    }
+
+    // Tokens are a hash of the requester's IP and a secret that rotates every
+    // few minutes; the previous secret stays valid for one rotation.
+    fn token(&self, from: &SocketAddrV4, previous: bool) -> [u8; 20] {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated.elapsed() > TOKEN_ROTATION {
+            secrets.previous = secrets.current;
+            secrets.current = random_id();
+            secrets.rotated = Instant::now();
+        }
+        let secret = if previous {
+            secrets.previous
+        } else {
+            secrets.current
+        };
+        let mut hasher = Sha1::new();
+        hasher.update(from.ip().octets());
+        hasher.update(secret);
+        hasher.finalize().into()
+    }
+
+    fn query(&self, addr: SocketAddrV4, method: &str, args: Args) -> Result<Response> {
+        let t = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (tx, rx) = mpsc::channel();
+        self.pending.lock().unwrap().insert(t.clone(), tx);
+
+        let bytes = serde_bencode::to_bytes(&Krpc::query(t.clone(), method, args))?;
+        self.socket.send_to(&bytes, addr)?;
+
+        let reply = rx.recv_timeout(QUERY_TIMEOUT);
+        self.pending.lock().unwrap().remove(&t);
+        let reply = reply.map_err(|_| anyhow!("{method} to {addr} timed out"))?;
+        if let Some((code, message)) = reply.e {
+            bail!("{method} to {addr} failed: {code} {message}");
+        }
+        reply.r.ok_or_else(|| anyhow!("empty response from {addr}"))
+    }
+
+    fn find_node(&self, addr: SocketAddrV4, target: &NodeId) -> Result<Response> {
+        let args = Args {
+            id: ByteBuf::from(self.id.to_vec()),
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..Default::default()
+        };
+        self.query(addr, "find_node", args)
+    }
+
+    /// Iterative Kademlia lookup: keep querying the closest nodes we know of
+    /// until none of the `K` closest are left unqueried.
+    fn lookup(&self, target: &NodeId, get_peers: bool) -> Lookup {
+        let mut candidates: BTreeMap<NodeId, (NodeId, SocketAddrV4)> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, target), (node.id, node.addr)))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut lookup = Lookup {
+            responded: BTreeMap::new(),
+            peers: HashSet::new(),
+        };
+
+        loop {
+            let batch: Vec<(NodeId, SocketAddrV4)> = candidates
+                .values()
+                .take(K)
+                .filter(|(id, _)| !queried.contains(id))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let results: Vec<_> = thread::scope(|s| {
+                let handles: Vec<_> = batch
+                    .iter()
+                    .map(|&(id, addr)| {
+                        s.spawn(move || {
+                            let result = if get_peers {
+                                let args = Args {
+                                    id: ByteBuf::from(self.id.to_vec()),
+                                    info_hash: Some(ByteBuf::from(target.to_vec())),
+                                    ..Default::default()
+                                };
+                                self.query(addr, "get_peers", args)
+                            } else {
+                                self.find_node(addr, target)
+                            };
+                            (id, addr, result)
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).collect()
+            });
+
+            for (id, addr, result) in results {
+                queried.insert(id);
+                let response = match result {
+                    Ok(response) => response,
+                    Err(_) => {
+                        self.table.lock().unwrap().mark_failed(&id);
+                        candidates.remove(&distance(&id, target));
+                        continue;
+                    }
+                };
+                for (node_id, node_addr) in krpc::decode_nodes(
+                    response
+                        .nodes
+                        .as_deref()
+                        .map(|n| n.as_slice())
+                        .unwrap_or(&[]),
+                ) {
+                    if node_id != self.id {
+                        candidates.insert(distance(&node_id, target), (node_id, node_addr));
+                    }
+                }
+                if let Some(values) = &response.values {
+                    lookup.peers.extend(krpc::decode_values(values));
+                }
+                lookup
+                    .responded
+                    .insert(distance(&id, target), (id, addr, response.token));
+            }
+        }
+        lookup
+    }
+}
+
+pub fn random_id() -> NodeId {
+    let mut hasher = Sha1::new();
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    hasher.update(nanos.to_be_bytes());
+    hasher.update(std::process::id().to_be_bytes());
+    hasher.update(RandomState::new().build_hasher().finish().to_be_bytes());
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_node(bootstrap: &[&Dht], state_file: Option<PathBuf>) -> Dht {
+        let config = DhtConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            bootstrap: bootstrap
+                .iter()
+                .map(|node| node.inner.socket.local_addr().unwrap().to_string())
+                .collect(),
+            state_file,
+        };
+        Dht::start(config).unwrap()
+    }
+
+    #[test]
+    fn finds_peers_announced_by_another_node() {
+        let router = local_node(&[], None);
+        let seeder = local_node(&[&router], None);
+        let leecher = local_node(&[&router], None);
+        seeder.bootstrap().unwrap();
+        leecher.bootstrap().unwrap();
+
+        let info_hash = [7; 20];
+        assert!(leecher.get_peers(&info_hash, None).unwrap().is_empty());
+        seeder.get_peers(&info_hash, Some(51413)).unwrap();
+
+        let peers = leecher.get_peers(&info_hash, None).unwrap();
+        assert_eq!(peers, vec!["127.0.0.1:51413".parse().unwrap()]);
+    }
+
+    #[test]
+    fn routing_table_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("dht.dat");
+        let router = local_node(&[], None);
+
+        let node = local_node(&[&router], Some(state_file.clone()));
+        node.bootstrap().unwrap();
+        let id = node.inner.id;
+        drop(node);
+
+        // No bootstrap nodes this time: the saved table is all it has.
+        let node = local_node(&[], Some(state_file));
+        assert_eq!(node.inner.id, id);
+        assert_eq!(node.node_count(), 1);
+        node.bootstrap().unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use super::NodeId;
+
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// A KRPC message (BEP 5). Queries carry `q` and `a`, responses `r` and
+/// errors `e`; `y` says which of the three it is.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Krpc {
+    pub t: ByteBuf,
+    pub y: String,
+    pub q: Option<String>,
+    pub a: Option<Args>,
+    pub r: Option<Response>,
+    pub e: Option<(i64, String)>,
+    pub v: Option<ByteBuf>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Args {
+    pub id: ByteBuf,
+    pub target: Option<ByteBuf>,
+    pub info_hash: Option<ByteBuf>,
+    pub port: Option<u16>,
+    pub token: Option<ByteBuf>,
+    pub implied_port: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Response {
+    pub id: ByteBuf,
+    pub nodes: Option<ByteBuf>,
+    pub values: Option<Vec<ByteBuf>>,
+    pub token: Option<ByteBuf>,
+}
+
+impl Krpc {
+    pub fn query(t: Vec<u8>, method: &str, args: Args) -> Self {
+        Krpc {
+            t: ByteBuf::from(t),
+            y: "q".to_string(),
+            q: Some(method.to_string()),
+            a: Some(args),
+            v: Some(ByteBuf::from(super::CLIENT_VERSION.to_vec())),
+            ..Default::default()
+        }
+    }
+
+    pub fn response(t: ByteBuf, r: Response) -> Self {
+        Krpc {
+            t,
+            y: "r".to_string(),
+            r: Some(r),
+            v: Some(ByteBuf::from(super::CLIENT_VERSION.to_vec())),
+            ..Default::default()
+        }
+    }
+
+    pub fn error(t: ByteBuf, code: i64, message: &str) -> Self {
+        Krpc {
+            t,
+            y: "e".to_string(),
+            e: Some((code, message.to_string())),
+            ..Default::default()
+        }
+    }
+
+    /// Id of the sending node, from either the query or the response.
+    pub fn sender_id(&self) -> Option<NodeId> {
+        let id = match (&self.a, &self.r) {
+            (Some(a), _) => &a.id,
+            (None, Some(r)) => &r.id,
+            (None, None) => return None,
+        };
+        id.as_slice().try_into().ok()
+    }
+}
+
+pub fn node_id(bytes: &Option<ByteBuf>) -> Result<NodeId> {
+    match bytes {
+        Some(bytes) => Ok(bytes.as_slice().try_into()?),
+        None => bail!("missing node id"),
+    }
+}
+
+/// Compact node info: 20-byte id followed by compact IPv4 peer info.
+pub fn encode_nodes(nodes: &[(NodeId, SocketAddrV4)]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(nodes.len() * 26);
+    for (id, addr) in nodes {
+        bytes.extend(id);
+        bytes.extend(encode_peer(addr));
+    }
+    bytes
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<(NodeId, SocketAddrV4)> {
+    bytes
+        .chunks_exact(26)
+        .map(|chunk| {
+            let id: NodeId = chunk[..20].try_into().unwrap();
+            (id, decode_peer(&chunk[20..]))
+        })
+        .collect()
+}
+
+pub fn encode_peer(addr: &SocketAddrV4) -> Vec<u8> {
+    let mut bytes = addr.ip().octets().to_vec();
+    bytes.extend(addr.port().to_be_bytes());
+    bytes
+}
+
+fn decode_peer(chunk: &[u8]) -> SocketAddrV4 {
+    let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+    SocketAddrV4::new(ip, u16::from_be_bytes([chunk[4], chunk[5]]))
+}
+
+pub fn decode_values(values: &[ByteBuf]) -> Vec<SocketAddr> {
+    values
+        .iter()
+        .filter(|value| value.len() == 6)
+        .map(|value| SocketAddr::V4(decode_peer(value)))
+        .collect()
+}
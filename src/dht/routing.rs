@@ -0,0 +1,130 @@
+use std::{
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use super::NodeId;
+
+/// Bucket size (the Kademlia `k`).
+pub const K: usize = 8;
+/// Nodes that failed to answer this many queries in a row are replaced first.
+const MAX_FAILURES: u32 = 2;
+/// A node is "good" if we heard from it within this window (BEP 5).
+pub const GOOD_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+    pub last_seen: Instant,
+    pub failures: u32,
+}
+
+impl Node {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0; 20];
+    for i in 0..20 {
+        d[i] = a[i] ^ b[i];
+    }
+    d
+}
+
+// Bucket `i` holds nodes whose distance from us has `i` leading zero bits.
+fn bucket_index(own_id: &NodeId, id: &NodeId) -> usize {
+    let d = distance(own_id, id);
+    let mut zeros = 0;
+    for byte in d {
+        if byte == 0 {
+            zeros += 8;
+        } else {
+            zeros += byte.leading_zeros() as usize;
+            break;
+        }
+    }
+    zeros.min(159)
+}
+
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        RoutingTable {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    /// Records that we heard from a node. New nodes go into their bucket if
+    /// there is room or a bad node to evict; otherwise they are dropped.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddrV4) {
+        if id == self.own_id {
+            return;
+        }
+        let bucket = &mut self.buckets[bucket_index(&self.own_id, &id)];
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == id) {
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            return;
+        }
+
+        let node = Node {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(bad) = bucket.iter_mut().find(|node| node.is_bad()) {
+            *bad = node;
+        }
+    }
+
+    pub fn mark_failed(&mut self, id: &NodeId) {
+        let bucket = &mut self.buckets[bucket_index(&self.own_id, id)];
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == *id) {
+            node.failures += 1;
+        }
+    }
+
+    /// The `n` known nodes closest to `target`, nearest first.
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|node| !node.is_bad())
+            .cloned()
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    /// Nodes we have not heard from recently and should ping.
+    pub fn questionable(&self) -> Vec<Node> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|node| node.last_seen.elapsed() > GOOD_WINDOW)
+            .cloned()
+            .collect()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flatten()
+    }
+}
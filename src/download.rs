@@ -0,0 +1,630 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::{
+    sync::{mpsc, oneshot, Semaphore},
+    task::JoinSet,
+    time::timeout,
+};
+
+use crate::{
+    events::{self, EventKind, EventSender},
+    extension::ExtensionRegistry,
+    log::{debug, span, trace, warning, Instrument},
+    metadata::MetadataHandler,
+    peer::{Message, PeerConnection, Timeouts, BLOCK_SIZE},
+    pex::{self, PexHandler},
+    pool::SharedPeerPool,
+    ratelimit::{Direction, Limits, RateLimits},
+    server::ServedTorrent,
+    webseed::{Fetched, Style, WebSeed},
+    Identity, TorrentFile, TorrentFileInfo,
+};
+
+/// How many peers we download from at once, unless configured otherwise.
+pub const MAX_PEERS: usize = 30;
+// How long to wait for LAN peers before giving up on an empty pool.
+const LSD_WAIT: Duration = Duration::from_secs(10);
+/// How often the torrent task looks for new peers when nothing happens.
+const REFILL_INTERVAL: Duration = Duration::from_millis(500);
+/// Failed requests in a row after which we give up on a web seed.
+const WEB_SEED_FAILURES: usize = 3;
+const WEB_SEED_RETRY: Duration = Duration::from_secs(2);
+
+/// Where pieces come from: a peer, or a web seed by its position in
+/// [`Download::web_seeds`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Source {
+    Peer(SocketAddr),
+    WebSeed(usize),
+}
+
+/// What peer tasks tell the torrent task.
+enum Event {
+    /// The peer wants a piece to fetch, chosen from `available`, which is
+    /// in the order the peer would like them.
+    Pick {
+        available: Vec<usize>,
+        reply: oneshot::Sender<Option<usize>>,
+    },
+    /// A piece that passed its hash check.
+    Piece {
+        from: Source,
+        index: usize,
+        data: Vec<u8>,
+    },
+    /// The peer could not deliver a piece it picked; someone else may try.
+    Released {
+        index: usize,
+    },
+    Finished {
+        from: Source,
+        result: Result<()>,
+    },
+}
+
+/// A download in progress. Each peer runs on its own task and reports to
+/// the torrent task in `run`, which hands out pieces and stores them.
+pub struct Download {
+    pub torrent: TorrentFile,
+    pub info_hash: [u8; 20],
+    pub served: Arc<ServedTorrent>,
+    pub limits: Arc<RateLimits>,
+    pub timeouts: Timeouts,
+    pub identity: Identity,
+    /// How many peers we download from at once.
+    pub max_peers: usize,
+    /// Connections the whole session may still open; each peer holds one.
+    pub connections: Arc<Semaphore>,
+    pub events: EventSender,
+    /// Peers we are downloading from right now.
+    pub connected: AtomicUsize,
+    /// Verified pieces waiting for `run` to write them to disk.
+    pub unwritten: AtomicUsize,
+}
+
+// Counts a peer as connected for as long as it lives, and reports the end
+// of the connection however its task ends.
+struct Connected<'a> {
+    download: &'a Download,
+    peer: SocketAddr,
+    error: Option<String>,
+}
+
+impl<'a> Connected<'a> {
+    fn new(download: &'a Download, peer: SocketAddr) -> Self {
+        download.connected.fetch_add(1, Ordering::Relaxed);
+        download.emit(EventKind::PeerConnected { peer });
+        Connected {
+            download,
+            peer,
+            error: None,
+        }
+    }
+}
+
+impl Drop for Connected<'_> {
+    fn drop(&mut self) {
+        self.download.connected.fetch_sub(1, Ordering::Relaxed);
+        self.download.emit(EventKind::PeerDisconnected {
+            peer: self.peer,
+            error: self.error.take(),
+        });
+    }
+}
+
+impl Download {
+    /// Fetches every missing piece, working through peers from the pool as
+    /// they turn up, and from the torrent's web seeds alongside them. With
+    /// `wait_for_peers`, an empty pool is given a while to fill (from LAN
+    /// discovery, say) before we give up.
+    pub async fn run(self: Arc<Self>, wait_for_peers: bool) -> Result<()> {
+        let (events, mut incoming) = mpsc::channel(self.max_peers);
+        let mut peers = JoinSet::new();
+        let mut active = 0;
+        let web_seeds = self.web_seeds()?;
+        if !self.served.is_complete() {
+            for (seed, web_seed) in web_seeds.iter().enumerate() {
+                let download = self.clone();
+                let events = events.clone();
+                let web_seed = web_seed.clone();
+                let span = span!("web_seed", url = web_seed.url);
+                let fetch = async move {
+                    let result = download.fetch_from_web_seed(&web_seed, seed, &events).await;
+                    let from = Source::WebSeed(seed);
+                    let _ = events.send(Event::Finished { from, result }).await;
+                };
+                peers.spawn(fetch.instrument(span));
+                active += 1;
+            }
+        }
+        // Pieces some peer is working on.
+        let mut pending = HashSet::new();
+        let mut idle_since = Instant::now();
+
+        while !self.served.is_complete() {
+            // Out of connections, we wait for other torrents to free some
+            // rather than give up.
+            let mut starved = false;
+            while active < self.max_peers {
+                let Ok(permit) = self.connections.clone().try_acquire_owned() else {
+                    starved = true;
+                    break;
+                };
+                let Some(peer) = self.served.pool.lock().unwrap().next_candidate() else {
+                    break;
+                };
+                let download = self.clone();
+                let events = events.clone();
+                let fetch = async move {
+                    let result = download.fetch_from(peer, &events).await;
+                    let from = Source::Peer(peer);
+                    let _ = events.send(Event::Finished { from, result }).await;
+                    drop(permit);
+                };
+                peers.spawn(fetch.instrument(span!("peer", addr = peer)));
+                active += 1;
+            }
+            if active > 0 || starved {
+                idle_since = Instant::now();
+            } else if !wait_for_peers || idle_since.elapsed() > LSD_WAIT {
+                bail!("ran out of peers");
+            }
+
+            let Ok(Some(event)) = timeout(REFILL_INTERVAL, incoming.recv()).await else {
+                continue;
+            };
+            match event {
+                Event::Pick { available, reply } => {
+                    let index = available
+                        .into_iter()
+                        .find(|&index| !self.served.has_piece(index) && !pending.contains(&index));
+                    if let Some(index) = index {
+                        pending.insert(index);
+                    }
+                    let _ = reply.send(index);
+                }
+                Event::Piece { from, index, data } => {
+                    pending.remove(&index);
+                    self.served.storage.write_piece(index, &data)?;
+                    self.unwritten.fetch_sub(1, Ordering::Relaxed);
+                    self.served.mark_have(index);
+                    let peer = match from {
+                        Source::Peer(peer) => Some(peer.ip()),
+                        Source::WebSeed(_) => None,
+                    };
+                    self.served.record_download(peer, data.len());
+                    let pieces = self.served.pieces();
+                    let verified = pieces.iter().filter(|&&have| have).count();
+                    self.emit(EventKind::PieceCompleted {
+                        index,
+                        verified,
+                        pieces: pieces.len(),
+                    });
+                }
+                Event::Released { index } => {
+                    pending.remove(&index);
+                }
+                Event::Finished {
+                    from: Source::Peer(peer),
+                    result,
+                } => {
+                    active -= 1;
+                    peers.join_next().await;
+                    if let Err(e) = &result {
+                        debug!("Peer {peer} failed: {e}");
+                    }
+                    let pool = &self.served.pool;
+                    pool.lock().unwrap().disconnected(peer, result.is_err());
+                }
+                // Peers carry on without it.
+                Event::Finished {
+                    from: Source::WebSeed(seed),
+                    result,
+                } => {
+                    active -= 1;
+                    peers.join_next().await;
+                    if let Err(e) = &result {
+                        let url = &web_seeds[seed].url;
+                        warning!("Gave up on web seed {url}: {e}");
+                    }
+                }
+            }
+        }
+        // Dropping the join set cancels the peers still connected.
+        Ok(())
+    }
+
+    pub(crate) fn emit(&self, kind: EventKind) {
+        events::emit(&self.events, self.info_hash, kind);
+    }
+
+    async fn fetch_from(&self, peer: SocketAddr, events: &mpsc::Sender<Event>) -> Result<()> {
+        let pool = &self.served.pool;
+        let mut stream = connect(
+            &self.torrent,
+            self.info_hash,
+            peer,
+            pool,
+            self.identity,
+            self.timeouts,
+        )
+        .await?;
+        debug!("Connected");
+        let mut connected = Connected::new(self, peer);
+        let result = self.fetch_pieces(&mut stream, events).await;
+        if let Err(e) = &result {
+            connected.error = Some(e.to_string());
+        }
+        result
+    }
+
+    // The torrent's web seeds: those in `url-list` (BEP 19), then those in
+    // `httpseeds` (BEP 17).
+    fn web_seeds(&self) -> Result<Vec<Arc<WebSeed>>> {
+        let info_hash = self.info_hash;
+        let hoffman = Style::Hoffman { info_hash };
+        let get_right = self
+            .torrent
+            .url_list
+            .iter()
+            .map(|url| (url, Style::GetRight));
+        let hoffman = self.torrent.httpseeds.iter().map(|url| (url, hoffman));
+        get_right
+            .chain(hoffman)
+            .map(|(url, style)| Ok(Arc::new(WebSeed::new(url.as_str(), style)?)))
+            .collect()
+    }
+
+    // Fetches whichever pieces we are missing from a web seed until none
+    // are left. Each failure hands the piece back for peers to fetch; a few
+    // in a row, or a single corrupt piece, and we stop using the seed. A
+    // busy seed is left alone for as long as it asks.
+    async fn fetch_from_web_seed(
+        &self,
+        web_seed: &WebSeed,
+        seed: usize,
+        events: &mpsc::Sender<Event>,
+    ) -> Result<()> {
+        let stopped = |_| anyhow!("download finished");
+        // The global and torrent limits apply, and the per-peer ones.
+        let limits = Limits::unlimited();
+        let throttle = self
+            .limits
+            .throttle_for(&self.served.limits, &limits)
+            .with(limits);
+        let info = &self.torrent.info;
+        let mut failures = 0;
+        loop {
+            let available = (0..info.num_pieces()).collect();
+            let (reply, answer) = oneshot::channel();
+            events
+                .send(Event::Pick { available, reply })
+                .await
+                .map_err(stopped)?;
+            let Some(index) = answer.await? else {
+                return Ok(());
+            };
+
+            let data = match web_seed.fetch_piece(info, index).await {
+                Ok(Fetched::Piece(data)) if info.verify_piece(index, &data) => data,
+                piece => {
+                    events
+                        .send(Event::Released { index })
+                        .await
+                        .map_err(stopped)?;
+                    let e = match piece {
+                        Ok(Fetched::RetryAfter(wait)) => {
+                            debug!("Web seed busy, retrying in {wait:?}");
+                            tokio::time::sleep(wait).await;
+                            continue;
+                        }
+                        Ok(Fetched::Piece(_)) => {
+                            bail!("web seed sent corrupt data for piece {index}")
+                        }
+                        Err(e) => e,
+                    };
+                    failures += 1;
+                    if failures == WEB_SEED_FAILURES {
+                        return Err(e);
+                    }
+                    debug!("Piece {index} failed: {e}");
+                    tokio::time::sleep(WEB_SEED_RETRY).await;
+                    continue;
+                }
+            };
+            failures = 0;
+            throttle.transfer(Direction::Download, data.len(), 0).await;
+            let from = Source::WebSeed(seed);
+            let piece = Event::Piece { from, index, data };
+            self.unwritten.fetch_add(1, Ordering::Relaxed);
+            if let Err(e) = events.send(piece).await {
+                self.unwritten.fetch_sub(1, Ordering::Relaxed);
+                return Err(stopped(e));
+            }
+        }
+    }
+
+    // Fetches pieces over a fresh connection until there are none left
+    // that the peer has.
+    async fn fetch_pieces(
+        &self,
+        stream: &mut PeerConnection,
+        events: &mpsc::Sender<Event>,
+    ) -> Result<()> {
+        let stopped = |_| anyhow!("download finished");
+        let peer = stream.addr;
+        stream.set_throttle(
+            self.limits
+                .throttle_for(&self.served.limits, &stream.limits),
+        );
+        start_download(stream).await?;
+
+        // Pieces this peer rejected or sent corrupt data for.
+        let mut skipped = HashSet::new();
+        loop {
+            let available = available_pieces(stream, self.torrent.info.num_pieces())
+                .into_iter()
+                .filter(|index| !skipped.contains(index))
+                .collect();
+            let (reply, answer) = oneshot::channel();
+            events
+                .send(Event::Pick { available, reply })
+                .await
+                .map_err(stopped)?;
+            let Some(index) = answer.await? else {
+                return Ok(());
+            };
+
+            let piece = download_piece(stream, &self.torrent.info, index).await;
+            match piece {
+                Ok(Some(data)) if self.torrent.info.verify_piece(index, &data) => {
+                    let from = Source::Peer(peer);
+                    let piece = Event::Piece { from, index, data };
+                    self.unwritten.fetch_add(1, Ordering::Relaxed);
+                    if let Err(e) = events.send(piece).await {
+                        self.unwritten.fetch_sub(1, Ordering::Relaxed);
+                        return Err(stopped(e));
+                    }
+                }
+                piece => {
+                    events
+                        .send(Event::Released { index })
+                        .await
+                        .map_err(stopped)?;
+                    skipped.insert(index);
+                    if piece?.is_some() {
+                        self.emit(EventKind::PieceFailed { index, peer });
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub async fn connect(
+    torrent: &TorrentFile,
+    info_hash: [u8; 20],
+    peer: SocketAddr,
+    pool: &SharedPeerPool,
+    identity: Identity,
+    timeouts: Timeouts,
+) -> Result<PeerConnection> {
+    let metadata = Arc::new(serde_bencode::to_bytes(&torrent.info)?);
+    let mut extensions = ExtensionRegistry::new()
+        .with_metadata_size(metadata.len())
+        .with_listen_port(identity.port);
+    extensions.register(Box::new(MetadataHandler::serving(metadata)));
+    if !torrent.info.is_private() {
+        extensions.register(Box::new(PexHandler::new(pool.clone(), peer)));
+    }
+    let v2 = torrent.info.is_v2();
+    let mut stream =
+        PeerConnection::connect(peer, info_hash, identity.peer_id, v2, extensions, timeouts)
+            .await?;
+    stream.set_num_pieces(torrent.info.num_pieces());
+    pool.lock().unwrap().connected(peer, pex::FLAG_REACHABLE);
+    Ok(stream)
+}
+
+// Declares interest and waits until there is something we may request:
+// either we are unchoked or the peer granted us allowed-fast pieces.
+pub async fn start_download(stream: &mut PeerConnection) -> Result<()> {
+    stream.send(&Message::Interested).await?;
+    let since = Instant::now();
+    loop {
+        match stream.poll().await? {
+            Some(Message::Unchoke) => {
+                debug!("Unchoked");
+                return Ok(());
+            }
+            Some(Message::AllowedFast(_)) if stream.fast => return Ok(()),
+            _ if since.elapsed() >= stream.timeouts.snub => {
+                bail!("peer {} kept us choked", stream.addr)
+            }
+            _ => {}
+        }
+    }
+}
+
+// The pieces this peer can give us, best first: pieces it suggested, then,
+// while we are choked, pieces it allows us to fetch anyway, then the rest.
+fn available_pieces(stream: &PeerConnection, num_pieces: usize) -> Vec<usize> {
+    let suggested = stream.suggested.iter().map(|&index| index as usize);
+    let allowed_fast = stream
+        .allowed_fast
+        .iter()
+        .filter(|_| stream.peer_choking)
+        .map(|&index| index as usize);
+    let mut available: Vec<usize> = suggested
+        .chain(allowed_fast)
+        .chain(0..num_pieces)
+        .filter(|&index| index < num_pieces && stream.has_piece(index))
+        .collect();
+    let mut seen = HashSet::new();
+    available.retain(|&index| seen.insert(index));
+    available
+}
+
+/// Downloads one piece, returning `None` if the peer rejected a request for
+/// it while we were allowed to ask. Fails if the peer snubs us, sending no
+/// blocks for the snub timeout.
+pub async fn download_piece(
+    stream: &mut PeerConnection,
+    info: &TorrentFileInfo,
+    piece_index: usize,
+) -> Result<Option<Vec<u8>>> {
+    let this_piece_length = info.piece_len(piece_index);
+    let index = piece_index as u32;
+
+    let num_blocks = this_piece_length.div_ceil(BLOCK_SIZE);
+    let mut to_request: Vec<(u32, u32)> = (0..num_blocks)
+        .map(|block_index| {
+            let block_begin = block_index * BLOCK_SIZE;
+            let block_length = BLOCK_SIZE.min(this_piece_length - block_begin);
+            (block_begin as u32, block_length as u32)
+        })
+        .collect();
+    let mut outstanding: HashMap<u32, u32> = HashMap::new();
+
+    let mut piece_data = vec![0; this_piece_length];
+    let mut blocks_received = 0;
+    let mut last_block = Instant::now();
+
+    while blocks_received < num_blocks {
+        // request blocks within the piece, once we are allowed to
+        if !stream.peer_choking || stream.allowed_fast.contains(&index) {
+            for (begin, length) in to_request.drain(..) {
+                stream
+                    .send(&Message::Request {
+                        index,
+                        begin,
+                        length,
+                    })
+                    .await?;
+                outstanding.insert(begin, length);
+                let block_index = begin as usize / BLOCK_SIZE;
+                trace!("Requested block {block_index} of piece {piece_index}");
+            }
+        }
+
+        let Some(message) = stream.poll().await? else {
+            if last_block.elapsed() >= stream.timeouts.snub {
+                bail!("peer {} snubbed us", stream.addr);
+            }
+            continue;
+        };
+        match message {
+            Message::Piece {
+                index: i,
+                begin,
+                block,
+            } if i == index && outstanding.contains_key(&begin) => {
+                // A block of the wrong length, or one that would not fit the
+                // piece, costs the peer its connection; the piece goes to
+                // others.
+                let end = begin as usize + block.len();
+                if outstanding[&begin] as usize != block.len() || end > piece_data.len() {
+                    bail!(
+                        "peer {} sent {} bytes for the block at {begin} of piece {index}",
+                        stream.addr,
+                        block.len()
+                    );
+                }
+                outstanding.remove(&begin);
+                last_block = Instant::now();
+                let begin = begin as usize;
+                piece_data[begin..end].copy_from_slice(&block);
+                blocks_received += 1;
+                trace!("Received block {blocks_received}/{num_blocks} for piece {index}");
+            }
+            Message::RejectRequest {
+                index: i, begin, ..
+            } if i == index => {
+                // Only blocks we asked for go back on the list, at the length
+                // we asked for; a reject for anything else is ignored.
+                let Some(length) = outstanding.remove(&begin) else {
+                    continue;
+                };
+                if stream.peer_choking && !stream.allowed_fast.contains(&index) {
+                    // Rejected because we got choked; ask again once unchoked.
+                    to_request.push((begin, length));
+                } else {
+                    debug!("Peer rejected block at {begin} of piece {piece_index}");
+                    return Ok(None);
+                }
+            }
+            // Without the fast extension a choke silently drops every
+            // outstanding request.
+            Message::Choke if !stream.fast => {
+                to_request.extend(outstanding.drain());
+            }
+            // Blocks we no longer wait for are ignored rather than held
+            // against the peer: they may have been in flight when it choked
+            // us, or belong to a piece we gave up on.
+            _ => {}
+        }
+    }
+
+    Ok(Some(piece_data))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::testing::{connect, recv, send_all};
+
+    #[tokio::test]
+    async fn ignores_rejects_for_blocks_it_never_asked_for() {
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| i as u8).collect();
+        let info = TorrentFileInfo {
+            name: "a".to_string(),
+            length: data.len(),
+            piece_length: data.len(),
+            pieces: ByteBuf::from(Sha1::digest(&data).to_vec()),
+            ..Default::default()
+        };
+        let (mut connection, mut peer) = connect(1).await;
+        let seed = async {
+            // A reject out of the blue, hoping we ask for it once unchoked.
+            let reject = Message::RejectRequest {
+                index: 0,
+                begin: u32::MAX - 10,
+                length: BLOCK_SIZE as u32,
+            };
+            send_all(&mut peer, &[reject, Message::Unchoke]).await;
+            let mut served = 0;
+            while served < 2 {
+                let Some(Message::Request { begin, length, .. }) = recv(&mut peer).await else {
+                    continue;
+                };
+                let begin = begin as usize;
+                let block = data[begin..begin + length as usize].to_vec();
+                let piece = Message::Piece {
+                    index: 0,
+                    begin: begin as u32,
+                    block,
+                };
+                send_all(&mut peer, &[piece]).await;
+                served += 1;
+            }
+        };
+        let (piece, _) = tokio::join!(download_piece(&mut connection, &info, 0), seed);
+        assert_eq!(piece.unwrap(), Some(data.clone()));
+        drop(connection);
+        while let Some(message) = recv(&mut peer).await {
+            assert!(!matches!(message, Message::Request { .. }), "{message:?}");
+        }
+    }
+}
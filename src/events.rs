@@ -0,0 +1,111 @@
+//! What happens to a session's torrents, as a stream of events for
+//! progress displays, logs and monitoring. See [`crate::Session::events`].
+
+use std::{net::SocketAddr, time::Duration};
+
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+use crate::session::TorrentState;
+
+/// Events kept for subscribers that fall behind; older ones are dropped.
+pub const CAPACITY: usize = 1024;
+
+pub type EventSender = broadcast::Sender<TorrentEvent>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TorrentEvent {
+    pub info_hash: [u8; 20],
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventKind {
+    StateChanged(TorrentState),
+    /// A piece passed its hash check and was written.
+    PieceCompleted {
+        index: usize,
+        verified: usize,
+        pieces: usize,
+    },
+    PieceFailed {
+        index: usize,
+        peer: SocketAddr,
+    },
+    /// We connected to a peer to download from it.
+    PeerConnected {
+        peer: SocketAddr,
+    },
+    /// A connection from [`EventKind::PeerConnected`] ended, with the
+    /// error that ended it if there was one.
+    PeerDisconnected {
+        peer: SocketAddr,
+        error: Option<String>,
+    },
+    /// Sampled every second while the torrent is active; bytes per second.
+    Rates {
+        download: u64,
+        upload: u64,
+    },
+    /// An announce went through; `elapsed` is how long the tracker took.
+    TrackerResponse {
+        peers: usize,
+        elapsed: Duration,
+    },
+    TrackerFailed {
+        error: String,
+        elapsed: Duration,
+    },
+}
+
+impl TorrentEvent {
+    /// One JSON object per event, named by its `event` field.
+    pub fn to_json(&self) -> Value {
+        let mut value = match &self.kind {
+            EventKind::StateChanged(state) => {
+                let mut value = json!({ "event": "state_changed", "state": state.name() });
+                if let TorrentState::Failed(error) = state {
+                    value["error"] = error.clone().into();
+                }
+                value
+            }
+            EventKind::PieceCompleted {
+                index,
+                verified,
+                pieces,
+            } => json!({
+                "event": "piece_completed",
+                "index": index,
+                "verified": verified,
+                "pieces": pieces,
+            }),
+            EventKind::PieceFailed { index, peer } => {
+                json!({ "event": "piece_failed", "index": index, "peer": peer })
+            }
+            EventKind::PeerConnected { peer } => json!({ "event": "peer_connected", "peer": peer }),
+            EventKind::PeerDisconnected { peer, error } => {
+                json!({ "event": "peer_disconnected", "peer": peer, "error": error })
+            }
+            EventKind::Rates { download, upload } => {
+                json!({ "event": "rates", "download": download, "upload": upload })
+            }
+            EventKind::TrackerResponse { peers, elapsed } => json!({
+                "event": "tracker_response",
+                "peers": peers,
+                "elapsed_secs": elapsed.as_secs_f64(),
+            }),
+            EventKind::TrackerFailed { error, elapsed } => json!({
+                "event": "tracker_failed",
+                "error": error,
+                "elapsed_secs": elapsed.as_secs_f64(),
+            }),
+        };
+        value["info_hash"] = hex::encode(self.info_hash).into();
+        value
+    }
+}
+
+/// Sends `kind` about `info_hash`; nobody listening is fine.
+pub(crate) fn emit(events: &EventSender, info_hash: [u8; 20], kind: EventKind) {
+    let _ = events.send(TorrentEvent { info_hash, kind });
+}
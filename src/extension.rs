@@ -0,0 +1,289 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use anyhow::{anyhow, Result};
+use serde::{
+    de::{DeserializeOwned, Error},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+
+use crate::{bencode, peer::Message};
+
+pub const CLIENT_VERSION: &str = concat!("rust-bittorrent/", env!("CARGO_PKG_VERSION"));
+pub const LISTEN_PORT: u16 = 6881;
+pub const MAX_OUTSTANDING_REQUESTS: usize = 250;
+
+/// The bencoded payload of extended message 0 (BEP 10). Peers get the
+/// optional fields wrong often enough that a field of the wrong type is
+/// dropped rather than failing the handshake, as is an extension in `m`
+/// without an integer id.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ExtendedHandshake {
+    #[serde(default, deserialize_with = "extension_ids")]
+    pub m: BTreeMap<String, i64>,
+    #[serde(
+        default,
+        deserialize_with = "lenient_text",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub v: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub p: Option<u16>,
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub reqq: Option<usize>,
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub yourip: Option<ByteBuf>,
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub metadata_size: Option<usize>,
+}
+
+// The field as a `T`, or `None` if it is something else.
+fn lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let value = Value::deserialize(deserializer)?;
+    let encoded = serde_bencode::to_bytes(&value).map_err(D::Error::custom)?;
+    Ok(serde_bencode::from_bytes(&encoded).ok())
+}
+
+// Client names that aren't UTF-8 are still worth showing.
+fn lenient_text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::Bytes(bytes) => Ok(Some(String::from_utf8_lossy(&bytes).into_owned())),
+        _ => Ok(None),
+    }
+}
+
+fn extension_ids<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, i64>, D::Error> {
+    let Value::Dict(entries) = Value::deserialize(deserializer)? else {
+        return Ok(BTreeMap::new());
+    };
+    let ids = entries.into_iter().filter_map(|(name, id)| match id {
+        Value::Int(id) => Some((String::from_utf8(name).ok()?, id)),
+        _ => None,
+    });
+    Ok(ids.collect())
+}
+
+/// A single extension protocol, e.g. `ut_pex`.
+pub trait ExtensionHandler: Send {
+    /// Name advertised in the `m` dictionary.
+    fn name(&self) -> &'static str;
+
+    /// Called once the remote extended handshake has been received.
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake) {}
+
+    /// Handles a message addressed to this extension. A returned payload is
+    /// sent back to the peer under the peer's id for this extension.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Called regularly while the connection is active; a returned payload
+    /// is sent to the peer. Handlers keep their own schedule.
+    fn on_tick(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Per-connection set of extensions. Local ids are assigned in registration
+/// order starting at 1; remote ids come from the peer's handshake.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    metadata_size: Option<usize>,
+    listen_port: Option<u16>,
+    pub remote: Option<ExtendedHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_metadata_size(mut self, size: usize) -> Self {
+        self.metadata_size = Some(size);
+        self
+    }
+
+    /// Advertises the port we accept connections on, so that the peer can
+    /// pass it on over PEX.
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.listen_port = Some(port);
+        self
+    }
+
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) {
+        self.handlers.push(handler);
+    }
+
+    pub fn local_handshake(&self, peer_ip: IpAddr) -> Result<Vec<u8>> {
+        let m = self
+            .handlers
+            .iter()
+            .enumerate()
+            .map(|(i, handler)| (handler.name().to_string(), i as i64 + 1))
+            .collect();
+        let yourip = match peer_ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let handshake = ExtendedHandshake {
+            m,
+            v: Some(CLIENT_VERSION.to_string()),
+            p: self.listen_port,
+            reqq: Some(MAX_OUTSTANDING_REQUESTS),
+            yourip: Some(ByteBuf::from(yourip)),
+            metadata_size: self.metadata_size,
+        };
+        Ok(serde_bencode::to_bytes(&handshake)?)
+    }
+
+    fn remote_id(&self, name: &str) -> Option<u8> {
+        let id = *self.remote.as_ref()?.m.get(name)?;
+        // An id of 0 means the peer disabled the extension.
+        u8::try_from(id).ok().filter(|&id| id != 0)
+    }
+
+    /// Wraps `payload` in an extended message the peer will route to `name`.
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<Message> {
+        let id = self.remote_id(name)?;
+        Some(Message::Extended { id, payload })
+    }
+
+    /// Collects periodic messages from handlers the peer supports.
+    pub fn tick(&mut self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for i in 0..self.handlers.len() {
+            let name = self.handlers[i].name();
+            if let Some(payload) = self.handlers[i].on_tick() {
+                messages.extend(self.message(name, payload));
+            }
+        }
+        messages
+    }
+
+    /// Dispatches an incoming extended message and returns any replies.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Vec<Message>> {
+        if id == 0 {
+            let handshake: ExtendedHandshake = bencode::from_bytes(payload)?;
+            for handler in &mut self.handlers {
+                handler.on_handshake(&handshake);
+            }
+            self.remote = Some(handshake);
+            return Ok(Vec::new());
+        }
+
+        let handler = self
+            .handlers
+            .get_mut(id as usize - 1)
+            .ok_or_else(|| anyhow!("unknown extension message id {id}"))?;
+        let name = handler.name();
+        let mut replies = Vec::new();
+        if let Some(reply) = handler.on_message(payload)? {
+            replies.extend(self.message(name, reply));
+        }
+        Ok(replies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers every message with its payload reversed, and sends "tick" on
+    // every tick.
+    struct Echo(&'static str);
+
+    impl ExtensionHandler for Echo {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+            Ok(Some(payload.iter().rev().copied().collect()))
+        }
+
+        fn on_tick(&mut self) -> Option<Vec<u8>> {
+            Some(b"tick".to_vec())
+        }
+    }
+
+    fn registry() -> ExtensionRegistry {
+        let mut registry = ExtensionRegistry::new()
+            .with_metadata_size(1234)
+            .with_listen_port(6889);
+        registry.register(Box::new(Echo("ut_a")));
+        registry.register(Box::new(Echo("ut_b")));
+        registry
+    }
+
+    #[test]
+    fn round_trips_the_handshake() {
+        let ip = IpAddr::from([10, 0, 0, 7]);
+        let payload = registry().local_handshake(ip).unwrap();
+        let handshake: ExtendedHandshake = bencode::from_bytes(&payload).unwrap();
+        let ids: Vec<_> = handshake.m.into_iter().collect();
+        assert_eq!(ids, [("ut_a".to_string(), 1), ("ut_b".to_string(), 2)]);
+        assert_eq!(handshake.v.as_deref(), Some(CLIENT_VERSION));
+        assert_eq!(handshake.p, Some(6889));
+        assert_eq!(handshake.reqq, Some(MAX_OUTSTANDING_REQUESTS));
+        assert_eq!(handshake.yourip.unwrap().as_slice(), [10, 0, 0, 7]);
+        assert_eq!(handshake.metadata_size, Some(1234));
+    }
+
+    #[test]
+    fn drops_fields_of_the_wrong_type() {
+        let payload =
+            b"d1:md4:ut_ai3e4:ut_b1:xe1:pi70000e4:reqq3:lot1:v3:\xffAB6:yourip4:\x01\x02\x03\x04e";
+        let handshake: ExtendedHandshake = bencode::from_bytes(payload).unwrap();
+        let ids: Vec<_> = handshake.m.into_iter().collect();
+        assert_eq!(ids, [("ut_a".to_string(), 3)]);
+        assert_eq!(handshake.v.as_deref(), Some("\u{fffd}AB"));
+        assert_eq!((handshake.p, handshake.reqq), (None, None));
+        assert_eq!(handshake.yourip.unwrap().as_slice(), [1, 2, 3, 4]);
+
+        let handshake: ExtendedHandshake = bencode::from_bytes(b"d1:mi1e1:vi2ee").unwrap();
+        assert!(handshake.m.is_empty());
+        assert_eq!(handshake.v, None);
+    }
+
+    #[test]
+    fn routes_messages_by_id() {
+        let mut registry = registry();
+        // Nothing goes out before the peer says which extensions it has.
+        assert_eq!(registry.tick(), []);
+
+        let handshake = b"d1:md4:ut_ai7e4:ut_bi0eee";
+        assert_eq!(registry.handle(0, handshake).unwrap(), []);
+        let extended = |id, payload: &[u8]| Message::Extended {
+            id,
+            payload: payload.to_vec(),
+        };
+        // Replies go out under the peer's id; ut_b is disabled on its side.
+        assert_eq!(registry.handle(1, b"abc").unwrap(), [extended(7, b"cba")]);
+        assert_eq!(registry.handle(2, b"abc").unwrap(), []);
+        assert_eq!(registry.tick(), [extended(7, b"tick")]);
+        assert_eq!(registry.message("ut_b", Vec::new()), None);
+        assert!(registry.handle(3, b"abc").is_err());
+    }
+}
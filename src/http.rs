@@ -0,0 +1,327 @@
+//! Just enough of an HTTP/1.1 server for the control APIs and metrics: one
+//! request per connection, bodies sized by Content-Length.
+
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use crate::log::debug;
+
+/// Bodies beyond this are refused; a .torrent sent inline fits easily.
+const MAX_BODY: usize = 16 * 1024 * 1024;
+const MAX_HEADERS: usize = 100;
+/// The longest request line or header, line ending included.
+const MAX_LINE: usize = 8 * 1024;
+/// How long a client gets to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Request {
+    pub method: String,
+    /// The path and query, as sent.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Where the request came from.
+    pub client: SocketAddr,
+}
+
+impl Request {
+    /// The path without its query.
+    pub fn route(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+
+    /// The query's parameters in order, with the values percent-decoded to
+    /// bytes since they need not be text. Repeated names are kept.
+    pub fn query(&self) -> Vec<(String, Vec<u8>)> {
+        let Some((_, query)) = self.path.split_once('?') else {
+            return Vec::new();
+        };
+        query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let name = String::from_utf8_lossy(&percent_decode(name)).into_owned();
+                (name, percent_decode(value))
+            })
+            .collect()
+    }
+
+    /// The first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: body.into(),
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Response::new(status, "text/plain; charset=utf-8", body.into())
+    }
+
+    pub fn json(status: u16, body: &impl Serialize) -> Self {
+        let body = serde_json::to_vec(body).expect("JSON values always serialize");
+        Response::new(status, "application/json", body)
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+}
+
+/// Answers every connection on `listener` with `handler`, until the
+/// listener fails.
+pub async fn serve<F, Fut>(listener: TcpListener, handler: F) -> Result<()>
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send,
+{
+    let handler = Arc::new(handler);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, addr, handler.as_ref()).await {
+                debug!("HTTP client {addr} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle<F, Fut>(mut stream: TcpStream, client: SocketAddr, handler: &F) -> Result<()>
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    let response = match timeout(READ_TIMEOUT, read_request(&mut stream, client)).await {
+        Ok(Ok(request)) => handler(request).await,
+        Ok(Err(e)) => Response::text(400, format!("{e}\n")),
+        Err(_) => Response::text(408, "request timed out\n"),
+    };
+    write_response(&mut stream, &response).await
+}
+
+async fn read_request(stream: &mut TcpStream, client: SocketAddr) -> Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    read_line(&mut reader, &mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        bail!("malformed request line");
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut headers = Vec::new();
+    loop {
+        if read_line(&mut reader, &mut line).await? == 0 {
+            bail!("connection closed in the headers");
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            bail!("too many headers");
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| anyhow!("malformed header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+        client,
+    };
+    let length: usize = request
+        .header("Content-Length")
+        .map(str::parse)
+        .transpose()
+        .context("bad Content-Length")?
+        .unwrap_or(0);
+    if length > MAX_BODY {
+        bail!("body too large");
+    }
+    request.body.resize(length, 0);
+    reader.read_exact(&mut request.body).await?;
+    Ok(request)
+}
+
+// Replaces `line` with the next line, returning its length, 0 at the end.
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin), line: &mut String) -> Result<usize> {
+    line.clear();
+    let read = reader.take(MAX_LINE as u64).read_line(line).await?;
+    if read == MAX_LINE && !line.ends_with('\n') {
+        bail!("line too long");
+    }
+    Ok(read)
+}
+
+async fn write_response(stream: &mut TcpStream, response: &Response) -> Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    for (name, value) in &response.headers {
+        head += &format!("{name}: {value}\r\n");
+    }
+    head += &format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// `+` is a space, and a `%` without two hex digits after it is left as is.
+fn percent_decode(encoded: &str) -> Vec<u8> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn answers_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, |request: Request| async move {
+            let echo = format!(
+                "{} {} {} {}",
+                request.method,
+                request.path,
+                request.header("x-test").unwrap_or("-"),
+                String::from_utf8_lossy(&request.body)
+            );
+            Response::text(200, echo).with_header("X-Reply", "yes")
+        }));
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{addr}/echo?a=1"))
+            .header("X-Test", "header")
+            .body("hello")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["x-reply"], "yes");
+        assert_eq!(
+            response.text().await.unwrap(),
+            "POST /echo?a=1 header hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_overlong_lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, |_| async { Response::text(200, "ok") }));
+
+        let client = reqwest::Client::new();
+        let path = "a".repeat(MAX_LINE);
+        let long = client.get(format!("http://{addr}/{path}")).send();
+        assert_eq!(long.await.unwrap().status(), 400);
+        let header = client
+            .get(format!("http://{addr}/"))
+            .header("X-Long", &path);
+        assert_eq!(header.send().await.unwrap().status(), 400);
+        let short = client.get(format!("http://{addr}/")).send();
+        assert_eq!(short.await.unwrap().status(), 200);
+    }
+
+    #[test]
+    fn decodes_queries() {
+        let request = Request {
+            method: "GET".to_string(),
+            path: "/announce?info_hash=%12%ab4&port=6881&a+b=%zz&&flag&x=1&x=2".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            client: "127.0.0.1:1".parse().unwrap(),
+        };
+        assert_eq!(request.route(), "/announce");
+        let query = request.query();
+        let pairs: Vec<(&str, &[u8])> = query
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_slice()))
+            .collect();
+        assert_eq!(
+            pairs,
+            [
+                ("info_hash", &[0x12, 0xab, b'4'][..]),
+                ("port", b"6881"),
+                ("a b", b"%zz"),
+                ("flag", b""),
+                ("x", b"1"),
+                ("x", b"2"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! A BitTorrent client library.
+//!
+//! A [`Session`] listens for peers and finds them through trackers, the DHT
+//! and the local network; each [`Torrent`] added to it downloads into a file
+//! and uploads what it has to other peers. The session queues its torrents
+//! so that only so many are active at once.
+//!
+//! ```no_run
+//! use bittorrent_starter_rust::{Session, SessionConfig, TorrentFile};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let session = Session::start(SessionConfig::default()).await?;
+//! let metainfo = TorrentFile::from_file("sample.torrent")?;
+//! let torrent = session.add_torrent(metainfo, "sample.txt").await?;
+//! torrent.completed().await?;
+//! // Keep seeding until the process is stopped.
+//! session.run().await
+//! # }
+//! ```
+//!
+//! The lower layers are public too: [`bencode`] and [`metainfo`] for the
+//! file formats, [`tracker`] and [`dht`] for finding peers, [`peer`] for the
+//! wire protocol and [`storage`] for the data on disk.
+
+pub mod bencode;
+pub mod choker;
+pub mod dht;
+pub mod download;
+pub mod events;
+pub mod extension;
+pub mod http;
+pub mod log;
+pub mod lsd;
+pub mod magnet;
+pub mod merkle;
+pub mod metadata;
+pub mod metainfo;
+pub mod metrics;
+pub mod peer;
+pub mod pex;
+pub mod pool;
+pub mod ratelimit;
+pub mod rpc;
+pub mod server;
+pub mod session;
+pub mod sha256;
+pub mod storage;
+#[cfg(test)]
+mod testing;
+pub mod tracker;
+pub mod transmission;
+pub mod webseed;
+
+pub use metainfo::{TorrentFile, TorrentFileInfo};
+pub use session::{QueueMove, Session, SessionConfig, Torrent, TorrentState};
+
+/// The peer id we identify ourselves with unless told otherwise.
+pub const PEER_ID: [u8; 20] = *b"00112233445566778899";
+
+/// What we tell trackers and peers about ourselves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub peer_id: [u8; 20],
+    /// The port we accept peer connections on.
+    pub port: u16,
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Identity {
+            peer_id: PEER_ID,
+            port: extension::LISTEN_PORT,
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! Leveled diagnostics, written to stderr or a log file so that stdout only
+//! carries command results.
+//!
+//! Messages come from the `error!`, `warning!`, `info!`, `debug!` and `trace!`
+//! macros, and are filtered by the module they come from: a filter like
+//! `info,bittorrent_starter_rust::download=trace` shows everything from
+//! `download` and only the info and above from elsewhere. Each message is
+//! labelled with the [`Span`]s it happened in, like the torrent and the peer
+//! connection, and what was recorded on them.
+
+use std::{
+    cell::RefCell,
+    fmt::{self, Display, Write as _},
+    fs::{File, OpenOptions},
+    future::Future,
+    io::Write,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+
+/// The environment variable holding the filter, as in [`init`].
+pub const FILTER_VAR: &str = "RUST_LOG";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Something failed that the user asked for.
+    Error = 1,
+    /// Something failed that we can do without, like one tracker or the DHT.
+    Warn,
+    /// Progress worth seeing on every run.
+    Info,
+    /// What each peer connection is up to.
+    Debug,
+    /// Per-block and per-message chatter.
+    Trace,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Option<Level>> {
+        let level = match name.to_ascii_lowercase().as_str() {
+            "off" => None,
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => return None,
+        };
+        Some(level)
+    }
+}
+
+/// Which messages are shown: a default level, and levels for the modules
+/// under some targets. `None` shows nothing.
+#[derive(Clone, Debug, PartialEq)]
+struct Filter {
+    default: Option<Level>,
+    targets: Vec<(String, Option<Level>)>,
+}
+
+impl Filter {
+    // Comma-separated `level`, `target=level` or `target` directives, the
+    // last of which wins. A bare target shows everything from it.
+    fn parse(mut self, spec: &str) -> Result<Self> {
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let invalid = || anyhow!("invalid {FILTER_VAR} directive {directive:?}");
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = Level::parse(level).ok_or_else(invalid)?;
+                    self.targets.retain(|(known, _)| known != target);
+                    self.targets.push((target.to_string(), level));
+                }
+                None => match Level::parse(directive) {
+                    Some(level) => self.default = level,
+                    None if directive
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || "_:".contains(c)) =>
+                    {
+                        self.targets.retain(|(known, _)| known != directive);
+                        self.targets
+                            .push((directive.to_string(), Some(Level::Trace)));
+                    }
+                    None => return Err(invalid()),
+                },
+            }
+        }
+        Ok(self)
+    }
+
+    fn level_for(&self, target: &str) -> Option<Level> {
+        let within = |prefix: &str| {
+            target
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        };
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| within(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    // The most verbose level anything is shown at.
+    fn max(&self) -> Option<Level> {
+        let targets = self.targets.iter().map(|&(_, level)| level);
+        targets.chain([self.default]).max().flatten()
+    }
+}
+
+// Unit tests check results, and libtest can't capture what is written to
+// stderr directly, so they show nothing unless a test asks.
+#[cfg(not(test))]
+const DEFAULT_LEVEL: Option<Level> = Some(Level::Info);
+#[cfg(test)]
+const DEFAULT_LEVEL: Option<Level> = None;
+
+static FILTER: RwLock<Filter> = RwLock::new(Filter {
+    default: DEFAULT_LEVEL,
+    targets: Vec::new(),
+});
+// The filter's `max`, to skip the lock for messages nobody sees.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(match DEFAULT_LEVEL {
+    Some(level) => level as u8,
+    None => 0,
+});
+// Where messages go instead of stderr.
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Shows messages at `level` and below, adjusted by the directives in
+/// `filter`, if any; see the module docs. With a `file`, messages are
+/// appended to it rather than written to stderr.
+pub fn init(level: Option<Level>, filter: Option<&str>, file: Option<&Path>) -> Result<()> {
+    let filter = Filter {
+        default: level,
+        targets: Vec::new(),
+    }
+    .parse(filter.unwrap_or_default())?;
+    if let Some(path) = file {
+        let opened = OpenOptions::new().create(true).append(true).open(path);
+        let opened = opened.map_err(|e| anyhow!("cannot open {}: {e}", path.display()))?;
+        *FILE.lock().unwrap() = Some(opened);
+    }
+    MAX_LEVEL.store(
+        filter.max().map_or(0, |level| level as u8),
+        Ordering::Relaxed,
+    );
+    *FILTER.write().unwrap() = filter;
+    Ok(())
+}
+
+/// Whether messages at `level` from the module `target` are shown.
+pub fn enabled(target: &str, level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+        && FILTER.read().unwrap().level_for(target) >= Some(level)
+}
+
+pub(crate) fn write(target: &str, level: Level, message: fmt::Arguments) {
+    let mut line = String::new();
+    let mut file = FILE.lock().unwrap();
+    // The log file is read later, so it says when and where.
+    if file.is_some() {
+        let _ = write!(
+            line,
+            "{} {:>5} {target}: ",
+            timestamp(SystemTime::now()),
+            level.name()
+        );
+    } else {
+        let _ = write!(line, "{:>5} ", level.name());
+    }
+    CURRENT.with(|current| {
+        if let Some(span) = &*current.borrow() {
+            let _ = write!(line, "{span}: ");
+        }
+    });
+    let _ = writeln!(line, "{message}");
+    match &mut *file {
+        Some(file) => {
+            let _ = file.write_all(line.as_bytes());
+        }
+        None => {
+            let _ = std::io::stderr().lock().write_all(line.as_bytes());
+        }
+    }
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled(module_path!(), $level) {
+            $crate::log::write(module_path!(), $level, format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Error, $($arg)*) };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Debug, $($arg)*) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log::log!($crate::log::Level::Trace, $($arg)*) };
+}
+
+/// A [`Span`] named `name` with `key = value` fields, inside the current one.
+macro_rules! span {
+    ($name:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::log::Span::new($name, vec![$((stringify!($key), $value.to_string())),*])
+    };
+}
+
+pub(crate) use {debug, error, info, log, span, trace, warning};
+
+thread_local! {
+    // The span of the future being polled on this thread.
+    static CURRENT: RefCell<Option<Span>> = const { RefCell::new(None) };
+}
+
+/// A stretch of work that messages are labelled with, like
+/// `torrent{name=ubuntu.iso}:peer{addr=10.0.0.7:51413}`. Spans nest: a new
+/// span is inside the one current where it is created.
+#[derive(Clone)]
+pub struct Span(Arc<SpanData>);
+
+struct SpanData {
+    name: &'static str,
+    parent: Option<Span>,
+    fields: Mutex<Vec<(&'static str, String)>>,
+}
+
+impl Span {
+    pub fn new(name: &'static str, fields: Vec<(&'static str, String)>) -> Self {
+        Span(Arc::new(SpanData {
+            name,
+            parent: Span::current(),
+            fields: Mutex::new(fields),
+        }))
+    }
+
+    /// The span of the running task, if it has one.
+    pub fn current() -> Option<Span> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Sets `key`, for things learned after the span began, like a peer's id.
+    pub fn record(&self, key: &'static str, value: impl Display) {
+        let mut fields = self.0.fields.lock().unwrap();
+        let value = value.to_string();
+        match fields.iter_mut().find(|(known, _)| *known == key) {
+            Some((_, known)) => *known = value,
+            None => fields.push((key, value)),
+        }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(parent) = &self.0.parent {
+            write!(f, "{parent}:")?;
+        }
+        f.write_str(self.0.name)?;
+        let fields = self.0.fields.lock().unwrap();
+        for (i, (key, value)) in fields.iter().enumerate() {
+            let separator = if i == 0 { '{' } else { ' ' };
+            write!(f, "{separator}{key}={value}")?;
+        }
+        if !fields.is_empty() {
+            f.write_str("}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs futures in a [`Span`].
+pub trait Instrument: Future + Sized {
+    /// Makes `span` current whenever this future is polled.
+    fn instrument(self, span: Span) -> Instrumented<Self> {
+        Instrumented {
+            future: Box::pin(self),
+            span: Some(span),
+        }
+    }
+
+    /// Keeps the span current where this is called, as spawned tasks don't
+    /// inherit it.
+    fn in_current_span(self) -> Instrumented<Self> {
+        Instrumented {
+            future: Box::pin(self),
+            span: Span::current(),
+        }
+    }
+}
+
+impl<F: Future> Instrument for F {}
+
+pub struct Instrumented<F> {
+    future: Pin<Box<F>>,
+    span: Option<Span>,
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let outer = CURRENT.with(|current| current.replace(self.span.clone()));
+        let poll = self.future.as_mut().poll(cx);
+        CURRENT.with(|current| *current.borrow_mut() = outer);
+        poll
+    }
+}
+
+// Like 2024-05-01T12:30:05.123Z.
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's
+    // `civil_from_days`, in eras of 400 years starting in March.
+    let days = days as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn filters_by_target() {
+        let filter = Filter {
+            default: Some(Level::Info),
+            targets: Vec::new(),
+        };
+        let filter = filter
+            .parse("warn, crate::download=trace,crate::download::x=off,crate::dht")
+            .unwrap();
+        assert_eq!(filter.level_for("crate::session"), Some(Level::Warn));
+        assert_eq!(filter.level_for("crate::download"), Some(Level::Trace));
+        assert_eq!(filter.level_for("crate::downloads"), Some(Level::Warn));
+        assert_eq!(filter.level_for("crate::download::x::y"), None);
+        assert_eq!(filter.level_for("crate::dht"), Some(Level::Trace));
+        assert_eq!(filter.max(), Some(Level::Trace));
+        assert!(filter.clone().parse("download=loud").is_err());
+        assert!(filter.parse("not a target").is_err());
+    }
+
+    #[tokio::test]
+    async fn spans_follow_their_futures() {
+        let torrent = span!("torrent", name = "a b");
+        let peer = async {
+            let peer = span!("peer", addr = "10.0.0.7:51413");
+            peer.record("client", "Transmission 3.00");
+            async { Span::current().unwrap().to_string() }
+                .instrument(peer)
+                .await
+        };
+        let shown = peer.instrument(torrent).await;
+        assert_eq!(
+            shown,
+            "torrent{name=a b}:peer{addr=10.0.0.7:51413 client=Transmission 3.00}"
+        );
+        assert!(Span::current().is_none());
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        let at = |secs| timestamp(UNIX_EPOCH + Duration::from_millis(secs));
+        assert_eq!(at(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951_782_400_000), "2000-02-29T00:00:00.000Z");
+        assert_eq!(at(1_714_566_605_123), "2024-05-01T12:30:05.123Z");
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::pool::{PeerSource, SharedPeerPool};
+
+pub const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const MULTICAST_PORT: u16 = 6771;
+
+/// BEP 14 asks for no more than one announce per torrent every few minutes.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+struct Inner {
+    socket: UdpSocket,
+    port: u16,
+    // Identifies our own announces when the multicast loops back to us.
+    cookie: String,
+    torrents: Mutex<HashMap<[u8; 20], SharedPeerPool>>,
+    shutdown: AtomicBool,
+}
+
+/// Local Service Discovery: announces our torrents on the LAN multicast
+/// group and adds peers announcing the same torrents to their pools.
+pub struct Lsd {
+    inner: Arc<Inner>,
+}
+
+impl Lsd {
+    /// `port` is the TCP port we accept peer connections on.
+    pub fn start(port: u16) -> Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, MULTICAST_PORT))?;
+        socket.join_multicast_v4(&MULTICAST_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+
+        let inner = Arc::new(Inner {
+            socket,
+            port,
+            cookie: hex::encode(&crate::dht::random_id()[..8]),
+            torrents: Mutex::new(HashMap::new()),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let listener = inner.clone();
+        thread::spawn(move || listener.receive_loop());
+        let announcer = inner.clone();
+        thread::spawn(move || announcer.announce_loop());
+
+        Ok(Lsd { inner })
+    }
+
+    /// Starts announcing `info_hash`; peers found for it go into `pool`.
+    /// Callers must not add private torrents.
+    pub fn add_torrent(&self, info_hash: [u8; 20], pool: SharedPeerPool) -> Result<()> {
+        self.inner.torrents.lock().unwrap().insert(info_hash, pool);
+        self.inner.announce(&[info_hash])
+    }
+
+    /// Stops announcing `info_hash` and feeding its pool.
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.inner.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    pub fn has_torrent(&self, info_hash: &[u8; 20]) -> bool {
+        self.inner.torrents.lock().unwrap().contains_key(info_hash)
+    }
+}
+
+impl Drop for Lsd {
+    fn drop(&mut self) {
+        self.inner.shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Inner {
+    fn announce(&self, info_hashes: &[[u8; 20]]) -> Result<()> {
+        if info_hashes.is_empty() {
+            return Ok(());
+        }
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {MULTICAST_GROUP}:{MULTICAST_PORT}\r\nPort: {}\r\n",
+            self.port
+        );
+        for info_hash in info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        message.push_str(&format!("cookie: {}\r\n\r\n\r\n", self.cookie));
+
+        let group = SocketAddrV4::new(MULTICAST_GROUP, MULTICAST_PORT);
+        self.socket.send_to(message.as_bytes(), group)?;
+        Ok(())
+    }
+
+    fn announce_loop(&self) {
+        let mut last_announce = Instant::now();
+        while !self.shutdown.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(500));
+            if last_announce.elapsed() < ANNOUNCE_INTERVAL {
+                continue;
+            }
+            last_announce = Instant::now();
+            let info_hashes: Vec<[u8; 20]> =
+                self.torrents.lock().unwrap().keys().copied().collect();
+            let _ = self.announce(&info_hashes);
+        }
+    }
+
+    fn receive_loop(&self) {
+        let mut buffer = [0u8; 1500];
+        while !self.shutdown.load(Ordering::Relaxed) {
+            let Ok((len, from)) = self.socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            let Some(announce) = parse_announce(&buffer[..len]) else {
+                continue;
+            };
+            if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+                continue;
+            }
+
+            let peer = SocketAddr::new(from.ip(), announce.port);
+            let torrents = self.torrents.lock().unwrap();
+            for info_hash in &announce.info_hashes {
+                if let Some(pool) = torrents.get(info_hash) {
+                    pool.lock().unwrap().add([peer], PeerSource::Lsd);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Announce {
+    port: u16,
+    info_hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+// Parses a BT-SEARCH datagram from anyone on the LAN; `None` unless it has
+// a port and at least one well-formed info hash.
+fn parse_announce(packet: &[u8]) -> Option<Announce> {
+    let text = std::str::from_utf8(packet).ok()?;
+    let mut lines = text.split("\r\n");
+    if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+
+    let mut announce = Announce {
+        port: 0,
+        info_hashes: Vec::new(),
+        cookie: None,
+    };
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => announce.port = value.parse().ok()?,
+            "infohash" => {
+                let mut info_hash = [0; 20];
+                if hex::decode_to_slice(value, &mut info_hash).is_ok() {
+                    announce.info_hashes.push(info_hash);
+                }
+            }
+            "cookie" => announce.cookie = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    (announce.port != 0 && !announce.info_hashes.is_empty()).then_some(announce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(headers: &[&str]) -> Vec<u8> {
+        let mut packet = "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n".to_string();
+        for header in headers {
+            packet += &format!("{header}\r\n");
+        }
+        (packet + "\r\n\r\n").into_bytes()
+    }
+
+    #[test]
+    fn parses_announces() {
+        let hash = "ab".repeat(20);
+        let announce = parse_announce(&packet(&[
+            "Port: 6881",
+            &format!("Infohash: {hash}"),
+            "cookie: c00k1e",
+        ]));
+        assert_eq!(
+            announce,
+            Some(Announce {
+                port: 6881,
+                info_hashes: vec![[0xab; 20]],
+                cookie: Some("c00k1e".to_string()),
+            })
+        );
+
+        // Several torrents in one announce, skipping malformed hashes.
+        let packet = packet(&[
+            &format!("infohash: {}", "01".repeat(20)),
+            "Infohash: xyz",
+            &format!("Infohash: {}", "01".repeat(19)),
+            "port:7000",
+            &format!("INFOHASH: {}", "02".repeat(20)),
+        ]);
+        let announce = parse_announce(&packet).unwrap();
+        assert_eq!(announce.port, 7000);
+        assert_eq!(announce.info_hashes, [[1; 20], [2; 20]]);
+        assert_eq!(announce.cookie, None);
+    }
+
+    #[test]
+    fn rejects_bad_announces() {
+        let info_hash = format!("Infohash: {}", "ab".repeat(20));
+        for headers in [
+            &["Port: 6881"][..],
+            &["Port: 6881", "Infohash: not hex"],
+            &["Port: 6881", "Infohash: abab"],
+            &[&info_hash],
+            &["Port: 0", &info_hash],
+            &["Port: 65536", &info_hash],
+            &["Port: -1", &info_hash],
+            &["Port: http", &info_hash],
+        ] {
+            assert_eq!(parse_announce(&packet(headers)), None, "{headers:?}");
+        }
+        let search = packet(&["Port: 6881", &info_hash]);
+        let other = [b"M-SEARCH".as_slice(), &search[9..]].concat();
+        assert_eq!(parse_announce(&other), None);
+        assert_eq!(parse_announce(&[0xff, 0xfe]), None);
+    }
+}
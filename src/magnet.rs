@@ -0,0 +1,105 @@
+use anyhow::{anyhow, bail, Result};
+
+/// A parsed `magnet:` link (BEP 9): enough to find peers for a torrent and
+/// fetch its metadata from them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// Suggested name, until the metadata says otherwise.
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(link: &str) -> Result<Self> {
+        let query = link
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| anyhow!("not a magnet link"))?;
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in serde_urlencoded::from_str::<Vec<(String, String)>>(query)? {
+            match key.as_str() {
+                "xt" => {
+                    // Links may carry several exact topics; we only know v1.
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                _ => {}
+            }
+        }
+        Ok(Magnet {
+            info_hash: info_hash.ok_or_else(|| anyhow!("magnet link has no urn:btih topic"))?,
+            name,
+            trackers,
+        })
+    }
+}
+
+// Info hashes come as 40 hex digits or, in older links, 32 base32 ones.
+fn parse_info_hash(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash)?,
+        32 => base32_decode(hash)?,
+        _ => bail!("info hash {hash} is neither hex nor base32"),
+    };
+    Ok(bytes.try_into().expect("both encodings give 20 bytes"))
+}
+
+fn base32_decode(text: &str) -> Result<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())
+            .ok_or_else(|| anyhow!("{:?} is not base32", c as char))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_links_with_trackers() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165\
+             &dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce",
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "ad42ce8109f54c99613ce38f9b4d87e70f24a165"
+        );
+        assert_eq!(magnet.name.as_deref(), Some("magnet1.gif"));
+        assert_eq!(
+            magnet.trackers,
+            ["http://bittorrent-test-tracker.codecrafters.io/announce"]
+        );
+    }
+
+    #[test]
+    fn base32_and_hex_hashes_agree() {
+        let hex = Magnet::parse("magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165");
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF");
+        assert_eq!(hex.unwrap().info_hash, base32.unwrap().info_hash);
+    }
+
+    #[test]
+    fn rejects_links_without_a_hash() {
+        assert!(Magnet::parse("magnet:?dn=nothing").is_err());
+        assert!(Magnet::parse("http://example.com").is_err());
+    }
+}
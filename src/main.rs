@@ -1,682 +1,913 @@
 use std::{
-    collections::HashMap,
     env, fs,
-    io::{Read, Write},
-    iter::Peekable,
-    net::TcpStream,
+    future::Future,
+    io::{self, IsTerminal},
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Result};
-use serde::{Deserialize, Serialize};
-use serde_bytes::ByteBuf;
-use serde_urlencoded;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueHint};
+use serde::Serialize;
+use serde_json::{json, Value};
 use sha1::{Digest, Sha1};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    task::JoinSet,
+};
 
-fn parse_ben_string<'a>(iter: &mut Peekable<std::slice::Iter<'a, u8>>) -> String {
-    let mut length_str = Vec::new();
-    loop {
-        let char = iter.next().unwrap();
-        if *char == b':' {
-            break;
-        }
-        length_str.push(*char);
-    }
-    let length = String::from_utf8(length_str)
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
-    let mut string = String::with_capacity(length);
-    for _ in 0..length {
-        string.push(*iter.next().unwrap() as char);
-    }
-    string
+use bittorrent_starter_rust::{
+    bencode,
+    download::{connect, download_piece, start_download, MAX_PEERS},
+    log::{self, Level},
+    metrics::MetricsServer,
+    peer::Timeouts,
+    pool::PeerPool,
+    ratelimit::{Direction, RateLimits},
+    rpc::{self, RpcServer},
+    tracker::{
+        request_peers,
+        server::{self, TrackerConfig, TrackerServer},
+    },
+    transmission::{self, TransmissionServer},
+    Identity, Session, SessionConfig, Torrent, TorrentFile, PEER_ID,
+};
+
+mod completions;
+mod output;
+mod progress;
+
+use completions::Shell;
+use output::PieceSource;
+use progress::{Mode, Progress};
+
+#[derive(Parser)]
+#[command(version, about = "A BitTorrent client", propagate_version = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Print more progress; repeat for per-block detail. RUST_LOG can
+    /// adjust it per module, like `info,bittorrent_starter_rust::dht=debug`
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print nothing but results and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print results as JSON, one object per line; see docs/json.md
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Append diagnostics to this file, with times, instead of stderr
+    #[arg(long, global = true, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    log_file: Option<PathBuf>,
 }
 
-fn parse_ben_int<'a>(iter: &mut Peekable<std::slice::Iter<'a, u8>>) -> serde_json::Value {
-    iter.next(); // Skip the 'i'
-    let mut num_str = Vec::new();
-    loop {
-        let char = iter.next().unwrap();
-        if *char == b'e' {
-            break;
-        }
-        num_str.push(*char);
-    }
-    let num = String::from_utf8(num_str).unwrap().parse::<i64>().unwrap();
-    serde_json::Value::Number(serde_json::Number::from(num))
+#[derive(Subcommand)]
+enum Command {
+    /// Decode a bencoded value and print it as JSON
+    Decode {
+        /// A bencoded value, like `d3:foo3:bare`
+        value: String,
+    },
+    /// Show what a torrent file describes
+    Info {
+        /// The .torrent file
+        #[arg(value_hint = ValueHint::FilePath)]
+        torrent: PathBuf,
+    },
+    /// List the peers the tracker knows for a torrent
+    Peers {
+        /// The .torrent file
+        #[arg(value_hint = ValueHint::FilePath)]
+        torrent: PathBuf,
+        /// Connect to every peer to learn its peer id
+        #[arg(long)]
+        handshake: bool,
+        #[command(flatten)]
+        peer: PeerOptions,
+    },
+    /// Connect to a peer and print its peer id
+    Handshake {
+        /// The .torrent file
+        #[arg(value_hint = ValueHint::FilePath)]
+        torrent: PathBuf,
+        /// Address of the peer, as ip:port
+        peer_addr: SocketAddr,
+        #[command(flatten)]
+        peer: PeerOptions,
+    },
+    /// Download a single piece from the first peer the tracker returns
+    #[command(name = "download_piece")]
+    DownloadPiece {
+        /// Where to write the piece
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        output: PathBuf,
+        /// The .torrent file
+        #[arg(value_hint = ValueHint::FilePath)]
+        torrent: PathBuf,
+        /// Index of the piece, from 0
+        index: usize,
+        #[command(flatten)]
+        peer: PeerOptions,
+    },
+    /// Download a whole torrent, resuming from what is already on disk
+    Download {
+        /// Where to write the data
+        #[arg(short, long, value_hint = ValueHint::AnyPath)]
+        output: PathBuf,
+        /// The .torrent file
+        #[arg(value_hint = ValueHint::FilePath)]
+        torrent: PathBuf,
+        /// Keep uploading once the download is done
+        #[arg(long)]
+        seed: bool,
+        #[command(flatten)]
+        peer: PeerOptions,
+        #[command(flatten)]
+        transfer: TransferOptions,
+    },
+    /// Upload a complete torrent to anyone who asks
+    Seed {
+        /// The .torrent file
+        #[arg(value_hint = ValueHint::FilePath)]
+        torrent: PathBuf,
+        /// The torrent's data
+        #[arg(value_hint = ValueHint::AnyPath)]
+        data: PathBuf,
+        #[command(flatten)]
+        peer: PeerOptions,
+        #[command(flatten)]
+        transfer: TransferOptions,
+    },
+    /// Run torrents in the background, controlled over JSON-RPC
+    Daemon {
+        /// Port for the RPC API, which only listens on localhost
+        #[arg(long, default_value_t = rpc::DEFAULT_PORT)]
+        rpc_port: u16,
+        /// Where torrents go unless added with a path
+        #[arg(long, value_hint = ValueHint::DirPath, default_value = ".")]
+        download_dir: PathBuf,
+        /// Also serve Transmission's RPC protocol at this address, like
+        /// 127.0.0.1:9091; addresses beyond localhost need
+        /// --transmission-auth
+        #[arg(long, value_name = "ADDR")]
+        transmission: Option<SocketAddr>,
+        /// Require this user name and password for Transmission RPC calls
+        #[arg(long, value_name = "USER:PASSWORD", requires = "transmission")]
+        transmission_auth: Option<String>,
+        /// Serve Prometheus metrics at /metrics on this address, like
+        /// 127.0.0.1:9100
+        #[arg(long, value_name = "ADDR")]
+        metrics: Option<SocketAddr>,
+        /// Most torrents downloading at once
+        #[arg(long, default_value_t = SessionConfig::default().max_active_downloads)]
+        max_active_downloads: usize,
+        /// Most torrents seeding at once
+        #[arg(long, default_value_t = SessionConfig::default().max_active_seeds)]
+        max_active_seeds: usize,
+        #[command(flatten)]
+        peer: PeerOptions,
+        #[command(flatten)]
+        transfer: TransferOptions,
+    },
+    /// Control a running daemon
+    Remote {
+        /// The daemon's RPC endpoint
+        #[arg(long, default_value_t = format!("http://127.0.0.1:{}/rpc", rpc::DEFAULT_PORT))]
+        rpc_url: String,
+        #[command(subcommand)]
+        command: RemoteCommand,
+    },
+    /// Run a tracker for other clients to announce to
+    Tracker {
+        /// Where to answer HTTP announces and scrapes
+        #[arg(long, value_name = "ADDR", default_value_t = SocketAddr::from(([0, 0, 0, 0], server::DEFAULT_PORT)))]
+        listen: SocketAddr,
+        /// Also answer UDP announces (BEP 15) at this address, like
+        /// 0.0.0.0:6969
+        #[arg(long, value_name = "ADDR")]
+        udp: Option<SocketAddr>,
+        /// Seconds peers are asked to wait between announces; peers that
+        /// miss two are dropped
+        #[arg(long, default_value_t = TrackerConfig::default().interval.as_secs())]
+        interval: u64,
+        /// Track only this torrent, given by info hash or .torrent file;
+        /// repeat for more. Without it, any torrent is tracked
+        #[arg(long, value_name = "HASH|TORRENT")]
+        allow: Vec<String>,
+        /// Keep the swarms in this file, so they survive restarts
+        #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+        state: Option<PathBuf>,
+    },
+    /// Print a shell completion script
+    Completions { shell: Shell },
 }
 
-fn parse_ben_list<'a>(iter: &mut Peekable<std::slice::Iter<'a, u8>>) -> serde_json::Value {
-    iter.next(); // Skip the 'l'
-    let mut items = Vec::new();
-    loop {
-        match iter.peek() {
-            Some(&b'e') => {
-                iter.next(); // Consume the 'e'
-                break;
-            }
-            _ => items.push(decode_bencoded_value(iter)),
-        }
-    }
-    serde_json::Value::Array(items)
+#[derive(Subcommand)]
+enum RemoteCommand {
+    /// Add a .torrent file or a magnet link
+    Add {
+        /// Path of a .torrent file, as the daemon sees it, or a magnet link
+        torrent: String,
+        /// Where the data goes, instead of the download directory
+        #[arg(short, long, value_hint = ValueHint::AnyPath)]
+        output: Option<PathBuf>,
+        /// Add without starting
+        #[arg(long)]
+        paused: bool,
+    },
+    /// Show every torrent, in queue order
+    List,
+    /// Halt a torrent, keeping its place in the queue
+    Pause { info_hash: String },
+    /// Queue a paused, stopped or failed torrent again
+    Resume { info_hash: String },
+    /// Halt a torrent and tell its tracker we left
+    Stop { info_hash: String },
+    /// Move a torrent in the queue
+    Move {
+        info_hash: String,
+        #[arg(value_parser = ["top", "up", "down", "bottom"])]
+        to: String,
+    },
+    /// Show or change rate limits, in KiB/s with 0 meaning unlimited
+    Limits {
+        /// Limit this torrent instead of the whole session
+        #[arg(long)]
+        info_hash: Option<String>,
+        #[arg(long, value_name = "KIB")]
+        upload: Option<u64>,
+        #[arg(long, value_name = "KIB")]
+        download: Option<u64>,
+        /// Upload limit for each peer
+        #[arg(long, value_name = "KIB")]
+        peer_upload: Option<u64>,
+        /// Download limit for each peer
+        #[arg(long, value_name = "KIB")]
+        peer_download: Option<u64>,
+    },
+    /// Forget a torrent
+    Remove {
+        info_hash: String,
+        /// Delete its data too
+        #[arg(long)]
+        delete_data: bool,
+    },
 }
 
-fn parse_ben_dict<'a>(iter: &mut Peekable<std::slice::Iter<'a, u8>>) -> serde_json::Value {
-    iter.next(); // Skip the 'd'
-    let mut map = HashMap::new();
-    loop {
-        match iter.peek() {
-            Some(&b'e') => {
-                iter.next(); // Consume the 'e'
-                break;
-            }
-            Some(_) => {
-                let key = parse_ben_string(iter);
-                let value = decode_bencoded_value(iter);
-                map.insert(key, value);
-            }
-            None => panic!("Invalid dictionary format"),
+/// How we present ourselves to trackers and peers, and how long we wait
+/// for them.
+#[derive(Args)]
+#[command(next_help_heading = "Peer options")]
+struct PeerOptions {
+    /// Our peer id: 20 characters, or 40 hex digits
+    #[arg(long, value_parser = parse_peer_id)]
+    peer_id: Option<[u8; 20]>,
+
+    /// Port we accept peers on and announce to trackers
+    #[arg(short, long, default_value_t = Identity::default().port)]
+    port: u16,
+
+    /// Seconds to wait for a TCP connection
+    #[arg(long, value_name = "SECS", default_value_t = Timeouts::default().connect.as_secs())]
+    connect_timeout: u64,
+
+    /// Seconds to wait for a peer's handshake
+    #[arg(long, value_name = "SECS", default_value_t = Timeouts::default().handshake.as_secs())]
+    handshake_timeout: u64,
+
+    /// Seconds a peer may stay silent before we drop it
+    #[arg(long, value_name = "SECS", default_value_t = Timeouts::default().idle.as_secs())]
+    idle_timeout: u64,
+
+    /// Seconds without a block before a peer counts as snubbing us
+    #[arg(long, value_name = "SECS", default_value_t = Timeouts::default().snub.as_secs())]
+    snub_timeout: u64,
+}
+
+impl PeerOptions {
+    fn identity(&self) -> Identity {
+        Identity {
+            peer_id: self.peer_id.unwrap_or(PEER_ID),
+            port: self.port,
         }
     }
-    serde_json::json!(map)
-}
 
-fn decode_bencoded_value<'a>(iter: &mut Peekable<std::slice::Iter<'a, u8>>) -> serde_json::Value {
-    let mut iter_clone = iter.clone();
-    match iter_clone.peek() {
-        Some(&byte) if byte.is_ascii_digit() => {
-            let string = parse_ben_string(iter);
-            serde_json::Value::String(string)
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: Duration::from_secs(self.connect_timeout),
+            handshake: Duration::from_secs(self.handshake_timeout),
+            idle: Duration::from_secs(self.idle_timeout),
+            snub: Duration::from_secs(self.snub_timeout),
         }
-        Some(&b'i') => parse_ben_int(iter),
-        Some(&b'l') => parse_ben_list(iter),
-        Some(&b'd') => parse_ben_dict(iter),
-        _ => panic!("Invalid format"),
     }
 }
 
-#[derive(Deserialize, Serialize)]
-struct TorrentFile {
-    announce: String,
-    info: TorrentFileInfo,
+/// Peer and bandwidth limits. Rates are in KiB/s, 0 meaning unlimited.
+#[derive(Args)]
+#[command(next_help_heading = "Transfer limits")]
+struct TransferOptions {
+    /// Most peers to download from at once
+    #[arg(long, default_value_t = MAX_PEERS)]
+    max_peers: usize,
+
+    /// Most peer connections in total, incoming and outgoing
+    #[arg(long, default_value_t = SessionConfig::default().max_connections)]
+    max_connections: usize,
+
+    /// Upload limit for the whole session
+    #[arg(long, value_name = "KIB", default_value_t = 0)]
+    max_upload_rate: u64,
+
+    /// Download limit for the whole session
+    #[arg(long, value_name = "KIB", default_value_t = 0)]
+    max_download_rate: u64,
+
+    /// Upload limit for each peer
+    #[arg(long, value_name = "KIB", default_value_t = 0)]
+    max_peer_upload_rate: u64,
+
+    /// Download limit for each peer
+    #[arg(long, value_name = "KIB", default_value_t = 0)]
+    max_peer_download_rate: u64,
+
+    /// Count protocol overhead against the limits, not just piece data
+    #[arg(long)]
+    limit_overhead: bool,
 }
 
-#[derive(Deserialize, Serialize)]
-struct TorrentFileInfo {
-    length: usize,
-    name: String,
-    #[serde(rename = "piece length")]
-    piece_length: usize,
-    pieces: ByteBuf,
+impl TransferOptions {
+    fn rate_limits(&self) -> Arc<RateLimits> {
+        let limits = RateLimits::unlimited();
+        let settings = [
+            (self.max_upload_rate, &limits.global, Direction::Upload),
+            (self.max_download_rate, &limits.global, Direction::Download),
+            (
+                self.max_peer_upload_rate,
+                &limits.per_peer,
+                Direction::Upload,
+            ),
+            (
+                self.max_peer_download_rate,
+                &limits.per_peer,
+                Direction::Download,
+            ),
+        ];
+        for (kib, level, direction) in settings {
+            level.bucket(direction).set_rate(kib.saturating_mul(1024));
+        }
+        limits.set_count_overhead(self.limit_overhead);
+        limits
+    }
 }
 
-fn urlencode(t: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(3 * t.len());
-    for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+fn parse_peer_id(value: &str) -> Result<[u8; 20], String> {
+    if let Ok(id) = value.as_bytes().try_into() {
+        return Ok(id);
     }
-    encoded
+    hex::decode(value)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| "expected 20 characters or 40 hex digits".to_string())
 }
 
-#[derive(Serialize)]
-struct QueryParams {
-    peer_id: String,
-    port: usize,
-    uploaded: usize,
-    downloaded: usize,
-    left: usize,
-    compact: usize,
+fn session_config(peer: &PeerOptions, transfer: &TransferOptions) -> SessionConfig {
+    SessionConfig {
+        identity: peer.identity(),
+        limits: transfer.rate_limits(),
+        timeouts: peer.timeouts(),
+        max_peers: transfer.max_peers,
+        max_connections: transfer.max_connections,
+        ..SessionConfig::default()
+    }
 }
 
-#[derive(Deserialize)]
-struct TrackerResponse {
-    interval: usize,
-    peers: ByteBuf,
+// Serves peers until the process is stopped; the session announces the
+// torrent once it gets a seeding slot.
+async fn seed(session: Session, torrent: &Torrent, port: u16, mode: Mode) -> Result<()> {
+    let name = &torrent.metainfo().info.name;
+    if mode == Mode::Json {
+        let (verified, pieces) = torrent.progress();
+        print_json(&output::Seeding {
+            seeding: name.clone(),
+            info_hash: hex::encode(torrent.info_hash()),
+            port,
+            verified,
+            pieces,
+        });
+    } else {
+        println!("Seeding {name} on port {port}");
+    }
+    // Started after the lines above, which the live view would draw over.
+    let progress = Progress::start(session.events(), mode);
+    progress.track(torrent);
+    session.run().await
 }
 
-struct Handshake {
-    length_p_string: usize,
-    p_string: String,
-    reserved_bytes: Vec<u8>,
-    sha1_infohash: Vec<u8>,
-    peer_id: Vec<u8>,
+// The live view only makes sense on a terminal, and would garble the
+// extra lines of --verbose.
+fn progress_mode(cli: &Cli) -> Mode {
+    if cli.json {
+        Mode::Json
+    } else if !cli.quiet && cli.verbose == 0 && io::stderr().is_terminal() {
+        Mode::View
+    } else {
+        Mode::Lines
+    }
 }
 
-fn parse_ips(ips: &[u8]) -> Vec<String> {
-    ips.chunks(6)
-        .map(|chunk| {
-            let ip = format!("{}.{}.{}.{}", chunk[0], chunk[1], chunk[2], chunk[3]);
-            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-            format!("{ip}:{port}")
-        })
-        .collect()
+impl RemoteCommand {
+    fn call(self) -> (&'static str, Value) {
+        match self {
+            RemoteCommand::Add {
+                torrent,
+                output,
+                paused,
+            } => {
+                // The daemon may be running in another directory.
+                let cwd = std::env::current_dir().unwrap_or_default();
+                let output = output.map(|output| cwd.join(output));
+                let mut params = json!({ "path": output, "paused": paused });
+                if torrent.starts_with("magnet:") {
+                    params["magnet"] = torrent.into();
+                } else {
+                    params["torrent"] = json!(cwd.join(torrent));
+                }
+                ("add", params)
+            }
+            RemoteCommand::List => ("list", Value::Null),
+            RemoteCommand::Pause { info_hash } => ("pause", json!({ "info_hash": info_hash })),
+            RemoteCommand::Resume { info_hash } => ("resume", json!({ "info_hash": info_hash })),
+            RemoteCommand::Stop { info_hash } => ("stop", json!({ "info_hash": info_hash })),
+            RemoteCommand::Move { info_hash, to } => {
+                ("move", json!({ "info_hash": info_hash, "to": to }))
+            }
+            RemoteCommand::Limits {
+                info_hash,
+                upload,
+                download,
+                peer_upload,
+                peer_download,
+            } => {
+                let params = json!({
+                    "info_hash": info_hash,
+                    "upload": upload,
+                    "download": download,
+                    "peer_upload": peer_upload,
+                    "peer_download": peer_download,
+                });
+                ("limits", params)
+            }
+            RemoteCommand::Remove {
+                info_hash,
+                delete_data,
+            } => (
+                "remove",
+                json!({ "info_hash": info_hash, "delete_data": delete_data }),
+            ),
+        }
+    }
 }
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let command = &args[1];
-
-    if command == "decode" {
-        let encoded_value = &args[2].clone().into_bytes();
-        let mut iter = encoded_value.iter().peekable();
-        let decoded_value = decode_bencoded_value(&mut iter);
-        println!("{decoded_value}");
-        Ok(())
-    } else if command == "info" {
-        let file_name = &args[2];
-        let bytes = fs::read(file_name).unwrap();
-
-        let torrent: TorrentFile = serde_bencode::from_bytes(&bytes)?;
-        let encoded_info = serde_bencode::to_bytes(&torrent.info)?;
-
-        let mut hasher = Sha1::new();
-        hasher.update(encoded_info);
-        let hash = hasher.finalize();
-
-        println!(
-            "Tracker URL: {}\nLength: {}\nInfo Hash: {}\nPiece Length: {}\nPiece Hashes:",
-            torrent.announce,
-            torrent.info.length,
-            hex::encode(hash),
-            torrent.info.piece_length,
-        );
-        for hash in torrent.info.pieces.chunks_exact(20) {
-            println!("{}", hex::encode(hash));
-        }
-        Ok(())
-    } else if command == "peers" {
-        let file_name = &args[2];
-        let bytes = fs::read(file_name)?;
-
-        let torrent: TorrentFile = serde_bencode::from_bytes(&bytes)?;
-        let info_encoded = serde_bencode::to_bytes(&torrent.info)?;
-
-        let request: QueryParams = QueryParams {
-            peer_id: "00112233445566778899".to_string(),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: torrent.info.piece_length,
-            compact: 1,
+// One line per torrent: its hash, state, progress and name.
+fn print_torrents(torrents: &Value) {
+    for torrent in torrents.as_array().into_iter().flatten() {
+        let progress = match (torrent["verified"].as_u64(), torrent["pieces"].as_u64()) {
+            (Some(verified), Some(pieces)) if pieces > 0 => {
+                format!("{:5.1}%", verified as f64 * 100.0 / pieces as f64)
+            }
+            _ => "     -".to_string(),
         };
-
-        let url_params = serde_urlencoded::to_string(&request)?;
-
-        let mut hasher = Sha1::new();
-        hasher.update(&info_encoded);
-        let info_hash: [u8; 20] = hasher.finalize().try_into()?;
-
-        let tracker_url = format!(
-            "{}?{}&info_hash={}",
-            torrent.announce,
-            url_params,
-            &urlencode(&info_hash)
+        println!(
+            "{}  {:<11} {progress}  {}",
+            torrent["info_hash"].as_str().unwrap_or_default(),
+            torrent["state"].as_str().unwrap_or_default(),
+            torrent["name"].as_str().unwrap_or("?"),
         );
-
-        let res = reqwest::blocking::get(tracker_url)?;
-        let body = res.bytes()?;
-        let decoded: TrackerResponse = serde_bencode::from_bytes(&body)?;
-        let peers = parse_ips(&decoded.peers);
-        for peer in peers {
-            println!("{peer}");
+        if let Some(error) = torrent["error"].as_str() {
+            println!("    {error}");
         }
+    }
+}
 
-        Ok(())
-    } else if command == "handshake" {
-        let file_name = &args[2];
-        let bytes = fs::read(file_name)?;
-
-        let torrent: TorrentFile = serde_bencode::from_bytes(&bytes)?;
-        let info_encoded = serde_bencode::to_bytes(&torrent.info)?;
-
-        let peer = &args[3];
-
-        let p_string = "BitTorrent protocol";
-        let reserved_bytes = [0; 8];
-        let peer_id = "00112233445566778899".as_bytes();
-
-        let mut hasher = Sha1::new();
-        hasher.update(&info_encoded);
-        let info_hash: [u8; 20] = hasher.finalize().try_into()?;
-
-        let handshake = Handshake {
-            length_p_string: p_string.len(),
-            p_string: p_string.to_string(),
-            reserved_bytes: reserved_bytes.into(),
-            sha1_infohash: info_hash.into(),
-            peer_id: peer_id.into(),
-        };
-
-        let mut handshake_bytes = Vec::new();
-        handshake_bytes.push(handshake.length_p_string as u8);
-        handshake_bytes.extend(handshake.p_string.as_bytes());
-        handshake_bytes.extend(&handshake.reserved_bytes);
-        handshake_bytes.extend(&handshake.sha1_infohash);
-        handshake_bytes.extend(&handshake.peer_id);
-
-        let mut stream = TcpStream::connect(peer)?;
-
-        stream.write_all(&handshake_bytes)?;
-
-        let mut response = [0; 68];
-        stream.read_exact(&mut response)?;
-
-        let length_p_string = response[0] as usize;
-        let peer_id = response[length_p_string + 29..length_p_string + 49].to_vec();
-
-        println!("Peer ID: {}", hex::encode(peer_id));
-
-        Ok(())
-    } else if command == "download_piece" {
-        let output_path = &args[3];
-        let file_name = &args[4];
-        let piece_index: usize = args[5].parse()?;
-
-        // 1. Read the torrent file
-        let bytes = fs::read(file_name)?;
-        let torrent: TorrentFile = serde_bencode::from_bytes(&bytes)?;
-
-        let tracker_url = &torrent.announce;
-        let info = &torrent.info;
-        let piece_length = info.piece_length;
-        let total_length = info.length;
-        let num_pieces = (total_length + piece_length - 1) / piece_length;
-
-        let this_piece_length = if piece_index < num_pieces - 1 {
-            piece_length
-        } else {
-            total_length - (num_pieces - 1) * piece_length
-        };
-
-        let info_encoded = serde_bencode::to_bytes(&info)?;
-        let mut hasher = Sha1::new();
-        hasher.update(&info_encoded);
-        let info_hash: [u8; 20] = hasher.finalize().try_into()?;
-
-        // 2. Perform the tracker request
-
-        let request: QueryParams = QueryParams {
-            peer_id: "00112233445566778899".to_string(),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: torrent.info.piece_length,
-            compact: 1,
-        };
-
-        let url_params = serde_urlencoded::to_string(&request)?;
-
-        let tracker_url = format!(
-            "{}?{}&info_hash={}",
-            torrent.announce,
-            url_params,
-            &urlencode(&info_hash)
-        );
-
-        let res = reqwest::blocking::get(tracker_url)?;
-        let body = res.bytes()?;
-        let decoded: TrackerResponse = serde_bencode::from_bytes(&body)?;
-        let peers = parse_ips(&decoded.peers);
-
-        // 3. Establish a connection with a peer and perform the handshake
-
-        let peer = &peers[0];
-        let p_string = "BitTorrent protocol";
-        let reserved_bytes = [0; 8];
-        let peer_id = "00112233445566778899".as_bytes();
-
-        let mut hasher = Sha1::new();
-        hasher.update(&info_encoded);
-        let handshake = Handshake {
-            length_p_string: p_string.len(),
-            p_string: p_string.to_string(),
-            reserved_bytes: reserved_bytes.into(),
-            sha1_infohash: info_hash.into(),
-            peer_id: peer_id.into(),
-        };
-
-        let mut handshake_bytes = Vec::new();
-        handshake_bytes.push(handshake.length_p_string as u8);
-        handshake_bytes.extend(handshake.p_string.as_bytes());
-        handshake_bytes.extend(&handshake.reserved_bytes);
-        handshake_bytes.extend(&handshake.sha1_infohash);
-        handshake_bytes.extend(&handshake.peer_id);
-
-        let mut stream = TcpStream::connect(peer)?;
-
-        stream.write_all(&handshake_bytes)?;
-
-        let mut response = [0; 68];
-        stream.read_exact(&mut response)?;
+// Runs `server` if there is one; else waits forever, like a server would.
+async fn serve_if(server: Option<impl Future<Output = Result<()>>>) -> Result<()> {
+    match server {
+        Some(server) => server.await,
+        None => std::future::pending().await,
+    }
+}
 
-        // 4. Exchange peer messages to request and download a piece
-        let mut buffer = vec![0; 4];
-        stream.read_exact(&mut buffer)?;
-        let message_length =
-            u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+/// An info hash in hex, or the info hash of a .torrent file.
+fn allowed_info_hash(hash_or_torrent: &str) -> Result<[u8; 20]> {
+    let mut info_hash = [0; 20];
+    if hex::decode_to_slice(hash_or_torrent, &mut info_hash).is_ok() {
+        return Ok(info_hash);
+    }
+    let torrent = TorrentFile::from_file(hash_or_torrent)
+        .with_context(|| format!("{hash_or_torrent} is neither an info hash nor a torrent"))?;
+    torrent.info_hash()
+}
 
-        if message_length > 0 {
-            buffer.resize(message_length, 0);
-            stream.read(&mut buffer)?;
+fn print_json(value: &impl Serialize) {
+    println!(
+        "{}",
+        serde_json::to_string(value).expect("output always serializes")
+    );
+}
 
-            // check for bitfield message
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let json = cli.json;
+    match run(cli).await {
+        Err(e) if json => {
+            print_json(&output::Error {
+                error: format!("{e:#}"),
+            });
+            std::process::exit(1);
         }
+        result => result,
+    }
+}
 
-        // send interested message
-        let interested = [0u8, 0, 0, 1, 2];
-        stream.write_all(&interested)?;
-
-        // wait for unchoke message
-        loop {
-            buffer.resize(4, 0);
-            stream.read_exact(&mut buffer)?;
-            let message_length =
-                u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
-
-            if message_length == 0 {
-                continue;
-            }
-
-            buffer.resize(message_length, 0);
-            stream.read_exact(&mut buffer)?;
-
-            if buffer[0] == 1 {
-                println!("Unchoked!");
-                break;
-            }
+async fn run(cli: Cli) -> Result<()> {
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => Level::Error,
+        (false, 0) => Level::Info,
+        (false, 1) => Level::Debug,
+        (false, _) => Level::Trace,
+    };
+    let filter = env::var(log::FILTER_VAR).ok();
+    log::init(Some(level), filter.as_deref(), cli.log_file.as_deref())?;
+    let json = cli.json;
+    let mode = progress_mode(&cli);
+
+    match cli.command {
+        Command::Decode { value } => {
+            let decoded_value = bencode::decode(value.as_bytes())?;
+            println!("{decoded_value}");
         }
-
-        // request blocks within the piece
-        let num_blocks = (this_piece_length + (16 * 1024) - 1) / (16 * 1024);
-        for block_index in 0..num_blocks {
-            let block_begin = block_index * 16 * 1024;
-            let block_length = if block_index < num_blocks - 1 {
-                16 * 1024
+        Command::Info { torrent } => {
+            let torrent = TorrentFile::from_file(&torrent)?;
+            let info = output::Info::new(&torrent, torrent.info_hash()?);
+            if json {
+                print_json(&info);
             } else {
-                this_piece_length - block_begin
-            };
-
-            let request_message = [
-                0u8,
-                0,
-                0,
-                13,
-                6, // 13 is the length of the message, 6 is the request message ID
-                (piece_index >> 24) as u8,
-                (piece_index >> 16) as u8,
-                (piece_index >> 8) as u8,
-                piece_index as u8,
-                (block_begin >> 24) as u8,
-                (block_begin >> 16) as u8,
-                (block_begin >> 8) as u8,
-                block_begin as u8,
-                (block_length >> 24) as u8,
-                (block_length >> 16) as u8,
-                (block_length >> 8) as u8,
-                block_length as u8,
-            ];
-
-            stream.write_all(&request_message)?;
-            println!("Requested block {block_index} of piece {piece_index}");
+                println!(
+                    "Tracker URL: {}\nLength: {}\nInfo Hash: {}\nPiece Length: {}\nPiece Hashes:",
+                    info.tracker_url, info.length, info.info_hash, info.piece_length,
+                );
+                for hash in info.piece_hashes {
+                    println!("{hash}");
+                }
+            }
         }
-
-        // 5. Write the piece to the output file
-        let mut piece_data = vec![0; this_piece_length];
-        let mut blocks_received = 0;
-
-        while blocks_received < num_blocks {
-            let mut length_prefix = [0u8; 4];
-            stream.read_exact(&mut length_prefix)?;
-            let message_length = u32::from_be_bytes(length_prefix) as usize;
-
-            if message_length == 0 {
-                continue;
+        Command::Peers {
+            torrent,
+            handshake,
+            peer,
+        } => {
+            let torrent = TorrentFile::from_file(&torrent)?;
+            let info_hash = torrent.info_hash()?;
+            let left = torrent.info.size();
+            let addresses = request_peers(&torrent, &info_hash, peer.identity(), left).await?;
+            let mut peers: Vec<output::Peer> = addresses
+                .into_iter()
+                .map(|address| output::Peer {
+                    address,
+                    peer_id: None,
+                    error: None,
+                })
+                .collect();
+            if handshake {
+                let torrent = Arc::new(torrent);
+                let mut handshakes = JoinSet::new();
+                for (index, found) in peers.iter().enumerate() {
+                    let (torrent, address) = (torrent.clone(), found.address);
+                    let (identity, timeouts) = (peer.identity(), peer.timeouts());
+                    handshakes.spawn(async move {
+                        let pool = PeerPool::new().shared();
+                        let connection =
+                            connect(&torrent, info_hash, address, &pool, identity, timeouts);
+                        (index, connection.await.map(|c| c.handshake.peer_id))
+                    });
+                }
+                while let Some(joined) = handshakes.join_next().await {
+                    let (index, result) = joined?;
+                    match result {
+                        Ok(peer_id) => peers[index].peer_id = Some(hex::encode(peer_id)),
+                        Err(e) => peers[index].error = Some(e.to_string()),
+                    }
+                }
             }
 
-            let mut message = vec![0u8; message_length];
-            stream.read_exact(&mut message)?;
-            if message[0] == 7 {
-                // If it's a piece message
-                let index =
-                    u32::from_be_bytes([message[1], message[2], message[3], message[4]]) as usize;
-                let begin =
-                    u32::from_be_bytes([message[5], message[6], message[7], message[8]]) as usize;
-                let block_data = &message[9..]; // The rest of the message is the block data
-
-                // Ensure the piece and block data fits within the bounds of piece_data
-                if begin + block_data.len() <= piece_data.len() {
-                    piece_data[begin..begin + block_data.len()].copy_from_slice(block_data);
-                    blocks_received += 1;
-                    println!("Received block {blocks_received}/{num_blocks} for piece {index}");
-                } else {
-                    println!("Error: Block data exceeds piece data bounds.");
+            if json {
+                print_json(&output::Peers {
+                    info_hash: hex::encode(info_hash),
+                    peers,
+                });
+            } else {
+                for peer in peers {
+                    match (peer.peer_id, peer.error) {
+                        (Some(peer_id), _) => println!("{} {peer_id}", peer.address),
+                        (None, Some(error)) => println!("{} ({error})", peer.address),
+                        (None, None) => println!("{}", peer.address),
+                    }
                 }
             }
         }
-
-        let piece_hash = Sha1::digest(&piece_data);
-        let expected_hash = &torrent.info.pieces[piece_index * 20..(piece_index + 1) * 20];
-        if piece_hash.as_slice() == expected_hash {
-            match fs::write(output_path, &piece_data) {
-                Ok(_) => println!("Piece {piece_index} successfully downloaded and verified"),
-                Err(e) => println!("Error writing piece {piece_index} to file: {e}"),
+        Command::Handshake {
+            torrent,
+            peer_addr,
+            peer,
+        } => {
+            let torrent = TorrentFile::from_file(&torrent)?;
+            let pool = PeerPool::new().shared();
+            let info_hash = torrent.info_hash()?;
+            let (identity, timeouts) = (peer.identity(), peer.timeouts());
+            let stream = connect(&torrent, info_hash, peer_addr, &pool, identity, timeouts).await?;
+
+            let handshake = &stream.handshake;
+            let peer_id = hex::encode(handshake.peer_id);
+            if json {
+                print_json(&output::Handshake {
+                    peer: peer_addr,
+                    peer_id,
+                    supports_extensions: handshake.supports_extensions(),
+                    supports_fast: handshake.supports_fast(),
+                });
+            } else {
+                println!("Peer ID: {peer_id}");
             }
-        } else {
-            println!("Piece {piece_index} failed verification");
         }
-
-        Ok(())
-        // Assuming command == "download", similar structure to your existing code
-    } else if command == "download" {
-        let output_path = &args[3];
-        let file_name = &args[4];
-
-        // Read the torrent file
-        let bytes = fs::read(file_name)?;
-        let torrent: TorrentFile = serde_bencode::from_bytes(&bytes)?;
-
-        // Initialize an empty vector to hold the downloaded file data
-        let mut file_data = Vec::new();
-
-        // Calculate the number of pieces
-        let num_pieces = torrent.info.pieces.len() / 20; // Each hash is 20 bytes
-
-        for piece_index in 0..num_pieces {
+        Command::DownloadPiece {
+            output,
+            torrent,
+            index,
+            peer,
+        } => {
             // 1. Read the torrent file
-            let info = &torrent.info;
-            let piece_length = info.piece_length;
-            let total_length = info.length;
-            let num_pieces = (total_length + piece_length - 1) / piece_length;
-
-            let this_piece_length = if piece_index < num_pieces - 1 {
-                piece_length
-            } else {
-                total_length - (num_pieces - 1) * piece_length
-            };
-
-            let info_encoded = serde_bencode::to_bytes(&info)?;
-            let mut hasher = Sha1::new();
-            hasher.update(&info_encoded);
-            let info_hash: [u8; 20] = hasher.finalize().try_into()?;
+            let torrent = TorrentFile::from_file(&torrent)?;
+            let info_hash = torrent.info_hash()?;
+            let num_pieces = torrent.info.num_pieces();
+            if index >= num_pieces {
+                bail!("piece {index} out of range (torrent has {num_pieces} pieces)");
+            }
 
             // 2. Perform the tracker request
-
-            let request: QueryParams = QueryParams {
-                peer_id: "00112233445566778899".to_string(),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: torrent.info.piece_length,
-                compact: 1,
-            };
-
-            let url_params = serde_urlencoded::to_string(&request)?;
-
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                torrent.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-
-            let res = reqwest::blocking::get(tracker_url)?;
-            let body = res.bytes()?;
-            let decoded: TrackerResponse = serde_bencode::from_bytes(&body)?;
-            let peers = parse_ips(&decoded.peers);
+            let identity = peer.identity();
+            let left = torrent.info.size();
+            let peers = request_peers(&torrent, &info_hash, identity, left).await?;
+            let first = *peers
+                .first()
+                .ok_or_else(|| anyhow!("the tracker returned no peers"))?;
 
             // 3. Establish a connection with a peer and perform the handshake
-
-            let peer = &peers[0];
-            let p_string = "BitTorrent protocol";
-            let reserved_bytes = [0; 8];
-            let peer_id = "00112233445566778899".as_bytes();
-
-            let mut hasher = Sha1::new();
-            hasher.update(&info_encoded);
-            let handshake = Handshake {
-                length_p_string: p_string.len(),
-                p_string: p_string.to_string(),
-                reserved_bytes: reserved_bytes.into(),
-                sha1_infohash: info_hash.into(),
-                peer_id: peer_id.into(),
-            };
-
-            let mut handshake_bytes = Vec::new();
-            handshake_bytes.push(handshake.length_p_string as u8);
-            handshake_bytes.extend(handshake.p_string.as_bytes());
-            handshake_bytes.extend(&handshake.reserved_bytes);
-            handshake_bytes.extend(&handshake.sha1_infohash);
-            handshake_bytes.extend(&handshake.peer_id);
-
-            let mut stream = TcpStream::connect(peer)?;
-
-            stream.write_all(&handshake_bytes)?;
-
-            let mut response = [0; 68];
-            stream.read_exact(&mut response)?;
+            let pool = PeerPool::new().shared();
+            let timeouts = peer.timeouts();
+            let mut stream = connect(&torrent, info_hash, first, &pool, identity, timeouts).await?;
 
             // 4. Exchange peer messages to request and download a piece
-            let mut buffer = vec![0; 4];
-            stream.read_exact(&mut buffer)?;
-            let message_length =
-                u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
-
-            if message_length > 0 {
-                buffer.resize(message_length, 0);
-                stream.read(&mut buffer)?;
+            start_download(&mut stream).await?;
+            let piece_data = download_piece(&mut stream, &torrent.info, index)
+                .await?
+                .ok_or_else(|| anyhow!("peer rejected piece {index}"))?;
 
-                // check for bitfield message
+            // 5. Write the piece to the output file
+            let piece_hash = Sha1::digest(&piece_data);
+            let verified = torrent.info.verify_piece(index, &piece_data);
+            if verified {
+                fs::write(&output, &piece_data)
+                    .map_err(|e| anyhow!("error writing piece {index} to file: {e}"))?;
+            }
+            if json {
+                print_json(&output::DownloadedPiece {
+                    piece: index,
+                    peer: first,
+                    length: piece_data.len(),
+                    hash: hex::encode(piece_hash),
+                    verified,
+                    output: verified.then_some(output),
+                });
+            } else if verified {
+                println!("Piece {index} successfully downloaded and verified");
+            } else {
+                println!("Piece {index} failed verification");
+            }
+        }
+        Command::Download {
+            output,
+            torrent: path,
+            seed: keep_seeding,
+            peer,
+            transfer,
+        } => {
+            let session = Session::start(session_config(&peer, &transfer)).await?;
+            let progress = Progress::start(session.events(), mode);
+            let torrent = session
+                .add_torrent(TorrentFile::from_file(&path)?, &output)
+                .await?;
+            let started = Instant::now();
+            let resumed = torrent.pieces();
+            let resumed_bytes = torrent.verified_bytes();
+            progress.track(&torrent);
+            let completed = torrent.completed().await;
+            progress.finish().await;
+            completed?;
+            if json {
+                let info = &torrent.metainfo().info;
+                let elapsed = started.elapsed().as_secs_f64();
+                let downloaded = info.size() as u64 - resumed_bytes;
+                let pieces = resumed
+                    .iter()
+                    .enumerate()
+                    .map(|(index, &resumed)| output::Piece {
+                        index,
+                        hash: hex::encode(info.piece_hash(index)),
+                        source: if resumed {
+                            PieceSource::Resumed
+                        } else {
+                            PieceSource::Downloaded
+                        },
+                    });
+                print_json(&output::Download {
+                    torrent: path.clone(),
+                    output: output.clone(),
+                    info_hash: hex::encode(torrent.info_hash()),
+                    length: info.size(),
+                    downloaded,
+                    uploaded: torrent.uploaded(),
+                    elapsed_secs: elapsed,
+                    download_rate: if elapsed > 0.0 {
+                        downloaded as f64 / elapsed
+                    } else {
+                        0.0
+                    },
+                    pieces: pieces.collect(),
+                });
+            } else {
+                println!("Downloaded {} to {}.", path.display(), output.display());
             }
 
-            // send interested message
-            let interested = [0u8, 0, 0, 1, 2];
-            stream.write_all(&interested)?;
-
-            // wait for unchoke message
-            loop {
-                buffer.resize(4, 0);
-                stream.read_exact(&mut buffer)?;
-                let message_length =
-                    u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
-
-                if message_length == 0 {
-                    continue;
+            if keep_seeding {
+                seed(session, &torrent, peer.port, mode).await?;
+            }
+        }
+        Command::Seed {
+            torrent,
+            data,
+            peer,
+            transfer,
+        } => {
+            let session = Session::start(session_config(&peer, &transfer)).await?;
+            let torrent = session
+                .add_torrent(TorrentFile::from_file(&torrent)?, &data)
+                .await?;
+            if !json {
+                let (verified, total) = torrent.progress();
+                println!("Verified {verified}/{total} pieces");
+            }
+            seed(session, &torrent, peer.port, mode).await?;
+        }
+        Command::Daemon {
+            rpc_port,
+            download_dir,
+            transmission,
+            transmission_auth,
+            metrics,
+            max_active_downloads,
+            max_active_seeds,
+            peer,
+            transfer,
+        } => {
+            if let Some(addr) = transmission {
+                transmission::check_address(addr, transmission_auth.as_deref())?;
+            }
+            let config = SessionConfig {
+                max_active_downloads,
+                max_active_seeds,
+                ..session_config(&peer, &transfer)
+            };
+            let session = Arc::new(Session::start(config).await?);
+            // Nobody watches a daemon's terminal, so no live view.
+            let mode = if json { Mode::Json } else { Mode::Lines };
+            let _progress = Progress::start(session.events(), mode);
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, rpc_port)).await?;
+            let url = format!("http://{}/rpc", listener.local_addr()?);
+            let rpc = RpcServer::new(session.clone(), download_dir.clone()).serve(listener);
+            let (transmission, transmission_url) = match transmission {
+                Some(addr) => {
+                    let listener = TcpListener::bind(addr).await?;
+                    let url = format!("http://{}/transmission/rpc", listener.local_addr()?);
+                    let server =
+                        TransmissionServer::new(session.clone(), download_dir, transmission_auth);
+                    (Some(server.serve(listener)), Some(url))
                 }
+                None => (None, None),
+            };
+            let (metrics, metrics_url) = match metrics {
+                Some(addr) => {
+                    let listener = TcpListener::bind(addr).await?;
+                    let url = format!("http://{}/metrics", listener.local_addr()?);
+                    (Some(MetricsServer::new(session).serve(listener)), Some(url))
+                }
+                None => (None, None),
+            };
 
-                buffer.resize(message_length, 0);
-                stream.read_exact(&mut buffer)?;
-
-                if buffer[0] == 1 {
-                    println!("Unchoked!");
-                    break;
+            if json {
+                print_json(&output::Daemon {
+                    rpc: url,
+                    transmission: transmission_url,
+                    metrics: metrics_url,
+                    port: peer.port,
+                });
+            } else {
+                println!("Listening for RPC on {url}");
+                if let Some(url) = transmission_url {
+                    println!("Listening for Transmission RPC on {url}");
+                }
+                if let Some(url) = metrics_url {
+                    println!("Serving metrics on {url}");
                 }
             }
-
-            // request blocks within the piece
-            let num_blocks = (this_piece_length + (16 * 1024) - 1) / (16 * 1024);
-            for block_index in 0..num_blocks {
-                let block_begin = block_index * 16 * 1024;
-                let block_length = if block_index < num_blocks - 1 {
-                    16 * 1024
-                } else {
-                    this_piece_length - block_begin
-                };
-
-                let request_message = [
-                    0u8,
-                    0,
-                    0,
-                    13,
-                    6, // 13 is the length of the message, 6 is the request message ID
-                    (piece_index >> 24) as u8,
-                    (piece_index >> 16) as u8,
-                    (piece_index >> 8) as u8,
-                    piece_index as u8,
-                    (block_begin >> 24) as u8,
-                    (block_begin >> 16) as u8,
-                    (block_begin >> 8) as u8,
-                    block_begin as u8,
-                    (block_length >> 24) as u8,
-                    (block_length >> 16) as u8,
-                    (block_length >> 8) as u8,
-                    block_length as u8,
-                ];
-
-                stream.write_all(&request_message)?;
-                println!("Requested block {block_index} of piece {piece_index}");
+            tokio::try_join!(rpc, serve_if(transmission), serve_if(metrics))?;
+        }
+        Command::Remote { rpc_url, command } => {
+            let (method, params) = command.call();
+            let result = rpc::Client::new(rpc_url).call(method, params).await?;
+            if json {
+                println!("{result}");
+            } else if result.is_array() {
+                print_torrents(&result);
+            } else if result.get("info_hash").is_some() {
+                print_torrents(&Value::Array(vec![result]));
+            } else if let Some(limits) = result.as_object() {
+                for (name, kib) in limits {
+                    println!("{name}: {kib} KiB/s");
+                }
             }
-
-            // 5. Write the piece to the output file
-            let mut piece_data = vec![0; this_piece_length];
-            let mut blocks_received = 0;
-
-            while blocks_received < num_blocks {
-                let mut length_prefix = [0u8; 4];
-                stream.read_exact(&mut length_prefix)?;
-                let message_length = u32::from_be_bytes(length_prefix) as usize;
-
-                if message_length == 0 {
-                    continue;
+        }
+        Command::Tracker {
+            listen,
+            udp,
+            interval,
+            allow,
+            state,
+        } => {
+            let allowed: Vec<[u8; 20]> = allow
+                .iter()
+                .map(|allowed| allowed_info_hash(allowed))
+                .collect::<Result<_>>()?;
+            let allowed = (!allowed.is_empty()).then(|| allowed.into_iter().collect());
+            let interval = Duration::from_secs(interval);
+            let tracker = TrackerServer::new(TrackerConfig {
+                interval,
+                peer_timeout: 2 * interval,
+                allowed,
+                state_file: state,
+            })?;
+            let listener = TcpListener::bind(listen).await?;
+            let addr = listener.local_addr()?;
+            let http = tracker.clone().serve(listener);
+            let (udp, udp_url) = match udp {
+                Some(addr) => {
+                    let socket = UdpSocket::bind(addr).await?;
+                    let url = format!("udp://{}/announce", socket.local_addr()?);
+                    (Some(tracker.clone().serve_udp(socket)), Some(url))
                 }
+                None => (None, None),
+            };
 
-                let mut message = vec![0u8; message_length];
-                stream.read_exact(&mut message)?;
-                if message[0] == 7 {
-                    // If it's a piece message
-                    let index = u32::from_be_bytes([message[1], message[2], message[3], message[4]])
-                        as usize;
-                    let begin = u32::from_be_bytes([message[5], message[6], message[7], message[8]])
-                        as usize;
-                    let block_data = &message[9..]; // The rest of the message is the block data
-
-                    // Ensure the piece and block data fits within the bounds of piece_data
-                    if begin + block_data.len() <= piece_data.len() {
-                        piece_data[begin..begin + block_data.len()].copy_from_slice(block_data);
-                        blocks_received += 1;
-                        println!("Received block {blocks_received}/{num_blocks} for piece {index}");
-                    } else {
-                        println!("Error: Block data exceeds piece data bounds.");
-                    }
+            let tracking = output::Tracking {
+                announce: format!("http://{addr}/announce"),
+                scrape: format!("http://{addr}/scrape"),
+                udp: udp_url,
+            };
+            if json {
+                print_json(&tracking);
+            } else {
+                println!("Tracking at {}", tracking.announce);
+                if let Some(url) = &tracking.udp {
+                    println!("Tracking over UDP at {url}");
                 }
             }
-
-            let piece_hash = Sha1::digest(&piece_data);
-            let expected_hash = &torrent.info.pieces[piece_index * 20..(piece_index + 1) * 20];
-            if piece_hash.as_slice() == expected_hash {
-                println!("Piece {piece_index} successfully downloaded and verified");
-            } else {
-                println!("Piece {piece_index} failed verification");
+            tokio::select! {
+                served = async { tokio::try_join!(http, serve_if(udp)) } => {
+                    served?;
+                }
+                _ = tokio::signal::ctrl_c() => {}
             }
-            file_data.extend(piece_data);
+            tracker.save()?;
+        }
+        Command::Completions { shell } => {
+            shell.generate(&Cli::command(), &mut io::stdout())?;
         }
-
-        // Once all pieces are downloaded and verified, save the combined file data to disk
-        fs::write(output_path, file_data)?;
-        println!("Downloaded {} to {}.", file_name, output_path);
-
-        Ok(())
-    } else {
-        Err(anyhow!("Command not found: {}", command))
     }
+    Ok(())
 }
@@ -0,0 +1,166 @@
+//! The merkle trees v2 torrents (BEP 52) verify their data with. A file's
+//! tree has the SHA-256 hashes of its 16 KiB blocks as leaves, padded with
+//! zeros to a power of two; its root is the file's `pieces root`, and the
+//! layer whose nodes each cover a piece is its piece layer.
+
+use crate::sha256;
+
+/// The data each leaf hashes.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+pub type Hash = [u8; 32];
+
+fn parent(left: &Hash, right: &Hash) -> Hash {
+    let mut pair = [0; 64];
+    pair[..32].copy_from_slice(left);
+    pair[32..].copy_from_slice(right);
+    sha256::digest(&pair)
+}
+
+/// The root of a subtree of `2^levels` zero leaves, which stands in for
+/// the nodes at that height past the end of a file.
+pub fn pad(levels: u32) -> Hash {
+    (0..levels).fold([0; 32], |hash, _| parent(&hash, &hash))
+}
+
+/// Every layer of a tree from `base` up to the root, with `base` padded
+/// to `width`, a power of two, with `pad`.
+pub fn layers(base: &[Hash], width: usize, pad: Hash) -> Vec<Vec<Hash>> {
+    let mut layer = base.to_vec();
+    layer.resize(width.max(1), pad);
+    let mut layers = vec![layer];
+    while let Some(layer) = layers.last().filter(|layer| layer.len() > 1) {
+        let next = layer.chunks(2).map(|pair| parent(&pair[0], &pair[1]));
+        layers.push(next.collect());
+    }
+    layers
+}
+
+pub fn root(base: &[Hash], width: usize, pad: Hash) -> Hash {
+    layers(base, width, pad).pop().unwrap()[0]
+}
+
+/// The hashes of the blocks of `data`, the last of which may be short.
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE).map(sha256::digest).collect()
+}
+
+/// A piece's node in its file's piece layer.
+pub fn piece_hash(data: &[u8], piece_length: usize) -> Hash {
+    root(&block_hashes(data), piece_length / BLOCK_SIZE, [0; 32])
+}
+
+/// The root of a file's tree, from its piece layer.
+pub fn file_root(piece_layer: &[Hash], piece_length: usize) -> Hash {
+    let width = piece_layer.len().next_power_of_two();
+    let levels = (piece_length / BLOCK_SIZE).trailing_zeros();
+    root(piece_layer, width, pad(levels))
+}
+
+/// The root of a file of at most one piece, which has no piece layer.
+pub fn small_file_root(data: &[u8]) -> Hash {
+    let leaves = block_hashes(data);
+    root(&leaves, leaves.len().next_power_of_two(), [0; 32])
+}
+
+/// Answers a hash request (BEP 52) from the `layers` of a tree: `length`
+/// hashes of the bottom layer from `index`, then the uncles proving them
+/// for up to `proof_layers` layers, lowest first. `None` if the request
+/// doesn't fit the tree.
+pub fn proof(
+    layers: &[Vec<Hash>],
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Option<Vec<Hash>> {
+    if !length.is_power_of_two() || !index.is_multiple_of(length) {
+        return None;
+    }
+    let mut hashes = layers
+        .first()?
+        .get(index..index.checked_add(length)?)?
+        .to_vec();
+    // The uncles start beside the node the requested hashes add up to.
+    let height = length.trailing_zeros() as usize;
+    let mut node = index / length;
+    for layer in layers.get(height..)?.iter().take(proof_layers) {
+        let Some(&uncle) = layer.get(node ^ 1) else {
+            break;
+        };
+        hashes.push(uncle);
+        node /= 2;
+    }
+    Some(hashes)
+}
+
+// Checks the answer to a hash request for `length` hashes from `index`
+// against `root`, which the uncles after them must reach. Only tests need
+// it: we answer hash requests but, with piece layers from the .torrent
+// file, never make them.
+#[cfg(test)]
+pub(crate) fn verify(root: &Hash, hashes: &[Hash], index: usize, length: usize) -> bool {
+    if !length.is_power_of_two() || !index.is_multiple_of(length) || hashes.len() < length {
+        return false;
+    }
+    let (requested, uncles) = hashes.split_at(length);
+    let mut hash = layers(requested, length, [0; 32]).pop().unwrap()[0];
+    let mut node = index / length;
+    for uncle in uncles {
+        hash = if node.is_multiple_of(2) {
+            parent(&hash, uncle)
+        } else {
+            parent(uncle, &hash)
+        };
+        node /= 2;
+    }
+    node == 0 && hash == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Rng;
+
+    #[test]
+    fn piece_layers_add_up_to_the_root() {
+        let mut rng = Rng::new(52);
+        let piece_length = 4 * BLOCK_SIZE;
+        for length in [1, BLOCK_SIZE, 3 * BLOCK_SIZE + 5, 9 * piece_length + 7] {
+            let data = rng.bytes(length);
+            let leaves = block_hashes(&data);
+            let whole = root(&leaves, leaves.len().next_power_of_two(), [0; 32]);
+            assert_eq!(small_file_root(&data), whole);
+            let layer: Vec<Hash> = data
+                .chunks(piece_length)
+                .map(|piece| piece_hash(piece, piece_length))
+                .collect();
+            if layer.len() > 1 {
+                assert_eq!(file_root(&layer, piece_length), whole);
+            }
+        }
+    }
+
+    #[test]
+    fn proves_hashes() {
+        let base: Vec<Hash> = (0..11u8).map(|byte| [byte; 32]).collect();
+        let layers = layers(&base, 16, pad(2));
+        let root = layers.last().unwrap()[0];
+        for (index, length) in [(0, 1), (4, 4), (8, 8), (10, 2), (0, 16)] {
+            let hashes = proof(&layers, index, length, 10).unwrap();
+            assert_eq!(hashes[..length], layers[0][index..index + length]);
+            assert!(verify(&root, &hashes, index, length));
+
+            let mut tampered = hashes.clone();
+            tampered[0][0] ^= 1;
+            assert!(!verify(&root, &tampered, index, length));
+            if hashes.len() > length {
+                let short = &hashes[..hashes.len() - 1];
+                assert!(!verify(&root, short, index, length));
+            }
+        }
+        assert_eq!(proof(&layers, 0, 4, 1).unwrap().len(), 5);
+        assert_eq!(proof(&layers, 2, 4, 10), None);
+        assert_eq!(proof(&layers, 16, 1, 10), None);
+        assert_eq!(proof(&layers, 0, 3, 10), None);
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bencode,
+    extension::{ExtendedHandshake, ExtensionHandler, ExtensionRegistry},
+    peer::{PeerConnection, Timeouts},
+    Identity, TorrentFileInfo,
+};
+
+pub const NAME: &str = "ut_metadata";
+
+/// Metadata travels in pieces of this size; only the last may be shorter.
+const PIECE_SIZE: usize = 16 * 1024;
+/// Claims beyond this are not worth believing.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+const REQUEST: u8 = 0;
+const DATA: u8 = 1;
+const REJECT: u8 = 2;
+
+/// The bencoded header of every ut_metadata message. Data messages carry
+/// the piece itself right after it.
+#[derive(Deserialize, Serialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+/// What a peer has sent us of the metadata so far.
+#[derive(Default)]
+struct Received {
+    size: Option<usize>,
+    pieces: BTreeMap<usize, Vec<u8>>,
+    rejected: bool,
+}
+
+/// ut_metadata (BEP 9) for a single connection. With the info dictionary
+/// at hand it answers the peer's requests for it; without, it collects the
+/// pieces the peer sends us.
+pub struct MetadataHandler {
+    info: Option<Arc<Vec<u8>>>,
+    received: Arc<Mutex<Received>>,
+}
+
+impl MetadataHandler {
+    /// Serves `info`, the bencoded info dictionary.
+    pub fn serving(info: Arc<Vec<u8>>) -> Self {
+        MetadataHandler {
+            info: Some(info),
+            received: Arc::default(),
+        }
+    }
+
+    fn fetching(received: Arc<Mutex<Received>>) -> Self {
+        MetadataHandler {
+            info: None,
+            received,
+        }
+    }
+
+    fn piece(&self, piece: usize) -> Option<(&[u8], usize)> {
+        let info = self.info.as_ref()?;
+        let start = piece.checked_mul(PIECE_SIZE).filter(|&s| s < info.len())?;
+        let end = (start + PIECE_SIZE).min(info.len());
+        Some((&info[start..end], info.len()))
+    }
+}
+
+impl ExtensionHandler for MetadataHandler {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake) {
+        if self.info.is_none() {
+            self.received.lock().unwrap().size = handshake.metadata_size;
+        }
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        // The header parses fine with a data piece after it.
+        let message: MetadataMessage = bencode::from_bytes(payload)?;
+        match message.msg_type {
+            REQUEST => {
+                let Some((data, total_size)) = self.piece(message.piece) else {
+                    let reject = MetadataMessage {
+                        msg_type: REJECT,
+                        piece: message.piece,
+                        total_size: None,
+                    };
+                    return Ok(Some(serde_bencode::to_bytes(&reject)?));
+                };
+                let header = MetadataMessage {
+                    msg_type: DATA,
+                    piece: message.piece,
+                    total_size: Some(total_size),
+                };
+                let mut reply = serde_bencode::to_bytes(&header)?;
+                reply.extend_from_slice(data);
+                Ok(Some(reply))
+            }
+            DATA if self.info.is_none() => {
+                let mut received = self.received.lock().unwrap();
+                let size = received.size.unwrap_or_default();
+                let start = message
+                    .piece
+                    .checked_mul(PIECE_SIZE)
+                    .filter(|&start| start < size)
+                    .ok_or_else(|| anyhow!("metadata piece {} is out of range", message.piece))?;
+                let len = (size - start).min(PIECE_SIZE);
+                if payload.len() < len {
+                    bail!("metadata piece {} is short", message.piece);
+                }
+                let data = payload[payload.len() - len..].to_vec();
+                received.pieces.insert(message.piece, data);
+                Ok(None)
+            }
+            REJECT => {
+                self.received.lock().unwrap().rejected = true;
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Downloads the info dictionary for `info_hash` from `peer` and checks it
+/// against the hash.
+pub async fn fetch_info(
+    peer: SocketAddr,
+    info_hash: [u8; 20],
+    identity: Identity,
+    timeouts: Timeouts,
+) -> Result<TorrentFileInfo> {
+    let received = Arc::new(Mutex::new(Received::default()));
+    let mut extensions = ExtensionRegistry::new().with_listen_port(identity.port);
+    extensions.register(Box::new(MetadataHandler::fetching(received.clone())));
+    // Magnet links are fetched as v1 torrents, by their SHA-1 info hash.
+    let mut connection = PeerConnection::connect(
+        peer,
+        info_hash,
+        identity.peer_id,
+        false,
+        extensions,
+        timeouts,
+    )
+    .await?;
+    if !connection.handshake.supports_extensions() {
+        bail!("peer {peer} does not support extensions");
+    }
+
+    // Peers that have the metadata say how big it is in their handshake.
+    let since = Instant::now();
+    let too_slow = || since.elapsed() >= timeouts.snub;
+    while connection.extensions.remote.is_none() {
+        if too_slow() {
+            bail!("peer {peer} sent no extended handshake");
+        }
+        connection.poll().await?;
+    }
+    let size = received
+        .lock()
+        .unwrap()
+        .size
+        .filter(|&size| size > 0 && size <= MAX_METADATA_SIZE)
+        .ok_or_else(|| anyhow!("peer {peer} has no metadata to share"))?;
+
+    let count = size.div_ceil(PIECE_SIZE);
+    for piece in 0..count {
+        let request = MetadataMessage {
+            msg_type: REQUEST,
+            piece,
+            total_size: None,
+        };
+        let message = connection
+            .extensions
+            .message(NAME, serde_bencode::to_bytes(&request)?)
+            .ok_or_else(|| anyhow!("peer {peer} does not serve metadata"))?;
+        connection.send(&message).await?;
+    }
+    loop {
+        {
+            let received = received.lock().unwrap();
+            if received.rejected {
+                bail!("peer {peer} refused to send the metadata");
+            }
+            if received.pieces.len() == count {
+                break;
+            }
+        }
+        if too_slow() {
+            bail!("peer {peer} was too slow sending the metadata");
+        }
+        connection.poll().await?;
+    }
+
+    let metadata: Vec<u8> = received
+        .lock()
+        .unwrap()
+        .pieces
+        .values()
+        .flatten()
+        .copied()
+        .collect();
+    if Sha1::digest(&metadata).as_slice() != info_hash {
+        bail!("metadata from {peer} does not match the info hash");
+    }
+    let info: TorrentFileInfo =
+        bencode::from_bytes(&metadata).map_err(|e| anyhow!("unsupported metadata: {e}"))?;
+    // The torrent goes by the hash of the info as we encode it.
+    if serde_bencode::to_bytes(&info)? != metadata {
+        bail!("metadata from {peer} is not in canonical form");
+    }
+    info.validate()?;
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pool::PeerPool,
+        server::{ServedTorrent, Server},
+        storage::Storage,
+        TorrentFile,
+    };
+
+    #[tokio::test]
+    async fn fetches_info_with_keys_we_do_not_know() {
+        let data = b"data";
+        let info = [
+            &b"d6:lengthi4e4:name1:a12:piece lengthi4e6:pieces20:"[..],
+            &Sha1::digest(data),
+            b"6:sourcei7ee",
+        ]
+        .concat();
+        let info_hash: [u8; 20] = Sha1::digest(&info).into();
+        let bytes = [&b"d8:announce3:url4:info"[..], &info, b"e"].concat();
+        let torrent = TorrentFile::from_bytes(&bytes).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, data).unwrap();
+        let storage = Storage::open(&path, &torrent.info).unwrap();
+        let pool = PeerPool::new().shared();
+        let served = ServedTorrent::new(info_hash, torrent.info, storage, vec![true], pool);
+
+        let server = Server::bind(0).await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        server.add_torrent(served.unwrap());
+        server.spawn();
+        let identity = Identity {
+            peer_id: [9; 20],
+            port: 1,
+        };
+        let peer = SocketAddr::from(([127, 0, 0, 1], port));
+        let fetched = fetch_info(peer, info_hash, identity, Timeouts::default());
+        let fetched = fetched.await.unwrap();
+        assert_eq!(serde_bencode::to_bytes(&fetched).unwrap(), info);
+        // What the session adds the torrent under.
+        let torrent = TorrentFile {
+            announce: String::new(),
+            info: fetched,
+            url_list: Vec::new(),
+            httpseeds: Vec::new(),
+            piece_layers: Default::default(),
+        };
+        assert_eq!(torrent.info_hash().unwrap(), info_hash);
+    }
+}
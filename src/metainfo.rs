@@ -0,0 +1,501 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::{ByteBuf, Bytes};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bencode,
+    merkle::{self, Hash},
+    sha256,
+};
+
+/// A parsed `.torrent` file.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TorrentFile {
+    pub announce: String,
+    pub info: TorrentFileInfo,
+    /// Web seeds (BEP 19): HTTP servers with a copy of the data.
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "one_or_more",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub url_list: Vec<String>,
+    /// HTTP seeds (BEP 17): scripts that serve pieces by index.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub httpseeds: Vec<String>,
+    /// The piece layers of a v2 torrent, by the `pieces root` of their
+    /// files.
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+}
+
+/// The info dictionary, whose hash identifies the torrent. A v1 torrent
+/// has `length` and `pieces`, a v2 one (BEP 52) a `file tree`, and a hybrid
+/// both.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct TorrentFileInfo {
+    /// The v1 length, zero in a v2-only torrent; see [`Self::size`].
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub length: usize,
+    /// The files of a multi-file v1 torrent, which [`Self::validate`]
+    /// rejects: everything else assumes the data is a single file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Value>,
+    pub name: String,
+    #[serde(rename = "piece length")]
+    pub piece_length: usize,
+    /// Concatenated SHA-1 hashes of the pieces, none in a v2-only torrent.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pieces: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u8>,
+    /// Kept as read, so that the info hash comes out the same.
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<Value>,
+    /// Keys we have no use for, kept so that the info hash comes out the
+    /// same.
+    #[serde(flatten)]
+    pub other: BTreeMap<ByteBuf, Value>,
+    /// The file tree's file, once [`TorrentFile::from_bytes`] has checked
+    /// it against its piece layer.
+    #[serde(skip)]
+    pub v2: Option<FileV2>,
+}
+
+/// The file of a v2 torrent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileV2 {
+    pub length: usize,
+    pub pieces_root: Hash,
+    /// The merkle tree's nodes for each piece; empty for a file of a
+    /// single piece, whose node is the root.
+    pub piece_layer: Vec<Hash>,
+    /// Every layer of the tree from the piece layer, padded, up to the
+    /// root: what hash requests are answered from.
+    pub layers: Vec<Vec<Hash>>,
+}
+
+fn is_zero(number: &usize) -> bool {
+    *number == 0
+}
+
+impl TorrentFile {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Parses the contents of a `.torrent` file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut torrent: Self = bencode::from_bytes(bytes)?;
+        torrent.info.load_v2(&torrent.piece_layers)?;
+        torrent.info.validate()?;
+        Ok(torrent)
+    }
+
+    /// What peers and trackers know the torrent by: the SHA-1 of its info
+    /// dictionary, or for a v2-only torrent the SHA-256 cut to 20 bytes.
+    pub fn info_hash(&self) -> Result<[u8; 20]> {
+        let info_encoded = serde_bencode::to_bytes(&self.info)?;
+        if self.info.pieces.is_empty() && self.info.is_v2() {
+            return Ok(sha256::digest(&info_encoded)[..20].try_into()?);
+        }
+        Ok(Sha1::digest(info_encoded).into())
+    }
+
+    /// The whole SHA-256 info hash of a v2 or hybrid torrent.
+    pub fn info_hash_v2(&self) -> Result<Option<Hash>> {
+        self.info.info_hash_v2()
+    }
+}
+
+// The length and root of the one file in a v2 file tree.
+fn single_file(tree: &Value) -> Result<(usize, Hash)> {
+    let not_single = || anyhow!("multi-file torrents are not supported");
+    let Value::Dict(files) = tree else {
+        bail!("file tree is not a dictionary");
+    };
+    let (Some(Value::Dict(node)), 1) = (files.values().next(), files.len()) else {
+        return Err(not_single());
+    };
+    let (Some(Value::Dict(file)), 1) = (node.get(&b""[..]), node.len()) else {
+        return Err(not_single());
+    };
+    let length = match file.get(&b"length"[..]) {
+        Some(&Value::Int(length)) => usize::try_from(length)?,
+        _ => bail!("file without a length"),
+    };
+    let pieces_root = match file.get(&b"pieces root"[..]) {
+        Some(Value::Bytes(root)) => root.as_slice().try_into()?,
+        _ => bail!("file without a pieces root"),
+    };
+    Ok((length, pieces_root))
+}
+
+// `url-list` may be a single URL rather than a list, and is sometimes an
+// empty string for none.
+fn one_or_more<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore {
+        One(String),
+        More(Vec<String>),
+    }
+    let urls = match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(url) => vec![url],
+        OneOrMore::More(urls) => urls,
+    };
+    Ok(urls.into_iter().filter(|url| !url.is_empty()).collect())
+}
+
+impl TorrentFileInfo {
+    /// Checks that the pieces add up to the length, as everything that
+    /// indexes pieces relies on.
+    pub fn validate(&self) -> Result<()> {
+        if self.files.is_some() {
+            bail!("multi-file torrents are not supported");
+        }
+        if let Some(version) = self.meta_version.filter(|&version| version != 2) {
+            bail!("unsupported meta version {version}");
+        }
+        let v1 = !self.pieces.is_empty() || self.v2.is_none();
+        if v1 && !self.pieces.len().is_multiple_of(20) {
+            bail!("piece hashes are not 20 bytes each");
+        }
+        if self.is_v2() && self.v2.is_none() && self.pieces.is_empty() {
+            bail!("v2 torrent without its file tree and piece layers");
+        }
+        if self.piece_length == 0 || self.size() == 0 {
+            bail!("torrent has no data");
+        }
+        let pieces = self.size().div_ceil(self.piece_length);
+        if v1 && pieces != self.pieces.len() / 20 {
+            bail!(
+                "{} piece hashes for {pieces} pieces",
+                self.pieces.len() / 20
+            );
+        }
+        if let Some(file) = &self.v2 {
+            if self.length != 0 && self.length != file.length {
+                bail!("v1 and v2 lengths differ");
+            }
+        }
+        Ok(())
+    }
+
+    // Reads the file tree of a v2 torrent, checking its piece layer against
+    // its root. Only a single file is supported, like v1 torrents.
+    fn load_v2(&mut self, piece_layers: &BTreeMap<ByteBuf, ByteBuf>) -> Result<()> {
+        let Some(tree) = &self.file_tree else {
+            return Ok(());
+        };
+        if !self.is_v2() {
+            bail!("file tree without meta version 2");
+        }
+        let piece_length = self.piece_length;
+        if !piece_length.is_power_of_two() || piece_length < merkle::BLOCK_SIZE {
+            bail!("v2 piece length {piece_length} is not a power of two of 16 KiB or more");
+        }
+
+        let (length, pieces_root) = single_file(tree)?;
+        let num_pieces = length.div_ceil(piece_length);
+        let (mut piece_layer, mut layers) = (Vec::new(), Vec::new());
+        if num_pieces > 1 {
+            let layer = piece_layers
+                .get(Bytes::new(&pieces_root))
+                .ok_or_else(|| anyhow!("piece layer missing"))?;
+            if layer.len() != num_pieces * 32 {
+                bail!(
+                    "piece layer of {} bytes for {num_pieces} pieces",
+                    layer.len()
+                );
+            }
+            piece_layer = layer
+                .chunks_exact(32)
+                .map(|hash| hash.try_into().unwrap())
+                .collect();
+            let width = piece_layer.len().next_power_of_two();
+            let levels = (piece_length / merkle::BLOCK_SIZE).trailing_zeros();
+            layers = merkle::layers(&piece_layer, width, merkle::pad(levels));
+            if layers.last().unwrap()[0] != pieces_root {
+                bail!("piece layer doesn't match its pieces root");
+            }
+        }
+        self.v2 = Some(FileV2 {
+            length,
+            pieces_root,
+            piece_layer,
+            layers,
+        });
+        Ok(())
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2)
+    }
+
+    /// See [`TorrentFile::info_hash_v2`].
+    pub fn info_hash_v2(&self) -> Result<Option<Hash>> {
+        if !self.is_v2() {
+            return Ok(None);
+        }
+        Ok(Some(sha256::digest(&serde_bencode::to_bytes(self)?)))
+    }
+
+    /// The length of the data, whether the torrent is v1 or v2.
+    pub fn size(&self) -> usize {
+        self.v2.as_ref().map_or(self.length, |file| file.length)
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        if self.pieces.is_empty() {
+            self.size().div_ceil(self.piece_length)
+        } else {
+            self.pieces.len() / 20
+        }
+    }
+
+    pub fn piece_len(&self, piece_index: usize) -> usize {
+        if piece_index < self.num_pieces() - 1 {
+            self.piece_length
+        } else {
+            self.size() - (self.num_pieces() - 1) * self.piece_length
+        }
+    }
+
+    /// The piece's SHA-1 hash, or in a v2-only torrent its merkle node.
+    pub fn piece_hash(&self, piece_index: usize) -> &[u8] {
+        match &self.v2 {
+            Some(file) if self.pieces.is_empty() => file
+                .piece_layer
+                .get(piece_index)
+                .unwrap_or(&file.pieces_root),
+            _ => &self.pieces[piece_index * 20..(piece_index + 1) * 20],
+        }
+    }
+
+    /// Whether `data` is the piece: it has to match the v1 hash and the v2
+    /// merkle tree, of those the torrent has.
+    pub fn verify_piece(&self, piece_index: usize, data: &[u8]) -> bool {
+        let v1 = self.pieces.is_empty()
+            || Sha1::digest(data).as_slice() == &self.pieces[piece_index * 20..][..20];
+        let v2 = match &self.v2 {
+            None => true,
+            Some(file) if file.piece_layer.is_empty() => {
+                merkle::small_file_root(data) == file.pieces_root
+            }
+            Some(file) => {
+                merkle::piece_hash(data, self.piece_length) == file.piece_layer[piece_index]
+            }
+        };
+        v1 && v2
+    }
+
+    /// The name to store the data under inside a download directory, or
+    /// `None` if the name could escape it. Names come from strangers.
+    pub fn file_name(&self) -> Option<&str> {
+        let name = self.name.as_str();
+        let unsafe_name = name.is_empty() || name == "." || name == "..";
+        (!unsafe_name && !name.contains(['/', '\\'])).then_some(name)
+    }
+
+    // Private torrents (BEP 27) must only get peers from their tracker.
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Rng};
+
+    fn torrent(length: usize, piece_length: usize, hash_bytes: usize) -> Vec<u8> {
+        let info = TorrentFileInfo {
+            length,
+            name: "a".to_string(),
+            piece_length,
+            pieces: ByteBuf::from(vec![0; hash_bytes]),
+            private: None,
+            ..Default::default()
+        };
+        let announce = "http://tracker/announce".to_string();
+        serde_bencode::to_bytes(&TorrentFile {
+            announce,
+            info,
+            url_list: Vec::new(),
+            httpseeds: Vec::new(),
+            piece_layers: Default::default(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn loads_only_consistent_torrents() {
+        let loaded = TorrentFile::from_bytes(&torrent(10, 4, 60)).unwrap();
+        let lengths: Vec<_> = (0..3).map(|piece| loaded.info.piece_len(piece)).collect();
+        assert_eq!(lengths, [4, 4, 2]);
+
+        for (length, piece_length, hash_bytes) in [
+            (10, 4, 40),
+            (10, 4, 80),
+            (10, 4, 59),
+            (0, 4, 0),
+            (10, 0, 60),
+        ] {
+            let bytes = torrent(length, piece_length, hash_bytes);
+            assert!(TorrentFile::from_bytes(&bytes).is_err());
+        }
+
+        let mut multi_file: TorrentFile = serde_bencode::from_bytes(&torrent(0, 4, 60)).unwrap();
+        let file = std::collections::HashMap::from([
+            (b"length".to_vec(), Value::Int(10)),
+            (
+                b"path".to_vec(),
+                Value::List(vec![Value::Bytes(b"b".to_vec())]),
+            ),
+        ]);
+        multi_file.info.files = Some(Value::List(vec![Value::Dict(file)]));
+        let bytes = serde_bencode::to_bytes(&multi_file).unwrap();
+        let error = TorrentFile::from_bytes(&bytes).err().unwrap();
+        assert_eq!(error.to_string(), "multi-file torrents are not supported");
+    }
+
+    #[test]
+    fn keeps_unknown_info_keys() {
+        let info = [
+            &b"d6:lengthi4e4:name1:a12:piece lengthi4e10:piece type5:plain6:pieces20:"[..],
+            &[7; 20],
+            b"6:sourcei7ee",
+        ]
+        .concat();
+        let bytes = [&b"d8:announce3:url4:info"[..], &info, b"e"].concat();
+        let loaded = TorrentFile::from_bytes(&bytes).unwrap();
+        assert_eq!(serde_bencode::to_bytes(&loaded.info).unwrap(), info);
+        let info_hash: [u8; 20] = Sha1::digest(&info).into();
+        assert_eq!(loaded.info_hash().unwrap(), info_hash);
+    }
+
+    #[test]
+    fn reads_web_seeds() {
+        let plain = torrent(10, 4, 60);
+        let info_hash = TorrentFile::from_bytes(&plain)
+            .unwrap()
+            .info_hash()
+            .unwrap();
+        // The key sorts after "info", just before the closing "e".
+        let with = |url_list: &[u8]| [&plain[..plain.len() - 1], url_list, b"e"].concat();
+        for (url_list, urls) in [
+            (
+                &b"8:url-list19:http://mirror/a.iso"[..],
+                &["http://mirror/a.iso"][..],
+            ),
+            (
+                b"8:url-listl9:http://a/9:http://b/e",
+                &["http://a/", "http://b/"],
+            ),
+            (b"8:url-list0:", &[]),
+        ] {
+            let loaded = TorrentFile::from_bytes(&with(url_list)).unwrap();
+            assert_eq!(loaded.url_list, urls);
+            // The info hash doesn't cover web seeds.
+            assert_eq!(loaded.info_hash().unwrap(), info_hash);
+        }
+        let loaded = TorrentFile::from_bytes(&with(b"9:httpseedsl14:http://a/seed1e")).unwrap();
+        assert_eq!(loaded.httpseeds, ["http://a/seed1"]);
+    }
+
+    #[test]
+    fn loads_v2_torrents() {
+        let piece_length = 2 * merkle::BLOCK_SIZE;
+        let data = Rng::new(52).bytes(3 * piece_length + 100);
+        for hybrid in [false, true] {
+            let torrent = testing::torrent_v2(&data, piece_length, hybrid);
+            let bytes = serde_bencode::to_bytes(&torrent).unwrap();
+            let loaded = TorrentFile::from_bytes(&bytes).unwrap();
+            assert_eq!(loaded.info.size(), data.len());
+            assert_eq!(loaded.info.num_pieces(), 4);
+            assert_eq!(loaded.info.piece_len(3), 100);
+
+            let info_encoded = serde_bencode::to_bytes(&loaded.info).unwrap();
+            let info_hash_v2 = sha256::digest(&info_encoded);
+            assert_eq!(loaded.info_hash_v2().unwrap(), Some(info_hash_v2));
+            let info_hash = loaded.info_hash().unwrap();
+            if hybrid {
+                assert_eq!(info_hash, <[u8; 20]>::from(Sha1::digest(&info_encoded)));
+            } else {
+                assert_eq!(info_hash, info_hash_v2[..20]);
+            }
+
+            for (index, piece) in data.chunks(piece_length).enumerate() {
+                assert!(loaded.info.verify_piece(index, piece));
+                let mut corrupt = piece.to_vec();
+                corrupt[0] ^= 1;
+                assert!(!loaded.info.verify_piece(index, &corrupt));
+            }
+        }
+
+        // A single piece has no layer; its root checks it.
+        let small = &data[..1000];
+        let torrent = testing::torrent_v2(small, piece_length, false);
+        let loaded = TorrentFile::from_bytes(&serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+        assert!(loaded.info.verify_piece(0, small));
+        assert!(!loaded.info.verify_piece(0, &data[1..1001]));
+    }
+
+    #[test]
+    fn rejects_bad_v2_torrents() {
+        let piece_length = 2 * merkle::BLOCK_SIZE;
+        let data = Rng::new(7).bytes(3 * piece_length);
+        let load = |torrent: &TorrentFile| {
+            TorrentFile::from_bytes(&serde_bencode::to_bytes(torrent).unwrap())
+        };
+
+        let mut torrent = testing::torrent_v2(&data, piece_length, false);
+        for layer in torrent.piece_layers.values_mut() {
+            layer[0] ^= 1;
+        }
+        assert!(load(&torrent).is_err());
+
+        let mut torrent = testing::torrent_v2(&data, piece_length, false);
+        torrent.piece_layers.clear();
+        assert!(load(&torrent).is_err());
+
+        let mut torrent = testing::torrent_v2(&data, piece_length, false);
+        torrent.info.meta_version = None;
+        assert!(load(&torrent).is_err());
+
+        let mut torrent = testing::torrent_v2(&data, piece_length, true);
+        torrent.info.length += 1;
+        assert!(load(&torrent).is_err());
+
+        let odd = testing::torrent_v2(&data, piece_length, false);
+        let mut torrent = odd.clone();
+        torrent.info.piece_length = 3 * merkle::BLOCK_SIZE;
+        assert!(load(&torrent).is_err());
+
+        let Some(Value::Dict(files)) = &mut torrent.info.file_tree else {
+            unreachable!();
+        };
+        let file = files[&b"a"[..]].clone();
+        files.insert(b"b".to_vec(), file);
+        torrent.info.piece_length = piece_length;
+        let error = load(&torrent).err().unwrap();
+        assert_eq!(error.to_string(), "multi-file torrents are not supported");
+        assert!(load(&odd).is_ok());
+    }
+}
@@ -0,0 +1,416 @@
+//! Prometheus metrics for a session, served in the text exposition format
+//! at `/metrics`.
+//!
+//! Transfer totals, peers and the disk queue are read from the torrents at
+//! each scrape. Pieces and tracker announces are counted from the session's
+//! events, so they start from zero when the server is created.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Write as _},
+    sync::{Arc, Mutex, Weak},
+};
+
+use anyhow::Result;
+use tokio::{net::TcpListener, sync::broadcast};
+
+use crate::{
+    events::{EventKind, TorrentEvent},
+    http::{self, Request, Response},
+    session::{Session, Torrent},
+};
+
+/// A common choice for exporters that have no port of their own.
+pub const DEFAULT_PORT: u16 = 9100;
+
+const PATH: &str = "/metrics";
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// Upper bounds of the announce duration buckets, in seconds.
+const ANNOUNCE_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+const STATES: [&str; 7] = [
+    "queued",
+    "downloading",
+    "seeding",
+    "paused",
+    "stopped",
+    "failed",
+    "resolving",
+];
+
+/// A metric with a sample per torrent.
+struct Family<T> {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&T) -> u64,
+}
+
+const TORRENT_FAMILIES: [Family<Torrent>; 6] = [
+    Family {
+        name: "bittorrent_downloaded_bytes_total",
+        kind: "counter",
+        help: "Bytes of verified pieces downloaded from peers.",
+        value: Torrent::downloaded,
+    },
+    Family {
+        name: "bittorrent_uploaded_bytes_total",
+        kind: "counter",
+        help: "Bytes uploaded to peers.",
+        value: Torrent::uploaded,
+    },
+    Family {
+        name: "bittorrent_size_bytes",
+        kind: "gauge",
+        help: "Size of the torrent's data.",
+        value: |torrent| torrent.metainfo().info.size() as u64,
+    },
+    Family {
+        name: "bittorrent_verified_bytes",
+        kind: "gauge",
+        help: "Bytes of the torrent's data we have verified.",
+        value: Torrent::verified_bytes,
+    },
+    Family {
+        name: "bittorrent_pieces_verified",
+        kind: "gauge",
+        help: "Pieces we have, including those on disk when it was added.",
+        value: |torrent| torrent.progress().0 as u64,
+    },
+    Family {
+        name: "bittorrent_disk_queue_pieces",
+        kind: "gauge",
+        help: "Verified pieces waiting to be written to disk.",
+        value: |torrent| torrent.unwritten() as u64,
+    },
+];
+
+/// Counted from events rather than read from the torrent.
+const COUNTED_FAMILIES: [Family<Counters>; 4] = [
+    Family {
+        name: "bittorrent_pieces_downloaded_total",
+        kind: "counter",
+        help: "Pieces downloaded and verified.",
+        value: |counters| counters.pieces_downloaded,
+    },
+    Family {
+        name: "bittorrent_pieces_failed_total",
+        kind: "counter",
+        help: "Pieces that failed their hash check.",
+        value: |counters| counters.pieces_failed,
+    },
+    Family {
+        name: "bittorrent_tracker_announces_total",
+        kind: "counter",
+        help: "Announces made to the tracker.",
+        value: |counters| counters.announces,
+    },
+    Family {
+        name: "bittorrent_tracker_announce_errors_total",
+        kind: "counter",
+        help: "Announces that failed.",
+        value: |counters| counters.announce_errors,
+    },
+];
+
+/// What one torrent's events added up to.
+#[derive(Default)]
+struct Counters {
+    pieces_downloaded: u64,
+    pieces_failed: u64,
+    announces: u64,
+    announce_errors: u64,
+}
+
+#[derive(Default)]
+struct Counted {
+    torrents: HashMap<[u8; 20], Counters>,
+    announce_duration: Histogram,
+}
+
+/// Observations counted into cumulative buckets, as Prometheus wants them.
+struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            bounds: &ANNOUNCE_BUCKETS,
+            buckets: vec![0; ANNOUNCE_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bound, bucket) in self.bounds.iter().zip(&mut self.buckets) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Counted {
+    fn count(&mut self, event: &TorrentEvent) {
+        let torrent = &event.info_hash;
+        match &event.kind {
+            EventKind::PieceCompleted { .. } => self.torrent(torrent).pieces_downloaded += 1,
+            EventKind::PieceFailed { .. } => self.torrent(torrent).pieces_failed += 1,
+            EventKind::TrackerResponse { elapsed, .. } => {
+                self.torrent(torrent).announces += 1;
+                self.announce_duration.observe(elapsed.as_secs_f64());
+            }
+            EventKind::TrackerFailed { elapsed, .. } => {
+                let counters = self.torrent(torrent);
+                counters.announces += 1;
+                counters.announce_errors += 1;
+                self.announce_duration.observe(elapsed.as_secs_f64());
+            }
+            _ => {}
+        }
+    }
+
+    fn torrent(&mut self, info_hash: &[u8; 20]) -> &mut Counters {
+        self.torrents.entry(*info_hash).or_default()
+    }
+}
+
+/// Text in the exposition format: families of samples, each family
+/// introduced by its help and type.
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}");
+        let _ = writeln!(self.0, "# TYPE {name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.0 += name;
+        for (i, (label, value)) in labels.iter().enumerate() {
+            let separator = if i == 0 { '{' } else { ',' };
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            let _ = write!(self.0, "{separator}{label}=\"{value}\"");
+        }
+        if !labels.is_empty() {
+            self.0.push('}');
+        }
+        let _ = writeln!(self.0, " {value}");
+    }
+
+    fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.family(name, "histogram", help);
+        let bucket = format!("{name}_bucket");
+        for (bound, count) in histogram.bounds.iter().zip(&histogram.buckets) {
+            self.sample(&bucket, &[("le", &bound.to_string())], count);
+        }
+        self.sample(&bucket, &[("le", "+Inf")], histogram.count);
+        self.sample(&format!("{name}_sum"), &[], histogram.sum);
+        self.sample(&format!("{name}_count"), &[], histogram.count);
+    }
+}
+
+/// Serves a session's metrics for Prometheus to scrape.
+pub struct MetricsServer {
+    session: Arc<Session>,
+    counted: Arc<Mutex<Counted>>,
+}
+
+impl MetricsServer {
+    /// Starts counting the session's events.
+    pub fn new(session: Arc<Session>) -> Arc<Self> {
+        let counted = Arc::new(Mutex::new(Counted::default()));
+        tokio::spawn(count_loop(session.events(), Arc::downgrade(&counted)));
+        Arc::new(MetricsServer { session, counted })
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        http::serve(listener, move |request| {
+            let metrics = self.clone();
+            async move { metrics.answer(request) }
+        })
+        .await
+    }
+
+    fn answer(&self, request: Request) -> Response {
+        if request.path != PATH {
+            return Response::text(404, "not found\n");
+        }
+        if request.method != "GET" {
+            return Response::text(405, "use GET\n").with_header("Allow", "GET");
+        }
+        Response::new(200, CONTENT_TYPE, self.render())
+    }
+
+    /// Every metric, in the text exposition format.
+    pub fn render(&self) -> String {
+        let torrents: Vec<Labelled> = self
+            .session
+            .torrents()
+            .into_iter()
+            .map(|torrent| Labelled {
+                info_hash: hex::encode(torrent.info_hash()),
+                name: torrent.metainfo().info.name.clone(),
+                torrent,
+            })
+            .collect();
+        let counted = self.counted.lock().unwrap();
+        let mut out = Exposition::default();
+
+        out.family("bittorrent_torrents", "gauge", "Torrents by state.");
+        let mut states: HashMap<&str, usize> = HashMap::new();
+        for labelled in &torrents {
+            *states.entry(labelled.torrent.state().name()).or_default() += 1;
+        }
+        states.insert("resolving", self.session.resolving().len());
+        for state in STATES {
+            let count = states.get(state).copied().unwrap_or(0);
+            out.sample("bittorrent_torrents", &[("state", state)], count);
+        }
+
+        for family in TORRENT_FAMILIES {
+            out.family(family.name, family.kind, family.help);
+            for labelled in &torrents {
+                let value = (family.value)(&labelled.torrent);
+                out.sample(family.name, &labelled.labels(), value);
+            }
+        }
+
+        let name = "bittorrent_peers";
+        out.family(
+            name,
+            "gauge",
+            "Connected peers, by whether we download or upload.",
+        );
+        for labelled in &torrents {
+            let torrent = &labelled.torrent;
+            for (direction, peers) in [
+                ("download", torrent.peers()),
+                ("upload", torrent.uploading_to()),
+            ] {
+                let [info_hash, torrent_name] = labelled.labels();
+                out.sample(
+                    name,
+                    &[info_hash, torrent_name, ("direction", direction)],
+                    peers,
+                );
+            }
+        }
+
+        for family in COUNTED_FAMILIES {
+            out.family(family.name, family.kind, family.help);
+            for labelled in &torrents {
+                let counters = counted.torrents.get(&labelled.torrent.info_hash());
+                let value = counters.map_or(0, family.value);
+                out.sample(family.name, &labelled.labels(), value);
+            }
+        }
+
+        out.histogram(
+            "bittorrent_tracker_announce_duration_seconds",
+            "How long tracker announces took, failed ones included.",
+            &counted.announce_duration,
+        );
+        out.0
+    }
+}
+
+/// A torrent with the labels its samples carry.
+struct Labelled {
+    torrent: Arc<Torrent>,
+    info_hash: String,
+    name: String,
+}
+
+impl Labelled {
+    fn labels(&self) -> [(&str, &str); 2] {
+        [("info_hash", &self.info_hash), ("name", &self.name)]
+    }
+}
+
+async fn count_loop(mut events: broadcast::Receiver<TorrentEvent>, counted: Weak<Mutex<Counted>>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            // Counts go a little low rather than stop.
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let Some(counted) = counted.upgrade() else {
+            return;
+        };
+        counted.lock().unwrap().count(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::TorrentState;
+
+    #[test]
+    fn writes_the_exposition_format() {
+        let mut counted = Counted::default();
+        for (ms, failed) in [(30, false), (700, false), (45_000, true)] {
+            let elapsed = Duration::from_millis(ms);
+            let kind = if failed {
+                EventKind::TrackerFailed {
+                    error: "refused".to_string(),
+                    elapsed,
+                }
+            } else {
+                EventKind::TrackerResponse { peers: 3, elapsed }
+            };
+            counted.count(&TorrentEvent {
+                info_hash: [1; 20],
+                kind,
+            });
+        }
+        let counters = &counted.torrents[&[1; 20]];
+        assert_eq!((counters.announces, counters.announce_errors), (3, 1));
+
+        let mut out = Exposition::default();
+        let name = "announce_seconds";
+        out.histogram(name, "Announces.", &counted.announce_duration);
+        out.sample("odd", &[("name", "a \"b\"\\c\nd")], 1);
+        let text = out.0;
+        assert!(text.starts_with(
+            "# HELP announce_seconds Announces.\n# TYPE announce_seconds histogram\n"
+        ));
+        assert!(text.contains("announce_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(text.contains("announce_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(text.contains("announce_seconds_bucket{le=\"30\"} 2\n"));
+        assert!(text.contains("announce_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("announce_seconds_sum 45.73\n"));
+        assert!(text.contains("announce_seconds_count 3\n"));
+        assert!(text.ends_with("odd{name=\"a \\\"b\\\"\\\\c\\nd\"} 1\n"));
+
+        let failed = TorrentState::Failed(String::new());
+        let states = [
+            TorrentState::Queued,
+            TorrentState::Downloading,
+            TorrentState::Seeding,
+        ];
+        let states =
+            states
+                .into_iter()
+                .chain([TorrentState::Paused, TorrentState::Stopped, failed]);
+        let mut names: Vec<_> = states.map(|state| state.name()).collect();
+        names.push("resolving");
+        assert_eq!(names, STATES);
+    }
+}
@@ -0,0 +1,160 @@
+//! What commands print with `--json`: one JSON object per line on stdout.
+//! These shapes are a stable interface for scripts; fields may be added,
+//! but none are renamed or removed. `docs/json.md` has examples.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use serde::Serialize;
+
+use bittorrent_starter_rust::TorrentFile;
+
+/// Printed instead of a result when a command fails; the exit status is 1.
+#[derive(Serialize)]
+pub struct Error {
+    pub error: String,
+}
+
+/// `info`: the metainfo file's fields.
+#[derive(Serialize)]
+pub struct Info {
+    pub tracker_url: String,
+    pub name: String,
+    /// Total size in bytes.
+    pub length: usize,
+    pub info_hash: String,
+    pub piece_length: usize,
+    pub piece_count: usize,
+    pub piece_hashes: Vec<String>,
+    pub private: bool,
+    pub files: Vec<File>,
+}
+
+#[derive(Serialize)]
+pub struct File {
+    /// Relative to the download location.
+    pub path: PathBuf,
+    pub length: usize,
+}
+
+impl Info {
+    pub fn new(torrent: &TorrentFile, info_hash: [u8; 20]) -> Self {
+        let info = &torrent.info;
+        Info {
+            tracker_url: torrent.announce.clone(),
+            name: info.name.clone(),
+            length: info.size(),
+            info_hash: hex::encode(info_hash),
+            piece_length: info.piece_length,
+            piece_count: info.num_pieces(),
+            piece_hashes: (0..info.num_pieces())
+                .map(|index| hex::encode(info.piece_hash(index)))
+                .collect(),
+            private: info.is_private(),
+            // Single-file torrents are all we support.
+            files: vec![File {
+                path: info.name.clone().into(),
+                length: info.size(),
+            }],
+        }
+    }
+}
+
+/// `peers`: what the tracker returned.
+#[derive(Serialize)]
+pub struct Peers {
+    pub info_hash: String,
+    pub peers: Vec<Peer>,
+}
+
+#[derive(Serialize)]
+pub struct Peer {
+    pub address: SocketAddr,
+    /// Hex; only known after a handshake, so null without `--handshake`.
+    pub peer_id: Option<String>,
+    /// Why the handshake failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// `handshake`: who answered.
+#[derive(Serialize)]
+pub struct Handshake {
+    pub peer: SocketAddr,
+    pub peer_id: String,
+    pub supports_extensions: bool,
+    pub supports_fast: bool,
+}
+
+/// `download_piece`: the piece fetched from the tracker's first peer.
+#[derive(Serialize)]
+pub struct DownloadedPiece {
+    pub piece: usize,
+    pub peer: SocketAddr,
+    pub length: usize,
+    /// SHA-1 of the data received, in hex.
+    pub hash: String,
+    pub verified: bool,
+    /// Where the piece was written; null if it failed verification.
+    pub output: Option<PathBuf>,
+}
+
+/// `download`: printed once every piece is verified.
+#[derive(Serialize)]
+pub struct Download {
+    pub torrent: PathBuf,
+    pub output: PathBuf,
+    pub info_hash: String,
+    pub length: usize,
+    /// Bytes fetched from peers, leaving out what was already on disk.
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub elapsed_secs: f64,
+    /// Average over `elapsed_secs`, in bytes per second.
+    pub download_rate: f64,
+    pub pieces: Vec<Piece>,
+}
+
+#[derive(Serialize)]
+pub struct Piece {
+    pub index: usize,
+    pub hash: String,
+    /// `resumed` if it was on disk already, else `downloaded`.
+    pub source: PieceSource,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PieceSource {
+    Resumed,
+    Downloaded,
+}
+
+/// `seed`, and `download --seed` after the download: what we serve.
+#[derive(Serialize)]
+pub struct Seeding {
+    pub seeding: String,
+    pub info_hash: String,
+    pub port: u16,
+    pub verified: usize,
+    pub pieces: usize,
+}
+
+/// `tracker`: where it listens.
+#[derive(Serialize)]
+pub struct Tracking {
+    pub announce: String,
+    pub scrape: String,
+    /// Null unless `--udp` is given.
+    pub udp: Option<String>,
+}
+
+/// `daemon`: where it listens.
+#[derive(Serialize)]
+pub struct Daemon {
+    pub rpc: String,
+    /// Null unless `--transmission` is given.
+    pub transmission: Option<String>,
+    /// Null unless `--metrics` is given.
+    pub metrics: Option<String>,
+    pub port: u16,
+}
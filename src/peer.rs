@@ -0,0 +1,949 @@
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc,
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{
+    extension::ExtensionRegistry,
+    log::Span,
+    ratelimit::{Direction, Limits, Throttle},
+};
+
+pub const PROTOCOL: &str = "BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 68;
+/// The block size every client accepts requests for.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+// Reserved bit 20 from the right (byte 5, 0x10) advertises BEP 10 support.
+const EXTENSION_BYTE: usize = 5;
+const EXTENSION_BIT: u8 = 0x10;
+// The last reserved byte's 0x04 bit advertises the fast extension (BEP 6).
+const FAST_BYTE: usize = 7;
+const FAST_BIT: u8 = 0x04;
+// Its 0x10 bit advertises v2 torrents and their hash messages (BEP 52).
+const V2_BIT: u8 = 0x10;
+
+/// Clients by the two letters their peer ids start with, in the
+/// `-TR3000-` style most of them use.
+const CLIENTS: [(&str, &str); 11] = [
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("KT", "KTorrent"),
+    ("LT", "libTorrent"),
+    ("TR", "Transmission"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("lt", "libtorrent"),
+    ("qB", "qBittorrent"),
+];
+
+/// We send a keep-alive after this long without sending anything else.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+/// How long a read blocks before the caller gets a chance to send
+/// keep-alives and check for dead peers.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Messages read ahead of the connection's owner before the reader waits.
+const MESSAGE_BUFFER: usize = 64;
+/// The longest message we accept. Blocks are 16 KiB, and this leaves room
+/// for the bitfield of a torrent with eight million pieces.
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+/// Suggested and allowed-fast pieces we keep per peer; BEP 6 peers grant
+/// about ten.
+const MAX_PIECE_HINTS: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub handshake: Duration,
+    /// A peer silent for this long is dropped. Peers send keep-alives every
+    /// two minutes, so this should be comfortably longer.
+    pub idle: Duration,
+    /// A peer that leaves our requests unanswered for this long is snubbing us.
+    pub snub: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(10),
+            idle: Duration::from_secs(180),
+            snub: Duration::from_secs(60),
+        }
+    }
+}
+
+pub struct Handshake {
+    pub reserved_bytes: [u8; 8],
+    pub sha1_infohash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    /// Our handshake, advertising v2 support for torrents with v2 data.
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20], v2: bool) -> Self {
+        let mut reserved_bytes = [0; 8];
+        reserved_bytes[EXTENSION_BYTE] |= EXTENSION_BIT;
+        reserved_bytes[FAST_BYTE] |= FAST_BIT;
+        if v2 {
+            reserved_bytes[FAST_BYTE] |= V2_BIT;
+        }
+        Handshake {
+            reserved_bytes,
+            sha1_infohash: info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved_bytes[EXTENSION_BYTE] & EXTENSION_BIT != 0
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.reserved_bytes[FAST_BYTE] & FAST_BIT != 0
+    }
+
+    /// The peer's client and version, like `Transmission 3.0`, if its
+    /// peer id says.
+    pub fn client(&self) -> Option<String> {
+        let id = &self.peer_id;
+        let version = id.get(3..7)?;
+        if id[0] != b'-' || id[7] != b'-' || !version.iter().all(u8::is_ascii_alphanumeric) {
+            return None;
+        }
+        let code = std::str::from_utf8(&id[1..3]).ok()?;
+        let name = CLIENTS.iter().find(|&&(known, _)| known == code);
+        let name = name.map_or(code, |&(_, name)| name);
+        // Versions are a character per part, with trailing zeros left out.
+        let parts = std::str::from_utf8(version).ok()?.trim_end_matches('0');
+        let version: Vec<String> = parts.chars().map(String::from).collect();
+        match version.len() {
+            0 => Some(format!("{name} 0")),
+            1 => Some(format!("{name} {}.0", version[0])),
+            _ => Some(format!("{name} {}", version.join("."))),
+        }
+    }
+
+    /// The peer id as text if it is, else in hex.
+    pub fn printable_id(&self) -> String {
+        match std::str::from_utf8(&self.peer_id) {
+            Ok(id) if id.chars().all(|c| c.is_ascii_graphic()) => id.to_string(),
+            _ => hex::encode(self.peer_id),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HANDSHAKE_LEN);
+        bytes.push(PROTOCOL.len() as u8);
+        bytes.extend(PROTOCOL.as_bytes());
+        bytes.extend(&self.reserved_bytes);
+        bytes.extend(&self.sha1_infohash);
+        bytes.extend(&self.peer_id);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; HANDSHAKE_LEN]) -> Result<Self> {
+        let length_p_string = bytes[0] as usize;
+        if length_p_string != PROTOCOL.len() || &bytes[1..20] != PROTOCOL.as_bytes() {
+            bail!("peer does not speak the BitTorrent protocol");
+        }
+        Ok(Handshake {
+            reserved_bytes: bytes[20..28].try_into()?,
+            sha1_infohash: bytes[28..48].try_into()?,
+            peer_id: bytes[48..68].try_into()?,
+        })
+    }
+}
+
+/// Which hashes of a v2 file's merkle tree a hash request is for: `length`
+/// hashes of layer `base_layer` (0 being the blocks) from `index`, with the
+/// uncles proving them for `proof_layers` layers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    const LEN: usize = 48;
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = self.pieces_root.to_vec();
+        for field in [self.base_layer, self.index, self.length, self.proof_layers] {
+            bytes.extend(field.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(payload: &[u8]) -> Result<Self> {
+        let pieces_root = payload
+            .get(..32)
+            .ok_or_else(|| anyhow!("message too short"))?;
+        Ok(HashRequest {
+            pieces_root: pieces_root.try_into()?,
+            base_layer: read_u32(payload, 32)?,
+            index: read_u32(payload, 36)?,
+            length: read_u32(payload, 40)?,
+            proof_layers: read_u32(payload, 44)?,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    HashRequest(HashRequest),
+    /// The hashes asked for, then the uncles proving them.
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<[u8; 32]>,
+    },
+    HashReject(HashRequest),
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// Piece data carried by the message, as opposed to protocol overhead.
+    fn payload_len(&self) -> usize {
+        match self {
+            Message::Piece { block, .. } => block.len(),
+            _ => 0,
+        }
+    }
+
+    pub const EXTENDED: u8 = 20;
+
+    /// Encodes the message including its 4-byte length prefix.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            Message::KeepAlive => {}
+            Message::Choke => body.push(0),
+            Message::Unchoke => body.push(1),
+            Message::Interested => body.push(2),
+            Message::NotInterested => body.push(3),
+            Message::Have(index) => {
+                body.push(4);
+                body.extend(index.to_be_bytes());
+            }
+            Message::Bitfield(bits) => {
+                body.push(5);
+                body.extend(bits);
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                body.push(6);
+                body.extend(index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(length.to_be_bytes());
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                body.push(7);
+                body.extend(index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(block);
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                body.push(8);
+                body.extend(index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(length.to_be_bytes());
+            }
+            Message::SuggestPiece(index) => {
+                body.push(13);
+                body.extend(index.to_be_bytes());
+            }
+            Message::HaveAll => body.push(14),
+            Message::HaveNone => body.push(15),
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                body.push(16);
+                body.extend(index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(length.to_be_bytes());
+            }
+            Message::AllowedFast(index) => {
+                body.push(17);
+                body.extend(index.to_be_bytes());
+            }
+            Message::Extended { id, payload } => {
+                body.push(Self::EXTENDED);
+                body.push(*id);
+                body.extend(payload);
+            }
+            Message::HashRequest(request) => {
+                body.push(21);
+                body.extend(request.to_bytes());
+            }
+            Message::Hashes { request, hashes } => {
+                body.push(22);
+                body.extend(request.to_bytes());
+                body.extend(hashes.concat());
+            }
+            Message::HashReject(request) => {
+                body.push(23);
+                body.extend(request.to_bytes());
+            }
+            Message::Unknown { id, payload } => {
+                body.push(*id);
+                body.extend(payload);
+            }
+        }
+        let mut bytes = (body.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(body);
+        bytes
+    }
+
+    /// Decodes a message body, i.e. everything after the length prefix.
+    pub fn from_bytes(body: &[u8]) -> Result<Self> {
+        let Some((&id, payload)) = body.split_first() else {
+            return Ok(Message::KeepAlive);
+        };
+        let message = match id {
+            0 => Message::Choke,
+            1 => Message::Unchoke,
+            2 => Message::Interested,
+            3 => Message::NotInterested,
+            4 => Message::Have(read_u32(payload, 0)?),
+            5 => Message::Bitfield(payload.to_vec()),
+            6 | 8 | 16 => {
+                let (index, begin, length) = (
+                    read_u32(payload, 0)?,
+                    read_u32(payload, 4)?,
+                    read_u32(payload, 8)?,
+                );
+                match id {
+                    6 => Message::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    8 => Message::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => Message::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            7 => Message::Piece {
+                index: read_u32(payload, 0)?,
+                begin: read_u32(payload, 4)?,
+                block: payload[8..].to_vec(),
+            },
+            13 => Message::SuggestPiece(read_u32(payload, 0)?),
+            14 => Message::HaveAll,
+            15 => Message::HaveNone,
+            17 => Message::AllowedFast(read_u32(payload, 0)?),
+            Self::EXTENDED => {
+                let Some((&id, payload)) = payload.split_first() else {
+                    bail!("extended message without an extension id");
+                };
+                Message::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                }
+            }
+            21 => Message::HashRequest(HashRequest::from_bytes(payload)?),
+            22 => {
+                let hashes = payload.get(HashRequest::LEN..).unwrap_or_default();
+                if !hashes.len().is_multiple_of(32) {
+                    bail!("hashes are not 32 bytes each");
+                }
+                Message::Hashes {
+                    request: HashRequest::from_bytes(payload)?,
+                    hashes: hashes
+                        .chunks_exact(32)
+                        .map(|hash| hash.try_into().unwrap())
+                        .collect(),
+                }
+            }
+            23 => Message::HashReject(HashRequest::from_bytes(payload)?),
+            id => Message::Unknown {
+                id,
+                payload: payload.to_vec(),
+            },
+        };
+        Ok(message)
+    }
+}
+
+fn read_u32(payload: &[u8], offset: usize) -> Result<u32> {
+    let bytes = payload
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow!("message too short"))?;
+    Ok(u32::from_be_bytes(bytes.try_into()?))
+}
+
+pub struct PeerConnection {
+    writer: OwnedWriteHalf,
+    messages: mpsc::Receiver<Result<Message>>,
+    reader: JoinHandle<()>,
+    pub addr: SocketAddr,
+    pub handshake: Handshake,
+    pub extensions: ExtensionRegistry,
+    /// Both sides speak the fast extension.
+    pub fast: bool,
+    pub peer_choking: bool,
+    have_all: bool,
+    have: Vec<u8>,
+    /// The torrent's pieces, which the peer's messages must stay within;
+    /// until set, as many as a bitfield can hold.
+    num_pieces: usize,
+    /// Pieces we may request even while choked.
+    pub allowed_fast: HashSet<u32>,
+    pub suggested: Vec<u32>,
+    /// This peer's own rate limits, adjustable while connected.
+    pub limits: Arc<Limits>,
+    throttle: Arc<Mutex<Throttle>>,
+    pub timeouts: Timeouts,
+    last_sent: Instant,
+}
+
+impl PeerConnection {
+    /// Connects to `addr`, exchanges handshakes and, if both sides support
+    /// BEP 10, sends our extended handshake. `v2` says whether the torrent
+    /// has v2 data.
+    pub async fn connect(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        v2: bool,
+        extensions: ExtensionRegistry,
+        timeouts: Timeouts,
+    ) -> Result<Self> {
+        let mut stream = timeout(timeouts.connect, TcpStream::connect(addr))
+            .await
+            .map_err(|_| anyhow!("connecting to {addr} timed out"))?
+            .map_err(|e| anyhow!("connecting to {addr}: {e}"))?;
+        let hello = Handshake::new(info_hash, peer_id, v2).to_bytes();
+        timeout(timeouts.handshake, stream.write_all(&hello)).await??;
+
+        let handshake = Self::read_handshake(&mut stream, timeouts.handshake).await?;
+        if handshake.sha1_infohash != info_hash {
+            bail!("peer {addr} answered with a different info hash");
+        }
+        Self::establish(stream, handshake, extensions, timeouts).await
+    }
+
+    pub async fn read_handshake(stream: &mut TcpStream, limit: Duration) -> Result<Handshake> {
+        let mut response = [0; HANDSHAKE_LEN];
+        timeout(limit, stream.read_exact(&mut response))
+            .await
+            .map_err(|_| anyhow!("no handshake within {limit:?}"))??;
+        let handshake = Handshake::from_bytes(&response)?;
+        if let Some(span) = Span::current() {
+            span.record("id", handshake.printable_id());
+            if let Some(client) = handshake.client() {
+                span.record("client", client);
+            }
+        }
+        Ok(handshake)
+    }
+
+    /// Answers the handshake of a peer that connected to us, once the caller
+    /// has checked that we serve the torrent it asked for.
+    pub async fn accept(
+        mut stream: TcpStream,
+        handshake: Handshake,
+        peer_id: [u8; 20],
+        v2: bool,
+        extensions: ExtensionRegistry,
+        timeouts: Timeouts,
+    ) -> Result<Self> {
+        let hello = Handshake::new(handshake.sha1_infohash, peer_id, v2).to_bytes();
+        timeout(timeouts.handshake, stream.write_all(&hello)).await??;
+        Self::establish(stream, handshake, extensions, timeouts).await
+    }
+
+    async fn establish(
+        stream: TcpStream,
+        handshake: Handshake,
+        extensions: ExtensionRegistry,
+        timeouts: Timeouts,
+    ) -> Result<Self> {
+        let addr = stream.peer_addr()?;
+        let (read_half, writer) = stream.into_split();
+        let limits = Limits::unlimited();
+        let throttle = Arc::new(Mutex::new(Throttle::default().with(limits.clone())));
+
+        // Messages are read on their own task so that waiting for one never
+        // loses half a message, and so the read side can be throttled.
+        let (tx, messages) = mpsc::channel(MESSAGE_BUFFER);
+        let reader = tokio::spawn(read_messages(
+            read_half,
+            tx,
+            throttle.clone(),
+            timeouts.idle,
+        ));
+
+        let mut connection = PeerConnection {
+            writer,
+            messages,
+            reader,
+            addr,
+            fast: handshake.supports_fast(),
+            handshake,
+            extensions,
+            peer_choking: true,
+            have_all: false,
+            have: Vec::new(),
+            num_pieces: MAX_MESSAGE_LEN * 8,
+            allowed_fast: HashSet::new(),
+            suggested: Vec::new(),
+            throttle,
+            limits,
+            timeouts,
+            last_sent: Instant::now(),
+        };
+        if connection.handshake.supports_extensions() {
+            let payload = connection
+                .extensions
+                .local_handshake(connection.addr.ip())?;
+            connection
+                .send(&Message::Extended { id: 0, payload })
+                .await?;
+        }
+        Ok(connection)
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.have_all
+            || self
+                .have
+                .get(index / 8)
+                .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    /// Tells the connection how many pieces the torrent has, so that a
+    /// peer claiming pieces past them is dropped.
+    pub fn set_num_pieces(&mut self, num_pieces: usize) {
+        self.num_pieces = num_pieces;
+    }
+
+    /// Puts the connection under `throttle` (usually the session and
+    /// torrent limits) on top of the peer's own limits.
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        *self.throttle.lock().unwrap() = throttle.with(self.limits.clone());
+    }
+
+    pub async fn send(&mut self, message: &Message) -> Result<()> {
+        let bytes = message.to_bytes();
+        let payload = message.payload_len();
+        let throttle = self.throttle.lock().unwrap().clone();
+        throttle
+            .transfer(Direction::Upload, payload, bytes.len() - payload)
+            .await;
+        // A peer that stops reading altogether is as dead as a silent one.
+        timeout(self.timeouts.idle, self.writer.write_all(&bytes))
+            .await
+            .map_err(|_| anyhow!("peer stopped reading"))??;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Reads the next message, or `None` if nothing for the caller arrived
+    /// within about a second. Extension messages are dispatched to the
+    /// registered handlers and never returned; periodic messages are sent
+    /// before reading. A peer silent for the idle timeout is an error.
+    pub async fn poll(&mut self) -> Result<Option<Message>> {
+        self.recv_timeout(POLL_INTERVAL).await
+    }
+
+    /// Like `poll`, waiting at most `limit`; a zero limit only takes a
+    /// message that has already arrived.
+    pub async fn recv_timeout(&mut self, limit: Duration) -> Result<Option<Message>> {
+        self.tick().await?;
+        match timeout(limit, self.messages.recv()).await {
+            Ok(Some(message)) => self.process(message?).await,
+            Ok(None) => bail!("peer closed the connection"),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Sends whatever periodic messages the extensions have queued up, and
+    /// a keep-alive if we have been quiet for a while.
+    pub async fn tick(&mut self) -> Result<()> {
+        for message in self.extensions.tick() {
+            self.send(&message).await?;
+        }
+        if self.last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+            self.send(&Message::KeepAlive).await?;
+        }
+        Ok(())
+    }
+
+    /// Dispatches extension messages and updates our view of the peer;
+    /// everything but extension messages is handed back.
+    async fn process(&mut self, message: Message) -> Result<Option<Message>> {
+        if let Message::Extended { id, payload } = message {
+            for reply in self.extensions.handle(id, &payload)? {
+                self.send(&reply).await?;
+            }
+            return Ok(None);
+        }
+        self.update_state(&message)?;
+        Ok(Some(message))
+    }
+
+    // Tracks what the peer told us about its pieces and our choke status.
+    // Hints about pieces we don't have room for are ignored.
+    fn update_state(&mut self, message: &Message) -> Result<()> {
+        let in_range = |index: u32| (index as usize) < self.num_pieces;
+        match message {
+            Message::Choke => self.peer_choking = true,
+            Message::Unchoke => self.peer_choking = false,
+            Message::Bitfield(bits) => {
+                if bits.len() > self.num_pieces.div_ceil(8) {
+                    bail!("peer sent a bitfield of {} pieces", bits.len() * 8);
+                }
+                self.have = bits.clone();
+            }
+            Message::Have(index) => {
+                if !in_range(*index) {
+                    bail!("peer has piece {index} of {}", self.num_pieces);
+                }
+                let index = *index as usize;
+                if self.have.len() <= index / 8 {
+                    self.have.resize(index / 8 + 1, 0);
+                }
+                self.have[index / 8] |= 0x80 >> (index % 8);
+            }
+            Message::HaveAll if self.fast => self.have_all = true,
+            Message::HaveNone if self.fast => {
+                self.have_all = false;
+                self.have.clear();
+            }
+            Message::AllowedFast(index)
+                if self.fast && in_range(*index) && self.allowed_fast.len() < MAX_PIECE_HINTS =>
+            {
+                self.allowed_fast.insert(*index);
+            }
+            Message::SuggestPiece(index)
+                if self.fast && in_range(*index) && !self.suggested.contains(index) =>
+            {
+                // The latest suggestions are the ones worth following.
+                if self.suggested.len() >= MAX_PIECE_HINTS {
+                    self.suggested.remove(0);
+                }
+                self.suggested.push(*index);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PeerConnection {
+    // Dropping the write half shuts down our side of the socket.
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Feeds messages to the connection until the peer hangs up or has been
+/// silent for `idle`, then passes on the error.
+async fn read_messages(
+    mut stream: OwnedReadHalf,
+    messages: mpsc::Sender<Result<Message>>,
+    throttle: Arc<Mutex<Throttle>>,
+    idle: Duration,
+) {
+    loop {
+        let message = match timeout(idle, read_message(&mut stream)).await {
+            Ok(Ok((message, length))) => {
+                // Waiting here before reading on lets TCP push back on the sender.
+                let payload = message.payload_len();
+                let throttle = throttle.lock().unwrap().clone();
+                throttle
+                    .transfer(Direction::Download, payload, length - payload)
+                    .await;
+                Ok(message)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(anyhow!("peer has been silent for {idle:?}")),
+        };
+        let failed = message.is_err();
+        if messages.send(message).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Reads one message, returning it with its length on the wire.
+async fn read_message(stream: &mut OwnedReadHalf) -> Result<(Message, usize)> {
+    let mut length_prefix = [0u8; 4];
+    stream.read_exact(&mut length_prefix).await?;
+    let message_length = u32::from_be_bytes(length_prefix) as usize;
+    if message_length > MAX_MESSAGE_LEN {
+        bail!("peer sent a message of {message_length} bytes");
+    }
+
+    let mut body = vec![0u8; message_length];
+    stream.read_exact(&mut body).await?;
+    Ok((Message::from_bytes(&body)?, 4 + message_length))
+}
+
+/// The canonical allowed-fast set for a peer (BEP 6), derived from its /24
+/// network and the info hash so every client computes the same pieces.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8; 20], num_pieces: u32, k: usize) -> Vec<u32> {
+    let k = k.min(num_pieces as usize);
+    let mut set = Vec::with_capacity(k);
+    let mut x = (u32::from(ip) & 0xFFFF_FF00).to_be_bytes().to_vec();
+    x.extend(info_hash);
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() == k {
+                break;
+            }
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{connect, send_all, Rng};
+
+    #[test]
+    fn names_clients() {
+        let client = |id: &[u8; 20]| Handshake::new([0; 20], *id, false).client();
+        let transmission = client(b"-TR3000-abcdefghijkl");
+        assert_eq!(transmission.as_deref(), Some("Transmission 3.0"));
+        let qbittorrent = client(b"-qB4510-abcdefghijkl");
+        assert_eq!(qbittorrent.as_deref(), Some("qBittorrent 4.5.1"));
+        assert_eq!(client(b"-XX1200-abcdefghijkl").as_deref(), Some("XX 1.2"));
+        assert_eq!(client(b"00112233445566778899"), None);
+    }
+
+    fn arbitrary(rng: &mut Rng) -> Message {
+        let (index, begin, length) = (rng.next_u32(), rng.next_u32(), rng.next_u32());
+        let payload = |rng: &mut Rng| {
+            let len = rng.below(40);
+            rng.bytes(len)
+        };
+        let request = HashRequest {
+            pieces_root: rng.bytes(32).try_into().unwrap(),
+            base_layer: rng.next_u32(),
+            index,
+            length,
+            proof_layers: rng.next_u32(),
+        };
+        match rng.below(22) {
+            0 => Message::KeepAlive,
+            1 => Message::Choke,
+            2 => Message::Unchoke,
+            3 => Message::Interested,
+            4 => Message::NotInterested,
+            5 => Message::Have(index),
+            6 => Message::Bitfield(payload(rng)),
+            7 => Message::Request {
+                index,
+                begin,
+                length,
+            },
+            8 => Message::Piece {
+                index,
+                begin,
+                block: payload(rng),
+            },
+            9 => Message::Cancel {
+                index,
+                begin,
+                length,
+            },
+            10 => Message::SuggestPiece(index),
+            11 => Message::HaveAll,
+            12 => Message::HaveNone,
+            13 => Message::RejectRequest {
+                index,
+                begin,
+                length,
+            },
+            14 => Message::AllowedFast(index),
+            15 | 16 => Message::Extended {
+                id: rng.next_u32() as u8,
+                payload: payload(rng),
+            },
+            17 => Message::HashRequest(request),
+            18 => Message::Hashes {
+                request,
+                hashes: (0..rng.below(4))
+                    .map(|_| rng.bytes(32).try_into().unwrap())
+                    .collect(),
+            },
+            19 => Message::HashReject(request),
+            // Ids no message of ours has.
+            _ => Message::Unknown {
+                id: [9, 10, 11, 12, 18, 19, 24, 255][rng.below(8)],
+                payload: payload(rng),
+            },
+        }
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let mut rng = Rng::new(0x5eed);
+        for _ in 0..2000 {
+            let message = arbitrary(&mut rng);
+            let bytes = message.to_bytes();
+            let length = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+            assert_eq!(length, bytes.len() - 4);
+            assert_eq!(Message::from_bytes(&bytes[4..]).unwrap(), message);
+            // Cut short, it is an error or a shorter message, never a panic.
+            for end in 4..bytes.len() {
+                let _ = Message::from_bytes(&bytes[4..end]);
+            }
+        }
+
+        let handshake = Handshake::new(
+            rng.bytes(20).try_into().unwrap(),
+            *b"-TR3000-abcdefghijkl",
+            true,
+        );
+        let bytes: [u8; HANDSHAKE_LEN] = handshake.to_bytes().try_into().unwrap();
+        let decoded = Handshake::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn rejects_short_messages() {
+        for body in [
+            &[4][..],
+            &[6, 0, 0, 0, 1],
+            &[7, 0, 0, 0, 1, 0, 0],
+            &[17, 0],
+            &[20],
+            &[21; 48],
+            &[22; 80],
+        ] {
+            assert!(Message::from_bytes(body).is_err(), "{body:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_peers_claiming_pieces_past_the_end() {
+        let wait = Duration::from_secs(5);
+        for bad in [
+            Message::Have(10),
+            Message::Have(u32::MAX),
+            Message::Bitfield(vec![0xff; 3]),
+        ] {
+            let (mut connection, mut peer) = connect(10).await;
+            send_all(&mut peer, &[Message::Have(9), bad]).await;
+            let first = connection.recv_timeout(wait).await.unwrap();
+            assert_eq!(first, Some(Message::Have(9)));
+            assert!(connection.has_piece(9));
+            assert!(connection.recv_timeout(wait).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_piece_hints_in_bounds() {
+        let (mut connection, mut peer) = connect(100).await;
+        let mut messages = vec![
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::AllowedFast(100),
+            Message::SuggestPiece(100),
+        ];
+        for _ in 0..2 {
+            messages.extend((0..100).map(Message::AllowedFast));
+            messages.extend((0..100).map(Message::SuggestPiece));
+        }
+        send_all(&mut peer, &messages).await;
+        for _ in &messages {
+            let message = connection.recv_timeout(Duration::from_secs(5)).await;
+            assert!(message.unwrap().is_some());
+        }
+
+        assert!(!connection.has_piece(0));
+        assert_eq!(connection.allowed_fast.len(), MAX_PIECE_HINTS);
+        assert!(connection.allowed_fast.iter().all(|&index| index < 100));
+        let suggested: HashSet<u32> = connection.suggested.iter().copied().collect();
+        assert_eq!(connection.suggested.len(), MAX_PIECE_HINTS);
+        assert_eq!(suggested.len(), MAX_PIECE_HINTS);
+        assert!(suggested.iter().all(|&index| index < 100));
+    }
+}
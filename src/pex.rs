@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    bencode,
+    extension::{ExtendedHandshake, ExtensionHandler},
+    pool::{PeerSource, SharedPeerPool},
+    tracker::{compact, parse_ips, parse_ips6},
+};
+
+pub const NAME: &str = "ut_pex";
+
+/// BEP 11 asks for at most one message per minute in each direction.
+const SEND_INTERVAL: Duration = Duration::from_secs(60);
+/// Some slack for clocks and scheduling before we start ignoring a chatty peer.
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+/// Peers per `added`/`dropped` list, matching what other clients accept.
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+#[derive(Default, Deserialize, Serialize)]
+struct PexMessage {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    added_f: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    added6_f: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+/// ut_pex for a single connection: tells the peer which peers we are
+/// connected to and feeds the peers it tells us about into the pool.
+pub struct PexHandler {
+    pool: SharedPeerPool,
+    peer: SocketAddr,
+    enabled: bool,
+    advertised: HashMap<SocketAddr, u8>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexHandler {
+    pub fn new(pool: SharedPeerPool, peer: SocketAddr) -> Self {
+        PexHandler {
+            pool,
+            peer,
+            enabled: false,
+            advertised: HashMap::new(),
+            last_sent: None,
+            last_received: None,
+        }
+    }
+}
+
+impl ExtensionHandler for PexHandler {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake) {
+        self.enabled = handshake.m.get(NAME).is_some_and(|&id| id != 0);
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        let now = Instant::now();
+        if self
+            .last_received
+            .is_some_and(|last| now.duration_since(last) < MIN_RECEIVE_INTERVAL)
+        {
+            return Ok(None);
+        }
+        self.last_received = Some(now);
+
+        let message: PexMessage = bencode::from_bytes(payload)?;
+        let peers = parse_ips(&message.added)
+            .into_iter()
+            .chain(parse_ips6(&message.added6))
+            .take(MAX_PEERS_PER_MESSAGE);
+        self.pool.lock().unwrap().add(peers, PeerSource::Pex);
+        Ok(None)
+    }
+
+    fn on_tick(&mut self) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        if !self.enabled
+            || self
+                .last_sent
+                .is_some_and(|last| now.duration_since(last) < SEND_INTERVAL)
+        {
+            return None;
+        }
+
+        let connected = self.pool.lock().unwrap().connected_peers().clone();
+        let added: Vec<(SocketAddr, u8)> = connected
+            .iter()
+            .filter(|(peer, _)| **peer != self.peer && !self.advertised.contains_key(*peer))
+            .map(|(&peer, &flags)| (peer, flags))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .advertised
+            .keys()
+            .filter(|peer| !connected.contains_key(*peer))
+            .copied()
+            .take(MAX_PEERS_PER_MESSAGE)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        let mut message = PexMessage::default();
+        for &(peer, flags) in &added {
+            match peer {
+                SocketAddr::V4(_) => {
+                    message.added.extend(compact(peer));
+                    message.added_f.push(flags);
+                }
+                SocketAddr::V6(_) => {
+                    message.added6.extend(compact(peer));
+                    message.added6_f.push(flags);
+                }
+            }
+            self.advertised.insert(peer, flags);
+        }
+        for peer in dropped {
+            match peer {
+                SocketAddr::V4(_) => message.dropped.extend(compact(peer)),
+                SocketAddr::V6(_) => message.dropped6.extend(compact(peer)),
+            }
+            self.advertised.remove(&peer);
+        }
+
+        self.last_sent = Some(now);
+        serde_bencode::to_bytes(&message).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::PeerPool;
+
+    fn peer(n: usize) -> SocketAddr {
+        SocketAddr::from(([10, 0, (n >> 8) as u8, n as u8], 6881))
+    }
+
+    fn handler() -> PexHandler {
+        let mut handler = PexHandler::new(PeerPool::new().shared(), peer(0));
+        let mut handshake = ExtendedHandshake::default();
+        handshake.m.insert(NAME.to_string(), 1);
+        handler.on_handshake(&handshake);
+        handler
+    }
+
+    fn candidates(handler: &PexHandler) -> Vec<SocketAddr> {
+        let mut pool = handler.pool.lock().unwrap();
+        std::iter::from_fn(|| pool.next_candidate()).collect()
+    }
+
+    #[test]
+    fn advertises_connected_peers() {
+        let mut handler = handler();
+        let v6 = SocketAddr::from(([0xfe80, 0, 0, 0, 0, 0, 0, 1], 7000));
+        {
+            let mut pool = handler.pool.lock().unwrap();
+            // The peer we are talking to isn't news to it.
+            pool.connected(peer(0), 0);
+            pool.connected(peer(1), FLAG_REACHABLE);
+            pool.connected(v6, 0);
+        }
+        let sent: PexMessage = bencode::from_bytes(&handler.on_tick().unwrap()).unwrap();
+        assert_eq!(parse_ips(&sent.added), [peer(1)]);
+        assert_eq!(sent.added_f.as_slice(), [FLAG_REACHABLE]);
+        assert_eq!(parse_ips6(&sent.added6), [v6]);
+        assert_eq!(sent.added6_f.as_slice(), [0]);
+        // Once a minute at most.
+        handler.pool.lock().unwrap().disconnected(peer(1), false);
+        assert_eq!(handler.on_tick(), None);
+
+        handler.last_sent = None;
+        let sent: PexMessage = bencode::from_bytes(&handler.on_tick().unwrap()).unwrap();
+        assert!(sent.added.is_empty() && sent.added6.is_empty());
+        assert_eq!(parse_ips(&sent.dropped), [peer(1)]);
+        handler.last_sent = None;
+        assert_eq!(handler.on_tick(), None);
+    }
+
+    #[test]
+    fn takes_a_limited_number_of_peers() {
+        let mut handler = handler();
+        let mut message = PexMessage::default();
+        for n in 1..=60 {
+            message.added.extend(compact(peer(n)));
+        }
+        let payload = serde_bencode::to_bytes(&message).unwrap();
+        assert_eq!(handler.on_message(&payload).unwrap(), None);
+        let expected: Vec<_> = (1..=MAX_PEERS_PER_MESSAGE).map(peer).collect();
+        assert_eq!(candidates(&handler), expected);
+
+        // Another message this soon is ignored, however it looks.
+        let mut message = PexMessage::default();
+        message.added.extend(compact(peer(100)));
+        let payload = serde_bencode::to_bytes(&message).unwrap();
+        handler.on_message(&payload).unwrap();
+        assert_eq!(handler.on_message(b"garbage").unwrap(), None);
+        assert_eq!(candidates(&handler), []);
+
+        handler.last_received = Some(Instant::now() - MIN_RECEIVE_INTERVAL);
+        handler.on_message(&payload).unwrap();
+        assert_eq!(candidates(&handler), [peer(100)]);
+    }
+
+    #[test]
+    fn stays_quiet_for_peers_without_pex() {
+        let mut handler = PexHandler::new(PeerPool::new().shared(), peer(0));
+        handler.pool.lock().unwrap().connected(peer(1), 0);
+        assert_eq!(handler.on_tick(), None);
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Upper bound on peers we remember per torrent, regardless of source, not
+/// counting ones that failed.
+const MAX_KNOWN_PEERS: usize = 1000;
+/// How many failed peers we remember before forgetting them all, which
+/// lets them be retried if they turn up again.
+const MAX_FAILED_PEERS: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Pex,
+    Dht,
+    Lsd,
+}
+
+/// Candidate peers for one torrent, fed by the tracker and by peer sources
+/// such as PEX, plus the set of peers we are currently connected to.
+#[derive(Default)]
+pub struct PeerPool {
+    known: HashMap<SocketAddr, PeerSource>,
+    candidates: VecDeque<SocketAddr>,
+    connected: HashMap<SocketAddr, u8>,
+    failed: HashSet<SocketAddr>,
+}
+
+pub type SharedPeerPool = Arc<Mutex<PeerPool>>;
+
+impl PeerPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shared(self) -> SharedPeerPool {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Adds peers we have not seen before. Returns how many were new.
+    pub fn add(
+        &mut self,
+        peers: impl IntoIterator<Item = SocketAddr>,
+        source: PeerSource,
+    ) -> usize {
+        let mut added = 0;
+        for peer in peers {
+            if self.known.len() - self.failed.len() >= MAX_KNOWN_PEERS {
+                break;
+            }
+            if self.known.contains_key(&peer) {
+                continue;
+            }
+            self.known.insert(peer, source);
+            self.candidates.push_back(peer);
+            added += 1;
+        }
+        added
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty()
+    }
+
+    /// Next peer worth dialling, skipping connected and failed ones.
+    pub fn next_candidate(&mut self) -> Option<SocketAddr> {
+        while let Some(peer) = self.candidates.pop_front() {
+            if !self.connected.contains_key(&peer) && !self.failed.contains(&peer) {
+                return Some(peer);
+            }
+        }
+        None
+    }
+
+    /// Records a live connection along with its PEX flags.
+    pub fn connected(&mut self, peer: SocketAddr, flags: u8) {
+        self.connected.insert(peer, flags);
+    }
+
+    pub fn disconnected(&mut self, peer: SocketAddr, failed: bool) {
+        self.connected.remove(&peer);
+        // Only known peers, which are the ones we would dial again.
+        if failed && self.known.contains_key(&peer) {
+            if self.failed.len() >= MAX_FAILED_PEERS {
+                for peer in self.failed.drain() {
+                    self.known.remove(&peer);
+                }
+            }
+            self.failed.insert(peer);
+        }
+    }
+
+    pub fn connected_peers(&self) -> &HashMap<SocketAddr, u8> {
+        &self.connected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: usize) -> SocketAddr {
+        SocketAddr::from(([10, 0, (n >> 8) as u8, n as u8], 6881))
+    }
+
+    #[test]
+    fn failed_peers_make_room() {
+        let mut pool = PeerPool::new();
+        let first = (0..MAX_KNOWN_PEERS).map(peer);
+        assert_eq!(pool.add(first, PeerSource::Pex), MAX_KNOWN_PEERS);
+        assert_eq!(pool.add([peer(2000)], PeerSource::Pex), 0);
+
+        for n in 0..10 {
+            let candidate = pool.next_candidate().unwrap();
+            assert_eq!(candidate, peer(n));
+            pool.connected(candidate, 0);
+            pool.disconnected(candidate, true);
+        }
+        let more = (2000..2020).map(peer);
+        assert_eq!(pool.add(more, PeerSource::Tracker), 10);
+        // Failed peers are known, so not added again while remembered.
+        assert_eq!(pool.add([peer(0)], PeerSource::Tracker), 0);
+        assert_eq!(pool.next_candidate(), Some(peer(10)));
+    }
+
+    #[test]
+    fn forgets_failures_eventually() {
+        let mut pool = PeerPool::new();
+        for n in 0..=MAX_FAILED_PEERS {
+            pool.add([peer(n)], PeerSource::Pex);
+            pool.next_candidate();
+            pool.disconnected(peer(n), true);
+        }
+        assert_eq!(pool.failed.len(), 1);
+        assert_eq!(pool.add([peer(0)], PeerSource::Pex), 1);
+        assert_eq!(pool.next_candidate(), Some(peer(0)));
+    }
+}
@@ -0,0 +1,301 @@
+//! How commands show what their torrents are doing, from the session's
+//! events: a view redrawn in place on a terminal, or else a line per event.
+//! Either way it goes to stderr, so stdout keeps only results.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{broadcast, oneshot},
+    task::JoinHandle,
+};
+
+use bittorrent_starter_rust::{
+    events::{EventKind, TorrentEvent},
+    log::{self, Level},
+    Torrent, TorrentState,
+};
+
+/// How often the live view is redrawn.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+const BAR_WIDTH: usize = 30;
+const NAME_WIDTH: usize = 40;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Percentage, rates, ETA and peers, redrawn in place.
+    View,
+    /// A line for each notable event, more with `--verbose`.
+    Lines,
+    /// Every event as a JSON object on its own line.
+    Json,
+}
+
+/// A torrent as the view knows it.
+struct Tracked {
+    info_hash: [u8; 20],
+    name: String,
+    path: PathBuf,
+    size: u64,
+    piece_length: u64,
+    verified: usize,
+    pieces: usize,
+    state: TorrentState,
+    download_rate: u64,
+    upload_rate: u64,
+    peers: usize,
+    tracker: Option<Result<usize, String>>,
+}
+
+#[derive(Default)]
+struct View {
+    torrents: Vec<Tracked>,
+    // Lines drawn last time, which the next frame draws over.
+    drawn: usize,
+}
+
+pub struct Progress {
+    view: Arc<Mutex<View>>,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Progress {
+    pub fn start(mut events: broadcast::Receiver<TorrentEvent>, mode: Mode) -> Self {
+        let view = Arc::new(Mutex::new(View::default()));
+        let (stop, mut stopped) = oneshot::channel();
+        let shown = view.clone();
+        let task = tokio::spawn(async move {
+            let mut redraw = tokio::time::interval(REDRAW_INTERVAL);
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(event) => shown.lock().unwrap().show(&event, mode),
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = redraw.tick(), if mode == Mode::View => shown.lock().unwrap().draw(),
+                    _ = &mut stopped => break,
+                }
+            }
+            // Whatever happened before we were stopped still gets shown.
+            let mut shown = shown.lock().unwrap();
+            while let Ok(event) = events.try_recv() {
+                shown.show(&event, mode);
+            }
+            if mode == Mode::View {
+                shown.draw();
+            }
+        });
+        Progress { view, stop, task }
+    }
+
+    /// Adds `torrent` to the view; the other modes show every torrent.
+    pub fn track(&self, torrent: &Torrent) {
+        let info = &torrent.metainfo().info;
+        let (verified, pieces) = torrent.progress();
+        let (download_rate, upload_rate) = torrent.rates();
+        self.view.lock().unwrap().torrents.push(Tracked {
+            info_hash: torrent.info_hash(),
+            name: info.name.clone(),
+            path: torrent.path().to_path_buf(),
+            size: info.size() as u64,
+            piece_length: info.piece_length as u64,
+            verified,
+            pieces,
+            state: torrent.state(),
+            download_rate,
+            upload_rate,
+            peers: torrent.peers(),
+            tracker: None,
+        });
+    }
+
+    /// Shows what is still pending and stops, leaving the last frame of the
+    /// view on screen.
+    pub async fn finish(self) {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+    }
+}
+
+impl View {
+    fn show(&mut self, event: &TorrentEvent, mode: Mode) {
+        match mode {
+            Mode::View => self.update(event),
+            Mode::Lines => print_line(&event.kind),
+            Mode::Json => {
+                let rates = matches!(event.kind, EventKind::Rates { .. });
+                if log::enabled(
+                    module_path!(),
+                    if rates { Level::Debug } else { Level::Info },
+                ) {
+                    eprintln!("{}", event.to_json());
+                }
+            }
+        }
+    }
+
+    fn update(&mut self, event: &TorrentEvent) {
+        let Some(torrent) = self
+            .torrents
+            .iter_mut()
+            .find(|torrent| torrent.info_hash == event.info_hash)
+        else {
+            return;
+        };
+        match &event.kind {
+            EventKind::StateChanged(state) => {
+                torrent.state = state.clone();
+                if !state.is_active() {
+                    (torrent.download_rate, torrent.upload_rate) = (0, 0);
+                }
+            }
+            EventKind::PieceCompleted {
+                verified, pieces, ..
+            } => (torrent.verified, torrent.pieces) = (*verified, *pieces),
+            EventKind::PeerConnected { .. } => torrent.peers += 1,
+            EventKind::PeerDisconnected { .. } => {
+                torrent.peers = torrent.peers.saturating_sub(1);
+            }
+            EventKind::Rates { download, upload } => {
+                (torrent.download_rate, torrent.upload_rate) = (*download, *upload);
+            }
+            EventKind::TrackerResponse { peers, .. } => torrent.tracker = Some(Ok(*peers)),
+            EventKind::TrackerFailed { error, .. } => torrent.tracker = Some(Err(error.clone())),
+            EventKind::PieceFailed { .. } => {}
+        }
+    }
+
+    fn draw(&mut self) {
+        let mut frame = String::new();
+        if self.drawn > 0 {
+            // Back to the top of the last frame.
+            let _ = write!(frame, "\x1b[{}A", self.drawn);
+        }
+        let lines: Vec<String> = self.torrents.iter().flat_map(Tracked::lines).collect();
+        for line in &lines {
+            let _ = writeln!(frame, "\r\x1b[2K{line}");
+        }
+        // Clear what is left of a taller last frame.
+        frame += "\x1b[J";
+        self.drawn = lines.len();
+        let mut stderr = io::stderr().lock();
+        let _ = stderr.write_all(frame.as_bytes());
+        let _ = stderr.flush();
+    }
+}
+
+impl Tracked {
+    fn lines(&self) -> [String; 2] {
+        let done = if self.pieces == self.verified {
+            self.size
+        } else {
+            (self.verified as u64 * self.piece_length).min(self.size)
+        };
+        let percent = if self.size > 0 {
+            done as f64 * 100.0 / self.size as f64
+        } else {
+            100.0
+        };
+        let eta = match self.size - done {
+            0 => "done".to_string(),
+            _ if self.download_rate == 0 => "-".to_string(),
+            left => duration(left / self.download_rate),
+        };
+        let tracker = match &self.tracker {
+            None => String::new(),
+            Some(Ok(peers)) => format!("  tracker: {peers} peers"),
+            Some(Err(_)) => "  tracker: failed".to_string(),
+        };
+        let mut name = self.name.clone();
+        if name.chars().count() > NAME_WIDTH {
+            name = name.chars().take(NAME_WIDTH - 1).collect::<String>() + "…";
+        }
+        let summary = format!(
+            "{name}  {:<11} {percent:5.1}%  ↓ {}/s  ↑ {}/s  ETA {eta}  {} peers{tracker}",
+            self.state.name(),
+            bytes(self.download_rate),
+            bytes(self.upload_rate),
+            self.peers,
+        );
+
+        let filled = (percent / 100.0 * BAR_WIDTH as f64) as usize;
+        let bar = "#".repeat(filled) + &".".repeat(BAR_WIDTH - filled);
+        let file = format!(
+            "  [{bar}] {}/{} pieces  {} of {}  {}",
+            self.verified,
+            self.pieces,
+            bytes(done),
+            bytes(self.size),
+            self.path.display()
+        );
+        [summary, file]
+    }
+}
+
+fn print_line(event: &EventKind) {
+    let (level, line) = match event {
+        EventKind::PieceCompleted { index, .. } => (
+            Level::Info,
+            format!("Piece {index} successfully downloaded and verified"),
+        ),
+        EventKind::PieceFailed { index, peer } => (
+            Level::Info,
+            format!("Piece {index} from {peer} failed verification"),
+        ),
+        EventKind::TrackerFailed { error, .. } => {
+            (Level::Info, format!("Tracker announce failed: {error}"))
+        }
+        EventKind::TrackerResponse { peers, .. } => {
+            (Level::Debug, format!("Tracker returned {peers} peers"))
+        }
+        EventKind::StateChanged(TorrentState::Failed(error)) => {
+            (Level::Info, format!("Download failed: {error}"))
+        }
+        EventKind::StateChanged(state) => (Level::Debug, format!("Torrent is {}", state.name())),
+        EventKind::PeerConnected { peer } => (Level::Debug, format!("Connected to {peer}")),
+        EventKind::PeerDisconnected {
+            peer,
+            error: Some(error),
+        } => (Level::Debug, format!("Peer {peer} failed: {error}")),
+        EventKind::PeerDisconnected { peer, error: None } => {
+            (Level::Debug, format!("Disconnected from {peer}"))
+        }
+        // Too frequent for a log.
+        EventKind::Rates { .. } => return,
+    };
+    if log::enabled(module_path!(), level) {
+        eprintln!("{line}");
+    }
+}
+
+// Like 1.5 MiB.
+fn bytes(count: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if count < 1024 {
+        return format!("{count} B");
+    }
+    let mut value = count as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+// Like 1:02:03, or 2:03 under an hour.
+fn duration(secs: u64) -> String {
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{secs:02}")
+    } else {
+        format!("{minutes}:{secs:02}")
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// A token bucket refilled at `rate` bytes per second, holding at most one
+/// second's worth. A rate of 0 means unlimited.
+pub struct TokenBucket {
+    rate: AtomicU64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: AtomicU64::new(rate),
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Applies from the next transfer on.
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Takes `bytes` tokens, going into debt if there are not enough, and
+    /// returns how long the caller has to wait for the debt to be repaid.
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let rate = self.rate();
+        let mut state = self.state.lock().unwrap();
+        if rate == 0 {
+            state.updated = now;
+            return Duration::ZERO;
+        }
+        let elapsed = now.saturating_duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate as f64).min(rate as f64);
+        state.updated = now;
+        state.tokens -= bytes as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate as f64)
+        }
+    }
+}
+
+/// Upload and download limits at one level: the whole session, a torrent
+/// or a single peer.
+pub struct Limits {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl Limits {
+    /// Rates are in bytes per second; 0 leaves that direction unlimited.
+    pub fn new(upload: u64, download: u64) -> Arc<Self> {
+        Arc::new(Limits {
+            upload: TokenBucket::new(upload),
+            download: TokenBucket::new(download),
+        })
+    }
+
+    pub fn unlimited() -> Arc<Self> {
+        Self::new(0, 0)
+    }
+
+    pub fn bucket(&self, direction: Direction) -> &TokenBucket {
+        match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        }
+    }
+}
+
+/// Session-wide limits, the limits each new peer starts with, and whether
+/// protocol overhead counts against the limits as well as piece payload.
+pub struct RateLimits {
+    pub global: Arc<Limits>,
+    pub per_peer: Arc<Limits>,
+    count_overhead: Arc<AtomicBool>,
+}
+
+impl RateLimits {
+    pub fn new(global: Arc<Limits>, per_peer: Arc<Limits>, count_overhead: bool) -> Arc<Self> {
+        Arc::new(RateLimits {
+            global,
+            per_peer,
+            count_overhead: Arc::new(AtomicBool::new(count_overhead)),
+        })
+    }
+
+    pub fn unlimited() -> Arc<Self> {
+        Self::new(Limits::unlimited(), Limits::unlimited(), false)
+    }
+
+    pub fn set_count_overhead(&self, count_overhead: bool) {
+        self.count_overhead.store(count_overhead, Ordering::Relaxed);
+    }
+
+    /// The throttle for a new connection: the global limits plus the
+    /// torrent's. `peer` gets the default per-peer rates; the connection
+    /// adds it itself.
+    pub fn throttle_for(&self, torrent: &Arc<Limits>, peer: &Limits) -> Throttle {
+        for direction in [Direction::Upload, Direction::Download] {
+            let rate = self.per_peer.bucket(direction).rate();
+            peer.bucket(direction).set_rate(rate);
+        }
+        Throttle {
+            levels: vec![self.global.clone(), torrent.clone()],
+            count_overhead: self.count_overhead.clone(),
+        }
+    }
+}
+
+/// Every limit a connection is subject to. Transfers wait until all of
+/// them have room, so the strictest one wins.
+#[derive(Clone, Default)]
+pub struct Throttle {
+    levels: Vec<Arc<Limits>>,
+    count_overhead: Arc<AtomicBool>,
+}
+
+impl Throttle {
+    pub fn with(mut self, limits: Arc<Limits>) -> Self {
+        self.levels.push(limits);
+        self
+    }
+
+    /// Accounts for a transfer of `payload` piece bytes plus `overhead`
+    /// bytes of framing and other messages, sleeping as long as it takes
+    /// to stay within the limits.
+    pub async fn transfer(&self, direction: Direction, payload: usize, overhead: usize) {
+        let mut bytes = payload as u64;
+        if self.count_overhead.load(Ordering::Relaxed) {
+            bytes += overhead as u64;
+        }
+        if bytes == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let wait = self
+            .levels
+            .iter()
+            .map(|limits| limits.bucket(direction).reserve(bytes, now))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_then_paces_at_the_rate() {
+        let bucket = TokenBucket::new(1000);
+        let start = Instant::now();
+        assert_eq!(bucket.reserve(1000, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, start), Duration::from_millis(500));
+        // Half a second later the debt is repaid, but nothing more.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.reserve(250, later), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn rate_changes_apply_immediately() {
+        let bucket = TokenBucket::new(0);
+        let start = Instant::now();
+        assert_eq!(bucket.reserve(1 << 30, start), Duration::ZERO);
+        bucket.set_rate(100);
+        assert_eq!(bucket.reserve(200, start), Duration::from_secs(2));
+    }
+}
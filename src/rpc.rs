@@ -0,0 +1,471 @@
+//! The daemon's control API: JSON-RPC 2.0 over HTTP POSTs to `/rpc`.
+//!
+//! Calls must be sent as `application/json` and carry the server's session
+//! id in `X-Session-Id`; a call without it gets a 409 carrying the current
+//! one. Neither is something a web page can send across origins without
+//! the server's consent, so pages the user visits cannot drive the daemon.
+//!
+//! Torrents are named by their hex info hash. Methods:
+//!
+//! - `add` `{torrent | magnet, path?, paused?}`: adds a .torrent file from
+//!   the daemon's filesystem or a magnet link. `path` is where the data
+//!   goes, by default the download directory. Magnet links are resolved in
+//!   the background and show up in `list` as `resolving` meanwhile.
+//! - `list`: every torrent, in queue order.
+//! - `pause`, `resume`, `stop` `{info_hash}`
+//! - `move` `{info_hash, to}`: `to` is `top`, `up`, `down` or `bottom`.
+//! - `limits` `{info_hash?, upload?, download?, peer_upload?,
+//!   peer_download?}`: sets the given rates, in KiB/s with 0 meaning
+//!   unlimited, and returns the ones now set. Without `info_hash` they are
+//!   the session's. Per-peer rates apply to peers connecting afterwards;
+//!   peers already connected keep the rates they started with.
+//! - `remove` `{info_hash, delete_data?}`
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+
+use crate::{
+    dht::random_id,
+    http::{self, Request, Response},
+    log::{error, info},
+    magnet::Magnet,
+    ratelimit::{Direction, Limits},
+    session::{QueueMove, Session, Torrent, TorrentState},
+    TorrentFile,
+};
+
+/// The port the daemon listens for RPC on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 6800;
+
+const SESSION_ID_HEADER: &str = "X-Session-Id";
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Anything that went wrong carrying out a valid call.
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        RpcError {
+            code: SERVER_ERROR,
+            message: e.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Call {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+#[derive(Deserialize)]
+struct AddParams {
+    torrent: Option<PathBuf>,
+    magnet: Option<String>,
+    path: Option<PathBuf>,
+    #[serde(default)]
+    paused: bool,
+}
+
+#[derive(Deserialize)]
+struct TorrentParams {
+    info_hash: String,
+}
+
+#[derive(Deserialize)]
+struct MoveParams {
+    info_hash: String,
+    to: String,
+}
+
+#[derive(Deserialize)]
+struct LimitsParams {
+    info_hash: Option<String>,
+    upload: Option<u64>,
+    download: Option<u64>,
+    peer_upload: Option<u64>,
+    peer_download: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RemoveParams {
+    info_hash: String,
+    #[serde(default)]
+    delete_data: bool,
+}
+
+/// Answers RPC calls against a session.
+pub struct RpcServer {
+    session: Arc<Session>,
+    session_id: String,
+    download_dir: PathBuf,
+}
+
+impl RpcServer {
+    /// Torrents added without a path get their data in `download_dir`.
+    pub fn new(session: Arc<Session>, download_dir: PathBuf) -> Arc<Self> {
+        Arc::new(RpcServer {
+            session,
+            session_id: hex::encode(random_id()),
+            download_dir,
+        })
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        http::serve(listener, move |request| {
+            let rpc = self.clone();
+            async move { rpc.answer(request).await }
+        })
+        .await
+    }
+
+    async fn answer(&self, request: Request) -> Response {
+        if request.path != "/rpc" {
+            return Response::text(404, "not found\n");
+        }
+        if request.method != "POST" {
+            return Response::text(405, "use POST\n").with_header("Allow", "POST");
+        }
+        let content_type = request.header("Content-Type").unwrap_or_default();
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if !media_type.eq_ignore_ascii_case("application/json") {
+            return Response::text(415, "use Content-Type: application/json\n");
+        }
+        if request.header(SESSION_ID_HEADER) != Some(&self.session_id) {
+            let message = format!("{SESSION_ID_HEADER}: {}\n", self.session_id);
+            return Response::text(409, message).with_header(SESSION_ID_HEADER, &self.session_id);
+        }
+        let (id, result) = match serde_json::from_slice::<Value>(&request.body) {
+            Err(e) => (Value::Null, Err(error(PARSE_ERROR, e))),
+            Ok(call) => match serde_json::from_value::<Call>(call) {
+                Err(e) => (Value::Null, Err(error(INVALID_REQUEST, e))),
+                Ok(call) if call.jsonrpc != "2.0" => (
+                    call.id,
+                    Err(error(INVALID_REQUEST, "expected JSON-RPC 2.0")),
+                ),
+                Ok(call) => (call.id, self.call(&call.method, call.params).await),
+            },
+        };
+        let reply = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": e.code, "message": e.message },
+            }),
+        };
+        Response::json(200, &reply)
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "add" => self.add(parse(params)?).await,
+            "list" => Ok(self.list()),
+            "pause" | "resume" | "stop" => {
+                let params: TorrentParams = parse(params)?;
+                let torrent = self.torrent(&params.info_hash)?;
+                match method {
+                    "pause" => torrent.pause(),
+                    "resume" => torrent.start(),
+                    _ => torrent.stop(),
+                }
+                Ok(status(&torrent, None))
+            }
+            "move" => {
+                let params: MoveParams = parse(params)?;
+                let to = match params.to.as_str() {
+                    "top" => QueueMove::Top,
+                    "up" => QueueMove::Up,
+                    "down" => QueueMove::Down,
+                    "bottom" => QueueMove::Bottom,
+                    to => return Err(error(INVALID_PARAMS, format!("cannot move {to}"))),
+                };
+                let info_hash = parse_info_hash(&params.info_hash)?;
+                self.session.move_in_queue(&info_hash, to)?;
+                Ok(self.list())
+            }
+            "limits" => self.limits(parse(params)?),
+            "remove" => {
+                let params: RemoveParams = parse(params)?;
+                let info_hash = parse_info_hash(&params.info_hash)?;
+                self.session.remove(&info_hash, params.delete_data)?;
+                Ok(Value::Null)
+            }
+            method => Err(error(METHOD_NOT_FOUND, format!("no method {method}"))),
+        }
+    }
+
+    async fn add(&self, params: AddParams) -> Result<Value, RpcError> {
+        let session = &self.session;
+        match (params.torrent, params.magnet) {
+            (Some(path), None) => {
+                let metainfo = TorrentFile::from_file(&path)
+                    .map_err(|e| anyhow!("cannot read {}: {e}", path.display()))?;
+                let torrent = match params.path {
+                    Some(data) => session.add_torrent(metainfo, data).await?,
+                    None => session.add_torrent_in(metainfo, &self.download_dir).await?,
+                };
+                if params.paused {
+                    torrent.pause();
+                }
+                info!("Added {}", torrent.metainfo().info.name);
+                Ok(status(&torrent, None))
+            }
+            (None, Some(link)) => {
+                let magnet = Magnet::parse(&link).map_err(|e| error(INVALID_PARAMS, e))?;
+                let info_hash = hex::encode(magnet.info_hash);
+                // Resolving happens in the background, so refuse what is
+                // bound to fail while we can still say so.
+                if session.torrent(&magnet.info_hash).is_some() {
+                    return Err(anyhow!("torrent {info_hash} was already added").into());
+                }
+                let reply = json!({
+                    "info_hash": info_hash,
+                    "name": magnet.name,
+                    "state": "resolving",
+                });
+                let dir = params.path.unwrap_or_else(|| self.download_dir.clone());
+                let (session, paused) = (session.clone(), params.paused);
+                tokio::spawn(async move {
+                    match session.add_magnet(magnet, dir).await {
+                        Ok(torrent) if paused => torrent.pause(),
+                        Ok(_) => {}
+                        Err(e) => error!("Could not add magnet link: {e}"),
+                    }
+                });
+                Ok(reply)
+            }
+            _ => Err(error(INVALID_PARAMS, "give either torrent or magnet")),
+        }
+    }
+
+    fn list(&self) -> Value {
+        let torrents = self.session.torrents();
+        let listed = torrents
+            .iter()
+            .enumerate()
+            .map(|(position, torrent)| status(torrent, Some(position)));
+        let resolving = self.session.resolving().into_iter().map(|magnet| {
+            json!({
+                "info_hash": hex::encode(magnet.info_hash),
+                "name": magnet.name,
+                "state": "resolving",
+            })
+        });
+        Value::Array(listed.chain(resolving).collect())
+    }
+
+    fn limits(&self, params: LimitsParams) -> Result<Value, RpcError> {
+        let set = |limits: &Limits, direction, kib: Option<u64>| {
+            if let Some(kib) = kib {
+                limits.bucket(direction).set_rate(kib.saturating_mul(1024));
+            }
+            limits.bucket(direction).rate() / 1024
+        };
+        if let Some(info_hash) = params.info_hash {
+            if params.peer_upload.is_some() || params.peer_download.is_some() {
+                let message = "per-peer limits are set for the whole session";
+                return Err(error(INVALID_PARAMS, message));
+            }
+            let torrent = self.torrent(&info_hash)?;
+            let limits = torrent.limits();
+            return Ok(json!({
+                "upload": set(limits, Direction::Upload, params.upload),
+                "download": set(limits, Direction::Download, params.download),
+            }));
+        }
+        let limits = self.session.limits();
+        let (global, per_peer) = (&limits.global, &limits.per_peer);
+        Ok(json!({
+            "upload": set(global, Direction::Upload, params.upload),
+            "download": set(global, Direction::Download, params.download),
+            "peer_upload": set(per_peer, Direction::Upload, params.peer_upload),
+            "peer_download": set(per_peer, Direction::Download, params.peer_download),
+        }))
+    }
+
+    fn torrent(&self, info_hash: &str) -> Result<Arc<Torrent>, RpcError> {
+        let info_hash = parse_info_hash(info_hash)?;
+        let torrent = self.session.torrent(&info_hash);
+        Ok(torrent.ok_or_else(|| anyhow!("no torrent {}", hex::encode(info_hash)))?)
+    }
+}
+
+fn error(code: i64, message: impl ToString) -> RpcError {
+    RpcError {
+        code,
+        message: message.to_string(),
+    }
+}
+
+fn parse<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    // Methods without parameters may be called without any.
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|e| error(INVALID_PARAMS, e))
+}
+
+fn parse_info_hash(info_hash: &str) -> Result<[u8; 20], RpcError> {
+    hex::decode(info_hash)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| error(INVALID_PARAMS, "info_hash must be 40 hex digits"))
+}
+
+fn status(torrent: &Torrent, position: Option<usize>) -> Value {
+    let (verified, pieces) = torrent.progress();
+    let (download_rate, upload_rate) = torrent.rates();
+    let state = torrent.state();
+    let mut status = json!({
+        "info_hash": hex::encode(torrent.info_hash()),
+        "name": torrent.metainfo().info.name,
+        "state": state.name(),
+        "size": torrent.metainfo().info.size(),
+        "pieces": pieces,
+        "verified": verified,
+        "uploaded": torrent.uploaded(),
+        "download_rate": download_rate,
+        "upload_rate": upload_rate,
+        "peers": torrent.peers(),
+        "path": torrent.path(),
+    });
+    if let Some(position) = position {
+        status["position"] = position.into();
+    }
+    if let TorrentState::Failed(reason) = state {
+        status["error"] = reason.into();
+    }
+    status
+}
+
+/// Calls a daemon's RPC methods.
+pub struct Client {
+    url: String,
+    http: reqwest::Client,
+    // The daemon's session id, once it has told us.
+    session_id: Mutex<Option<String>>,
+}
+
+impl Client {
+    /// `url` is the full endpoint, like `http://127.0.0.1:6800/rpc`.
+    pub fn new(url: impl Into<String>) -> Self {
+        Client {
+            url: url.into(),
+            http: reqwest::Client::new(),
+            session_id: Mutex::new(None),
+        }
+    }
+
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let call = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        let mut response = self.post(&call).await?;
+        if response.status() == reqwest::StatusCode::CONFLICT {
+            let session_id = response.headers().get(SESSION_ID_HEADER);
+            let session_id = session_id.and_then(|id| id.to_str().ok());
+            *self.session_id.lock().unwrap() = session_id.map(str::to_string);
+            response = self.post(&call).await?;
+        }
+        let reply: Value = response.error_for_status()?.json().await?;
+        if let Some(error) = reply.get("error") {
+            let message = error["message"].as_str().unwrap_or("unknown error");
+            bail!("{method} failed: {message}");
+        }
+        Ok(reply["result"].clone())
+    }
+
+    async fn post(&self, call: &Value) -> Result<reqwest::Response> {
+        let mut request = self.http.post(&self.url).json(call);
+        if let Some(session_id) = self.session_id.lock().unwrap().clone() {
+            request = request.header(SESSION_ID_HEADER, session_id);
+        }
+        Ok(request.send().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SessionConfig;
+
+    fn quiet_config() -> SessionConfig {
+        SessionConfig {
+            identity: crate::Identity {
+                port: 0,
+                ..Default::default()
+            },
+            local_discovery: false,
+            dht: false,
+            ..SessionConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn drives_a_session() {
+        let session = Arc::new(Session::start(quiet_config()).await.unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = Client::new(format!("http://{}/rpc", listener.local_addr().unwrap()));
+        tokio::spawn(RpcServer::new(session, dir.path().to_path_buf()).serve(listener));
+
+        let limits = json!({ "upload": 100, "peer_download": 20 });
+        let limits = client.call("limits", limits).await.unwrap();
+        assert_eq!(limits["upload"], 100);
+        assert_eq!(limits["download"], 0);
+        assert_eq!(limits["peer_download"], 20);
+        let huge = json!({ "download": u64::MAX });
+        let limits = client.call("limits", huge).await.unwrap();
+        assert_eq!(limits["download"], u64::MAX / 1024);
+
+        assert_eq!(client.call("list", Value::Null).await.unwrap(), json!([]));
+        let unknown = json!({ "info_hash": "00".repeat(20) });
+        let e = client.call("pause", unknown).await.unwrap_err();
+        assert!(e.to_string().contains("no torrent"), "{e}");
+        let e = client.call("frobnicate", json!({})).await.unwrap_err();
+        assert!(e.to_string().contains("no method"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn refuses_calls_a_web_page_could_make() {
+        let session = Arc::new(Session::start(quiet_config()).await.unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/rpc", listener.local_addr().unwrap());
+        tokio::spawn(RpcServer::new(session, PathBuf::new()).serve(listener));
+
+        let call = r#"{"jsonrpc": "2.0", "id": 1, "method": "list"}"#;
+        let http = reqwest::Client::new();
+        let post = |content_type: &str, session_id: &str| {
+            http.post(&url)
+                .header("Content-Type", content_type)
+                .header(SESSION_ID_HEADER, session_id)
+                .body(call)
+                .send()
+        };
+        let refused = post("application/json", "guess").await.unwrap();
+        assert_eq!(refused.status(), 409);
+        let session_id = refused.headers()[SESSION_ID_HEADER].to_str().unwrap();
+        let session_id = session_id.to_string();
+        let plain = post("text/plain", &session_id).await.unwrap();
+        assert_eq!(plain.status(), 415);
+        let json = post("application/json; charset=utf-8", &session_id);
+        assert_eq!(json.await.unwrap().status(), 200);
+    }
+}
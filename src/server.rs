@@ -0,0 +1,728 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    task::JoinHandle,
+};
+
+use crate::{
+    choker::{Choker, ChokerConfig, PeerStats, RateMeter},
+    extension::{ExtensionRegistry, MAX_OUTSTANDING_REQUESTS},
+    log::{debug, span, warning, Instrument, Span},
+    merkle,
+    metadata::MetadataHandler,
+    peer::{self, HashRequest, Message, PeerConnection, Timeouts, BLOCK_SIZE},
+    pex::PexHandler,
+    pool::SharedPeerPool,
+    ratelimit::{Limits, RateLimits},
+    storage::Storage,
+    Identity, TorrentFileInfo,
+};
+
+/// Largest block we serve; requests above this are refused.
+const MAX_REQUEST_LEN: usize = BLOCK_SIZE;
+/// Requests we queue per peer, as many as we tell peers they may send
+/// (`reqq`); any more are refused.
+const MAX_QUEUED_REQUESTS: usize = MAX_OUTSTANDING_REQUESTS;
+/// How many allowed-fast pieces we grant to fast-extension peers.
+const ALLOWED_FAST_COUNT: usize = 10;
+/// How often an idle connection wakes up to announce new pieces.
+const TICK: Duration = Duration::from_secs(1);
+/// How long to wait after failing to accept, which usually means running
+/// out of file descriptors, before trying again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// Torrent rates for display are averaged over this, to follow changes
+/// while smoothing over whole pieces arriving at once.
+const DISPLAY_RATE_WINDOW: Duration = Duration::from_secs(5);
+
+type Torrents = Mutex<HashMap<[u8; 20], Arc<ServedTorrent>>>;
+
+/// Choke state shared between a peer's upload loop and the choker.
+#[derive(Default)]
+struct UploadSlot {
+    interested: AtomicBool,
+    unchoked: AtomicBool,
+    uploaded: Mutex<RateMeter>,
+}
+
+/// A torrent we upload from: its data on disk and which pieces are verified.
+pub struct ServedTorrent {
+    pub info_hash: [u8; 20],
+    /// A hybrid torrent's SHA-256 info hash cut to 20 bytes, which peers
+    /// that speak v2 may ask for it by instead.
+    pub v2_info_hash: Option<[u8; 20]>,
+    pub info: TorrentFileInfo,
+    pub storage: Storage,
+    pub pool: SharedPeerPool,
+    pub uploaded: AtomicU64,
+    /// Bytes of verified pieces downloaded from peers.
+    pub downloaded: AtomicU64,
+    /// Limits shared by all of this torrent's connections.
+    pub limits: Arc<Limits>,
+    // Cleared while the torrent is paused or stopped, which ends its uploads.
+    active: AtomicBool,
+    have: Mutex<Vec<bool>>,
+    // The bencoded info dictionary, for peers that only have a magnet link.
+    metadata: Arc<Vec<u8>>,
+    peers: Mutex<HashMap<SocketAddr, Arc<UploadSlot>>>,
+    // Keyed by IP so pieces fetched over our outgoing connections count for
+    // the same host's incoming one.
+    downloaded_from: Mutex<HashMap<IpAddr, RateMeter>>,
+    // The whole torrent's transfer, for progress displays.
+    download_rate: Mutex<RateMeter>,
+    upload_rate: Mutex<RateMeter>,
+}
+
+impl ServedTorrent {
+    pub fn new(
+        info_hash: [u8; 20],
+        info: TorrentFileInfo,
+        storage: Storage,
+        have: Vec<bool>,
+        pool: SharedPeerPool,
+    ) -> Result<Arc<Self>> {
+        let v2_info_hash = info
+            .info_hash_v2()?
+            .map(|hash| <[u8; 20]>::try_from(&hash[..20]).unwrap())
+            .filter(|hash| *hash != info_hash);
+        Ok(Arc::new(ServedTorrent {
+            info_hash,
+            v2_info_hash,
+            metadata: Arc::new(serde_bencode::to_bytes(&info)?),
+            info,
+            storage,
+            pool,
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            limits: Limits::unlimited(),
+            active: AtomicBool::new(true),
+            have: Mutex::new(have),
+            peers: Mutex::new(HashMap::new()),
+            downloaded_from: Mutex::new(HashMap::new()),
+            download_rate: Mutex::new(RateMeter::with_window(DISPLAY_RATE_WINDOW)),
+            upload_rate: Mutex::new(RateMeter::with_window(DISPLAY_RATE_WINDOW)),
+        }))
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// While inactive, peers asking for the torrent are turned away and
+    /// uploads in progress end at their next tick.
+    pub fn set_active(&self, active: bool) {
+        self.active.store(active, Ordering::Relaxed);
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.have
+            .lock()
+            .unwrap()
+            .get(index)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Records a verified piece; connected peers hear about it shortly.
+    pub fn mark_have(&self, index: usize) {
+        self.have.lock().unwrap()[index] = true;
+    }
+
+    pub fn pieces(&self) -> Vec<bool> {
+        self.have.lock().unwrap().clone()
+    }
+
+    /// Counts data we downloaded, crediting the peer at `ip` if it came
+    /// from one rather than a web seed, which earns it upload slots.
+    pub fn record_download(&self, ip: Option<IpAddr>, bytes: usize) {
+        let now = Instant::now();
+        self.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(ip) = ip {
+            self.downloaded_from
+                .lock()
+                .unwrap()
+                .entry(ip)
+                .or_default()
+                .record(bytes as u64, now);
+        }
+        self.download_rate.lock().unwrap().record(bytes as u64, now);
+    }
+
+    /// Recent download and upload rates of the whole torrent, in bytes per
+    /// second.
+    pub fn rates(&self) -> (u64, u64) {
+        let now = Instant::now();
+        let download = self.download_rate.lock().unwrap().rate(now);
+        let upload = self.upload_rate.lock().unwrap().rate(now);
+        (download as u64, upload as u64)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.lock().unwrap().iter().all(|&have| have)
+    }
+
+    /// Peers connected to us that we upload to.
+    pub fn uploading_to(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    /// Whether a newly interested peer can be unchoked without waiting for
+    /// the next rechoke.
+    fn has_free_slot(&self, config: &ChokerConfig) -> bool {
+        let unchoked = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|slot| slot.unchoked.load(Ordering::Relaxed))
+            .count();
+        unchoked < config.regular_slots + config.optimistic_slots
+    }
+
+    fn rechoke(&self, choker: &mut Choker) {
+        let now = Instant::now();
+        let peers = self.peers.lock().unwrap();
+        let mut downloaded = self.downloaded_from.lock().unwrap();
+        let stats: Vec<PeerStats> = peers
+            .iter()
+            .map(|(addr, slot)| PeerStats {
+                addr: *addr,
+                interested: slot.interested.load(Ordering::Relaxed),
+                download_rate: downloaded
+                    .get_mut(&addr.ip())
+                    .map_or(0.0, |meter| meter.rate(now)),
+                upload_rate: slot.uploaded.lock().unwrap().rate(now),
+            })
+            .collect();
+        let unchoked = choker.rechoke(&stats, self.is_complete(), now);
+        for (addr, slot) in peers.iter() {
+            slot.unchoked
+                .store(unchoked.contains(addr), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Accepts incoming peer connections on the port we announce and serves
+/// blocks for every torrent registered with it.
+#[derive(Clone)]
+pub struct Server {
+    listener: Arc<TcpListener>,
+    torrents: Arc<Torrents>,
+    choker: ChokerConfig,
+    limits: Arc<RateLimits>,
+    timeouts: Timeouts,
+    identity: Identity,
+    connections: Arc<Semaphore>,
+}
+
+impl Server {
+    pub async fn bind(port: u16) -> Result<Self> {
+        Self::bind_with(port, ChokerConfig::default()).await
+    }
+
+    /// Like `bind`, with the given unchoke slots and intervals.
+    pub async fn bind_with(port: u16, choker: ChokerConfig) -> Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+        let torrents = Arc::new(Mutex::new(HashMap::new()));
+        tokio::spawn(choke_loop(Arc::downgrade(&torrents), choker.clone()));
+        Ok(Server {
+            listener: Arc::new(listener),
+            torrents,
+            choker,
+            limits: RateLimits::unlimited(),
+            timeouts: Timeouts::default(),
+            identity: Identity {
+                port,
+                ..Identity::default()
+            },
+            connections: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+        })
+    }
+
+    /// Where we listen, with the port picked if we bound to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Subjects incoming connections to `limits`.
+    pub fn with_limits(mut self, limits: Arc<RateLimits>) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Greets peers with `identity`'s peer id. Its port should be the one
+    /// we are bound to.
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Takes a permit from `connections` for every peer served, turning
+    /// peers away while there are none left.
+    pub fn with_connection_limit(mut self, connections: Arc<Semaphore>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Serves `torrent` to peers asking for any of its info hashes.
+    pub fn add_torrent(&self, torrent: Arc<ServedTorrent>) {
+        let mut torrents = self.torrents.lock().unwrap();
+        if let Some(v2_info_hash) = torrent.v2_info_hash {
+            torrents.insert(v2_info_hash, torrent.clone());
+        }
+        torrents.insert(torrent.info_hash, torrent);
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        let mut torrents = self.torrents.lock().unwrap();
+        if let Some(torrent) = torrents.remove(info_hash) {
+            if let Some(v2_info_hash) = &torrent.v2_info_hash {
+                torrents.remove(v2_info_hash);
+            }
+        }
+    }
+
+    /// Runs the accept loop on a background task.
+    pub fn spawn(&self) -> JoinHandle<()> {
+        let server = self.clone();
+        tokio::spawn(async move { server.run().await })
+    }
+
+    /// Accepts peers forever, serving each on its own task.
+    pub async fn run(&self) {
+        loop {
+            let (stream, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warning!("Could not accept a peer: {e}");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            // At the connection limit, dropping the stream turns the peer away.
+            let Ok(permit) = self.connections.clone().try_acquire_owned() else {
+                continue;
+            };
+            let server = self.clone();
+            let serve = async move {
+                if let Err(e) = server.serve_peer(stream).await {
+                    debug!("Upload ended: {e}");
+                }
+                drop(permit);
+            };
+            tokio::spawn(serve.instrument(span!("peer", addr = addr, incoming = true)));
+        }
+    }
+
+    async fn serve_peer(&self, mut stream: TcpStream) -> Result<()> {
+        let handshake =
+            PeerConnection::read_handshake(&mut stream, self.timeouts.handshake).await?;
+        let torrent = self
+            .torrents
+            .lock()
+            .unwrap()
+            .get(&handshake.sha1_infohash)
+            .filter(|torrent| torrent.is_active())
+            .cloned()
+            .ok_or_else(|| anyhow!("peer asked for a torrent we do not serve"))?;
+        if let Some(span) = Span::current() {
+            span.record("torrent", &torrent.info.name);
+        }
+
+        let addr = stream.peer_addr()?;
+        let mut extensions = ExtensionRegistry::new()
+            .with_metadata_size(torrent.metadata.len())
+            .with_listen_port(self.identity.port);
+        extensions.register(Box::new(MetadataHandler::serving(torrent.metadata.clone())));
+        if !torrent.info.is_private() {
+            extensions.register(Box::new(PexHandler::new(torrent.pool.clone(), addr)));
+        }
+        let mut connection = PeerConnection::accept(
+            stream,
+            handshake,
+            self.identity.peer_id,
+            torrent.info.is_v2(),
+            extensions,
+            self.timeouts,
+        )
+        .await?;
+        connection.set_num_pieces(torrent.info.num_pieces());
+        debug!("Connected");
+        let throttle = self
+            .limits
+            .throttle_for(&torrent.limits, &connection.limits);
+        connection.set_throttle(throttle);
+
+        // We do not know the port it listens on, so it is not advertised as reachable.
+        torrent.pool.lock().unwrap().connected(addr, 0);
+        let slot = Arc::new(UploadSlot::default());
+        torrent.peers.lock().unwrap().insert(addr, slot.clone());
+        let result = upload(&mut connection, &torrent, &slot, &self.choker).await;
+        torrent.peers.lock().unwrap().remove(&addr);
+        torrent.pool.lock().unwrap().disconnected(addr, false);
+        result
+    }
+}
+
+/// Reruns the choker for every torrent until the server is dropped.
+async fn choke_loop(torrents: Weak<Torrents>, config: ChokerConfig) {
+    let mut chokers: HashMap<[u8; 20], Choker> = HashMap::new();
+    loop {
+        tokio::time::sleep(config.rechoke_interval).await;
+        let Some(torrents) = torrents.upgrade() else {
+            return;
+        };
+        // Hybrid torrents are in there twice, once per info hash.
+        let torrents: Vec<Arc<ServedTorrent>> = torrents
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(info_hash, torrent)| **info_hash == torrent.info_hash)
+            .map(|(_, torrent)| torrent.clone())
+            .collect();
+        chokers.retain(|info_hash, _| torrents.iter().any(|t| t.info_hash == *info_hash));
+        for torrent in torrents {
+            let choker = chokers
+                .entry(torrent.info_hash)
+                .or_insert_with(|| Choker::new(config.clone()));
+            torrent.rechoke(choker);
+        }
+    }
+}
+
+async fn upload(
+    connection: &mut PeerConnection,
+    torrent: &ServedTorrent,
+    slot: &UploadSlot,
+    choker: &ChokerConfig,
+) -> Result<()> {
+    let mut announced = torrent.pieces();
+    send_pieces(connection, &announced).await?;
+    // Pieces the peer may request even while we choke it.
+    let mut granted = HashSet::new();
+    if let (true, IpAddr::V4(ip)) = (connection.fast, connection.addr.ip()) {
+        let num_pieces = torrent.info.num_pieces() as u32;
+        for index in peer::allowed_fast_set(ip, &torrent.info_hash, num_pieces, ALLOWED_FAST_COUNT)
+        {
+            if torrent.has_piece(index as usize) {
+                connection.send(&Message::AllowedFast(index)).await?;
+                granted.insert(index);
+            }
+        }
+    }
+
+    let mut queue: VecDeque<(u32, u32, u32)> = VecDeque::new();
+    let mut choking = true;
+    loop {
+        // With requests queued, only take messages that are already here
+        // so a cancel can still take effect before the block goes out.
+        let wait = if queue.is_empty() {
+            TICK
+        } else {
+            Duration::ZERO
+        };
+        // However the peer went away, the upload is simply over.
+        let Ok(incoming) = connection.recv_timeout(wait).await else {
+            return Ok(());
+        };
+        if !torrent.is_active() {
+            return Ok(());
+        }
+
+        if let Some(message) = incoming {
+            match message {
+                Message::Interested => {
+                    slot.interested.store(true, Ordering::Relaxed);
+                    // Don't make a peer wait for the next rechoke if a slot is free.
+                    if choking && torrent.has_free_slot(choker) {
+                        slot.unchoked.store(true, Ordering::Relaxed);
+                    }
+                }
+                Message::NotInterested => {
+                    slot.interested.store(false, Ordering::Relaxed);
+                }
+                Message::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let allowed = !choking || granted.contains(&index);
+                    if allowed
+                        && queue.len() < MAX_QUEUED_REQUESTS
+                        && torrent.has_piece(index as usize)
+                        && request_fits(&torrent.info, index, begin, length)
+                    {
+                        queue.push_back((index, begin, length));
+                    } else if connection.fast {
+                        connection
+                            .send(&Message::RejectRequest {
+                                index,
+                                begin,
+                                length,
+                            })
+                            .await?;
+                    }
+                }
+                Message::HashRequest(request) => {
+                    let reply = match piece_layer_hashes(&torrent.info, &request) {
+                        Some(hashes) => Message::Hashes { request, hashes },
+                        None => Message::HashReject(request),
+                    };
+                    connection.send(&reply).await?;
+                }
+                Message::Cancel {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let before = queue.len();
+                    queue.retain(|&request| request != (index, begin, length));
+                    // The fast extension wants every request answered, even cancelled ones.
+                    if connection.fast && queue.len() < before {
+                        connection
+                            .send(&Message::RejectRequest {
+                                index,
+                                begin,
+                                length,
+                            })
+                            .await?;
+                    }
+                }
+                _ => {}
+            }
+            // Apply an unchoke granted above straight away.
+            if !(choking && slot.unchoked.load(Ordering::Relaxed)) {
+                continue;
+            }
+        }
+
+        // Follow the choker's latest decision for this peer.
+        let unchoked = slot.unchoked.load(Ordering::Relaxed);
+        if choking && unchoked {
+            choking = false;
+            connection.send(&Message::Unchoke).await?;
+        } else if !choking && !unchoked {
+            choking = true;
+            connection.send(&Message::Choke).await?;
+            // Choking discards pending requests, except allowed-fast ones,
+            // which fast-extension peers are told about explicitly.
+            let mut kept = VecDeque::new();
+            for (index, begin, length) in queue.drain(..) {
+                if granted.contains(&index) {
+                    kept.push_back((index, begin, length));
+                } else if connection.fast {
+                    connection
+                        .send(&Message::RejectRequest {
+                            index,
+                            begin,
+                            length,
+                        })
+                        .await?;
+                }
+            }
+            queue = kept;
+        }
+
+        // Tell the peer about pieces verified since we last looked.
+        for (index, have) in torrent.pieces().into_iter().enumerate() {
+            if have && !announced[index] {
+                announced[index] = true;
+                connection.send(&Message::Have(index as u32)).await?;
+            }
+        }
+        connection.tick().await?;
+
+        if let Some((index, begin, length)) = queue.pop_front() {
+            let block =
+                torrent
+                    .storage
+                    .read_block(index as usize, begin as usize, length as usize)?;
+            connection
+                .send(&Message::Piece {
+                    index,
+                    begin,
+                    block,
+                })
+                .await?;
+            let now = Instant::now();
+            torrent.uploaded.fetch_add(length as u64, Ordering::Relaxed);
+            torrent
+                .upload_rate
+                .lock()
+                .unwrap()
+                .record(length as u64, now);
+            slot.uploaded.lock().unwrap().record(length as u64, now);
+        }
+    }
+}
+
+// Whether a request is for at most a block, all within its piece.
+fn request_fits(info: &TorrentFileInfo, index: u32, begin: u32, length: u32) -> bool {
+    let (index, begin, length) = (index as usize, begin as usize, length as usize);
+    index < info.num_pieces()
+        && (1..=MAX_REQUEST_LEN).contains(&length)
+        && begin + length <= info.piece_len(index)
+}
+
+// Answers a v2 hash request, if it is for hashes of the piece layer: the
+// lowest layer we keep, though the one peers want to check pieces against.
+fn piece_layer_hashes(info: &TorrentFileInfo, request: &HashRequest) -> Option<Vec<merkle::Hash>> {
+    let file = info
+        .v2
+        .as_ref()
+        .filter(|file| !file.piece_layer.is_empty())?;
+    let piece_layer = (info.piece_length / merkle::BLOCK_SIZE).trailing_zeros();
+    if request.pieces_root != file.pieces_root || request.base_layer != piece_layer {
+        return None;
+    }
+    let (index, length) = (request.index as usize, request.length as usize);
+    merkle::proof(&file.layers, index, length, request.proof_layers as usize)
+}
+
+async fn send_pieces(connection: &mut PeerConnection, have: &[bool]) -> Result<()> {
+    if connection.fast && have.iter().all(|&have| have) {
+        return connection.send(&Message::HaveAll).await;
+    }
+    if !have.contains(&true) {
+        if connection.fast {
+            connection.send(&Message::HaveNone).await?;
+        }
+        return Ok(());
+    }
+    let mut bitfield = vec![0u8; have.len().div_ceil(8)];
+    for (index, _) in have.iter().enumerate().filter(|(_, &have)| have) {
+        bitfield[index / 8] |= 0x80 >> (index % 8);
+    }
+    connection.send(&Message::Bitfield(bitfield)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        peer::{Handshake, HANDSHAKE_LEN},
+        pool::PeerPool,
+        testing::{self, Rng},
+        TorrentFile,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn answers_hash_requests_from_the_piece_layer() {
+        let piece_length = 4 * merkle::BLOCK_SIZE;
+        let data = Rng::new(17).bytes(5 * piece_length + 9);
+        let torrent = testing::torrent_v2(&data, piece_length, false);
+        let bytes = serde_bencode::to_bytes(&torrent).unwrap();
+        let info = TorrentFile::from_bytes(&bytes).unwrap().info;
+        let file = info.v2.as_ref().unwrap();
+        let request = |base_layer, index, length| HashRequest {
+            pieces_root: file.pieces_root,
+            base_layer,
+            index,
+            length,
+            proof_layers: 8,
+        };
+
+        for (index, length) in [(0, 8), (4, 2), (5, 1)] {
+            let hashes = piece_layer_hashes(&info, &request(2, index, length)).unwrap();
+            let (from, to) = (index as usize, length as usize);
+            let pieces = file.piece_layer.iter().skip(from).take(to);
+            assert!(hashes.iter().zip(pieces).all(|(hash, piece)| hash == piece));
+            assert!(merkle::verify(&file.pieces_root, &hashes, from, to));
+        }
+        // Only the piece layer is kept, and only whole subtrees of it.
+        assert_eq!(piece_layer_hashes(&info, &request(0, 0, 8)), None);
+        assert_eq!(piece_layer_hashes(&info, &request(2, 1, 2)), None);
+        assert_eq!(piece_layer_hashes(&info, &request(2, 8, 1)), None);
+        let mut other = request(2, 0, 1);
+        other.pieces_root[0] ^= 1;
+        assert_eq!(piece_layer_hashes(&info, &other), None);
+    }
+
+    #[test]
+    fn serves_only_blocks_within_their_piece() {
+        let info = TorrentFileInfo {
+            length: 2 * BLOCK_SIZE + 100,
+            piece_length: 2 * BLOCK_SIZE,
+            pieces: serde_bytes::ByteBuf::from(vec![0; 40]),
+            ..Default::default()
+        };
+        let block = BLOCK_SIZE as u32;
+        assert!(request_fits(&info, 0, 0, block));
+        assert!(request_fits(&info, 0, block, block));
+        assert!(request_fits(&info, 1, 0, 100));
+        for (index, begin, length) in [
+            (0, 0, block + 1),
+            (0, 0, 0),
+            (0, block + 1, block),
+            (0, u32::MAX, block),
+            (1, 0, 101),
+            (1, 100, 1),
+            (2, 0, 1),
+        ] {
+            assert!(
+                !request_fits(&info, index, begin, length),
+                "{index} {begin} {length}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_hybrid_torrents_under_both_info_hashes() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let data = Rng::new(3).bytes(3 * piece_length);
+        let torrent = testing::torrent_v2(&data, piece_length, true);
+        let torrent = TorrentFile::from_bytes(&serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, &data).unwrap();
+        let storage = Storage::open(&path, &torrent.info).unwrap();
+        let info_hash = torrent.info_hash().unwrap();
+        let pool = PeerPool::new().shared();
+        let served = ServedTorrent::new(
+            info_hash,
+            torrent.info.clone(),
+            storage,
+            vec![true; 3],
+            pool,
+        )
+        .unwrap();
+        let v2_info_hash = served.v2_info_hash.unwrap();
+        assert_eq!(v2_info_hash, torrent.info_hash_v2().unwrap().unwrap()[..20]);
+
+        let server = Server::bind(0).await.unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], server.local_addr().unwrap().port()));
+        server.add_torrent(served);
+        server.spawn();
+        // The answer to a handshake, if any.
+        let greet = |info_hash| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let hello = Handshake::new(info_hash, [7; 20], true).to_bytes();
+            stream.write_all(&hello).await.unwrap();
+            let mut reply = [0; HANDSHAKE_LEN];
+            stream.read_exact(&mut reply).await.ok()?;
+            Some(Handshake::from_bytes(&reply).unwrap().sha1_infohash)
+        };
+        assert_eq!(greet(info_hash).await, Some(info_hash));
+        assert_eq!(greet(v2_info_hash).await, Some(v2_info_hash));
+
+        server.remove_torrent(&info_hash);
+        assert!(server.torrents.lock().unwrap().is_empty());
+        assert_eq!(greet(v2_info_hash).await, None);
+    }
+}
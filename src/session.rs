@@ -0,0 +1,1068 @@
+use std::{
+    fs,
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use tokio::{
+    sync::{broadcast, watch, Notify, Semaphore},
+    task::{spawn_blocking, JoinHandle, JoinSet},
+};
+
+use crate::{
+    choker::ChokerConfig,
+    dht::{Dht, DhtConfig},
+    download::{Download, MAX_PEERS},
+    events::{self, EventKind, EventSender, TorrentEvent},
+    log::{span, warning, Instrument, Span},
+    lsd::Lsd,
+    magnet::Magnet,
+    metadata,
+    metainfo::{TorrentFile, TorrentFileInfo},
+    peer::Timeouts,
+    pool::{PeerPool, PeerSource},
+    ratelimit::{Limits, RateLimits},
+    server::{ServedTorrent, Server},
+    storage::Storage,
+    tracker::{self, Event},
+    Identity,
+};
+
+/// How often the queue is looked at even when nothing asks for it.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often active torrents report their transfer rates.
+const RATES_INTERVAL: Duration = Duration::from_secs(1);
+/// Peers asked for a magnet link's metadata at once.
+const METADATA_PEERS: usize = 10;
+/// What we tell trackers is left while we do not know the torrent's size.
+const UNKNOWN_LEFT: usize = 16 * 1024;
+
+/// Settings shared by every torrent in a session.
+#[derive(Clone)]
+pub struct SessionConfig {
+    /// Our peer id, and the port we listen on and announce.
+    pub identity: Identity,
+    pub limits: Arc<RateLimits>,
+    pub timeouts: Timeouts,
+    pub choker: ChokerConfig,
+    /// How many peers each torrent downloads from at once.
+    pub max_peers: usize,
+    /// Peer connections across all torrents, incoming and outgoing.
+    pub max_connections: usize,
+    /// Torrents downloading at once; the rest wait in the queue.
+    pub max_active_downloads: usize,
+    /// Complete torrents seeding at once.
+    pub max_active_seeds: usize,
+    /// Find peers on the local network (BEP 14).
+    pub local_discovery: bool,
+    /// Look torrents up in the mainline DHT when trackers have no peers.
+    pub dht: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            identity: Identity::default(),
+            limits: RateLimits::unlimited(),
+            timeouts: Timeouts::default(),
+            choker: ChokerConfig::default(),
+            max_peers: MAX_PEERS,
+            max_connections: 200,
+            max_active_downloads: 3,
+            max_active_seeds: 5,
+            local_discovery: true,
+            dht: true,
+        }
+    }
+}
+
+/// Where a torrent is in its life in the session.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Waiting for a download or seeding slot.
+    Queued,
+    Downloading,
+    Seeding,
+    /// Halted by [`Torrent::pause`]; `start` puts it back in its old place
+    /// in the queue.
+    Paused,
+    /// Halted by [`Torrent::stop`], which also tells the tracker we left;
+    /// `start` puts it at the back of the queue.
+    Stopped,
+    /// The download gave up, usually for lack of peers. `start` retries.
+    Failed(String),
+}
+
+impl TorrentState {
+    /// Whether the torrent holds a download or seeding slot.
+    pub fn is_active(&self) -> bool {
+        matches!(self, TorrentState::Downloading | TorrentState::Seeding)
+    }
+
+    /// The state's name in lowercase, for displays and APIs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TorrentState::Queued => "queued",
+            TorrentState::Downloading => "downloading",
+            TorrentState::Seeding => "seeding",
+            TorrentState::Paused => "paused",
+            TorrentState::Stopped => "stopped",
+            TorrentState::Failed(_) => "failed",
+        }
+    }
+}
+
+/// Ways to move a torrent in the queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueMove {
+    Top,
+    Up,
+    Down,
+    Bottom,
+}
+
+/// What torrents share with the session that schedules them.
+struct Queue {
+    wake: Notify,
+    next_position: AtomicU64,
+}
+
+impl Queue {
+    fn next_position(&self) -> u64 {
+        self.next_position.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// The parts of a session its background tasks need.
+struct Shared {
+    config: SessionConfig,
+    server: Option<Server>,
+    lsd: Option<Lsd>,
+    dht: Option<Arc<Dht>>,
+    connections: Arc<Semaphore>,
+    queue: Arc<Queue>,
+    torrents: Mutex<Vec<Arc<Torrent>>>,
+    // Magnet links whose metadata we are still looking for.
+    resolving: Mutex<Vec<Magnet>>,
+    events: EventSender,
+}
+
+/// A running client: the port peers connect to, the DHT node, LAN
+/// discovery, and the torrents being downloaded and seeded.
+///
+/// Torrents share the session's connection and bandwidth limits, and are
+/// started from a queue so that only so many download and seed at once.
+/// Private torrents (BEP 27) are kept off the DHT and LAN discovery
+/// whatever the configuration says.
+pub struct Session {
+    shared: Arc<Shared>,
+    accepting: Option<JoinHandle<()>>,
+}
+
+impl Session {
+    /// Starts listening for peers. If the port is taken the session still
+    /// works, but only for connections we make ourselves.
+    pub async fn start(config: SessionConfig) -> Result<Self> {
+        let connections = Arc::new(Semaphore::new(config.max_connections));
+        let port = config.identity.port;
+        let server = match Server::bind_with(port, config.choker.clone()).await {
+            Ok(server) => Some(
+                server
+                    .with_identity(config.identity)
+                    .with_limits(config.limits.clone())
+                    .with_timeouts(config.timeouts)
+                    .with_connection_limit(connections.clone()),
+            ),
+            Err(e) => {
+                warning!("Not accepting incoming peers: {e}");
+                None
+            }
+        };
+        let accepting = server.as_ref().map(Server::spawn);
+
+        let lsd = if config.local_discovery {
+            Lsd::start(port)
+                .map_err(|e| warning!("Local service discovery unavailable: {e}"))
+                .ok()
+        } else {
+            None
+        };
+
+        let dht = if config.dht {
+            match Dht::start(dht_config(port)) {
+                Ok(dht) => {
+                    let dht = Arc::new(dht);
+                    // Joining the network takes a while; lookups made in the
+                    // meantime bootstrap themselves.
+                    let joining = dht.clone();
+                    spawn_blocking(move || joining.bootstrap());
+                    Some(dht)
+                }
+                Err(e) => {
+                    warning!("DHT unavailable: {e}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let shared = Arc::new(Shared {
+            config,
+            server,
+            lsd,
+            dht,
+            connections,
+            queue: Arc::new(Queue {
+                wake: Notify::new(),
+                next_position: AtomicU64::new(0),
+            }),
+            torrents: Mutex::new(Vec::new()),
+            resolving: Mutex::new(Vec::new()),
+            events: broadcast::channel(events::CAPACITY).0,
+        });
+        tokio::spawn(schedule_loop(Arc::downgrade(&shared)));
+        tokio::spawn(rates_loop(Arc::downgrade(&shared)));
+
+        Ok(Session { shared, accepting })
+    }
+
+    /// Adds a torrent whose data lives at `path` and queues it. Data
+    /// already there is checked first, so an interrupted download resumes
+    /// where it stopped and a complete file goes straight to seeding.
+    pub async fn add_torrent(
+        &self,
+        metainfo: TorrentFile,
+        path: impl AsRef<Path>,
+    ) -> Result<Arc<Torrent>> {
+        let info_hash = metainfo.info_hash()?;
+        // Spares checking the data of a torrent we have; whether it was
+        // added meanwhile is settled when inserting it.
+        if self.torrent(&info_hash).is_some() {
+            bail!("torrent {} was already added", hex::encode(info_hash));
+        }
+        let info = metainfo.info.clone();
+        let path = path.as_ref().to_path_buf();
+        let data_path = path.clone();
+        // Hashing a large file takes a while, so keep it off the runtime.
+        let (storage, have) = spawn_blocking(move || -> Result<_> {
+            if !path.exists() {
+                let storage = Storage::create(&path, &info)?;
+                return Ok((storage, vec![false; info.num_pieces()]));
+            }
+            let storage = Storage::open(&path, &info)?;
+            let have = storage.verify(&info)?;
+            if have.iter().all(|&have| have) {
+                Ok((storage, have))
+            } else {
+                // Reopen for writing the pieces still missing.
+                Ok((Storage::create(&path, &info)?, have))
+            }
+        })
+        .await??;
+
+        let shared = &self.shared;
+        let private = metainfo.info.is_private();
+        let served = ServedTorrent::new(
+            info_hash,
+            metainfo.info.clone(),
+            storage,
+            have,
+            PeerPool::new().shared(),
+        )?;
+        // Nothing is served until the queue gets to the torrent.
+        served.set_active(false);
+
+        let (state, _) = watch::channel(TorrentState::Queued);
+        let torrent = Arc::new(Torrent {
+            download: Arc::new(Download {
+                torrent: metainfo,
+                info_hash,
+                served: served.clone(),
+                limits: shared.config.limits.clone(),
+                timeouts: shared.config.timeouts,
+                identity: shared.config.identity,
+                max_peers: shared.config.max_peers,
+                connections: shared.connections.clone(),
+                events: shared.events.clone(),
+                connected: AtomicUsize::new(0),
+                unwritten: AtomicUsize::new(0),
+            }),
+            private,
+            path: data_path,
+            state,
+            position: AtomicU64::new(shared.queue.next_position()),
+            task: Mutex::new(None),
+            queue: shared.queue.clone(),
+        });
+        {
+            let mut torrents = shared.torrents.lock().unwrap();
+            if torrents
+                .iter()
+                .any(|torrent| torrent.info_hash() == info_hash)
+            {
+                bail!("torrent {} was already added", hex::encode(info_hash));
+            }
+            torrents.push(torrent.clone());
+        }
+        if let Some(server) = &shared.server {
+            server.add_torrent(served);
+        }
+        shared.queue.wake.notify_one();
+        Ok(torrent)
+    }
+
+    /// Adds a torrent with its data in `dir`, named after the torrent.
+    pub async fn add_torrent_in(
+        &self,
+        metainfo: TorrentFile,
+        dir: impl AsRef<Path>,
+    ) -> Result<Arc<Torrent>> {
+        let name = match metainfo.info.file_name() {
+            Some(name) => name.to_string(),
+            None => hex::encode(metainfo.info_hash()?),
+        };
+        self.add_torrent(metainfo, dir.as_ref().join(name)).await
+    }
+
+    /// Fetches the metadata of a magnet link from peers found through its
+    /// trackers and the DHT, then adds the torrent with its data in `dir`.
+    pub async fn add_magnet(&self, magnet: Magnet, dir: impl AsRef<Path>) -> Result<Arc<Torrent>> {
+        let info_hash = magnet.info_hash;
+        {
+            let mut resolving = self.shared.resolving.lock().unwrap();
+            let pending = resolving
+                .iter()
+                .any(|pending| pending.info_hash == info_hash);
+            if pending || self.torrent(&info_hash).is_some() {
+                bail!("torrent {} was already added", hex::encode(info_hash));
+            }
+            resolving.push(magnet.clone());
+        }
+        let span = span!("magnet", info_hash = hex::encode(info_hash));
+        let resolved = self.resolve(&magnet).instrument(span).await;
+        self.shared
+            .resolving
+            .lock()
+            .unwrap()
+            .retain(|pending| pending.info_hash != info_hash);
+        let (info, peers) = resolved?;
+
+        let metainfo = TorrentFile {
+            announce: magnet.trackers.first().cloned().unwrap_or_default(),
+            info,
+            url_list: Vec::new(),
+            httpseeds: Vec::new(),
+            piece_layers: Default::default(),
+        };
+        let torrent = self.add_torrent_in(metainfo, dir).await?;
+        let pool = &torrent.download.served.pool;
+        pool.lock().unwrap().add(peers, PeerSource::Tracker);
+        Ok(torrent)
+    }
+
+    // Finds the info dictionary for `magnet`, along with the peers we found
+    // on the way.
+    async fn resolve(&self, magnet: &Magnet) -> Result<(TorrentFileInfo, Vec<SocketAddr>)> {
+        let config = &self.shared.config;
+        let info_hash = magnet.info_hash;
+        let mut peers = Vec::new();
+        for tracker in &magnet.trackers {
+            let announce =
+                tracker::announce_to(tracker, &info_hash, config.identity, UNKNOWN_LEFT, None);
+            match announce.await {
+                Ok(found) => peers.extend(found),
+                Err(e) => warning!("Tracker {tracker} failed: {e}"),
+            }
+        }
+        if let (true, Some(dht)) = (peers.is_empty(), self.shared.dht.clone()) {
+            peers = spawn_blocking(move || dht_lookup(&dht, &info_hash, None)).await??;
+        }
+        peers.sort();
+        peers.dedup();
+
+        let mut candidates = peers.iter().copied();
+        let mut fetches = JoinSet::new();
+        let fetch = |peer| {
+            let fetch = metadata::fetch_info(peer, info_hash, config.identity, config.timeouts);
+            fetch.instrument(span!("peer", addr = peer))
+        };
+        for peer in candidates.by_ref().take(METADATA_PEERS) {
+            fetches.spawn(fetch(peer));
+        }
+        while let Some(result) = fetches.join_next().await {
+            match result? {
+                Ok(info) => return Ok((info, peers)),
+                Err(e) => warning!("Metadata fetch failed: {e}"),
+            }
+            if let Some(peer) = candidates.next() {
+                fetches.spawn(fetch(peer));
+            }
+        }
+        bail!("no peer sent the metadata for {}", hex::encode(info_hash))
+    }
+
+    /// Magnet links still waiting for their metadata.
+    pub fn resolving(&self) -> Vec<Magnet> {
+        self.shared.resolving.lock().unwrap().clone()
+    }
+
+    /// Every torrent in the session, in queue order.
+    pub fn torrents(&self) -> Vec<Arc<Torrent>> {
+        self.shared.queued()
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<Arc<Torrent>> {
+        self.shared
+            .torrents
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|torrent| torrent.info_hash() == *info_hash)
+            .cloned()
+    }
+
+    /// Everything that happens to the session's torrents from now on.
+    pub fn events(&self) -> broadcast::Receiver<TorrentEvent> {
+        self.shared.events.subscribe()
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.shared.config
+    }
+
+    /// The limits every connection in the session is subject to; changes
+    /// apply right away.
+    pub fn limits(&self) -> &Arc<RateLimits> {
+        &self.shared.config.limits
+    }
+
+    /// Stops the torrent and forgets it, deleting its data too if asked.
+    pub fn remove(&self, info_hash: &[u8; 20], delete_data: bool) -> Result<()> {
+        let Some(torrent) = self.torrent(info_hash) else {
+            bail!("no torrent {}", hex::encode(info_hash));
+        };
+        torrent.stop();
+        let shared = &self.shared;
+        shared
+            .torrents
+            .lock()
+            .unwrap()
+            .retain(|torrent| torrent.info_hash() != *info_hash);
+        if let Some(server) = &shared.server {
+            server.remove_torrent(info_hash);
+        }
+        if let Some(lsd) = &shared.lsd {
+            lsd.remove_torrent(info_hash);
+        }
+        if delete_data {
+            fs::remove_file(torrent.path())?;
+        }
+        Ok(())
+    }
+
+    /// Moves a torrent in the queue, which decides who gets free slots.
+    pub fn move_in_queue(&self, info_hash: &[u8; 20], to: QueueMove) -> Result<()> {
+        let mut torrents = self.shared.queued();
+        let Some(from) = torrents.iter().position(|t| t.info_hash() == *info_hash) else {
+            bail!("no torrent {}", hex::encode(info_hash));
+        };
+        let torrent = torrents.remove(from);
+        let to = match to {
+            QueueMove::Top => 0,
+            QueueMove::Up => from.saturating_sub(1),
+            QueueMove::Down => (from + 1).min(torrents.len()),
+            QueueMove::Bottom => torrents.len(),
+        };
+        torrents.insert(to, torrent);
+        // Renumber everyone; torrents started later still go after them.
+        let queue = &self.shared.queue;
+        let _torrents = self.shared.torrents.lock().unwrap();
+        for torrent in &torrents {
+            torrent
+                .position
+                .store(queue.next_position(), Ordering::Relaxed);
+        }
+        queue.wake.notify_one();
+        Ok(())
+    }
+
+    /// Serves the session's torrents until the process is stopped.
+    pub async fn run(self) -> Result<()> {
+        let Some(accepting) = self.accepting else {
+            bail!("not accepting incoming peers");
+        };
+        accepting.await?;
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn queued(&self) -> Vec<Arc<Torrent>> {
+        let mut torrents = self.torrents.lock().unwrap().clone();
+        torrents.sort_by_key(|torrent| torrent.position.load(Ordering::Relaxed));
+        torrents
+    }
+
+    /// Hands free download and seeding slots to queued torrents, in queue
+    /// order, and keeps LAN discovery to the torrents that are active.
+    fn schedule(&self) {
+        let torrents = self.queued();
+        let count = |state: TorrentState| {
+            torrents
+                .iter()
+                .filter(|torrent| torrent.state() == state)
+                .count()
+        };
+        let mut downloads = count(TorrentState::Downloading);
+        let mut seeds = count(TorrentState::Seeding);
+
+        for torrent in &torrents {
+            if torrent.state() == TorrentState::Queued {
+                if torrent.is_complete() && seeds < self.config.max_active_seeds {
+                    seeds += torrent.activate(TorrentState::Seeding, self) as usize;
+                } else if !torrent.is_complete() && downloads < self.config.max_active_downloads {
+                    downloads += torrent.activate(TorrentState::Downloading, self) as usize;
+                } else {
+                    // A finished download waiting for a seeding slot.
+                    torrent.deactivate();
+                }
+            }
+
+            let Some(lsd) = &self.lsd else { continue };
+            let info_hash = torrent.info_hash();
+            let discover = torrent.state().is_active() && !torrent.private;
+            if discover && !lsd.has_torrent(&info_hash) {
+                let pool = torrent.download.served.pool.clone();
+                if let Err(e) = lsd.add_torrent(info_hash, pool) {
+                    warning!("Local service discovery announce failed: {e}");
+                }
+            } else if !discover {
+                lsd.remove_torrent(&info_hash);
+            }
+        }
+    }
+}
+
+/// Reschedules whenever a torrent changes state, until the session is
+/// dropped.
+async fn rates_loop(shared: Weak<Shared>) {
+    let mut interval = tokio::time::interval(RATES_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        for torrent in shared.torrents.lock().unwrap().iter() {
+            if torrent.state().is_active() {
+                let (download, upload) = torrent.rates();
+                torrent.download.emit(EventKind::Rates { download, upload });
+            }
+        }
+    }
+}
+
+async fn schedule_loop(shared: Weak<Shared>) {
+    loop {
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        shared.schedule();
+        let queue = shared.queue.clone();
+        drop(shared);
+        let _ = tokio::time::timeout(SCHEDULE_INTERVAL, queue.wake.notified()).await;
+    }
+}
+
+/// A torrent added to a [`Session`]. While it downloads or seeds, whatever
+/// of it we have is uploaded to peers that ask for it.
+pub struct Torrent {
+    download: Arc<Download>,
+    private: bool,
+    path: PathBuf,
+    state: watch::Sender<TorrentState>,
+    // Order in the queue; lower goes first.
+    position: AtomicU64,
+    // Finding peers and downloading, or announcing that we seed. Only
+    // changed with the lock held, together with the state.
+    task: Mutex<Option<JoinHandle<()>>>,
+    queue: Arc<Queue>,
+}
+
+impl Torrent {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.download.info_hash
+    }
+
+    pub fn metainfo(&self) -> &TorrentFile {
+        &self.download.torrent
+    }
+
+    /// Where the torrent's data is kept.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // What this torrent's messages are labelled with.
+    fn span(&self) -> Span {
+        span!("torrent", name = self.download.torrent.info.name)
+    }
+
+    /// Limits for this torrent's connections alone, on top of the
+    /// session's.
+    pub fn limits(&self) -> &Arc<Limits> {
+        &self.download.served.limits
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.state.borrow().clone()
+    }
+
+    /// Which pieces we have verified.
+    pub fn pieces(&self) -> Vec<bool> {
+        self.download.served.pieces()
+    }
+
+    /// Verified pieces, and the number of pieces in the torrent.
+    pub fn progress(&self) -> (usize, usize) {
+        let pieces = self.pieces();
+        let done = pieces.iter().filter(|&&have| have).count();
+        (done, pieces.len())
+    }
+
+    /// Bytes of verified data we hold.
+    pub fn verified_bytes(&self) -> u64 {
+        let info = &self.download.torrent.info;
+        let pieces = self.download.served.pieces();
+        let have = pieces.iter().enumerate().filter(|(_, &have)| have);
+        have.map(|(index, _)| info.piece_len(index) as u64).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.download.served.is_complete()
+    }
+
+    /// Bytes of piece data uploaded to peers so far.
+    pub fn uploaded(&self) -> u64 {
+        self.download.served.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// Recent download and upload rates, in bytes per second.
+    pub fn rates(&self) -> (u64, u64) {
+        self.download.served.rates()
+    }
+
+    /// Bytes downloaded from peers since the torrent was added.
+    pub fn downloaded(&self) -> u64 {
+        self.download.served.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Peers we are downloading from right now.
+    pub fn peers(&self) -> usize {
+        self.download.connected.load(Ordering::Relaxed)
+    }
+
+    /// Peers connected to us that we upload to.
+    pub fn uploading_to(&self) -> usize {
+        self.download.served.uploading_to()
+    }
+
+    /// Verified pieces not yet written to disk.
+    pub fn unwritten(&self) -> usize {
+        self.download.unwritten.load(Ordering::Relaxed)
+    }
+
+    /// Queues a paused, stopped or failed torrent again.
+    pub fn start(&self) {
+        let task = self.task.lock().unwrap();
+        match self.state() {
+            TorrentState::Paused => {}
+            TorrentState::Stopped | TorrentState::Failed(_) => {
+                let position = self.queue.next_position();
+                self.position.store(position, Ordering::Relaxed);
+            }
+            _ => return,
+        }
+        self.set_state(TorrentState::Queued);
+        drop(task);
+        self.queue.wake.notify_one();
+    }
+
+    /// Halts downloading and seeding, keeping the torrent's place in the
+    /// queue.
+    pub fn pause(&self) {
+        if self.halt(TorrentState::Paused).is_some() {
+            self.queue.wake.notify_one();
+        }
+    }
+
+    /// Halts downloading and seeding and tells the tracker we left.
+    pub fn stop(&self) {
+        let Some(was) = self.halt(TorrentState::Stopped) else {
+            return;
+        };
+        if was.is_active() {
+            let download = self.download.clone();
+            let announce = async move {
+                let Download {
+                    torrent,
+                    info_hash,
+                    identity,
+                    ..
+                } = &*download;
+                let left = left(&download);
+                let event = Some(Event::Stopped);
+                let announce = tracker::announce(torrent, info_hash, *identity, left, event);
+                report_announce(&download, announce).await;
+            };
+            tokio::spawn(announce.instrument(self.span()));
+        }
+        self.queue.wake.notify_one();
+    }
+
+    /// Waits until every piece is downloaded. Fails if the download does;
+    /// a paused or stopped torrent is waited for until it is started again.
+    pub async fn completed(&self) -> Result<()> {
+        let mut state = self.state.subscribe();
+        loop {
+            if self.is_complete() {
+                return Ok(());
+            }
+            if let TorrentState::Failed(e) = &*state.borrow_and_update() {
+                bail!("{e}");
+            }
+            state.changed().await?;
+        }
+    }
+
+    // Moves to `state`, telling whoever listens, and returns the old one.
+    fn set_state(&self, state: TorrentState) -> TorrentState {
+        let was = self.state.send_replace(state.clone());
+        if was != state {
+            self.download.emit(EventKind::StateChanged(state));
+        }
+        was
+    }
+
+    // Moves to `state` and drops every connection, returning the state we
+    // were in, if it was a different one.
+    fn halt(&self, state: TorrentState) -> Option<TorrentState> {
+        let mut task = self.task.lock().unwrap();
+        let was = self.set_state(state.clone());
+        if was == state {
+            return None;
+        }
+        if let Some(task) = task.take() {
+            task.abort();
+        }
+        self.download.served.set_active(false);
+        Some(was)
+    }
+
+    fn deactivate(&self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+        self.download.served.set_active(false);
+    }
+
+    // Gives a queued torrent the slot for `state`. Returns false if it was
+    // paused or stopped in the meantime.
+    fn activate(self: &Arc<Self>, state: TorrentState, session: &Shared) -> bool {
+        let mut task = self.task.lock().unwrap();
+        if self.state() != TorrentState::Queued {
+            return false;
+        }
+        self.set_state(state.clone());
+        self.download.served.set_active(true);
+
+        let torrent = self.clone();
+        let dht = session.dht.clone().filter(|_| !self.private);
+        let discovering = session.lsd.is_some() && !self.private;
+        let work = async move {
+            if state == TorrentState::Seeding {
+                torrent.announce_seed(dht).await;
+                return;
+            }
+            let result = torrent.fetch(dht, discovering).await;
+            let _task = torrent.task.lock().unwrap();
+            if torrent.state() != TorrentState::Downloading {
+                return;
+            }
+            match result {
+                // Back in the queue, to wait for a seeding slot.
+                Ok(()) => torrent.set_state(TorrentState::Queued),
+                Err(e) => {
+                    torrent.download.served.set_active(false);
+                    torrent.set_state(TorrentState::Failed(e.to_string()))
+                }
+            };
+            torrent.queue.wake.notify_one();
+        };
+        let previous = task.replace(tokio::spawn(work.instrument(self.span())));
+        if let Some(previous) = previous {
+            previous.abort();
+        }
+        true
+    }
+
+    // Downloads every missing piece. Peers come from the tracker, then the
+    // DHT if the tracker has none, and from PEX and LAN discovery as the
+    // download goes on.
+    async fn fetch(&self, dht: Option<Arc<Dht>>, discovering: bool) -> Result<()> {
+        let download = &self.download;
+        let pool = &download.served.pool;
+        let left = left(download);
+        let event = Some(Event::Started);
+        let info_hash = &download.info_hash;
+        let announce =
+            tracker::announce(&download.torrent, info_hash, download.identity, left, event);
+        if let Some(peers) = report_announce(download, announce).await {
+            pool.lock().unwrap().add(peers, PeerSource::Tracker);
+        }
+        // Without a working tracker, look the torrent up in the DHT instead.
+        let no_peers = pool.lock().unwrap().is_empty();
+        if let (true, Some(dht)) = (no_peers, dht) {
+            let info_hash = *info_hash;
+            match spawn_blocking(move || dht_lookup(&dht, &info_hash, None)).await? {
+                Ok(peers) => {
+                    pool.lock().unwrap().add(peers, PeerSource::Dht);
+                }
+                Err(e) => warning!("DHT lookup failed: {e}"),
+            }
+        }
+
+        download.clone().run(discovering).await?;
+
+        let event = Some(Event::Completed);
+        let announce = tracker::announce(&download.torrent, info_hash, download.identity, 0, event);
+        report_announce(download, announce).await;
+        Ok(())
+    }
+
+    // Tells the tracker and the DHT that we have the whole torrent, so that
+    // peers come to us.
+    async fn announce_seed(&self, dht: Option<Arc<Dht>>) {
+        let download = &self.download;
+        let info_hash = download.info_hash;
+        let identity = download.identity;
+        let announce = tracker::request_peers(&download.torrent, &info_hash, identity, 0);
+        report_announce(download, announce).await;
+        if let Some(dht) = dht {
+            // The DHT blocks while it looks around.
+            let port = Some(identity.port);
+            let lookup = spawn_blocking(move || dht_lookup(&dht, &info_hash, port)).await;
+            if let Err(e) = lookup.map_err(anyhow::Error::from).and_then(|peers| peers) {
+                warning!("DHT announce failed: {e}");
+            }
+        }
+    }
+}
+
+// Makes an announce and tells listeners how it went and how long it took,
+// passing on the peers it found.
+async fn report_announce(
+    download: &Download,
+    announce: impl Future<Output = Result<Vec<SocketAddr>>>,
+) -> Option<Vec<SocketAddr>> {
+    let started = Instant::now();
+    let announce = announce.await;
+    let elapsed = started.elapsed();
+    match announce {
+        Ok(peers) => {
+            let found = peers.len();
+            download.emit(EventKind::TrackerResponse {
+                peers: found,
+                elapsed,
+            });
+            Some(peers)
+        }
+        Err(e) => {
+            let error = e.to_string();
+            download.emit(EventKind::TrackerFailed { error, elapsed });
+            None
+        }
+    }
+}
+
+// Bytes we still need, for tracker announces.
+fn left(download: &Download) -> usize {
+    let info = &download.torrent.info;
+    let pieces = download.served.pieces();
+    (0..pieces.len())
+        .filter(|&index| !pieces[index])
+        .map(|index| info.piece_len(index))
+        .sum()
+}
+
+// The DHT shares its port number with the peer listener, over UDP.
+fn dht_config(port: u16) -> DhtConfig {
+    DhtConfig {
+        bind: SocketAddr::from(([0, 0, 0, 0], port)),
+        ..DhtConfig::default()
+    }
+}
+
+fn dht_lookup(
+    dht: &Dht,
+    info_hash: &[u8; 20],
+    announce_port: Option<u16>,
+) -> Result<Vec<SocketAddr>> {
+    if dht.node_count() == 0 {
+        dht.bootstrap()?;
+    }
+    dht.get_peers(info_hash, announce_port)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::TorrentFileInfo;
+
+    // A one-piece torrent whose data is already complete in `dir`.
+    fn complete_torrent(dir: &Path, name: &str) -> (TorrentFile, std::path::PathBuf) {
+        let data = name.repeat(100).into_bytes();
+        let path = dir.join(name);
+        std::fs::write(&path, &data).unwrap();
+        let metainfo = TorrentFile {
+            // Nothing listens there, so announces fail straight away.
+            announce: "http://127.0.0.1:1/announce".to_string(),
+            info: TorrentFileInfo {
+                length: data.len(),
+                name: name.to_string(),
+                piece_length: 16 * 1024,
+                pieces: ByteBuf::from(Sha1::digest(&data).to_vec()),
+                private: None,
+                ..Default::default()
+            },
+            url_list: Vec::new(),
+            httpseeds: Vec::new(),
+            piece_layers: Default::default(),
+        };
+        (metainfo, path)
+    }
+
+    async fn wait_for(torrent: &Torrent, state: TorrentState) {
+        let mut states = torrent.state.subscribe();
+        let reached = states.wait_for(|current| *current == state);
+        tokio::time::timeout(Duration::from_secs(5), reached)
+            .await
+            .unwrap_or_else(|_| panic!("torrent never got {state:?}"))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn queue_hands_slots_on_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::start(SessionConfig {
+            identity: Identity {
+                port: 0,
+                ..Identity::default()
+            },
+            max_active_seeds: 1,
+            local_discovery: false,
+            dht: false,
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+
+        let (first, path) = complete_torrent(dir.path(), "first");
+        let first = session.add_torrent(first, path).await.unwrap();
+        let (second, path) = complete_torrent(dir.path(), "second");
+        let second = session.add_torrent(second, path).await.unwrap();
+        wait_for(&first, TorrentState::Seeding).await;
+        assert_eq!(second.state(), TorrentState::Queued);
+
+        // Pausing frees the slot; starting again does not take it back.
+        first.pause();
+        wait_for(&second, TorrentState::Seeding).await;
+        first.start();
+        assert_eq!(first.state(), TorrentState::Queued);
+
+        // A stopped torrent goes to the back of the queue when restarted.
+        first.stop();
+        first.start();
+        let order: Vec<_> = session.torrents().iter().map(|t| t.info_hash()).collect();
+        assert_eq!(order, [second.info_hash(), first.info_hash()]);
+
+        session.remove(&second.info_hash(), false).unwrap();
+        assert_eq!(second.state(), TorrentState::Stopped);
+        wait_for(&first, TorrentState::Seeding).await;
+        assert_eq!(session.torrents().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reports_what_happens() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::start(SessionConfig {
+            identity: Identity {
+                port: 0,
+                ..Identity::default()
+            },
+            local_discovery: false,
+            dht: false,
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+        let mut events = session.events();
+
+        let (metainfo, path) = complete_torrent(dir.path(), "events");
+        let torrent = session.add_torrent(metainfo, path).await.unwrap();
+        // Rate samples may come in between anything else.
+        async fn next(events: &mut broadcast::Receiver<TorrentEvent>) -> EventKind {
+            loop {
+                let event = tokio::time::timeout(Duration::from_secs(5), events.recv());
+                match event.await.expect("no event").unwrap().kind {
+                    EventKind::Rates { .. } => continue,
+                    kind => return kind,
+                }
+            }
+        }
+        let seeding = EventKind::StateChanged(TorrentState::Seeding);
+        assert_eq!(next(&mut events).await, seeding);
+        // Nothing listens where the tracker should be.
+        let announce = next(&mut events).await;
+        assert!(matches!(announce, EventKind::TrackerFailed { .. }));
+
+        torrent.pause();
+        let paused = EventKind::StateChanged(TorrentState::Paused);
+        assert_eq!(next(&mut events).await, paused);
+    }
+
+    #[tokio::test]
+    async fn adds_a_torrent_only_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::start(SessionConfig {
+            identity: Identity {
+                port: 0,
+                ..Identity::default()
+            },
+            local_discovery: false,
+            dht: false,
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+
+        // Both get past the first check while the other hashes the data.
+        let (metainfo, path) = complete_torrent(dir.path(), "twice");
+        let (first, second) = tokio::join!(
+            session.add_torrent(metainfo.clone(), &path),
+            session.add_torrent(metainfo, &path),
+        );
+        assert!(first.is_ok() != second.is_ok());
+        assert_eq!(session.torrents().len(), 1);
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{bail, Result};
+
+use crate::metainfo::TorrentFileInfo;
+
+/// The torrent's data on disk, addressed by piece.
+pub struct Storage {
+    file: Mutex<File>,
+    length: usize,
+    piece_length: usize,
+}
+
+impl Storage {
+    /// Opens `path` for downloading into, creating it at full size.
+    pub fn create(path: impl AsRef<Path>, info: &TorrentFileInfo) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.set_len(info.size() as u64)?;
+        Ok(Self::new(file, info))
+    }
+
+    /// Opens an existing, complete file for seeding.
+    pub fn open(path: impl AsRef<Path>, info: &TorrentFileInfo) -> Result<Self> {
+        let file = File::open(path)?;
+        let actual = file.metadata()?.len();
+        if actual != info.size() as u64 {
+            bail!("file is {actual} bytes, torrent expects {}", info.size());
+        }
+        Ok(Self::new(file, info))
+    }
+
+    fn new(file: File, info: &TorrentFileInfo) -> Self {
+        Storage {
+            file: Mutex::new(file),
+            length: info.size(),
+            piece_length: info.piece_length,
+        }
+    }
+
+    pub fn read_block(&self, index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        let offset = index * self.piece_length + begin;
+        if offset + length > self.length {
+            bail!("block {index}:{begin}+{length} is past the end of the torrent");
+        }
+        let mut block = vec![0; length];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.read_exact(&mut block)?;
+        Ok(block)
+    }
+
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((index * self.piece_length) as u64))?;
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    /// Hashes every piece on disk and reports which ones are intact.
+    pub fn verify(&self, info: &TorrentFileInfo) -> Result<Vec<bool>> {
+        (0..info.num_pieces())
+            .map(|index| {
+                let piece = self.read_block(index, 0, info.piece_len(index))?;
+                Ok(info.verify_piece(index, &piece))
+            })
+            .collect()
+    }
+}
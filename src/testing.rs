@@ -0,0 +1,126 @@
+//! Helpers for the unit tests.
+
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    extension::ExtensionRegistry,
+    merkle::{self, Hash},
+    peer::{Handshake, Message, PeerConnection, Timeouts, HANDSHAKE_LEN},
+    TorrentFile, TorrentFileInfo,
+};
+
+/// A small deterministic generator (xorshift64*) for property tests, so a
+/// failing case comes back on every run.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// A number below `n`, which must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+/// A single-file v2 torrent of `data`, or a hybrid one with v1 hashes too.
+pub(crate) fn torrent_v2(data: &[u8], piece_length: usize, hybrid: bool) -> TorrentFile {
+    let layer: Vec<Hash> = data
+        .chunks(piece_length)
+        .map(|piece| merkle::piece_hash(piece, piece_length))
+        .collect();
+    // A file of one piece has no piece layer.
+    let (root, piece_layers) = match layer.len() {
+        1 => (merkle::small_file_root(data), Default::default()),
+        _ => {
+            let root = merkle::file_root(&layer, piece_length);
+            let layer = (ByteBuf::from(root), ByteBuf::from(layer.concat()));
+            (root, [layer].into())
+        }
+    };
+    let file = Value::Dict(
+        [
+            (b"length".to_vec(), Value::Int(data.len() as i64)),
+            (b"pieces root".to_vec(), Value::Bytes(root.to_vec())),
+        ]
+        .into(),
+    );
+    let node = Value::Dict([(Vec::new(), file)].into());
+    let mut info = TorrentFileInfo {
+        name: "a".to_string(),
+        piece_length,
+        meta_version: Some(2),
+        file_tree: Some(Value::Dict([(b"a".to_vec(), node)].into())),
+        ..Default::default()
+    };
+    if hybrid {
+        info.length = data.len();
+        let pieces = data.chunks(piece_length).flat_map(Sha1::digest);
+        info.pieces = ByteBuf::from(pieces.collect::<Vec<u8>>());
+    }
+    TorrentFile {
+        announce: "http://tracker/announce".to_string(),
+        info,
+        url_list: Vec::new(),
+        httpseeds: Vec::new(),
+        piece_layers,
+    }
+}
+
+/// A connection to a peer on localhost that speaks the fast extension, with
+/// the peer's end of it.
+pub(crate) async fn connect(num_pieces: usize) -> (PeerConnection, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = async {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut hello = [0; HANDSHAKE_LEN];
+        stream.read_exact(&mut hello).await.unwrap();
+        let reply = Handshake::from_bytes(&hello).unwrap().to_bytes();
+        stream.write_all(&reply).await.unwrap();
+        stream
+    };
+    let timeouts = Timeouts::default();
+    let extensions = ExtensionRegistry::new();
+    let connection = PeerConnection::connect(addr, [1; 20], [2; 20], false, extensions, timeouts);
+    let (connection, peer) = tokio::join!(connection, peer);
+    let mut connection = connection.unwrap();
+    connection.set_num_pieces(num_pieces);
+    (connection, peer)
+}
+
+pub(crate) async fn send_all(peer: &mut TcpStream, messages: &[Message]) {
+    let bytes: Vec<u8> = messages.iter().flat_map(Message::to_bytes).collect();
+    peer.write_all(&bytes).await.unwrap();
+}
+
+/// Reads the next message from the peer's end of a connection, or `None`
+/// once it is closed.
+pub(crate) async fn recv(peer: &mut TcpStream) -> Option<Message> {
+    let len = peer.read_u32().await.ok()? as usize;
+    let mut body = vec![0; len];
+    peer.read_exact(&mut body).await.ok()?;
+    Some(Message::from_bytes(&body).unwrap())
+}
@@ -0,0 +1,247 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{bencode, Identity, TorrentFile};
+
+pub mod server;
+
+/// Trackers that take longer than this are treated as down.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) fn urlencode(t: &[u8; 20]) -> String {
+    let mut encoded = String::with_capacity(3 * t.len());
+    for &byte in t {
+        encoded.push('%');
+        encoded.push_str(&hex::encode([byte]));
+    }
+    encoded
+}
+
+/// Tells the tracker why we announce, outside of the regular re-announces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    /// The event named in an announce. Empty means a regular announce.
+    pub fn parse(name: &str) -> Result<Option<Self>> {
+        Ok(match name {
+            "" | "empty" => None,
+            "started" => Some(Event::Started),
+            "completed" => Some(Event::Completed),
+            "stopped" => Some(Event::Stopped),
+            _ => bail!("unknown event {name:?}"),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct QueryParams {
+    port: usize,
+    uploaded: usize,
+    downloaded: usize,
+    left: usize,
+    compact: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<Event>,
+}
+
+/// A tracker's answer to an announce.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TrackerResponse {
+    /// Set instead of the rest when the tracker refuses the announce.
+    #[serde(rename = "failure reason", skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<String>,
+    /// Seconds until the next regular announce.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    /// Seeders in the swarm, for trackers that count them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub complete: Option<u64>,
+    /// Leechers in the swarm.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<u64>,
+    /// Missing only from failures.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peers: Option<Peers>,
+    /// IPv6 peers in compact form (BEP 7).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peers6: Option<ByteBuf>,
+}
+
+/// The peers of an announce response, as a list or, by BEP 23, packed in a
+/// string.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Peers {
+    Compact(ByteBuf),
+    List(Vec<PeerInfo>),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PeerInfo {
+    /// Left out when the announce asked for `no_peer_id`.
+    #[serde(rename = "peer id", skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<ByteBuf>,
+    /// An address, or possibly a host name.
+    pub ip: String,
+    pub port: u16,
+}
+
+/// A tracker's answer to a scrape: the swarms of the torrents asked about,
+/// by info hash.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ScrapeResponse {
+    pub files: BTreeMap<ByteBuf, ScrapeStats>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ScrapeStats {
+    /// Seeders.
+    pub complete: u64,
+    /// Times the torrent was completed.
+    pub downloaded: u64,
+    /// Leechers.
+    pub incomplete: u64,
+}
+
+/// A peer in compact form: its IP's bytes, then its port.
+pub fn compact(peer: SocketAddr) -> Vec<u8> {
+    let mut bytes = match peer {
+        SocketAddr::V4(addr) => addr.ip().octets().to_vec(),
+        SocketAddr::V6(addr) => addr.ip().octets().to_vec(),
+    };
+    bytes.extend(peer.port().to_be_bytes());
+    bytes
+}
+
+pub fn parse_ips6(ips: &[u8]) -> Vec<SocketAddr> {
+    ips.chunks_exact(18)
+        .map(|chunk| {
+            let ip: [u8; 16] = chunk[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            SocketAddr::from((Ipv6Addr::from(ip), port))
+        })
+        .collect()
+}
+
+pub fn parse_ips(ips: &[u8]) -> Vec<SocketAddr> {
+    ips.chunks_exact(6)
+        .map(|chunk| {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            SocketAddr::from((ip, port))
+        })
+        .collect()
+}
+
+/// A regular announce, without an event.
+pub async fn request_peers(
+    torrent: &TorrentFile,
+    info_hash: &[u8; 20],
+    identity: Identity,
+    left: usize,
+) -> Result<Vec<SocketAddr>> {
+    announce(torrent, info_hash, identity, left, None).await
+}
+
+/// Announces ourselves to the torrent's tracker, returning the peers it
+/// hands out.
+pub async fn announce(
+    torrent: &TorrentFile,
+    info_hash: &[u8; 20],
+    identity: Identity,
+    left: usize,
+    event: Option<Event>,
+) -> Result<Vec<SocketAddr>> {
+    announce_to(&torrent.announce, info_hash, identity, left, event).await
+}
+
+/// Like `announce`, for a tracker we know only the URL of, as with magnet
+/// links.
+pub async fn announce_to(
+    tracker: &str,
+    info_hash: &[u8; 20],
+    identity: Identity,
+    left: usize,
+    event: Option<Event>,
+) -> Result<Vec<SocketAddr>> {
+    let request: QueryParams = QueryParams {
+        port: identity.port as usize,
+        uploaded: 0,
+        downloaded: 0,
+        left,
+        compact: 1,
+        event,
+    };
+
+    let url_params = serde_urlencoded::to_string(&request)?;
+
+    // Both ids are raw bytes, which serde_urlencoded would want as text.
+    let tracker_url = format!(
+        "{}?{}&info_hash={}&peer_id={}",
+        tracker,
+        url_params,
+        &urlencode(info_hash),
+        &urlencode(&identity.peer_id)
+    );
+
+    let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+    let body = client.get(tracker_url).send().await?.bytes().await?;
+    parse_response(&body)
+}
+
+/// The peers in a tracker's bencoded response to an announce.
+pub fn parse_response(body: &[u8]) -> Result<Vec<SocketAddr>> {
+    let decoded: TrackerResponse = bencode::from_bytes(body)?;
+    if let Some(reason) = decoded.failure_reason {
+        bail!("tracker refused the announce: {reason}");
+    }
+    let mut peers = match decoded.peers {
+        None => Vec::new(),
+        Some(Peers::Compact(ips)) => parse_ips(&ips),
+        // Host names would need resolving; trackers hardly send them.
+        Some(Peers::List(peers)) => peers
+            .iter()
+            .filter_map(|peer| Some(SocketAddr::new(peer.ip.parse::<IpAddr>().ok()?, peer.port)))
+            .collect(),
+    };
+    peers.extend(decoded.peers6.map_or_else(Vec::new, |ips| parse_ips6(&ips)));
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_responses() {
+        let peers = parse_response(b"d8:intervali60e5:peers6:\x7f\0\0\x01\x1a\xe1e").unwrap();
+        assert_eq!(peers, ["127.0.0.1:6881".parse().unwrap()]);
+        let refused = parse_response(b"d14:failure reason7:go awaye").unwrap_err();
+        assert_eq!(refused.to_string(), "tracker refused the announce: go away");
+        assert!(parse_response(b"d5:peers999999999999:e").is_err());
+    }
+
+    #[test]
+    fn parses_peer_lists() {
+        let body = b"d8:intervali60e5:peersld2:ip8:10.0.0.17:peer id20:-TR3000-abcdefghijkl\
+                     4:porti51413eed2:ip9:localhost4:porti1eee6:peers618:\
+                     \0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1e";
+        let peers = parse_response(body).unwrap();
+        let expected: [SocketAddr; 2] =
+            ["10.0.0.1:51413", "[::1]:6881"].map(|a| a.parse().unwrap());
+        assert_eq!(peers, expected);
+    }
+}
@@ -0,0 +1,828 @@
+//! A tracker for other clients: announces and scrapes over HTTP, and over
+//! the UDP protocol of BEP 15 if asked to.
+//!
+//! Peers are recorded at the address their announce came from, with the
+//! port they announced, and are dropped once they stop announcing. Swarms
+//! live in memory and, if there is a state file, survive restarts.
+
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet},
+    fs,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::net::{TcpListener, UdpSocket};
+
+use super::{
+    compact, parse_ips, parse_ips6, Event, PeerInfo, Peers, ScrapeResponse, ScrapeStats,
+    TrackerResponse,
+};
+use crate::{
+    bencode,
+    http::{self, Request, Response},
+    log::{debug, info, warning},
+};
+
+/// The port trackers commonly use.
+pub const DEFAULT_PORT: u16 = 6969;
+
+const ANNOUNCE_PATH: &str = "/announce";
+const SCRAPE_PATH: &str = "/scrape";
+const CONTENT_TYPE: &str = "text/plain";
+/// Peers handed out when an announce doesn't say how many it wants.
+const DEFAULT_NUMWANT: usize = 50;
+const MAX_NUMWANT: usize = 200;
+/// How often expired peers are dropped and the state file is written.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Starts every UDP connect request.
+const UDP_PROTOCOL_ID: u64 = 0x0417_2710_1980;
+const CONNECT: u32 = 0;
+const ANNOUNCE: u32 = 1;
+const SCRAPE: u32 = 2;
+const ERROR: u32 = 3;
+const UDP_ANNOUNCE_LEN: usize = 98;
+/// The most info hashes a UDP scrape may ask about.
+const UDP_MAX_SCRAPE: usize = 74;
+/// Seconds a UDP connection id is good for, or up to twice that.
+const CONNECTION_LIFETIME: u64 = 120;
+
+pub struct TrackerConfig {
+    /// How long peers are asked to wait between announces.
+    pub interval: Duration,
+    /// Peers that haven't announced for this long are dropped.
+    pub peer_timeout: Duration,
+    /// The only torrents tracked, by info hash; any if `None`.
+    pub allowed: Option<HashSet<[u8; 20]>>,
+    /// Where swarms are kept between runs; only in memory if `None`.
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        TrackerConfig {
+            interval: Duration::from_secs(30 * 60),
+            peer_timeout: Duration::from_secs(60 * 60),
+            allowed: None,
+            state_file: None,
+        }
+    }
+}
+
+struct Peer {
+    peer_id: [u8; 20],
+    left: u64,
+    /// When it last announced, in seconds since the Unix epoch.
+    seen: u64,
+}
+
+#[derive(Default)]
+struct Swarm {
+    /// By the address other peers can reach them at.
+    peers: HashMap<SocketAddr, Peer>,
+    /// Times the torrent was completed.
+    downloaded: u64,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count() as u64;
+        ScrapeStats {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() as u64 - complete,
+        }
+    }
+
+    /// Up to `wanted` peers for `asking`, starting somewhere random so that
+    /// big swarms mix. Seeds only get leechers.
+    fn peers_for(
+        &self,
+        asking: SocketAddr,
+        seeding: bool,
+        wanted: usize,
+    ) -> Vec<(SocketAddr, [u8; 20])> {
+        let candidates: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|&(&addr, peer)| addr != asking && !(seeding && peer.left == 0))
+            .map(|(&addr, peer)| (addr, peer.peer_id))
+            .collect();
+        if candidates.is_empty() {
+            return candidates;
+        }
+        let start = RandomState::new().build_hasher().finish() as usize % candidates.len();
+        candidates
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(wanted.min(candidates.len()))
+            .copied()
+            .collect()
+    }
+}
+
+/// The swarms as written to the state file.
+#[derive(Default, Deserialize, Serialize)]
+struct State {
+    swarms: BTreeMap<ByteBuf, SavedSwarm>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SavedSwarm {
+    downloaded: u64,
+    peers: Vec<SavedPeer>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SavedPeer {
+    /// In compact form.
+    addr: ByteBuf,
+    peer_id: ByteBuf,
+    left: u64,
+    seen: u64,
+}
+
+/// An announce, from either protocol.
+struct Announce {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    addr: SocketAddr,
+    left: u64,
+    event: Option<Event>,
+    numwant: usize,
+}
+
+struct Answer {
+    peers: Vec<(SocketAddr, [u8; 20])>,
+    stats: ScrapeStats,
+}
+
+/// Tracks swarms for other clients.
+pub struct TrackerServer {
+    config: TrackerConfig,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+    /// Swarms changed since the state file was written.
+    changed: AtomicBool,
+    /// Signs UDP connection ids.
+    secret: [u8; 20],
+}
+
+impl TrackerServer {
+    /// Loads the state file, if there is one, and starts dropping peers
+    /// that stop announcing.
+    pub fn new(config: TrackerConfig) -> Result<Arc<Self>> {
+        let swarms = match &config.state_file {
+            Some(path) if path.exists() => {
+                let swarms = load(path).with_context(|| format!("loading {}", path.display()))?;
+                info!("Loaded {} swarms from {}", swarms.len(), path.display());
+                swarms
+            }
+            _ => HashMap::new(),
+        };
+        let tracker = Arc::new(TrackerServer {
+            config,
+            swarms: Mutex::new(swarms),
+            changed: AtomicBool::new(false),
+            secret: crate::dht::random_id(),
+        });
+        tokio::spawn(maintain_loop(Arc::downgrade(&tracker)));
+        Ok(tracker)
+    }
+
+    /// Answers announces at `/announce` and scrapes at `/scrape`.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        http::serve(listener, move |request| {
+            let tracker = self.clone();
+            async move { tracker.answer(request) }
+        })
+        .await
+    }
+
+    /// Answers BEP 15's connect, announce and scrape requests on `socket`.
+    pub async fn serve_udp(self: Arc<Self>, socket: UdpSocket) -> Result<()> {
+        let mut buffer = [0; 2048];
+        loop {
+            let (len, from) = socket.recv_from(&mut buffer).await?;
+            if let Some(reply) = self.answer_udp(&buffer[..len], from) {
+                // A reply that doesn't get through is retried by the client.
+                let _ = socket.send_to(&reply, from).await;
+            }
+        }
+    }
+
+    /// Writes the swarms to the state file, if there is one.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.config.state_file else {
+            return Ok(());
+        };
+        self.changed.store(false, Ordering::Relaxed);
+        let mut state = State::default();
+        for (info_hash, swarm) in self.swarms.lock().unwrap().iter() {
+            let peers = swarm.peers.iter().map(|(&addr, peer)| SavedPeer {
+                addr: ByteBuf::from(compact(addr)),
+                peer_id: ByteBuf::from(peer.peer_id.to_vec()),
+                left: peer.left,
+                seen: peer.seen,
+            });
+            let saved = SavedSwarm {
+                downloaded: swarm.downloaded,
+                peers: peers.collect(),
+            };
+            state
+                .swarms
+                .insert(ByteBuf::from(info_hash.to_vec()), saved);
+        }
+        // Written aside first, so a crash leaves the last good state.
+        let partial = path.with_extension("partial");
+        fs::write(&partial, serde_bencode::to_bytes(&state)?)?;
+        fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    fn allows(&self, info_hash: &[u8; 20]) -> bool {
+        self.config
+            .allowed
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(info_hash))
+    }
+
+    // Announces that fail are answered with the error as the reason.
+    fn announce(&self, announce: Announce) -> Result<Answer> {
+        if !self.allows(&announce.info_hash) {
+            bail!("unregistered torrent");
+        }
+        debug!(
+            "{} announced {:?} for {}",
+            announce.addr,
+            announce.event,
+            hex::encode(announce.info_hash)
+        );
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.entry(announce.info_hash).or_default();
+        let peers = if announce.event == Some(Event::Stopped) {
+            swarm.peers.remove(&announce.addr);
+            Vec::new()
+        } else {
+            if announce.event == Some(Event::Completed) {
+                swarm.downloaded += 1;
+            }
+            let peer = Peer {
+                peer_id: announce.peer_id,
+                left: announce.left,
+                seen: unix_time(),
+            };
+            swarm.peers.insert(announce.addr, peer);
+            swarm.peers_for(announce.addr, announce.left == 0, announce.numwant)
+        };
+        self.changed.store(true, Ordering::Relaxed);
+        Ok(Answer {
+            peers,
+            stats: swarm.stats(),
+        })
+    }
+
+    /// The swarms of `info_hashes`, or of every torrent if there are none.
+    /// Torrents nobody announced have empty swarms; ones that aren't allowed
+    /// are left out.
+    fn scrape(&self, info_hashes: &[[u8; 20]]) -> Vec<([u8; 20], ScrapeStats)> {
+        let swarms = self.swarms.lock().unwrap();
+        if info_hashes.is_empty() {
+            return swarms
+                .iter()
+                .map(|(&info_hash, swarm)| (info_hash, swarm.stats()))
+                .collect();
+        }
+        info_hashes
+            .iter()
+            .filter(|info_hash| self.allows(info_hash))
+            .map(|&info_hash| {
+                let stats = swarms.get(&info_hash).map(Swarm::stats);
+                (info_hash, stats.unwrap_or_default())
+            })
+            .collect()
+    }
+
+    fn expire(&self) {
+        let cutoff = unix_time().saturating_sub(self.config.peer_timeout.as_secs());
+        let mut swarms = self.swarms.lock().unwrap();
+        let before: usize = swarms.values().map(|swarm| swarm.peers.len()).sum();
+        for swarm in swarms.values_mut() {
+            swarm.peers.retain(|_, peer| peer.seen >= cutoff);
+        }
+        swarms.retain(|_, swarm| !swarm.peers.is_empty() || swarm.downloaded > 0);
+        let after: usize = swarms.values().map(|swarm| swarm.peers.len()).sum();
+        if after < before {
+            debug!("Dropped {} peers that stopped announcing", before - after);
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn answer(&self, request: Request) -> Response {
+        let body = match request.route() {
+            ANNOUNCE_PATH => self.answer_announce(&request),
+            SCRAPE_PATH => self.answer_scrape(&request),
+            _ => return Response::text(404, "not found\n"),
+        };
+        let body = body.unwrap_or_else(|e| {
+            let failure = TrackerResponse {
+                failure_reason: Some(e.to_string()),
+                ..TrackerResponse::default()
+            };
+            serde_bencode::to_bytes(&failure).expect("responses always encode")
+        });
+        Response::new(200, CONTENT_TYPE, body)
+    }
+
+    fn answer_announce(&self, request: &Request) -> Result<Vec<u8>> {
+        let query = request.query();
+        let param = |name: &str| {
+            let value = query.iter().find(|(param, _)| param == name);
+            value.map(|(_, value)| value.as_slice())
+        };
+        let number = |name: &str| -> Result<Option<u64>> {
+            let Some(value) = param(name) else {
+                return Ok(None);
+            };
+            let value = std::str::from_utf8(value).ok().and_then(|v| v.parse().ok());
+            value
+                .map(Some)
+                .ok_or_else(|| anyhow!("{name} is not a number"))
+        };
+        let id = |name: &str| -> Result<[u8; 20]> {
+            let value = param(name).ok_or_else(|| anyhow!("missing {name}"))?;
+            value
+                .try_into()
+                .map_err(|_| anyhow!("{name} is not 20 bytes"))
+        };
+
+        let port = number("port")?.ok_or_else(|| anyhow!("missing port"))?;
+        let port = u16::try_from(port).map_err(|_| anyhow!("port {port} is out of range"))?;
+        let event = std::str::from_utf8(param("event").unwrap_or_default())?;
+        let wanted = number("numwant")?.map_or(DEFAULT_NUMWANT, |n| n as usize);
+        let answer = self.announce(Announce {
+            info_hash: id("info_hash")?,
+            peer_id: id("peer_id")?,
+            addr: SocketAddr::new(request.client.ip().to_canonical(), port),
+            left: number("left")?.ok_or_else(|| anyhow!("missing left"))?,
+            event: Event::parse(event)?,
+            numwant: wanted.min(MAX_NUMWANT),
+        })?;
+
+        let mut response = TrackerResponse {
+            interval: Some(self.config.interval.as_secs()),
+            complete: Some(answer.stats.complete),
+            incomplete: Some(answer.stats.incomplete),
+            ..TrackerResponse::default()
+        };
+        // BEP 23 lets trackers answer compactly unless told not to.
+        if param("compact") == Some(b"0") {
+            let with_ids = param("no_peer_id") != Some(b"1");
+            let peers = answer.peers.into_iter().map(|(addr, peer_id)| PeerInfo {
+                peer_id: with_ids.then(|| ByteBuf::from(peer_id.to_vec())),
+                ip: addr.ip().to_string(),
+                port: addr.port(),
+            });
+            response.peers = Some(Peers::List(peers.collect()));
+        } else {
+            let (mut peers, mut peers6) = (Vec::new(), Vec::new());
+            for (addr, _) in answer.peers {
+                match addr {
+                    SocketAddr::V4(_) => peers.extend(compact(addr)),
+                    SocketAddr::V6(_) => peers6.extend(compact(addr)),
+                }
+            }
+            response.peers = Some(Peers::Compact(ByteBuf::from(peers)));
+            response.peers6 = (!peers6.is_empty()).then(|| ByteBuf::from(peers6));
+        }
+        Ok(serde_bencode::to_bytes(&response)?)
+    }
+
+    fn answer_scrape(&self, request: &Request) -> Result<Vec<u8>> {
+        let info_hashes = request
+            .query()
+            .into_iter()
+            .filter(|(name, _)| name == "info_hash")
+            .map(|(_, value)| {
+                value
+                    .try_into()
+                    .map_err(|_| anyhow!("info_hash is not 20 bytes"))
+            })
+            .collect::<Result<Vec<[u8; 20]>>>()?;
+        let files = self
+            .scrape(&info_hashes)
+            .into_iter()
+            .map(|(info_hash, stats)| (ByteBuf::from(info_hash.to_vec()), stats));
+        let response = ScrapeResponse {
+            files: files.collect(),
+        };
+        Ok(serde_bencode::to_bytes(&response)?)
+    }
+
+    fn answer_udp(&self, packet: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        // Too short to even say what it is, so there's nobody to answer.
+        if packet.len() < 16 {
+            return None;
+        }
+        let connection_id = u64_at(packet, 0);
+        let action = u32_at(packet, 8);
+        let transaction = &packet[12..16];
+        let mut reply = Vec::new();
+        let answered = match action {
+            CONNECT if connection_id == UDP_PROTOCOL_ID => {
+                let window = unix_time() / CONNECTION_LIFETIME;
+                reply.extend(self.connection_id(from, window).to_be_bytes());
+                Ok(())
+            }
+            CONNECT => Err(anyhow!("not a BitTorrent tracker request")),
+            _ if !self.connected(connection_id, from) => Err(anyhow!("connection id expired")),
+            ANNOUNCE => self.answer_udp_announce(packet, from, &mut reply),
+            SCRAPE => self.answer_udp_scrape(packet, &mut reply),
+            action => Err(anyhow!("unknown action {action}")),
+        };
+        let mut head = match answered {
+            Ok(()) => action.to_be_bytes().to_vec(),
+            Err(e) => {
+                reply = e.to_string().into_bytes();
+                ERROR.to_be_bytes().to_vec()
+            }
+        };
+        head.extend(transaction);
+        head.extend(reply);
+        Some(head)
+    }
+
+    fn answer_udp_announce(
+        &self,
+        packet: &[u8],
+        from: SocketAddr,
+        reply: &mut Vec<u8>,
+    ) -> Result<()> {
+        if packet.len() < UDP_ANNOUNCE_LEN {
+            bail!("announce too short");
+        }
+        let event = match u32_at(packet, 80) {
+            0 => None,
+            1 => Some(Event::Completed),
+            2 => Some(Event::Started),
+            3 => Some(Event::Stopped),
+            event => bail!("unknown event {event}"),
+        };
+        // Negative means the default.
+        let wanted = usize::try_from(u32_at(packet, 92) as i32).unwrap_or(DEFAULT_NUMWANT);
+        let port = u16::from_be_bytes([packet[96], packet[97]]);
+        let answer = self.announce(Announce {
+            info_hash: packet[16..36].try_into()?,
+            peer_id: packet[36..56].try_into()?,
+            addr: SocketAddr::new(from.ip().to_canonical(), port),
+            left: u64_at(packet, 64),
+            event,
+            numwant: wanted.min(MAX_NUMWANT),
+        })?;
+        reply.extend((self.config.interval.as_secs() as u32).to_be_bytes());
+        reply.extend((answer.stats.incomplete as u32).to_be_bytes());
+        reply.extend((answer.stats.complete as u32).to_be_bytes());
+        // Peers must be of the family the client asked over.
+        for (addr, _) in answer.peers {
+            if addr.is_ipv4() == from.ip().to_canonical().is_ipv4() {
+                reply.extend(compact(addr));
+            }
+        }
+        Ok(())
+    }
+
+    fn answer_udp_scrape(&self, packet: &[u8], reply: &mut Vec<u8>) -> Result<()> {
+        let info_hashes: Vec<[u8; 20]> = packet[16..]
+            .chunks_exact(20)
+            .take(UDP_MAX_SCRAPE)
+            .map(|info_hash| info_hash.try_into().unwrap())
+            .collect();
+        if info_hashes.is_empty() {
+            bail!("no info hashes to scrape");
+        }
+        let scraped: HashMap<_, _> = self.scrape(&info_hashes).into_iter().collect();
+        // Answers go in the order asked, so torrents left out count as empty.
+        for info_hash in &info_hashes {
+            let stats = scraped.get(info_hash).copied().unwrap_or_default();
+            for count in [stats.complete, stats.downloaded, stats.incomplete] {
+                reply.extend((count as u32).to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Ids are signed with the client's address and the time, so no state
+    /// is kept for them.
+    fn connection_id(&self, client: SocketAddr, window: u64) -> u64 {
+        let mut hasher = Sha1::new();
+        hasher.update(self.secret);
+        hasher.update(compact(client));
+        hasher.update(window.to_be_bytes());
+        u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+    }
+
+    fn connected(&self, connection_id: u64, client: SocketAddr) -> bool {
+        let window = unix_time() / CONNECTION_LIFETIME;
+        [window, window.saturating_sub(1)]
+            .iter()
+            .any(|&window| self.connection_id(client, window) == connection_id)
+    }
+}
+
+async fn maintain_loop(tracker: Weak<TrackerServer>) {
+    let mut ticks = tokio::time::interval(MAINTENANCE_INTERVAL);
+    loop {
+        ticks.tick().await;
+        let Some(tracker) = tracker.upgrade() else {
+            return;
+        };
+        tracker.expire();
+        if tracker.changed.load(Ordering::Relaxed) {
+            if let Err(e) = tracker.save() {
+                warning!("Couldn't save the tracker's state: {e}");
+            }
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<HashMap<[u8; 20], Swarm>> {
+    let state: State = bencode::from_bytes(&fs::read(path)?)?;
+    let mut swarms = HashMap::new();
+    for (info_hash, saved) in state.swarms {
+        let info_hash: [u8; 20] = info_hash.as_slice().try_into()?;
+        let mut swarm = Swarm {
+            peers: HashMap::new(),
+            downloaded: saved.downloaded,
+        };
+        for peer in saved.peers {
+            let addr = match peer.addr.len() {
+                6 => parse_ips(&peer.addr),
+                _ => parse_ips6(&peer.addr),
+            };
+            let addr = addr.first().ok_or_else(|| anyhow!("bad peer address"))?;
+            let peer_id = peer.peer_id.as_slice().try_into()?;
+            let (left, seen) = (peer.left, peer.seen);
+            swarm.peers.insert(
+                *addr,
+                Peer {
+                    peer_id,
+                    left,
+                    seen,
+                },
+            );
+        }
+        swarms.insert(info_hash, swarm);
+    }
+    Ok(swarms)
+}
+
+fn unix_time() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+    since_epoch.map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// Only for offsets the caller has checked the packet is long enough for.
+fn u32_at(packet: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(packet[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(packet: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(packet[at..at + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tracker::{announce_to, urlencode},
+        Identity,
+    };
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    async fn start(config: TrackerConfig) -> (Arc<TrackerServer>, SocketAddr) {
+        let tracker = TrackerServer::new(config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(tracker.clone().serve(listener));
+        (tracker, addr)
+    }
+
+    fn peer(id: u8, port: u16) -> Identity {
+        Identity {
+            peer_id: [id; 20],
+            port,
+        }
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(url: String) -> T {
+        let body = reqwest::get(url).await.unwrap().bytes().await.unwrap();
+        bencode::from_bytes(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tracks_swarms_over_http() {
+        let (_tracker, addr) = start(TrackerConfig::default()).await;
+        let url = format!("http://{addr}/announce");
+        let leecher = peer(1, 1111);
+        let seed = peer(2, 2222);
+        let started = Some(Event::Started);
+
+        let peers = announce_to(&url, &INFO_HASH, leecher, 100, started)
+            .await
+            .unwrap();
+        assert!(peers.is_empty());
+        let peers = announce_to(&url, &INFO_HASH, seed, 0, started)
+            .await
+            .unwrap();
+        assert_eq!(peers, ["127.0.0.1:1111".parse().unwrap()]);
+        let peers = announce_to(&url, &INFO_HASH, leecher, 100, None)
+            .await
+            .unwrap();
+        assert_eq!(peers, ["127.0.0.1:2222".parse().unwrap()]);
+
+        let query = format!(
+            "info_hash={}&peer_id={}",
+            urlencode(&INFO_HASH),
+            urlencode(&[3; 20])
+        );
+        let listed: TrackerResponse =
+            get(format!("{url}?{query}&port=3333&left=5&compact=0")).await;
+        assert_eq!(listed.interval, Some(30 * 60));
+        assert_eq!((listed.complete, listed.incomplete), (Some(1), Some(2)));
+        let Some(Peers::List(mut listed)) = listed.peers else {
+            panic!("asked for a list of peers");
+        };
+        listed.sort_by_key(|peer| peer.port);
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].peer_id, Some(ByteBuf::from([1; 20].to_vec())));
+        assert_eq!((listed[1].ip.as_str(), listed[1].port), ("127.0.0.1", 2222));
+
+        announce_to(&url, &INFO_HASH, leecher, 0, Some(Event::Completed))
+            .await
+            .unwrap();
+        announce_to(&url, &INFO_HASH, seed, 0, Some(Event::Stopped))
+            .await
+            .unwrap();
+        let scrape = format!("http://{addr}/scrape?info_hash={}", urlencode(&INFO_HASH));
+        let scraped: ScrapeResponse = get(scrape).await;
+        let stats = scraped.files[&ByteBuf::from(INFO_HASH.to_vec())];
+        let expected = ScrapeStats {
+            complete: 1,
+            downloaded: 1,
+            incomplete: 1,
+        };
+        assert_eq!(stats, expected);
+    }
+
+    #[tokio::test]
+    async fn refuses_torrents_not_allowed() {
+        let (_tracker, addr) = start(TrackerConfig {
+            allowed: Some(HashSet::from([INFO_HASH])),
+            ..TrackerConfig::default()
+        })
+        .await;
+        let url = format!("http://{addr}/announce");
+        assert!(announce_to(&url, &INFO_HASH, peer(1, 1), 0, None)
+            .await
+            .is_ok());
+        let e = announce_to(&url, &[8; 20], peer(1, 1), 0, None)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("unregistered torrent"), "{e}");
+
+        let scrape = format!("http://{addr}/scrape?info_hash={}", urlencode(&[8; 20]));
+        let scraped: ScrapeResponse = get(scrape).await;
+        assert!(scraped.files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tracks_swarms_over_udp() {
+        let tracker = TrackerServer::new(TrackerConfig::default()).unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(tracker.serve_udp(server));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server_addr).await.unwrap();
+        let request = |packet: Vec<u8>| {
+            let client = &client;
+            async move {
+                client.send(&packet).await.unwrap();
+                let mut reply = vec![0; 2048];
+                let len = client.recv(&mut reply).await.unwrap();
+                reply.truncate(len);
+                reply
+            }
+        };
+
+        let mut connect = UDP_PROTOCOL_ID.to_be_bytes().to_vec();
+        connect.extend(CONNECT.to_be_bytes());
+        connect.extend(*b"tid1");
+        let reply = request(connect).await;
+        assert_eq!((u32_at(&reply, 0), &reply[4..8]), (CONNECT, &b"tid1"[..]));
+        let connection_id = reply[8..16].to_vec();
+
+        let announce = |id: u8, left: u64, port: u16| {
+            let mut packet = connection_id.clone();
+            packet.extend(ANNOUNCE.to_be_bytes());
+            packet.extend(*b"tid2");
+            packet.extend(INFO_HASH);
+            packet.extend([id; 20]);
+            packet.extend(0u64.to_be_bytes());
+            packet.extend(left.to_be_bytes());
+            packet.extend(0u64.to_be_bytes());
+            packet.extend(2u32.to_be_bytes()); // started
+            packet.extend([0; 8]); // ip and key
+            packet.extend((-1i32).to_be_bytes());
+            packet.extend(port.to_be_bytes());
+            packet
+        };
+        request(announce(1, 0, 1111)).await;
+        let reply = request(announce(2, 10, 2222)).await;
+        assert_eq!(u32_at(&reply, 0), ANNOUNCE);
+        // Interval, leechers, seeders, then the seed.
+        assert_eq!(
+            [u32_at(&reply, 8), u32_at(&reply, 12), u32_at(&reply, 16)],
+            [1800, 1, 1]
+        );
+        assert_eq!(parse_ips(&reply[20..]), ["127.0.0.1:1111".parse().unwrap()]);
+
+        let mut scrape = connection_id.clone();
+        scrape.extend(SCRAPE.to_be_bytes());
+        scrape.extend(*b"tid3");
+        scrape.extend(INFO_HASH);
+        scrape.extend([9; 20]);
+        let reply = request(scrape).await;
+        let counts: Vec<u32> = (8..reply.len())
+            .step_by(4)
+            .map(|at| u32_at(&reply, at))
+            .collect();
+        assert_eq!(counts, [1, 0, 1, 0, 0, 0]);
+
+        let mut stale = announce(1, 0, 1111);
+        stale[..8].copy_from_slice(&[0; 8]);
+        let reply = request(stale).await;
+        assert_eq!(u32_at(&reply, 0), ERROR);
+        assert_eq!(&reply[8..], b"connection id expired");
+    }
+
+    #[tokio::test]
+    async fn keeps_swarms_in_the_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = || TrackerConfig {
+            state_file: Some(dir.path().join("tracker.dat")),
+            ..TrackerConfig::default()
+        };
+        let tracker = TrackerServer::new(config()).unwrap();
+        for (id, port, left) in [(1, 1111, 0), (2, 2222, 5)] {
+            let addr = SocketAddr::from(([10, 0, 0, id], port));
+            tracker
+                .announce(Announce {
+                    info_hash: INFO_HASH,
+                    peer_id: [id; 20],
+                    addr,
+                    left,
+                    event: Some(Event::Completed),
+                    numwant: DEFAULT_NUMWANT,
+                })
+                .unwrap();
+        }
+        tracker.save().unwrap();
+
+        let restarted = TrackerServer::new(config()).unwrap();
+        let expected = ScrapeStats {
+            complete: 1,
+            downloaded: 2,
+            incomplete: 1,
+        };
+        assert_eq!(restarted.scrape(&[]), [(INFO_HASH, expected)]);
+
+        // Peers that stop announcing go, but the count of completions stays.
+        for peer in restarted
+            .swarms
+            .lock()
+            .unwrap()
+            .values_mut()
+            .flat_map(|swarm| swarm.peers.values_mut())
+        {
+            peer.seen = 0;
+        }
+        restarted.expire();
+        let expected = ScrapeStats {
+            downloaded: 2,
+            ..ScrapeStats::default()
+        };
+        assert_eq!(restarted.scrape(&[INFO_HASH]), [(INFO_HASH, expected)]);
+    }
+}
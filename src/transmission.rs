@@ -0,0 +1,657 @@
+//! Enough of Transmission's RPC protocol for the web UIs and automation
+//! tools that speak it: `torrent-add`, `torrent-get`, `torrent-start`,
+//! `torrent-start-now`, `torrent-stop`, `torrent-remove`, the `queue-move-*`
+//! methods and `session-get`/`session-set`.
+//!
+//! Clients first have to fetch a session id: any request without the
+//! current one in `X-Transmission-Session-Id` gets a 409 carrying it. The
+//! session id keeps web pages from making calls, not people: anyone who can
+//! reach the port can delete data with `torrent-remove`, so the server only
+//! listens beyond localhost with a user name and password, sent with HTTP
+//! basic authentication.
+
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::net::TcpListener;
+
+use crate::{
+    dht::random_id,
+    http::{self, Request, Response},
+    log::{error, info},
+    magnet::Magnet,
+    ratelimit::Direction,
+    session::{QueueMove, Session, Torrent, TorrentState},
+    TorrentFile,
+};
+
+/// Transmission's own default port.
+pub const DEFAULT_PORT: u16 = 9091;
+
+const PATH: &str = "/transmission/rpc";
+const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+const RPC_VERSION: u32 = 17;
+const RPC_VERSION_MINIMUM: u32 = 14;
+/// Transmission's speed limits are in kB/s of 1000 bytes.
+const SPEED_UNIT: u64 = 1000;
+
+// Values of the `status` field.
+const STOPPED: u8 = 0;
+const DOWNLOAD_WAIT: u8 = 3;
+const DOWNLOADING: u8 = 4;
+const SEED_WAIT: u8 = 5;
+const SEEDING: u8 = 6;
+
+/// The `error` field's value for a problem on our side.
+const LOCAL_ERROR: u8 = 3;
+
+#[derive(Deserialize)]
+struct Call {
+    method: String,
+    #[serde(default)]
+    arguments: Map<String, Value>,
+    tag: Option<Value>,
+}
+
+/// A speed limit that can be switched off without forgetting its value.
+#[derive(Clone, Copy)]
+struct SpeedLimit {
+    kb: u64,
+    enabled: bool,
+}
+
+/// Something `torrent-get` reports on: a torrent, or a magnet link whose
+/// metadata has not arrived yet.
+enum Entry {
+    Torrent(Arc<Torrent>),
+    Resolving(Magnet),
+}
+
+/// Answers Transmission RPC calls against a session.
+pub struct TransmissionServer {
+    session: Arc<Session>,
+    session_id: String,
+    /// `user:password`, if calls need them.
+    credentials: Option<String>,
+    download_dir: Mutex<PathBuf>,
+    // Indexed by direction: upload, then download.
+    speed_limits: Mutex<[SpeedLimit; 2]>,
+    // Transmission names torrents by numbers that stay the same for the
+    // whole session; id n is the info hash at n - 1.
+    ids: Mutex<Vec<[u8; 20]>>,
+}
+
+impl TransmissionServer {
+    /// Torrents added without a `download-dir` get their data in
+    /// `download_dir`. With `credentials`, as `user:password`, every
+    /// request has to carry them.
+    pub fn new(
+        session: Arc<Session>,
+        download_dir: PathBuf,
+        credentials: Option<String>,
+    ) -> Arc<Self> {
+        let limits = &session.limits().global;
+        let limit = |direction| {
+            let rate = limits.bucket(direction).rate();
+            SpeedLimit {
+                // Transmission's default for a limit nobody has set.
+                kb: if rate > 0 { rate / SPEED_UNIT } else { 100 },
+                enabled: rate > 0,
+            }
+        };
+        let speed_limits = [limit(Direction::Upload), limit(Direction::Download)];
+        Arc::new(TransmissionServer {
+            session,
+            session_id: hex::encode(random_id()),
+            credentials,
+            download_dir: Mutex::new(download_dir),
+            speed_limits: Mutex::new(speed_limits),
+            ids: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        http::serve(listener, move |request| {
+            let server = self.clone();
+            async move { server.answer(request).await }
+        })
+        .await
+    }
+
+    async fn answer(&self, request: Request) -> Response {
+        if request.route() != PATH {
+            return Response::text(404, "not found\n");
+        }
+        if !self.authorized(&request) {
+            return Response::text(401, "unauthorized\n")
+                .with_header("WWW-Authenticate", "Basic realm=\"Transmission\"");
+        }
+        if request.header(SESSION_ID_HEADER) != Some(&self.session_id) {
+            let message = format!("{SESSION_ID_HEADER}: {}\n", self.session_id);
+            return Response::text(409, message).with_header(SESSION_ID_HEADER, &self.session_id);
+        }
+        if request.method != "POST" {
+            return Response::text(405, "use POST\n").with_header("Allow", "POST");
+        }
+        let call: Call = match serde_json::from_slice(&request.body) {
+            Ok(call) => call,
+            Err(e) => return Response::text(400, format!("{e}\n")),
+        };
+        let mut reply = match self.call(&call.method, call.arguments).await {
+            Ok(arguments) => json!({ "result": "success", "arguments": arguments }),
+            Err(e) => json!({ "result": e.to_string(), "arguments": {} }),
+        };
+        if let Some(tag) = call.tag {
+            reply["tag"] = tag;
+        }
+        Response::json(200, &reply)
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(credentials) = &self.credentials else {
+            return true;
+        };
+        let given = request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| decode_base64(encoded.trim()).ok());
+        given.as_deref() == Some(credentials.as_bytes())
+    }
+
+    async fn call(&self, method: &str, arguments: Map<String, Value>) -> Result<Value> {
+        match method {
+            "session-get" => Ok(self.session_get(fields(&arguments))),
+            "session-set" => {
+                self.session_set(&arguments)?;
+                Ok(json!({}))
+            }
+            "torrent-get" => {
+                let fields = fields(&arguments);
+                let entries = self.entries(arguments.get("ids"));
+                let torrents: Vec<Value> = entries
+                    .iter()
+                    .map(|entry| self.torrent_fields(entry, fields.as_deref()))
+                    .collect();
+                Ok(json!({ "torrents": torrents }))
+            }
+            "torrent-add" => self.torrent_add(&arguments).await,
+            "torrent-remove" => {
+                let delete_data = arguments.get("delete-local-data") == Some(&Value::Bool(true));
+                for torrent in self.torrents(arguments.get("ids")) {
+                    self.session.remove(&torrent.info_hash(), delete_data)?;
+                }
+                Ok(json!({}))
+            }
+            "torrent-start" | "torrent-start-now" | "torrent-stop" => {
+                for torrent in self.torrents(arguments.get("ids")) {
+                    match method {
+                        "torrent-stop" => torrent.stop(),
+                        "torrent-start-now" => {
+                            // Restarting a stopped torrent queues it last.
+                            torrent.start();
+                            let info_hash = torrent.info_hash();
+                            self.session.move_in_queue(&info_hash, QueueMove::Top)?;
+                        }
+                        _ => torrent.start(),
+                    }
+                }
+                Ok(json!({}))
+            }
+            "queue-move-top" | "queue-move-up" | "queue-move-down" | "queue-move-bottom" => {
+                let to = match method {
+                    "queue-move-top" => QueueMove::Top,
+                    "queue-move-up" => QueueMove::Up,
+                    "queue-move-down" => QueueMove::Down,
+                    _ => QueueMove::Bottom,
+                };
+                let mut torrents = self.torrents(arguments.get("ids"));
+                // Keep the order among the torrents that move together.
+                if matches!(to, QueueMove::Top | QueueMove::Down) {
+                    torrents.reverse();
+                }
+                for torrent in torrents {
+                    self.session.move_in_queue(&torrent.info_hash(), to)?;
+                }
+                Ok(json!({}))
+            }
+            _ => bail!("method name not recognized"),
+        }
+    }
+
+    fn session_get(&self, fields: Option<Vec<String>>) -> Value {
+        let session = &self.session;
+        let config = session.config();
+        let [upload, download] = *self.speed_limits.lock().unwrap();
+        let per_peer = &session.limits().per_peer;
+        let all = json!({
+            "session-id": self.session_id,
+            "version": format!("{} (bittorrent-starter-rust)", env!("CARGO_PKG_VERSION")),
+            "rpc-version": RPC_VERSION,
+            "rpc-version-minimum": RPC_VERSION_MINIMUM,
+            "download-dir": self.download_dir.lock().unwrap().clone(),
+            "peer-port": config.identity.port,
+            "peer-limit-global": config.max_connections,
+            "peer-limit-per-torrent": config.max_peers,
+            "download-queue-enabled": true,
+            "download-queue-size": config.max_active_downloads,
+            "seed-queue-enabled": true,
+            "seed-queue-size": config.max_active_seeds,
+            "speed-limit-up": upload.kb,
+            "speed-limit-up-enabled": upload.enabled,
+            "speed-limit-down": download.kb,
+            "speed-limit-down-enabled": download.enabled,
+            // Not part of Transmission, but the closest it gets.
+            "peer-speed-limit-up": per_peer.bucket(Direction::Upload).rate() / SPEED_UNIT,
+            "peer-speed-limit-down": per_peer.bucket(Direction::Download).rate() / SPEED_UNIT,
+            "dht-enabled": config.dht,
+            "lpd-enabled": config.local_discovery,
+            "pex-enabled": true,
+            "units": {
+                "speed-units": ["kB/s", "MB/s", "GB/s", "TB/s"],
+                "speed-bytes": SPEED_UNIT,
+                "size-units": ["kB", "MB", "GB", "TB"],
+                "size-bytes": 1000,
+                "memory-units": ["KiB", "MiB", "GiB", "TiB"],
+                "memory-bytes": 1024,
+            },
+        });
+        select(all, fields.as_deref())
+    }
+
+    // Anything we cannot change on the fly is ignored, as Transmission
+    // ignores arguments it does not know.
+    fn session_set(&self, arguments: &Map<String, Value>) -> Result<()> {
+        if let Some(dir) = arguments.get("download-dir") {
+            let dir = dir.as_str().ok_or_else(|| anyhow!("bad download-dir"))?;
+            *self.download_dir.lock().unwrap() = dir.into();
+        }
+        let mut speed_limits = self.speed_limits.lock().unwrap();
+        let directions = [("up", Direction::Upload), ("down", Direction::Download)];
+        for (limit, (name, direction)) in speed_limits.iter_mut().zip(directions) {
+            if let Some(kb) = arguments.get(&format!("speed-limit-{name}")) {
+                limit.kb = kb
+                    .as_u64()
+                    .ok_or_else(|| anyhow!("bad speed-limit-{name}"))?;
+            }
+            if let Some(enabled) = arguments.get(&format!("speed-limit-{name}-enabled")) {
+                limit.enabled = enabled.as_bool().unwrap_or_default();
+            }
+            let rate = if limit.enabled {
+                limit.kb * SPEED_UNIT
+            } else {
+                0
+            };
+            let global = &self.session.limits().global;
+            global.bucket(direction).set_rate(rate);
+        }
+        Ok(())
+    }
+
+    async fn torrent_add(&self, arguments: &Map<String, Value>) -> Result<Value> {
+        let dir = match arguments.get("download-dir").and_then(Value::as_str) {
+            Some(dir) => PathBuf::from(dir),
+            None => self.download_dir.lock().unwrap().clone(),
+        };
+        let paused = arguments.get("paused") == Some(&Value::Bool(true));
+        let filename = arguments.get("filename").and_then(Value::as_str);
+        let metainfo = match (filename, arguments.get("metainfo")) {
+            (_, Some(metainfo)) => {
+                let metainfo = metainfo.as_str().ok_or_else(|| anyhow!("bad metainfo"))?;
+                TorrentFile::from_bytes(&decode_base64(metainfo)?)?
+            }
+            (Some(link), None) if link.starts_with("magnet:") => {
+                return self.add_magnet(Magnet::parse(link)?, dir, paused);
+            }
+            (Some(url), None) if url.starts_with("http://") || url.starts_with("https://") => {
+                let bytes = reqwest::get(url).await?.error_for_status()?.bytes().await?;
+                TorrentFile::from_bytes(&bytes)?
+            }
+            (Some(path), None) => TorrentFile::from_file(path)?,
+            (None, None) => bail!("no filename or metainfo"),
+        };
+
+        let info_hash = metainfo.info_hash()?;
+        if let Some(torrent) = self.session.torrent(&info_hash) {
+            return Ok(json!({ "torrent-duplicate": self.added(&Entry::Torrent(torrent)) }));
+        }
+        let torrent = self.session.add_torrent_in(metainfo, dir).await?;
+        if paused {
+            torrent.pause();
+        }
+        info!("Added {}", torrent.metainfo().info.name);
+        Ok(json!({ "torrent-added": self.added(&Entry::Torrent(torrent)) }))
+    }
+
+    fn add_magnet(&self, magnet: Magnet, dir: PathBuf, paused: bool) -> Result<Value> {
+        let info_hash = magnet.info_hash;
+        let known = self.session.torrent(&info_hash).map(Entry::Torrent);
+        let resolving = self.session.resolving().into_iter();
+        let known = known.or_else(|| {
+            let mut resolving = resolving.filter(|pending| pending.info_hash == info_hash);
+            resolving.next().map(Entry::Resolving)
+        });
+        if let Some(entry) = known {
+            return Ok(json!({ "torrent-duplicate": self.added(&entry) }));
+        }
+
+        let entry = Entry::Resolving(magnet.clone());
+        let session = self.session.clone();
+        tokio::spawn(async move {
+            match session.add_magnet(magnet, dir).await {
+                Ok(torrent) if paused => torrent.pause(),
+                Ok(_) => {}
+                Err(e) => error!("Could not add magnet link: {e}"),
+            }
+        });
+        Ok(json!({ "torrent-added": self.added(&entry) }))
+    }
+
+    // What torrent-add says about the torrent it added.
+    fn added(&self, entry: &Entry) -> Value {
+        let fields = ["id", "name", "hashString"].map(str::to_string);
+        self.torrent_fields(entry, Some(&fields))
+    }
+
+    fn id(&self, info_hash: [u8; 20]) -> usize {
+        let mut ids = self.ids.lock().unwrap();
+        match ids.iter().position(|&known| known == info_hash) {
+            Some(index) => index + 1,
+            None => {
+                ids.push(info_hash);
+                ids.len()
+            }
+        }
+    }
+
+    /// The torrents and pending magnet links `ids` picks, in queue order.
+    /// Without `ids`, all of them.
+    fn entries(&self, ids: Option<&Value>) -> Vec<Entry> {
+        let torrents = self.session.torrents().into_iter().map(Entry::Torrent);
+        let resolving = self.session.resolving().into_iter().map(Entry::Resolving);
+        let all = torrents.chain(resolving);
+        let wanted: Vec<Value> = match ids {
+            None => return all.collect(),
+            // We keep no record of recent activity, so everything counts.
+            Some(Value::String(recent)) if recent == "recently-active" => {
+                return all.collect();
+            }
+            Some(Value::Array(ids)) => ids.clone(),
+            Some(id) => vec![id.clone()],
+        };
+        let matches = |entry: &Entry| {
+            let info_hash = entry.info_hash();
+            wanted.iter().any(|id| match id {
+                Value::Number(id) => id.as_u64() == Some(self.id(info_hash) as u64),
+                Value::String(hash) => hash.eq_ignore_ascii_case(&hex::encode(info_hash)),
+                _ => false,
+            })
+        };
+        all.filter(matches).collect()
+    }
+
+    // Like `entries`, without magnet links still resolving, which none of
+    // the actions apply to.
+    fn torrents(&self, ids: Option<&Value>) -> Vec<Arc<Torrent>> {
+        let entries = self.entries(ids).into_iter();
+        entries
+            .filter_map(|entry| match entry {
+                Entry::Torrent(torrent) => Some(torrent),
+                Entry::Resolving(_) => None,
+            })
+            .collect()
+    }
+
+    fn torrent_fields(&self, entry: &Entry, fields: Option<&[String]>) -> Value {
+        let info_hash = entry.info_hash();
+        let torrent = match entry {
+            Entry::Torrent(torrent) => torrent,
+            Entry::Resolving(magnet) => {
+                let name = magnet
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| hex::encode(info_hash));
+                let all = json!({
+                    "id": self.id(info_hash),
+                    "name": name,
+                    "hashString": hex::encode(info_hash),
+                    "status": DOWNLOAD_WAIT,
+                    "error": 0,
+                    "errorString": "",
+                    "metadataPercentComplete": 0,
+                    "percentDone": 0,
+                    "totalSize": 0,
+                    "sizeWhenDone": 0,
+                    "leftUntilDone": 0,
+                    "haveValid": 0,
+                    "downloadDir": self.download_dir.lock().unwrap().clone(),
+                    "isFinished": false,
+                    "eta": -1,
+                });
+                return select(all, fields);
+            }
+        };
+
+        let info = &torrent.metainfo().info;
+        let size = info.size() as u64;
+        let have = torrent.verified_bytes();
+        let state = torrent.state();
+        let status = match state {
+            TorrentState::Queued if torrent.is_complete() => SEED_WAIT,
+            TorrentState::Queued => DOWNLOAD_WAIT,
+            TorrentState::Downloading => DOWNLOADING,
+            TorrentState::Seeding => SEEDING,
+            TorrentState::Paused | TorrentState::Stopped | TorrentState::Failed(_) => STOPPED,
+        };
+        let (error, error_string) = match &state {
+            TorrentState::Failed(reason) => (LOCAL_ERROR, reason.clone()),
+            _ => (0, String::new()),
+        };
+        let position = self
+            .session
+            .torrents()
+            .iter()
+            .position(|queued| queued.info_hash() == info_hash);
+        let uploaded = torrent.uploaded();
+        let (download_rate, upload_rate) = torrent.rates();
+        let eta = match size - have {
+            0 => 0,
+            _ if download_rate == 0 => -1,
+            left => (left / download_rate) as i64,
+        };
+        let all = json!({
+            "id": self.id(info_hash),
+            "name": info.name,
+            "hashString": hex::encode(info_hash),
+            "status": status,
+            "error": error,
+            "errorString": error_string,
+            "metadataPercentComplete": 1,
+            "percentDone": if size > 0 { have as f64 / size as f64 } else { 1.0 },
+            "totalSize": size,
+            "sizeWhenDone": size,
+            "leftUntilDone": size - have,
+            "haveValid": have,
+            "uploadedEver": uploaded,
+            "uploadRatio": if size > 0 { uploaded as f64 / size as f64 } else { 0.0 },
+            "rateDownload": download_rate,
+            "rateUpload": upload_rate,
+            "peersConnected": torrent.peers(),
+            "downloadDir": torrent.path().parent(),
+            "isFinished": false,
+            "isPrivate": info.is_private(),
+            "pieceCount": info.num_pieces(),
+            "pieceSize": info.piece_length,
+            "queuePosition": position,
+            "eta": eta,
+        });
+        select(all, fields)
+    }
+}
+
+impl Entry {
+    fn info_hash(&self) -> [u8; 20] {
+        match self {
+            Entry::Torrent(torrent) => torrent.info_hash(),
+            Entry::Resolving(magnet) => magnet.info_hash,
+        }
+    }
+}
+
+fn fields(arguments: &Map<String, Value>) -> Option<Vec<String>> {
+    let fields = arguments.get("fields")?.as_array()?;
+    Some(
+        fields
+            .iter()
+            .filter_map(|field| Some(field.as_str()?.to_string()))
+            .collect(),
+    )
+}
+
+// Keeps the asked-for fields of `all`, or every one if none were asked for.
+fn select(all: Value, fields: Option<&[String]>) -> Value {
+    let (Value::Object(all), Some(fields)) = (&all, fields) else {
+        return all;
+    };
+    let selected = all
+        .iter()
+        .filter(|(name, _)| fields.contains(name))
+        .map(|(name, value)| (name.clone(), value.clone()));
+    Value::Object(selected.collect())
+}
+
+/// Decodes standard base64, the way torrent-add sends .torrent files.
+/// Line breaks are allowed, as some clients wrap the output.
+fn decode_base64(encoded: &str) -> Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for byte in encoded.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            b'\r' | b'\n' | b' ' => continue,
+            _ => bail!("invalid base64"),
+        };
+        bits = bits << 6 | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Ok(decoded)
+}
+
+/// Checks that the server may listen on `addr`: anywhere but localhost,
+/// only with credentials.
+pub fn check_address(addr: SocketAddr, credentials: Option<&str>) -> Result<()> {
+    if !addr.ip().is_loopback() && credentials.is_none() {
+        bail!("Transmission RPC beyond localhost, on {addr}, needs credentials");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Identity, SessionConfig};
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("aGVs\nbG8h").unwrap(), b"hello!");
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert!(decode_base64("a*b").is_err());
+    }
+
+    // A server for a new session, and its URL.
+    async fn start(credentials: Option<&str>) -> String {
+        let session = Session::start(SessionConfig {
+            identity: Identity {
+                port: 0,
+                ..Identity::default()
+            },
+            local_discovery: false,
+            dht: false,
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}{PATH}", listener.local_addr().unwrap());
+        let credentials = credentials.map(str::to_string);
+        let server = TransmissionServer::new(Arc::new(session), PathBuf::from("."), credentials);
+        tokio::spawn(server.serve(listener));
+        url
+    }
+
+    #[tokio::test]
+    async fn requires_the_session_id() {
+        let url = start(None).await;
+        let client = reqwest::Client::new();
+        let call = json!({
+            "method": "session-set",
+            "arguments": { "speed-limit-down": 50, "speed-limit-down-enabled": true },
+            "tag": 7,
+        });
+        let refused = client.post(&url).json(&call).send().await.unwrap();
+        assert_eq!(refused.status(), 409);
+        let session_id = refused.headers()[SESSION_ID_HEADER].clone();
+
+        let post = |call: Value| {
+            let request = client.post(&url).header(SESSION_ID_HEADER, &session_id);
+            async move {
+                let reply = request.json(&call).send().await.unwrap();
+                reply.json::<Value>().await.unwrap()
+            }
+        };
+        let reply = post(call).await;
+        assert_eq!(reply["result"], "success");
+        assert_eq!(reply["tag"], 7);
+
+        let get = json!({
+            "method": "session-get",
+            "arguments": { "fields": ["speed-limit-down", "speed-limit-down-enabled"] },
+        });
+        let reply = post(get).await;
+        let expected = json!({ "speed-limit-down": 50, "speed-limit-down-enabled": true });
+        assert_eq!(reply["arguments"], expected);
+
+        let reply = post(json!({ "method": "torrent-get", "arguments": { "ids": [1] } })).await;
+        assert_eq!(reply["arguments"]["torrents"], json!([]));
+        let reply = post(json!({ "method": "blocklist-update" })).await;
+        assert_eq!(reply["result"], "method name not recognized");
+    }
+
+    #[tokio::test]
+    async fn requires_credentials_when_set() {
+        let url = start(Some("admin:secret")).await;
+        let client = reqwest::Client::new();
+        let status = |user: &str, password: &str| {
+            let request = client.post(&url).basic_auth(user, Some(password));
+            async move { request.send().await.unwrap().status() }
+        };
+        // Only the right credentials get as far as the session id.
+        assert_eq!(client.post(&url).send().await.unwrap().status(), 401);
+        assert_eq!(status("admin", "wrong").await, 401);
+        assert_eq!(status("admin", "secret").await, 409);
+    }
+
+    #[test]
+    fn listens_beyond_localhost_only_with_credentials() {
+        let local = SocketAddr::from(([127, 0, 0, 1], DEFAULT_PORT));
+        let any = SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT));
+        assert!(check_address(local, None).is_ok());
+        assert!(check_address(any, None).is_err());
+        assert!(check_address(any, Some("admin:secret")).is_ok());
+    }
+}
@@ -0,0 +1,164 @@
+//! Web seeds: HTTP servers with a copy of a torrent's data, which we
+//! download pieces from as if they were peers. GetRight-style seeds (BEP 19)
+//! serve the files themselves, fetched with range requests; Hoffman-style
+//! seeds (BEP 17) serve pieces by info hash and index.
+//!
+//! Like the rest of the crate, this handles single-file torrents only, so a
+//! GetRight-style seed is a single file; the directory layout BEP 19 gives
+//! multi-file torrents is not mapped.
+
+use std::{ops::Range, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::{header, RequestBuilder, StatusCode, Url};
+
+use crate::{tracker, TorrentFileInfo};
+
+/// How long one request may take, body included.
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a busy seed is left alone when it doesn't say.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+/// The longest a busy seed may ask us to wait.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Where a web seed at `base` keeps the file of torrent `name`: the URL
+/// itself, or the name below it if it ends in a slash.
+pub fn file_url(base: &str, name: &str) -> Result<Url> {
+    let mut url = Url::parse(base).with_context(|| format!("bad web seed URL {base:?}"))?;
+    if base.ends_with('/') {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| anyhow!("web seed URL {base:?} can't have a path"))?;
+        segments.pop_if_empty().push(name);
+    }
+    Ok(url)
+}
+
+/// Where a Hoffman-style seed at `base` serves `ranges` of piece `index`,
+/// as offsets into the piece; no ranges is the whole piece.
+pub fn piece_url(
+    base: &str,
+    info_hash: &[u8; 20],
+    index: usize,
+    ranges: &[Range<usize>],
+) -> Result<Url> {
+    let mut url = Url::parse(base).with_context(|| format!("bad HTTP seed URL {base:?}"))?;
+    let mut query = url
+        .query()
+        .map_or_else(String::new, |query| format!("{query}&"));
+    // The info hash is raw bytes, which `Url` would want as text.
+    query += &format!("info_hash={}&piece={index}", tracker::urlencode(info_hash));
+    if !ranges.is_empty() {
+        let ranges: Vec<_> = ranges
+            .iter()
+            .map(|range| format!("{}-{}", range.start, range.end - 1))
+            .collect();
+        query += &format!("&ranges={}", ranges.join(","));
+    }
+    url.set_query(Some(&query));
+    Ok(url)
+}
+
+/// Which protocol a web seed speaks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// BEP 19: the URL is the torrent's data, fetched by byte range.
+    GetRight,
+    /// BEP 17: the URL is a script serving the pieces of `info_hash`.
+    Hoffman { info_hash: [u8; 20] },
+}
+
+/// What a web seed made of a request for a piece.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Fetched {
+    /// The piece, which the caller still has to verify.
+    Piece(Vec<u8>),
+    /// The seed is busy and wants us to come back after this long.
+    RetryAfter(Duration),
+}
+
+pub struct WebSeed {
+    pub url: String,
+    pub style: Style,
+    client: reqwest::Client,
+}
+
+impl WebSeed {
+    pub fn new(url: impl Into<String>, style: Style) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+        Ok(WebSeed {
+            url: url.into(),
+            style,
+            client,
+        })
+    }
+
+    /// Downloads piece `index`.
+    pub async fn fetch_piece(&self, info: &TorrentFileInfo, index: usize) -> Result<Fetched> {
+        let length = info.piece_len(index);
+        if let Style::Hoffman { info_hash } = self.style {
+            let url = piece_url(&self.url, &info_hash, index, &[])?;
+            return fetch(self.client.get(url), StatusCode::OK, length).await;
+        }
+
+        let start = index * info.piece_length;
+        let url = file_url(&self.url, &info.name)?;
+        let range = format!("bytes={start}-{}", start + length - 1);
+        let request = self.client.get(url).header(header::RANGE, range);
+        // A server that ignores the range sends the whole file instead.
+        fetch(request, StatusCode::PARTIAL_CONTENT, length).await
+    }
+}
+
+// Sends `request`, expecting `length` bytes with status `expected`, or a 503
+// saying how long to wait in its Retry-After header or, for BEP 17, its body.
+async fn fetch(request: RequestBuilder, expected: StatusCode, length: usize) -> Result<Fetched> {
+    let response = request.send().await?;
+    let status = response.status();
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        let header = response.headers().get(header::RETRY_AFTER).cloned();
+        let body = response.text().await.unwrap_or_default();
+        let seconds = header
+            .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+            .or_else(|| body.trim().parse().ok());
+        let wait = seconds.map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+        return Ok(Fetched::RetryAfter(wait.min(MAX_RETRY_AFTER)));
+    }
+    if status != expected {
+        bail!("web seed answered with {status}");
+    }
+    let body = response.bytes().await?;
+    if body.len() != length {
+        bail!("web seed sent {} bytes for {length}", body.len());
+    }
+    Ok(Fetched::Piece(body.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_file_urls() {
+        let url = |base| file_url(base, "My #1").unwrap().to_string();
+        assert_eq!(url("http://host/data.iso"), "http://host/data.iso");
+        assert_eq!(url("http://host/pub/"), "http://host/pub/My%20%231");
+        assert!(file_url("not a url", "x").is_err());
+    }
+
+    #[test]
+    fn finds_piece_urls() {
+        let info_hash = [0xab; 20];
+        let hash = "%ab".repeat(20);
+        let url = piece_url("http://host/seed.php", &info_hash, 3, &[]).unwrap();
+        assert_eq!(
+            url.as_str(),
+            format!("http://host/seed.php?info_hash={hash}&piece=3")
+        );
+        let url = piece_url("http://host/s?key=1", &info_hash, 0, &[0..10, 20..30]).unwrap();
+        assert_eq!(
+            url.as_str(),
+            format!("http://host/s?key=1&info_hash={hash}&piece=0&ranges=0-9,20-29")
+        );
+    }
+}
@@ -0,0 +1,353 @@
+//! Downloads from fake peers, found through a fake tracker, and from fake
+//! web seeds: through the commands, and through a session.
+
+mod harness;
+
+use std::{sync::Arc, time::Duration};
+
+use bittorrent_starter_rust::{
+    download::{connect, download_piece, start_download},
+    events::EventKind,
+    peer::Timeouts,
+    pool::PeerPool,
+    Identity, Session, SessionConfig, TorrentState,
+};
+use serde_json::Value;
+use tokio::process::Command;
+
+use harness::{Behavior, FakePeer, FakeTracker, FakeWebSeed, SeedBehavior};
+
+const BINARY: &str = env!("CARGO_BIN_EXE_bittorrent-starter-rust");
+/// Five pieces, the last of them short.
+const LENGTH: usize = 4 * harness::PIECE_LENGTH + 1000;
+
+fn timeouts() -> Timeouts {
+    Timeouts {
+        connect: Duration::from_secs(2),
+        handshake: Duration::from_secs(2),
+        idle: Duration::from_secs(5),
+        snub: Duration::from_secs(1),
+    }
+}
+
+// Runs a command in `dir`, failing the test unless it succeeds, and
+// returns what it printed. The fake peers keep serving meanwhile.
+async fn run(dir: &std::path::Path, args: &[&str]) -> String {
+    let output = Command::new(BINARY)
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .await
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{args:?} failed: {stderr}");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[tokio::test]
+async fn handshake_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = harness::data(LENGTH);
+    let torrent = harness::torrent("handshake", &data, "http://127.0.0.1:1/announce");
+    let peer = FakePeer::start(&torrent, &data, Behavior::Seed).await;
+    let path = harness::write_torrent(dir.path(), &torrent);
+
+    let path = path.to_str().unwrap();
+    let printed = run(dir.path(), &["handshake", path, &peer.addr.to_string()]).await;
+    let peer_id = hex::encode(harness::peer::PEER_ID);
+    assert_eq!(printed, format!("Peer ID: {peer_id}\n"));
+}
+
+#[tokio::test]
+async fn download_piece_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let tracker = FakeTracker::start().await;
+    let data = harness::data(LENGTH);
+    let torrent = harness::torrent("piece", &data, &tracker.url);
+    let peer = FakePeer::start(&torrent, &data, Behavior::Seed).await;
+    tracker.add_peer(peer.addr);
+    let path = harness::write_torrent(dir.path(), &torrent);
+
+    // The last piece, which is shorter than the rest.
+    let args = [
+        "--json",
+        "download_piece",
+        "-o",
+        "last",
+        path.to_str().unwrap(),
+        "4",
+    ];
+    let printed: Value = serde_json::from_str(&run(dir.path(), &args).await).unwrap();
+    assert_eq!(printed["verified"], true);
+    assert_eq!(printed["length"], 1000);
+    let piece = std::fs::read(dir.path().join("last")).unwrap();
+    assert_eq!(piece, data[4 * harness::PIECE_LENGTH..]);
+
+    let past_the_end = ["download_piece", "-o", "x", path.to_str().unwrap(), "5"];
+    let output = Command::new(BINARY)
+        .args(past_the_end)
+        .current_dir(dir.path())
+        .output()
+        .await
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("piece 5 out of range (torrent has 5 pieces)"),
+        "{stderr}"
+    );
+}
+
+#[tokio::test]
+async fn download_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let tracker = FakeTracker::start().await;
+    let data = harness::data(LENGTH);
+    let torrent = harness::torrent("whole", &data, &tracker.url);
+    let peer = FakePeer::start(&torrent, &data, Behavior::Seed).await;
+    tracker.add_peer(peer.addr);
+    let path = harness::write_torrent(dir.path(), &torrent);
+
+    let path = path.to_str().unwrap();
+    // A limit too large to count in bytes is as good as none.
+    let unlimited = u64::MAX.to_string();
+    let args = [
+        "--json",
+        "-q",
+        "download",
+        "-p",
+        "0",
+        "--max-download-rate",
+        &unlimited,
+        "-o",
+        "out",
+        path,
+    ];
+    let printed: Value = serde_json::from_str(&run(dir.path(), &args).await).unwrap();
+    assert_eq!(printed["downloaded"], LENGTH);
+    assert_eq!(std::fs::read(dir.path().join("out")).unwrap(), data);
+
+    // A slow download may also announce for more peers, without an event.
+    let announces = tracker.announces().into_iter();
+    let events: Vec<_> = announces.filter_map(|announce| announce.event).collect();
+    assert_eq!(events, ["started", "completed"]);
+    assert_eq!(tracker.announces()[0].left, LENGTH as u64);
+}
+
+#[tokio::test]
+async fn download_command_verifies_v2_pieces() {
+    let dir = tempfile::tempdir().unwrap();
+    let tracker = FakeTracker::start().await;
+    let data = harness::data(LENGTH);
+    let torrent = harness::torrent_v2("v2", &data, &tracker.url);
+    // Peers swarm under the SHA-256 info hash, cut to 20 bytes.
+    let mut peers = Vec::new();
+    for behavior in [Behavior::Corrupt, Behavior::Seed] {
+        let peer = FakePeer::start(&torrent, &data, behavior).await;
+        tracker.add_peer(peer.addr);
+        peers.push(peer);
+    }
+    let path = harness::write_torrent(dir.path(), &torrent);
+
+    let path = path.to_str().unwrap();
+    let args = ["--json", "-q", "download", "-p", "0", "-o", "out", path];
+    let printed: Value = serde_json::from_str(&run(dir.path(), &args).await).unwrap();
+    assert_eq!(printed["downloaded"], LENGTH);
+    assert_eq!(std::fs::read(dir.path().join("out")).unwrap(), data);
+}
+
+#[tokio::test]
+async fn piece_from_a_peer() {
+    harness::quiet_logs();
+    let data = harness::data(LENGTH);
+    let torrent = harness::torrent("direct", &data, "http://127.0.0.1:1/announce");
+    let info_hash = torrent.info_hash().unwrap();
+    let pool = PeerPool::new().shared();
+    let identity = Identity::default();
+
+    let peer = FakePeer::start(&torrent, &data, Behavior::Seed).await;
+    let mut stream = connect(&torrent, info_hash, peer.addr, &pool, identity, timeouts())
+        .await
+        .unwrap();
+    assert_eq!(stream.handshake.peer_id, harness::peer::PEER_ID);
+    assert!(!stream.handshake.supports_extensions());
+    start_download(&mut stream).await.unwrap();
+    let piece = download_piece(&mut stream, &torrent.info, 1).await.unwrap();
+    let range = harness::PIECE_LENGTH..2 * harness::PIECE_LENGTH;
+    assert_eq!(piece.as_deref(), Some(&data[range]));
+
+    let choking = FakePeer::start(&torrent, &data, Behavior::Choke).await;
+    let mut stream = connect(
+        &torrent,
+        info_hash,
+        choking.addr,
+        &pool,
+        identity,
+        timeouts(),
+    )
+    .await
+    .unwrap();
+    let e = start_download(&mut stream).await.unwrap_err();
+    assert!(e.to_string().contains("kept us choked"), "{e}");
+
+    let liar = FakePeer::start(&torrent, &data, Behavior::WrongInfoHash).await;
+    let connected = connect(&torrent, info_hash, liar.addr, &pool, identity, timeouts()).await;
+    let e = connected.err().unwrap();
+    assert!(e.to_string().contains("different info hash"), "{e}");
+}
+
+async fn start_session() -> Session {
+    harness::quiet_logs();
+    Session::start(SessionConfig {
+        identity: Identity {
+            port: 0,
+            ..Identity::default()
+        },
+        timeouts: timeouts(),
+        local_discovery: false,
+        dht: false,
+        ..SessionConfig::default()
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn session_gets_around_bad_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let tracker = FakeTracker::start().await;
+    let data = harness::data(LENGTH);
+    let torrent = harness::torrent("around", &data, &tracker.url);
+    let behaviors = [
+        Behavior::Corrupt,
+        Behavior::Oversize,
+        Behavior::Disconnect { after_blocks: 1 },
+        Behavior::Choke,
+        Behavior::WrongInfoHash,
+        Behavior::Seed,
+    ];
+    let mut peers = Vec::new();
+    for behavior in behaviors {
+        let peer = FakePeer::start(&torrent, &data, behavior).await;
+        tracker.add_peer(peer.addr);
+        peers.push(peer);
+    }
+
+    let session = start_session().await;
+    let mut events = session.events();
+    let output = dir.path().join("around");
+    let added = session.add_torrent(torrent, &output).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(30), added.completed())
+        .await
+        .expect("download never finished")
+        .unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+
+    let corrupt = peers[0].addr;
+    let mut failed = false;
+    while let Ok(event) = events.try_recv() {
+        if let EventKind::PieceFailed { peer, .. } = event.kind {
+            assert_eq!(peer, corrupt);
+            failed = true;
+        }
+    }
+    assert!(failed || peers[0].blocks_sent() == 0);
+    assert!(peers.iter().all(|peer| peer.connections() > 0));
+}
+
+#[tokio::test]
+async fn session_reports_a_refusing_tracker() {
+    let dir = tempfile::tempdir().unwrap();
+    let tracker = FakeTracker::start().await;
+    tracker.fail_with("unregistered torrent");
+    let data = harness::data(LENGTH);
+    let torrent = harness::torrent("refused", &data, &tracker.url);
+
+    let session = Arc::new(start_session().await);
+    let mut events = session.events();
+    let added = session
+        .add_torrent(torrent, dir.path().join("refused"))
+        .await
+        .unwrap();
+    let e = tokio::time::timeout(Duration::from_secs(10), added.completed())
+        .await
+        .expect("download never gave up")
+        .unwrap_err();
+    assert!(e.to_string().contains("ran out of peers"), "{e}");
+    assert!(matches!(added.state(), TorrentState::Failed(_)));
+
+    let mut reported = None;
+    while let Ok(event) = events.try_recv() {
+        if let EventKind::TrackerFailed { error, .. } = event.kind {
+            reported = Some(error);
+        }
+    }
+    let reported = reported.expect("no tracker failure reported");
+    assert!(reported.contains("unregistered torrent"), "{reported}");
+}
+
+#[tokio::test]
+async fn session_downloads_from_web_seeds() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = harness::data(LENGTH);
+    let mut torrent = harness::torrent("mirrored", &data, "http://127.0.0.1:1/announce");
+    let broken = FakeWebSeed::start("mirrored", &data, SeedBehavior::Fail).await;
+    let mirror = FakeWebSeed::start("mirrored", &data, SeedBehavior::Serve).await;
+    torrent.url_list = vec![broken.url.clone(), mirror.url.clone()];
+
+    let session = start_session().await;
+    let output = dir.path().join("mirrored");
+    let added = session.add_torrent(torrent, &output).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(30), added.completed())
+        .await
+        .expect("download never finished")
+        .unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    assert!(mirror.requests() > 0);
+    assert!(broken.requests() > 0);
+}
+
+#[tokio::test]
+async fn session_falls_back_to_peers_from_a_corrupt_web_seed() {
+    let dir = tempfile::tempdir().unwrap();
+    let tracker = FakeTracker::start().await;
+    let data = harness::data(LENGTH);
+    let mut torrent = harness::torrent("fallback", &data, &tracker.url);
+    let corrupt = FakeWebSeed::start("fallback", &data, SeedBehavior::Corrupt).await;
+    torrent.url_list = vec![corrupt.url.clone()];
+    let peer = FakePeer::start(&torrent, &data, Behavior::Seed).await;
+    tracker.add_peer(peer.addr);
+
+    let session = start_session().await;
+    let output = dir.path().join("fallback");
+    let added = session.add_torrent(torrent, &output).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(30), added.completed())
+        .await
+        .expect("download never finished")
+        .unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    // One corrupt piece is enough to give up on the seed.
+    assert_eq!(corrupt.requests(), 1);
+    assert!(peer.blocks_sent() > 0);
+}
+
+#[tokio::test]
+async fn session_waits_for_busy_http_seeds() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = harness::data(LENGTH);
+    let mut torrent = harness::torrent("seeded", &data, "http://127.0.0.1:1/announce");
+    let busy = SeedBehavior::Busy { times: 2 };
+    let seed = FakeWebSeed::start_hoffman(&torrent, &data, busy).await;
+    torrent.httpseeds = vec![seed.url.clone()];
+
+    let session = start_session().await;
+    let output = dir.path().join("seeded");
+    let added = session.add_torrent(torrent, &output).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(30), added.completed())
+        .await
+        .expect("download never finished")
+        .unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    // Five pieces, after being turned away twice.
+    assert_eq!(seed.requests(), 7);
+}
@@ -0,0 +1,108 @@
+//! Stand-ins for a tracker, peers and web seeds on localhost, so that
+//! downloads can be tested without the network.
+//!
+//! The tracker speaks HTTP only, as the client has no UDP tracker support.
+//! The peers speak the plain wire protocol, without extensions, from their
+//! own encoding rather than the crate's, and can be told to misbehave, as
+//! can the web seeds.
+
+// Each test file uses its own part of the harness.
+#![allow(dead_code, unused_imports)]
+
+pub mod peer;
+pub mod tracker;
+pub mod webseed;
+
+use std::path::{Path, PathBuf};
+
+use bittorrent_starter_rust::{merkle, TorrentFile, TorrentFileInfo};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+
+pub use peer::{Behavior, FakePeer};
+pub use tracker::FakeTracker;
+pub use webseed::{FakeWebSeed, SeedBehavior};
+
+/// Turns off the crate's messages for tests that run it in-process: they
+/// go straight to stderr, where libtest can't capture them.
+pub fn quiet_logs() {
+    bittorrent_starter_rust::log::init(None, None, None).unwrap();
+}
+
+/// The length of the pieces of [`torrent`]s: two blocks, so that pieces
+/// take more than one request.
+pub const PIECE_LENGTH: usize = 32 * 1024;
+
+/// `len` bytes that differ from piece to piece.
+pub fn data(len: usize) -> Vec<u8> {
+    let mut state: u32 = 0x2545_f491;
+    (0..len)
+        .map(|_| {
+            // xorshift32
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// A torrent of `data` called `name`, announced to `tracker`.
+pub fn torrent(name: &str, data: &[u8], tracker: &str) -> TorrentFile {
+    let pieces = data.chunks(PIECE_LENGTH).flat_map(Sha1::digest);
+    TorrentFile {
+        announce: tracker.to_string(),
+        info: TorrentFileInfo {
+            length: data.len(),
+            name: name.to_string(),
+            piece_length: PIECE_LENGTH,
+            pieces: ByteBuf::from(pieces.collect::<Vec<u8>>()),
+            private: None,
+            ..Default::default()
+        },
+        url_list: Vec::new(),
+        httpseeds: Vec::new(),
+        piece_layers: Default::default(),
+    }
+}
+
+/// A v2-only torrent (BEP 52) of `data` called `name`, announced to
+/// `tracker`.
+pub fn torrent_v2(name: &str, data: &[u8], tracker: &str) -> TorrentFile {
+    let layer: Vec<merkle::Hash> = data
+        .chunks(PIECE_LENGTH)
+        .map(|piece| merkle::piece_hash(piece, PIECE_LENGTH))
+        .collect();
+    let root = merkle::file_root(&layer, PIECE_LENGTH);
+    let file = Value::Dict(
+        [
+            (b"length".to_vec(), Value::Int(data.len() as i64)),
+            (b"pieces root".to_vec(), Value::Bytes(root.to_vec())),
+        ]
+        .into(),
+    );
+    let node = Value::Dict([(Vec::new(), file)].into());
+    let torrent = TorrentFile {
+        announce: tracker.to_string(),
+        info: TorrentFileInfo {
+            name: name.to_string(),
+            piece_length: PIECE_LENGTH,
+            meta_version: Some(2),
+            file_tree: Some(Value::Dict([(name.as_bytes().to_vec(), node)].into())),
+            ..Default::default()
+        },
+        url_list: Vec::new(),
+        httpseeds: Vec::new(),
+        piece_layers: [(ByteBuf::from(root), ByteBuf::from(layer.concat()))].into(),
+    };
+    // Reading it back checks it, and finds its file.
+    TorrentFile::from_bytes(&serde_bencode::to_bytes(&torrent).unwrap()).unwrap()
+}
+
+/// Saves `torrent` as a .torrent file in `dir`, for the commands.
+pub fn write_torrent(dir: &Path, torrent: &TorrentFile) -> PathBuf {
+    let path = dir.join(format!("{}.torrent", torrent.info.name));
+    std::fs::write(&path, serde_bencode::to_bytes(torrent).unwrap()).unwrap();
+    path
+}
@@ -0,0 +1,177 @@
+//! Peers that have a whole torrent and serve it, more or less faithfully.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use bittorrent_starter_rust::TorrentFile;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// What fake peers call themselves.
+pub const PEER_ID: [u8; 20] = *b"-FK0100-fakepeer0000";
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+
+/// How a fake peer treats the peers that connect to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Behavior {
+    /// Serves every block it is asked for.
+    Seed,
+    /// Never unchokes, so nothing can be requested.
+    Choke,
+    /// Serves blocks with their first byte flipped.
+    Corrupt,
+    /// Serves blocks with a byte too many, running past their piece.
+    Oversize,
+    /// Hangs up after serving this many blocks on a connection.
+    Disconnect { after_blocks: usize },
+    /// Answers the handshake for another torrent.
+    WrongInfoHash,
+}
+
+#[derive(Default)]
+struct Stats {
+    connections: AtomicUsize,
+    blocks_sent: AtomicUsize,
+}
+
+struct Served {
+    info_hash: [u8; 20],
+    piece_length: usize,
+    pieces: usize,
+    data: Vec<u8>,
+    behavior: Behavior,
+    stats: Stats,
+}
+
+pub struct FakePeer {
+    pub addr: SocketAddr,
+    served: Arc<Served>,
+}
+
+impl FakePeer {
+    /// Starts serving `data`, the whole of `torrent`.
+    pub async fn start(torrent: &TorrentFile, data: &[u8], behavior: Behavior) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = Arc::new(Served {
+            info_hash: torrent.info_hash().unwrap(),
+            piece_length: torrent.info.piece_length,
+            pieces: torrent.info.num_pieces(),
+            data: data.to_vec(),
+            behavior,
+            stats: Stats::default(),
+        });
+        let serving = served.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let served = serving.clone();
+                // A connection that breaks is the client's business.
+                tokio::spawn(async move { served.serve(stream).await });
+            }
+        });
+        FakePeer { addr, served }
+    }
+
+    /// Peers that have connected so far.
+    pub fn connections(&self) -> usize {
+        self.served.stats.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn blocks_sent(&self) -> usize {
+        self.served.stats.blocks_sent.load(Ordering::Relaxed)
+    }
+}
+
+impl Served {
+    async fn serve(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        self.stats.connections.fetch_add(1, Ordering::Relaxed);
+        let mut handshake = [0; 68];
+        stream.read_exact(&mut handshake).await?;
+        if handshake[0] as usize != PROTOCOL.len()
+            || &handshake[1..20] != PROTOCOL
+            || handshake[28..48] != self.info_hash
+        {
+            return Ok(());
+        }
+        let mut info_hash = self.info_hash;
+        if self.behavior == Behavior::WrongInfoHash {
+            info_hash[0] ^= 0xff;
+        }
+        let mut reply = vec![PROTOCOL.len() as u8];
+        reply.extend(PROTOCOL);
+        // No extensions, so the client sticks to the base protocol.
+        reply.extend([0; 8]);
+        reply.extend(info_hash);
+        reply.extend(PEER_ID);
+        stream.write_all(&reply).await?;
+
+        let mut bitfield = vec![0u8; self.pieces.div_ceil(8)];
+        for piece in 0..self.pieces {
+            bitfield[piece / 8] |= 0x80 >> (piece % 8);
+        }
+        send(&mut stream, BITFIELD, &bitfield).await?;
+
+        let mut sent_here = 0;
+        loop {
+            let length = stream.read_u32().await? as usize;
+            if length == 0 {
+                continue;
+            }
+            let mut message = vec![0; length];
+            stream.read_exact(&mut message).await?;
+            match (message[0], &message[1..]) {
+                (INTERESTED, _) if self.behavior == Behavior::Choke => {
+                    send(&mut stream, CHOKE, &[]).await?;
+                }
+                (INTERESTED, _) => send(&mut stream, UNCHOKE, &[]).await?,
+                (REQUEST, request) if request.len() == 12 && self.behavior != Behavior::Choke => {
+                    if let Behavior::Disconnect { after_blocks } = self.behavior {
+                        if sent_here == after_blocks {
+                            return Ok(());
+                        }
+                    }
+                    let field = |at: usize| {
+                        u32::from_be_bytes(request[at..at + 4].try_into().unwrap()) as usize
+                    };
+                    let (index, begin, length) = (field(0), field(4), field(8));
+                    let start = index * self.piece_length + begin;
+                    let Some(block) = self.data.get(start..start + length) else {
+                        return Ok(());
+                    };
+                    let mut piece = request[..8].to_vec();
+                    piece.extend(block);
+                    match self.behavior {
+                        Behavior::Corrupt => piece[8] ^= 0xff,
+                        Behavior::Oversize => piece.push(0),
+                        _ => {}
+                    }
+                    send(&mut stream, PIECE, &piece).await?;
+                    sent_here += 1;
+                    self.stats.blocks_sent.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn send(stream: &mut TcpStream, id: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut message = (payload.len() as u32 + 1).to_be_bytes().to_vec();
+    message.push(id);
+    message.extend(payload);
+    stream.write_all(&message).await
+}
@@ -0,0 +1,139 @@
+//! An HTTP tracker that hands out whichever peers the test gives it and
+//! remembers every announce.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use bittorrent_starter_rust::http::{self, Request, Response};
+use tokio::net::TcpListener;
+
+/// What a client told the tracker.
+#[derive(Clone, Debug)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub left: u64,
+    /// `started`, `completed` or `stopped`, or none for a regular announce.
+    pub event: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    peers: Vec<SocketAddr>,
+    failure: Option<String>,
+    announces: Vec<Announce>,
+}
+
+pub struct FakeTracker {
+    /// The announce URL.
+    pub url: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeTracker {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+        let answering = state.clone();
+        tokio::spawn(http::serve(listener, move |request| {
+            let response = answer(&answering, &request);
+            async move { response }
+        }));
+        FakeTracker { url, state }
+    }
+
+    /// Hands `peer` out from now on.
+    pub fn add_peer(&self, peer: SocketAddr) {
+        self.state.lock().unwrap().peers.push(peer);
+    }
+
+    /// Answers every announce with `reason` as its failure.
+    pub fn fail_with(&self, reason: &str) {
+        self.state.lock().unwrap().failure = Some(reason.to_string());
+    }
+
+    pub fn announces(&self) -> Vec<Announce> {
+        self.state.lock().unwrap().announces.clone()
+    }
+}
+
+fn answer(state: &Mutex<State>, request: &Request) -> Response {
+    let Some(("/announce", query)) = request.path.split_once('?') else {
+        return Response::text(404, "not found\n");
+    };
+    let Some(announce) = parse_announce(query) else {
+        return bencoded(failure("malformed announce"));
+    };
+    let mut state = state.lock().unwrap();
+    state.announces.push(announce);
+    if let Some(reason) = &state.failure {
+        return bencoded(failure(reason));
+    }
+
+    let mut peers = Vec::new();
+    for peer in &state.peers {
+        let SocketAddr::V4(peer) = peer else {
+            continue;
+        };
+        peers.extend(peer.ip().octets());
+        peers.extend(peer.port().to_be_bytes());
+    }
+    let mut body = b"d8:intervali60e5:peers".to_vec();
+    body.extend(format!("{}:", peers.len()).as_bytes());
+    body.extend(peers);
+    body.push(b'e');
+    bencoded(body)
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    format!("d14:failure reason{}:{reason}e", reason.len()).into_bytes()
+}
+
+fn bencoded(body: Vec<u8>) -> Response {
+    Response::new(200, "text/plain", body)
+}
+
+fn parse_announce(query: &str) -> Option<Announce> {
+    let mut info_hash = None;
+    let mut peer_id = None;
+    let (mut port, mut left, mut event) = (None, None, None);
+    for pair in query.split('&') {
+        let (key, value) = pair.split_once('=')?;
+        let value = percent_decode(value)?;
+        match key {
+            "info_hash" => info_hash = Some(value.try_into().ok()?),
+            "peer_id" => peer_id = Some(value.try_into().ok()?),
+            "port" => port = String::from_utf8(value).ok()?.parse().ok(),
+            "left" => left = String::from_utf8(value).ok()?.parse().ok(),
+            "event" => event = Some(String::from_utf8(value).ok()?),
+            _ => {}
+        }
+    }
+    Some(Announce {
+        info_hash: info_hash?,
+        peer_id: peer_id?,
+        port: port?,
+        left: left?,
+        event,
+    })
+}
+
+fn percent_decode(value: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        decoded.push(match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            b'+' => b' ',
+            byte => byte,
+        });
+    }
+    Some(decoded)
+}
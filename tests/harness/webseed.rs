@@ -0,0 +1,143 @@
+//! HTTP servers with a torrent's data, answering as web seeds, more or less
+//! faithfully: GetRight-style ones serve the file by byte range, and
+//! Hoffman-style ones serve pieces by info hash and index.
+
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use bittorrent_starter_rust::{
+    http::{self, Request, Response},
+    TorrentFile,
+};
+use tokio::net::TcpListener;
+
+/// How a fake web seed answers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeedBehavior {
+    /// Serves everything it is asked for.
+    Serve,
+    /// Serves data with its first byte flipped.
+    Corrupt,
+    /// Answers every request with a server error.
+    Fail,
+    /// Answers this many requests with a 503 asking to come back in a
+    /// second, then serves.
+    Busy { times: usize },
+}
+
+pub struct FakeWebSeed {
+    /// The URL to list in the torrent's `url-list` or `httpseeds`.
+    pub url: String,
+    requests: Arc<AtomicUsize>,
+}
+
+// What a seed serves, and how.
+struct Served {
+    data: Vec<u8>,
+    behavior: SeedBehavior,
+    /// The status of a successful answer.
+    status: u16,
+    requests: Arc<AtomicUsize>,
+}
+
+impl FakeWebSeed {
+    /// Serves `data` as the file `name`, at a URL ending in a slash, for
+    /// the torrent's `url-list`.
+    pub async fn start(name: &str, data: &[u8], behavior: SeedBehavior) -> Self {
+        let path = format!("/files/{name}");
+        Self::serve(data, behavior, "/files/", 206, move |request, _| {
+            if request.route() != path {
+                return None;
+            }
+            let range = request.header("Range")?.strip_prefix("bytes=")?;
+            let (first, last) = range.split_once('-')?;
+            Some(first.parse().ok()?..last.parse::<usize>().ok()? + 1)
+        })
+        .await
+    }
+
+    /// Serves the pieces of `torrent`, whose data is `data`, for the
+    /// torrent's `httpseeds`.
+    pub async fn start_hoffman(torrent: &TorrentFile, data: &[u8], behavior: SeedBehavior) -> Self {
+        let info_hash = torrent.info_hash().unwrap();
+        let piece_length = torrent.info.piece_length;
+        Self::serve(data, behavior, "/seed", 200, move |request, length| {
+            if request.route() != "/seed" {
+                return None;
+            }
+            let query = request.query();
+            let param = |name: &str| {
+                let value = query.iter().find(|(key, _)| key == name)?;
+                Some(value.1.as_slice())
+            };
+            if param("info_hash")? != info_hash {
+                return None;
+            }
+            let piece: usize = std::str::from_utf8(param("piece")?).ok()?.parse().ok()?;
+            // The whole piece; the client asks for no ranges.
+            let start = piece * piece_length;
+            Some(start..length.min(start + piece_length))
+        })
+        .await
+    }
+
+    // Answers requests on a new server at `path` with the range of `data`
+    // that `range` finds in them, if any, and `status`.
+    async fn serve<F>(
+        data: &[u8],
+        behavior: SeedBehavior,
+        path: &str,
+        status: u16,
+        range: F,
+    ) -> Self
+    where
+        F: Fn(&Request, usize) -> Option<Range<usize>> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}{path}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = Arc::new(Served {
+            data: data.to_vec(),
+            behavior,
+            status,
+            requests: requests.clone(),
+        });
+        tokio::spawn(http::serve(listener, move |request| {
+            let response = served.answer(range(&request, served.data.len()));
+            async move { response }
+        }));
+        FakeWebSeed { url, requests }
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+}
+
+impl Served {
+    fn answer(&self, range: Option<Range<usize>>) -> Response {
+        let seen = self.requests.fetch_add(1, Ordering::Relaxed);
+        match self.behavior {
+            SeedBehavior::Fail => return Response::text(500, "broken\n"),
+            SeedBehavior::Busy { times } if seen < times => {
+                return Response::text(503, "1");
+            }
+            _ => {}
+        }
+        let Some(mut block) = range
+            .and_then(|range| self.data.get(range))
+            .map(<[u8]>::to_vec)
+        else {
+            return Response::text(404, "not found\n");
+        };
+        if self.behavior == SeedBehavior::Corrupt {
+            block[0] ^= 0xff;
+        }
+        Response::new(self.status, "application/octet-stream", block)
+    }
+}
@@ -0,0 +1,74 @@
+//! The `tracker` command, with a download that finds its peer through it.
+
+mod harness;
+
+use std::process::Stdio;
+
+use bittorrent_starter_rust::{
+    tracker::{announce_to, Event},
+    Identity,
+};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+};
+
+use harness::{Behavior, FakePeer};
+
+const BINARY: &str = env!("CARGO_BIN_EXE_bittorrent-starter-rust");
+
+#[tokio::test]
+async fn downloads_through_the_tracker_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("tracker.dat");
+    let mut tracker = Command::new(BINARY)
+        .args([
+            "--json",
+            "-q",
+            "tracker",
+            "--listen",
+            "127.0.0.1:0",
+            "--state",
+        ])
+        .arg(&state)
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let stdout = tracker.stdout.take().unwrap();
+    let mut lines = BufReader::new(stdout).lines();
+    let listening: Value =
+        serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+    let url = listening["announce"].as_str().unwrap().to_string();
+    assert!(listening["scrape"].as_str().unwrap().ends_with("/scrape"));
+
+    let data = harness::data(3 * harness::PIECE_LENGTH);
+    let torrent = harness::torrent("tracked", &data, &url);
+    let seed = FakePeer::start(&torrent, &data, Behavior::Seed).await;
+    // Fake peers don't announce, so announce for the seed.
+    let identity = Identity {
+        peer_id: harness::peer::PEER_ID,
+        port: seed.addr.port(),
+    };
+    let info_hash = torrent.info_hash().unwrap();
+    announce_to(&url, &info_hash, identity, 0, Some(Event::Started))
+        .await
+        .unwrap();
+
+    let path = harness::write_torrent(dir.path(), &torrent);
+    let output = Command::new(BINARY)
+        .args(["-q", "download", "-p", "0", "-o", "out"])
+        .arg(path)
+        .current_dir(dir.path())
+        .output()
+        .await
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(std::fs::read(dir.path().join("out")).unwrap(), data);
+    assert!(seed.blocks_sent() > 0);
+}